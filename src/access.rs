@@ -0,0 +1,432 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::audit::record_call;
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_ELEVATION_SECS: u64 = 12 * 60 * 60; // Longest a temporary elevation may last
+
+/// Staff roles, ordered from least to most authority
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    Clerk,   // Day-to-day till and shelf work
+    Manager, // Store configuration and integrations
+    Owner,   // Full control, including staff roles
+}
+
+/// An operation that can be granted to a principal on its own, without the role that carries it
+///
+/// Every update endpoint checks a permission or a role, except the few open to any caller,
+/// whose comments say why.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Permission {
+    AddItems,     // Add or replace single items
+    AdjustStock,  // Set stock levels by hand, in store or per warehouse
+    ReceiveStock, // Book deliveries in at cost
+    CountStock,   // Submit stocktake counts
+    Stocktake,    // Start, finalize and cancel stocktakes and decide their variances
+    ChangePrices, // Set prices in bulk, per category or per channel
+    RemoveItems,  // Remove, archive and restore items
+    RecordSales,  // Record sales at a till
+    RecordWaste,  // Write stock off
+    SyncLabels,   // Acknowledge shelf label updates, as an ESL gateway does
+}
+
+impl Permission {
+    pub const ALL: [Permission; 10] = [
+        Permission::AddItems,
+        Permission::AdjustStock,
+        Permission::ReceiveStock,
+        Permission::CountStock,
+        Permission::Stocktake,
+        Permission::ChangePrices,
+        Permission::RemoveItems,
+        Permission::RecordSales,
+        Permission::RecordWaste,
+        Permission::SyncLabels,
+    ];
+
+    /// The least role that carries the permission without a grant
+    pub fn minimum_role(&self) -> Role {
+        match self {
+            Permission::Stocktake | Permission::ChangePrices => Role::Manager,
+            _ => Role::Clerk,
+        }
+    }
+}
+
+/// Which role carries each permission, and who holds permissions granted on their own
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PermissionMatrix {
+    pub roles: Vec<(Permission, Role)>,            // The least role carrying each permission
+    pub grants: Vec<(Principal, Vec<Permission>)>, // Permissions granted to principals regardless of their role
+}
+
+/// A temporary raise of a staff member's role
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Elevation {
+    pub id: u64,               // Sequential ID, used to tag actions taken under the elevation
+    pub principal: Principal,  // Staff member whose role is raised
+    pub role: Role,            // Role held while the elevation lasts
+    pub granted_by: Principal, // Manager who granted it
+    pub reason: String,        // Why it was granted, e.g. "refund authority for the evening shift"
+    pub expires_at: u64,       // End of the elevation in nanoseconds since the Unix epoch
+}
+
+/// Roles assigned to staff principals
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct AccessControl {
+    pub roles: HashMap<Principal, Role>,                  // Role keyed by staff principal
+    pub elevations: HashMap<Principal, Elevation>,        // Temporary elevations keyed by staff principal
+    pub next_elevation_id: u64,                           // ID handed to the next elevation
+    pub test_principals: HashSet<Principal>,              // Principals allowed to record test sales
+    pub grants: HashMap<Principal, BTreeSet<Permission>>, // Permissions granted on their own, by principal
+}
+
+impl AccessControl {
+    /// Role held by a principal, if any
+    pub fn role_of(&self, principal: &Principal) -> Option<Role> {
+        self.roles.get(principal).copied()
+    }
+
+    /// Elevation in force for a principal at `now`, if any
+    pub fn active_elevation(&self, principal: &Principal, now: u64) -> Option<&Elevation> {
+        self.elevations.get(principal).filter(|elevation| elevation.expires_at > now)
+    }
+
+    /// Role a principal acts with at `now`: their own role, or a higher one while elevated
+    pub fn effective_role(&self, principal: &Principal, now: u64) -> Option<Role> {
+        let elevated = self.active_elevation(principal, now).map(|elevation| elevation.role);
+        self.role_of(principal).max(elevated)
+    }
+
+    /// Whether a principal was granted a permission on its own
+    pub fn is_granted(&self, principal: &Principal, permission: Permission) -> bool {
+        self.grants.get(principal).is_some_and(|permissions| permissions.contains(&permission))
+    }
+
+    /// Makes a principal the owner, demoting the previous owner to manager
+    /// - `new_owner`: The principal taking over the store
+    pub fn transfer_owner(&mut self, new_owner: Principal) {
+        for role in self.roles.values_mut().filter(|role| **role == Role::Owner) {
+            *role = Role::Manager;
+        }
+        self.roles.insert(new_owner, Role::Owner);
+    }
+
+    /// Checks that a principal holds at least the given role, counting active elevations
+    /// - `principal`: The principal making the call
+    /// - `minimum`: The least role allowed to perform the action
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn require(&self, principal: &Principal, minimum: Role, now: u64) -> Result<(), InventoryError> {
+        match self.effective_role(principal, now) {
+            Some(role) if role >= minimum => Ok(()),
+            _ => Err(InventoryError::Unauthorized {
+                msg: format!("{} requires the {:?} role", principal, minimum),
+            }),
+        }
+    }
+}
+
+/// Checks that the caller of the current message holds at least the given role and is not suspended,
+/// recording the call in the access audit
+/// - `endpoint`: Name of the endpoint being called
+///
+/// A call that is only allowed because of a temporary elevation is tagged in the log.
+pub fn require_caller(endpoint: &str, minimum: Role) -> Result<(), InventoryError> {
+    record_call(endpoint, check_caller(minimum))
+}
+
+/// Checks the caller's role like `require_caller`, without recording the call
+pub fn check_caller(minimum: Role) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.audit.ensure_not_suspended(&caller, now)?;
+        inventory.access.require(&caller, minimum, now)?;
+        if inventory.access.role_of(&caller).is_none_or(|role| role < minimum) {
+            let elevation = inventory.access.active_elevation(&caller, now).map(|elevation| elevation.id);
+            let log = format!(
+                "{} acted with {:?} authority under elevation {} at {}",
+                caller,
+                minimum,
+                elevation.unwrap_or_default(),
+                SupermarketManager::get_current_time()
+            );
+            inventory.logs.push(log);
+        }
+        Ok(())
+    })
+}
+
+/// Checks that the caller of the current message may perform an operation, either through a
+/// grant of its permission or by holding the role that carries it, and is not suspended,
+/// recording the call in the access audit
+pub fn require_permission(endpoint: &str, permission: Permission) -> Result<(), InventoryError> {
+    record_call(endpoint, check_permission(permission))
+}
+
+fn check_permission(permission: Permission) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    let granted = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        inventory.audit.ensure_not_suspended(&caller, ic_cdk::api::time())?;
+        Ok::<_, InventoryError>(inventory.access.is_granted(&caller, permission))
+    })?;
+    if granted {
+        return Ok(());
+    }
+    check_caller(permission.minimum_role()).map_err(|error| match error {
+        InventoryError::Unauthorized { .. } => InventoryError::Unauthorized {
+            msg: format!("{} requires the {:?} permission or the {:?} role", caller, permission, permission.minimum_role()),
+        },
+        error => error,
+    })
+}
+
+// Assigns a staff role to a principal. Only the owner may do this, and ownership itself
+// cannot be handed out this way.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_role(principal: Principal, role: Role) -> Result<(), InventoryError> {
+    require_caller("set_role", Role::Owner)?;
+    if role == Role::Owner {
+        return Err(InventoryError::InvalidInput { msg: "The owner role cannot be assigned".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if inventory.access.role_of(&principal) == Some(Role::Owner) {
+            return Err(InventoryError::InvalidInput { msg: "The owner's role cannot be changed".to_string() });
+        }
+        inventory.access.roles.insert(principal, role);
+        Ok(())
+    })
+}
+
+// Hands the store to a new owner; the current owner stays on as a manager. Once governance is
+// configured this needs an approved `TransferOwnership` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn transfer_ownership(new_owner: Principal) -> Result<(), InventoryError> {
+    require_caller("transfer_ownership", Role::Owner)?;
+    if new_owner == Principal::anonymous() {
+        return Err(InventoryError::InvalidInput { msg: "The anonymous principal cannot own the store".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        inventory.access.transfer_owner(new_owner);
+        let log = format!("Ownership transferred to {} by {} at {}", new_owner, ic_cdk::caller(), SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Removes a principal's staff role.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn revoke_role(principal: Principal) -> Result<(), InventoryError> {
+    require_caller("revoke_role", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        match inventory.access.role_of(&principal) {
+            Some(Role::Owner) => Err(InventoryError::InvalidInput { msg: "The owner's role cannot be revoked".to_string() }),
+            Some(_) => {
+                inventory.access.roles.remove(&principal);
+                Ok(())
+            }
+            None => Err(InventoryError::NotFound { msg: format!("{} has no role", principal) }),
+        }
+    })
+}
+
+// Allows or stops a principal recording sales flagged as test transactions, e.g. an
+// integration test suite run against production.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_test_principal(principal: Principal, enabled: bool) -> Result<(), InventoryError> {
+    require_caller("set_test_principal", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if enabled {
+            inventory.access.test_principals.insert(principal);
+        } else {
+            inventory.access.test_principals.remove(&principal);
+        }
+        let log = format!(
+            "Test sales {} for {} at {}",
+            if enabled { "allowed" } else { "disallowed" },
+            principal,
+            SupermarketManager::get_current_time()
+        );
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the principals allowed to record test sales.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_test_principals() -> Result<Vec<Principal>, InventoryError> {
+    require_reader("get_test_principals", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().access.test_principals.iter().copied().collect()))
+}
+
+// Grants a principal one permission regardless of their role, e.g. stocktake counting for a
+// temporary worker who holds no role and so cannot change prices or remove items.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn grant_permission(principal: Principal, permission: Permission) -> Result<(), InventoryError> {
+    require_caller("grant_permission", Role::Manager)?;
+    if principal == Principal::anonymous() {
+        return Err(InventoryError::InvalidInput { msg: "Permissions cannot be granted to the anonymous principal".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.access.grants.entry(principal).or_default().insert(permission) {
+            return Err(InventoryError::Conflict { msg: format!("{} already holds {:?}", principal, permission) });
+        }
+        let log = format!("{:?} granted to {} by {} at {}", permission, principal, ic_cdk::caller(), SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Withdraws a permission granted on its own. Permissions carried by the principal's role stay.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn revoke_permission(principal: Principal, permission: Permission) -> Result<(), InventoryError> {
+    require_caller("revoke_permission", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let grants = &mut inventory.access.grants;
+        if !grants.get_mut(&principal).is_some_and(|permissions| permissions.remove(&permission)) {
+            return Err(InventoryError::NotFound { msg: format!("{} was not granted {:?}", principal, permission) });
+        }
+        grants.retain(|_, permissions| !permissions.is_empty());
+        let log = format!("{:?} revoked from {} by {} at {}", permission, principal, ic_cdk::caller(), SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the role carrying each permission and every permission granted on its own.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_permissions() -> Result<PermissionMatrix, InventoryError> {
+    require_reader("list_permissions", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let access = &inventory.borrow().access;
+        let mut grants: Vec<(Principal, Vec<Permission>)> = access.grants
+            .iter()
+            .map(|(principal, permissions)| (*principal, permissions.iter().copied().collect()))
+            .collect();
+        grants.sort_by_key(|(principal, _)| *principal);
+        Ok(PermissionMatrix {
+            roles: Permission::ALL.iter().map(|permission| (*permission, permission.minimum_role())).collect(),
+            grants,
+        })
+    })
+}
+
+// Retrieves every staff principal and their role.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_roles() -> Result<Vec<(Principal, Role)>, InventoryError> {
+    require_reader("get_roles", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().access.roles.iter().map(|(p, r)| (*p, *r)).collect())
+    })
+}
+
+// Retrieves the caller's own role, if any.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_my_role() -> Option<Role> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().access.role_of(&caller))
+}
+
+// Temporarily raises a staff member's role, e.g. to give a clerk refund authority for a shift.
+// Managers may elevate up to Manager for at most MAX_ELEVATION_SECS; the elevation ends by itself.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn grant_elevation(principal: Principal, role: Role, duration_secs: u64, reason: String) -> Result<Elevation, InventoryError> {
+    require_caller("grant_elevation", Role::Manager)?;
+    if role == Role::Owner {
+        return Err(InventoryError::InvalidInput { msg: "The owner role cannot be granted temporarily".to_string() });
+    }
+    if duration_secs == 0 || duration_secs > MAX_ELEVATION_SECS {
+        return Err(InventoryError::InvalidInput {
+            msg: format!("duration_secs must be between 1 and {}", MAX_ELEVATION_SECS),
+        });
+    }
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        match inventory.access.role_of(&principal) {
+            None => return Err(InventoryError::NotFound { msg: format!("{} has no staff role", principal) }),
+            Some(current) if current >= role => {
+                return Err(InventoryError::InvalidInput { msg: format!("{} already holds {:?}", principal, current) });
+            }
+            Some(_) => {}
+        }
+        let id = inventory.access.next_elevation_id;
+        inventory.access.next_elevation_id += 1;
+        let elevation = Elevation {
+            id,
+            principal,
+            role,
+            granted_by: caller,
+            reason,
+            expires_at: now.saturating_add(duration_secs * NANOS_PER_SEC),
+        };
+        inventory.access.elevations.insert(principal, elevation.clone());
+        let log = format!(
+            "Elevation {} granted {} {:?} for {}s by {}: {} at {}",
+            id,
+            principal,
+            role,
+            duration_secs,
+            caller,
+            elevation.reason,
+            SupermarketManager::get_current_time()
+        );
+        inventory.logs.push(log);
+        Ok(elevation)
+    })
+}
+
+// Ends a staff member's elevation early.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn revoke_elevation(principal: Principal) -> Result<(), InventoryError> {
+    require_caller("revoke_elevation", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let elevation = inventory.access.elevations.remove(&principal).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("{} has no elevation", principal),
+        })?;
+        let log = format!("Elevation {} of {} revoked at {}", elevation.id, principal, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every elevation that has not yet expired.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_active_elevations() -> Result<Vec<Elevation>, InventoryError> {
+    require_reader("get_active_elevations", Role::Manager)?;
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().access.elevations.values().filter(|e| e.expires_at > now).cloned().collect())
+    })
+}
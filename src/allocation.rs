@@ -0,0 +1,485 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::idempotency::run_once;
+use crate::ratelimit::rate_limit;
+use crate::validation::{validate_lines, Validator};
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const MAX_CLOSED_ORDERS: usize = 1000; // Dispatched and cancelled orders kept for reference
+
+/// A point on the map in decimal degrees
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GeoPoint {
+    /// Great-circle distance in kilometres
+    fn distance_km(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// A location B2B orders can be shipped from
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Warehouse {
+    pub id: String,
+    pub name: String,
+    pub position: GeoPoint,
+}
+
+/// How an order's lines are spread over warehouses
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Default)]
+pub enum AllocationStrategy {
+    #[default]
+    Nearest,        // Ship each line from the closest warehouse that has stock
+    MostStock,      // Ship each line from the warehouse holding the most of it
+    MinimizeSplits, // Use as few warehouses as possible, keeping lines whole where one warehouse can fill them
+}
+
+/// Units of one order line to ship from one warehouse
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct Allocation {
+    pub item_id: u32,
+    pub warehouse_id: String,
+    pub quantity: u32,
+}
+
+/// Where a B2B order is in its dispatch workflow
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum OrderStatus {
+    Proposed,   // A source plan is waiting for the dispatcher
+    Dispatched, // The plan was accepted and warehouse stock taken
+    Cancelled,  // Abandoned without touching stock
+}
+
+/// A business customer's order and the plan for fulfilling it
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct B2bOrder {
+    pub id: u64,
+    pub customer: String,
+    pub destination: GeoPoint,
+    pub lines: Vec<(u32, u32)>,       // Pairs of (item ID, quantity) ordered
+    pub strategy: AllocationStrategy, // Strategy the plan was proposed with
+    pub plan: Vec<Allocation>,        // Source of each line, split where needed
+    pub unallocated: Vec<(u32, u32)>, // Pairs of (item ID, quantity) no warehouse had stock for
+    pub overridden: bool,             // Whether the dispatcher replaced the proposed plan
+    pub status: OrderStatus,
+    pub created_at: u64,              // Time the order was entered in nanoseconds since the Unix epoch
+    pub dispatched_by: Option<Principal>,
+    pub dispatched_at: Option<u64>,
+}
+
+/// Warehouses, the stock each holds and the B2B orders shipped from them
+///
+/// Warehouse stock is kept apart from the store's shelf stock in `items`.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Fulfillment {
+    pub warehouses: BTreeMap<String, Warehouse>, // Warehouses keyed by ID
+    pub stock: BTreeMap<(String, u32), u32>,     // Units on hand keyed by (warehouse ID, item ID)
+    pub default_strategy: AllocationStrategy,    // Strategy used when an order does not name one
+    pub orders: BTreeMap<u64, B2bOrder>,         // Orders keyed by ID
+    pub next_order_id: u64,                      // ID handed to the next order
+}
+
+impl Fulfillment {
+    /// Proposes a source plan for order lines; whatever no warehouse can cover is returned unallocated
+    pub fn allocate(&self, lines: &[(u32, u32)], destination: &GeoPoint, strategy: AllocationStrategy) -> (Vec<Allocation>, Vec<(u32, u32)>) {
+        let mut available: HashMap<(&str, u32), u32> = self.stock
+            .iter()
+            .filter(|&(_, &units)| units > 0)
+            .map(|((warehouse_id, item_id), &units)| ((warehouse_id.as_str(), *item_id), units))
+            .collect();
+        let mut by_distance: Vec<(&str, f64)> = self.warehouses
+            .values()
+            .map(|w| (w.id.as_str(), w.position.distance_km(destination)))
+            .collect();
+        by_distance.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(b.0)));
+        let mut plan = Vec::new();
+        let mut remaining: Vec<(u32, u32)> = lines.to_vec();
+        if strategy == AllocationStrategy::MinimizeSplits {
+            // Repeatedly pick the warehouse that can fill the most remaining lines whole
+            loop {
+                let best = by_distance
+                    .iter()
+                    .map(|&(w, _)| (w, remaining.iter().filter(|&&(item_id, qty)| available.get(&(w, item_id)).is_some_and(|&units| units >= qty)).count()))
+                    .filter(|&(_, filled)| filled > 0)
+                    .fold(None, |best: Option<(&str, usize)>, candidate| match best {
+                        Some(best) if best.1 >= candidate.1 => Some(best), // Ties go to the nearer warehouse
+                        _ => Some(candidate),
+                    });
+                let Some((warehouse_id, _)) = best else { break };
+                remaining.retain(|&(item_id, quantity)| {
+                    let units = available.get_mut(&(warehouse_id, item_id)).filter(|units| **units >= quantity);
+                    let Some(units) = units else { return true };
+                    *units -= quantity;
+                    plan.push(Allocation { item_id, warehouse_id: warehouse_id.to_string(), quantity });
+                    false
+                });
+            }
+        }
+        let mut unallocated = Vec::new();
+        for (item_id, mut quantity) in remaining {
+            let mut sources: Vec<(&str, u32)> = by_distance
+                .iter()
+                .filter_map(|&(w, _)| available.get(&(w, item_id)).map(|&units| (w, units)))
+                .collect();
+            if strategy == AllocationStrategy::MostStock {
+                sources.sort_by_key(|&(_, units)| std::cmp::Reverse(units)); // Stable, so equal stock still goes nearest first
+            }
+            for (warehouse_id, units) in sources {
+                if quantity == 0 {
+                    break;
+                }
+                let take = units.min(quantity);
+                quantity -= take;
+                *available.get_mut(&(warehouse_id, item_id)).expect("source came from available") -= take;
+                plan.push(Allocation { item_id, warehouse_id: warehouse_id.to_string(), quantity: take });
+            }
+            if quantity > 0 {
+                unallocated.push((item_id, quantity));
+            }
+        }
+        plan.sort_by(|a, b| a.item_id.cmp(&b.item_id).then(a.warehouse_id.cmp(&b.warehouse_id)));
+        (plan, unallocated)
+    }
+
+    /// Checks a plan against an order's lines and the warehouse stock, returning what it leaves unallocated
+    fn check_plan(&self, lines: &[(u32, u32)], plan: &[Allocation]) -> Result<Vec<(u32, u32)>, InventoryError> {
+        let ordered: HashMap<u32, u32> = lines.iter().copied().collect();
+        let mut planned: HashMap<u32, u32> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut validator = Validator::new();
+        for (i, a) in plan.iter().enumerate() {
+            let field = format!("plan[{}]", i);
+            let stock = self.stock.get(&(a.warehouse_id.clone(), a.item_id)).copied().unwrap_or(0);
+            validator
+                .check(self.warehouses.contains_key(&a.warehouse_id), &field, "is not a known warehouse")
+                .check(ordered.contains_key(&a.item_id), &field, "is not an item on the order")
+                .check(seen.insert((&a.warehouse_id, a.item_id)), &field, "repeats a warehouse and item")
+                .check(a.quantity > 0, &field, "must have a positive quantity")
+                .check(a.quantity <= stock, &field, format!("is more than the {} units in stock there", stock));
+            *planned.entry(a.item_id).or_insert(0) += a.quantity;
+        }
+        for (&item_id, &quantity) in &planned {
+            validator.check(quantity <= ordered.get(&item_id).copied().unwrap_or(0), "plan", format!("ships more of item {} than was ordered", item_id));
+        }
+        validator.finish()?;
+        Ok(lines
+            .iter()
+            .map(|&(item_id, quantity)| (item_id, quantity - planned.get(&item_id).copied().unwrap_or(0)))
+            .filter(|&(_, quantity)| quantity > 0)
+            .collect())
+    }
+
+    fn order_mut(&mut self, order_id: u64) -> Result<&mut B2bOrder, InventoryError> {
+        let order = self.orders.get_mut(&order_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Order {} not found", order_id),
+        })?;
+        if order.status != OrderStatus::Proposed {
+            return Err(InventoryError::Conflict { msg: format!("Order {} is already {:?}", order_id, order.status) });
+        }
+        Ok(order)
+    }
+
+    fn prune_closed_orders(&mut self) {
+        let closed: Vec<u64> = self.orders.values().filter(|o| o.status != OrderStatus::Proposed).map(|o| o.id).collect();
+        for old in closed.iter().take(closed.len().saturating_sub(MAX_CLOSED_ORDERS)) {
+            self.orders.remove(old);
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Sets the strategy used for orders that do not name one
+    pub fn set_default_allocation_strategy(&mut self, strategy: AllocationStrategy) {
+        self.fulfillment.default_strategy = strategy;
+        let log = format!("Default allocation strategy set to {:?} at {}", strategy, SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Enters a B2B order and proposes where to ship each line from
+    pub fn propose_order(&mut self, customer: String, destination: GeoPoint, lines: Vec<(u32, u32)>, strategy: Option<AllocationStrategy>, now: u64) -> Result<B2bOrder, InventoryError> {
+        validate_lines(&lines)?;
+        let mut seen = HashSet::new();
+        let mut validator = Validator::new();
+        validator.name("customer", &customer);
+        for (i, &(item_id, _)) in lines.iter().enumerate() {
+            validator
+                .check(self.items.contains_key(&item_id), &format!("lines[{}].item_id", i), "is not a known item")
+                .check(seen.insert(item_id), &format!("lines[{}].item_id", i), "appears more than once");
+        }
+        validator.finish()?;
+        let strategy = strategy.unwrap_or(self.fulfillment.default_strategy);
+        let (plan, unallocated) = self.fulfillment.allocate(&lines, &destination, strategy);
+        let id = self.fulfillment.next_order_id;
+        self.fulfillment.next_order_id += 1;
+        let order = B2bOrder {
+            id,
+            customer,
+            destination,
+            lines,
+            strategy,
+            plan,
+            unallocated,
+            overridden: false,
+            status: OrderStatus::Proposed,
+            created_at: now,
+            dispatched_by: None,
+            dispatched_at: None,
+        };
+        self.fulfillment.orders.insert(id, order.clone());
+        let log = format!("B2B order {} for {} proposed at {}", id, order.customer, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(order)
+    }
+
+    /// Replaces an order's proposed plan with the dispatcher's own
+    pub fn override_allocation(&mut self, order_id: u64, plan: Vec<Allocation>) -> Result<B2bOrder, InventoryError> {
+        let lines = self.fulfillment.order_mut(order_id)?.lines.clone();
+        let unallocated = self.fulfillment.check_plan(&lines, &plan)?;
+        let order = self.fulfillment.order_mut(order_id)?;
+        order.plan = plan;
+        order.unallocated = unallocated;
+        order.overridden = true;
+        let order = order.clone();
+        let log = format!("B2B order {} allocation overridden at {}", order_id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(order)
+    }
+
+    /// Accepts an order's plan and takes the units from warehouse stock
+    ///
+    /// Fails without taking anything if stock has moved so the plan no longer fits.
+    pub fn dispatch_order(&mut self, order_id: u64, dispatched_by: Principal, now: u64) -> Result<B2bOrder, InventoryError> {
+        let order = self.fulfillment.order_mut(order_id)?;
+        let (lines, plan) = (order.lines.clone(), order.plan.clone());
+        self.fulfillment.check_plan(&lines, &plan).map_err(|_| InventoryError::Conflict {
+            msg: format!("Warehouse stock changed since order {} was planned; propose or override it again", order_id),
+        })?;
+        for a in &plan {
+            if let Some(units) = self.fulfillment.stock.get_mut(&(a.warehouse_id.clone(), a.item_id)) {
+                *units -= a.quantity;
+            }
+        }
+        let order = self.fulfillment.order_mut(order_id)?;
+        order.status = OrderStatus::Dispatched;
+        order.dispatched_by = Some(dispatched_by);
+        order.dispatched_at = Some(now);
+        let order = order.clone();
+        self.fulfillment.prune_closed_orders();
+        let log = format!(
+            "B2B order {} dispatched from {} warehouses at {}",
+            order_id,
+            plan.iter().map(|a| &a.warehouse_id).collect::<HashSet<_>>().len(),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(order)
+    }
+}
+
+// Adds a warehouse orders can be shipped from, or updates one with the same ID.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_warehouse(warehouse: Warehouse) -> Result<(), InventoryError> {
+    require_caller("set_warehouse", Role::Manager)?;
+    Validator::new()
+        .name("warehouse.id", &warehouse.id)
+        .name("warehouse.name", &warehouse.name)
+        .check((-90.0..=90.0).contains(&warehouse.position.latitude), "warehouse.position.latitude", "must be between -90 and 90")
+        .check((-180.0..=180.0).contains(&warehouse.position.longitude), "warehouse.position.longitude", "must be between -180 and 180")
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let log = format!("Warehouse {} set at {}", warehouse.id, SupermarketManager::get_current_time());
+        inventory.fulfillment.warehouses.insert(warehouse.id.clone(), warehouse);
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Removes a warehouse and forgets its stock. Orders already planned from it must be overridden.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_warehouse(id: String) -> Result<(), InventoryError> {
+    require_caller("remove_warehouse", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if inventory.fulfillment.warehouses.remove(&id).is_none() {
+            return Err(InventoryError::NotFound { msg: format!("Warehouse {} not found", id) });
+        }
+        inventory.fulfillment.stock.retain(|(warehouse_id, _), _| *warehouse_id != id);
+        let log = format!("Warehouse {} removed at {}", id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Sets the strategy used for orders that do not name one. Once a governance canister is set, this
+// takes an executed `SetDefaultAllocationStrategy` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_default_allocation_strategy(strategy: AllocationStrategy) -> Result<(), InventoryError> {
+    require_caller("set_default_allocation_strategy", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_default_allocation_strategy(strategy);
+        Ok(())
+    })
+}
+
+// Retrieves the warehouses and the default allocation strategy.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_warehouses() -> Result<(Vec<Warehouse>, AllocationStrategy), InventoryError> {
+    require_reader("get_warehouses", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let fulfillment = &inventory.borrow().fulfillment;
+        Ok((fulfillment.warehouses.values().cloned().collect(), fulfillment.default_strategy))
+    })
+}
+
+// Sets how many units of an item a warehouse holds, e.g. from its own stock system.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_warehouse_stock(warehouse_id: String, item_id: u32, quantity: u32) -> Result<(), InventoryError> {
+    require_permission("set_warehouse_stock", Permission::AdjustStock)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.fulfillment.warehouses.contains_key(&warehouse_id) {
+            return Err(InventoryError::NotFound { msg: format!("Warehouse {} not found", warehouse_id) });
+        }
+        if !inventory.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        let log = format!(
+            "Warehouse {} stock of item {} set to {} at {}",
+            warehouse_id,
+            item_id,
+            quantity,
+            SupermarketManager::get_current_time()
+        );
+        inventory.fulfillment.stock.insert((warehouse_id, item_id), quantity);
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the units of an item held at each warehouse.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_warehouse_stock(item_id: u32) -> Result<Vec<(String, u32)>, InventoryError> {
+    require_reader("get_warehouse_stock", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().fulfillment.stock
+            .iter()
+            .filter(|((_, id), _)| *id == item_id)
+            .map(|((warehouse_id, _), &units)| (warehouse_id.clone(), units))
+            .collect())
+    })
+}
+
+// Enters a B2B order given as (item ID, quantity) lines and proposes a source plan with the
+// given strategy, or the default one.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn propose_b2b_order(
+    customer: String,
+    destination: GeoPoint,
+    lines: Vec<(u32, u32)>,
+    strategy: Option<AllocationStrategy>,
+    idempotency_key: Option<String>,
+) -> Result<B2bOrder, InventoryError> {
+    require_caller("propose_b2b_order", Role::Clerk)?;
+    run_once("propose_b2b_order", idempotency_key, || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().propose_order(customer, destination, lines, strategy, ic_cdk::api::time())
+        })
+    })
+}
+
+// Proposes a new plan for an order that has not been dispatched, e.g. after warehouse stock changed.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn reallocate_b2b_order(order_id: u64, strategy: AllocationStrategy) -> Result<B2bOrder, InventoryError> {
+    require_caller("reallocate_b2b_order", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let fulfillment = &mut inventory.fulfillment;
+        let order = fulfillment.order_mut(order_id)?;
+        let (lines, destination) = (order.lines.clone(), order.destination);
+        let (plan, unallocated) = fulfillment.allocate(&lines, &destination, strategy);
+        let order = fulfillment.order_mut(order_id)?;
+        order.strategy = strategy;
+        order.plan = plan;
+        order.unallocated = unallocated;
+        order.overridden = false;
+        let order = order.clone();
+        let log = format!("B2B order {} reallocated with {:?} at {}", order_id, strategy, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(order)
+    })
+}
+
+// Replaces the proposed plan of an order with the dispatcher's own choice of sources.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn override_b2b_allocation(order_id: u64, plan: Vec<Allocation>) -> Result<B2bOrder, InventoryError> {
+    require_caller("override_b2b_allocation", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().override_allocation(order_id, plan)
+    })
+}
+
+// Accepts an order's plan, taking the units from the warehouses it names.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn dispatch_b2b_order(order_id: u64) -> Result<B2bOrder, InventoryError> {
+    require_caller("dispatch_b2b_order", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().dispatch_order(order_id, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Cancels an order that has not been dispatched.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_b2b_order(order_id: u64) -> Result<(), InventoryError> {
+    require_caller("cancel_b2b_order", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.fulfillment.order_mut(order_id)?.status = OrderStatus::Cancelled;
+        inventory.fulfillment.prune_closed_orders();
+        let log = format!("B2B order {} cancelled at {}", order_id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves B2B orders, optionally only those with the given status.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_b2b_orders(status: Option<OrderStatus>) -> Result<Vec<B2bOrder>, InventoryError> {
+    require_reader("get_b2b_orders", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().fulfillment.orders
+            .values()
+            .filter(|o| status.is_none_or(|status| o.status == status))
+            .cloned()
+            .collect())
+    })
+}
@@ -0,0 +1,237 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_ASSORTMENTS: usize = 100; // Assortments planned at once
+const MAX_LINES: usize = 500;       // Items in one assortment
+
+/// An item of an assortment and the stock it should have for the season
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AssortmentLine {
+    pub item_id: u32,
+    pub target_stock: u32, // Units to have on hand during the season, in grams or millilitres for weighed goods
+}
+
+/// Items stocked for a season or event, e.g. Christmas or a summer barbecue range
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Assortment {
+    pub id: u64,
+    pub name: String,
+    pub starts_at: u64, // Start of the season in nanoseconds since the Unix epoch
+    pub ends_at: u64,   // End of the season; stock left after it is reported for markdown
+    pub lines: Vec<AssortmentLine>,
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+/// How an item's stock compares with its assortment's plan
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AssortmentLineStatus {
+    pub item_id: u32,
+    pub name: String,
+    pub target_stock: u32,
+    pub on_hand: u32,
+    pub on_order: u32,            // Units in open reorder suggestions
+    pub gap: u32,                 // Units short of the target once what is on order arrives
+    pub excess: u32,              // Units on hand above the target
+    pub suggested_order: u32,     // Units to order to close the gap; 0 once the season has ended
+    pub supplier_id: Option<u32>, // Supplier from the item's reorder rule, if it has one
+    pub markdown_candidate: bool, // The season has ended with stock left that no running or coming season plans for
+}
+
+/// An assortment's plan compared with current stock
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AssortmentReport {
+    pub assortment_id: u64,
+    pub name: String,
+    pub ended: bool,
+    pub lines: Vec<AssortmentLineStatus>, // In the assortment's order
+    pub total_gap: u64,
+    pub total_excess: u64,
+    pub markdown_candidates: Vec<u32>,    // Items to mark down now the season is over
+}
+
+/// Planned assortments
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Assortments {
+    pub plans: BTreeMap<u64, Assortment>, // Assortments keyed by ID
+    pub next_id: u64,
+}
+
+impl SupermarketManager {
+    /// Checks an assortment's dates and lines
+    fn validate_assortment(&self, name: &str, starts_at: u64, ends_at: u64, lines: &[AssortmentLine]) -> Result<(), InventoryError> {
+        let mut validator = Validator::new();
+        validator
+            .name("name", name)
+            .check(ends_at > starts_at, "ends_at", "must be after starts_at")
+            .check(!lines.is_empty(), "lines", "must contain at least one item")
+            .check(lines.len() <= MAX_LINES, "lines", format!("must have at most {} entries", MAX_LINES));
+        let mut seen = HashSet::new();
+        for (i, line) in lines.iter().enumerate() {
+            let field = format!("lines[{}].item_id", i);
+            validator.check(seen.insert(line.item_id), &field, "appears more than once");
+            match self.items.get(&line.item_id) {
+                None => {
+                    validator.check(false, &field, "is not a known item");
+                }
+                Some(item) => {
+                    validator
+                        .check(
+                            !matches!(item.unit, Unit::Pack { .. }) && !self.bundles.definitions.contains_key(&item.id),
+                            &field,
+                            "must hold its own stock; plan the base item or the components instead",
+                        )
+                        .quantity(&format!("lines[{}].target_stock", i), line.target_stock, item.unit);
+                }
+            }
+        }
+        validator.finish()
+    }
+
+    /// Compares an assortment's plan with current stock and open reorder suggestions
+    pub fn assortment_report(&self, id: u64, now: u64) -> Result<AssortmentReport, InventoryError> {
+        let assortment = self.assortments.plans.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Assortment {} not found", id),
+        })?;
+        let ended = now >= assortment.ends_at;
+        // Items another season still needs are not marked down when this one ends
+        let still_planned: HashSet<u32> = self.assortments.plans
+            .values()
+            .filter(|other| other.id != id && other.ends_at > now)
+            .flat_map(|other| other.lines.iter().map(|line| line.item_id))
+            .collect();
+        let mut on_order: BTreeMap<u32, u32> = BTreeMap::new();
+        for suggestion in self.reorder.suggestions.values() {
+            let units = on_order.entry(suggestion.item_id).or_default();
+            *units = units.saturating_add(suggestion.suggested_qty);
+        }
+
+        let mut report = AssortmentReport {
+            assortment_id: id,
+            name: assortment.name.clone(),
+            ended,
+            lines: Vec::with_capacity(assortment.lines.len()),
+            total_gap: 0,
+            total_excess: 0,
+            markdown_candidates: Vec::new(),
+        };
+        for line in &assortment.lines {
+            let item = self.items.get(&line.item_id);
+            let on_hand = item.as_ref().map_or(0, |item| item.quantity);
+            let on_order = on_order.get(&line.item_id).copied().unwrap_or(0);
+            let gap = line.target_stock.saturating_sub(on_hand.saturating_add(on_order));
+            let excess = on_hand.saturating_sub(line.target_stock);
+            let markdown_candidate = ended && on_hand > 0 && !still_planned.contains(&line.item_id);
+            report.total_gap += gap as u64;
+            report.total_excess += excess as u64;
+            if markdown_candidate {
+                report.markdown_candidates.push(line.item_id);
+            }
+            report.lines.push(AssortmentLineStatus {
+                item_id: line.item_id,
+                name: item.map_or_else(|| format!("Item {}", line.item_id), |item| item.name),
+                target_stock: line.target_stock,
+                on_hand,
+                on_order,
+                gap,
+                excess,
+                suggested_order: if ended { 0 } else { gap },
+                supplier_id: self.reorder.rules.get(&line.item_id).map(|rule| rule.supplier_id),
+                markdown_candidate,
+            });
+        }
+        Ok(report)
+    }
+}
+
+// Plans an assortment of items with the stock each should have from `starts_at` to `ends_at`,
+// in nanoseconds since the Unix epoch.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn create_assortment(name: String, starts_at: u64, ends_at: u64, lines: Vec<AssortmentLine>) -> Result<Assortment, InventoryError> {
+    require_caller("create_assortment", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.validate_assortment(&name, starts_at, ends_at, &lines)?;
+        if inventory.assortments.plans.len() >= MAX_ASSORTMENTS {
+            return Err(InventoryError::Conflict { msg: format!("At most {} assortments can be planned", MAX_ASSORTMENTS) });
+        }
+        let id = inventory.assortments.next_id;
+        inventory.assortments.next_id += 1;
+        let assortment = Assortment {
+            id,
+            name: name.trim().to_string(),
+            starts_at,
+            ends_at,
+            lines,
+            created_by: ic_cdk::caller(),
+            created_at: ic_cdk::api::time(),
+        };
+        inventory.assortments.plans.insert(id, assortment.clone());
+        let log = format!("Assortment {} \"{}\" planned at {}", id, assortment.name, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(assortment)
+    })
+}
+
+// Replaces the items and target stock of an assortment.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn update_assortment_lines(id: u64, lines: Vec<AssortmentLine>) -> Result<Assortment, InventoryError> {
+    require_caller("update_assortment_lines", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let assortment = inventory.assortments.plans.get(&id).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Assortment {} not found", id),
+        })?;
+        inventory.validate_assortment(&assortment.name, assortment.starts_at, assortment.ends_at, &lines)?;
+        let assortment = inventory.assortments.plans.get_mut(&id).expect("checked above");
+        assortment.lines = lines;
+        let assortment = assortment.clone();
+        let log = format!("Assortment {} lines updated at {}", id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(assortment)
+    })
+}
+
+// Deletes an assortment.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn delete_assortment(id: u64) -> Result<(), InventoryError> {
+    require_caller("delete_assortment", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.assortments.plans.remove(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Assortment {} not found", id),
+        })?;
+        let log = format!("Assortment {} deleted at {}", id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every planned assortment, ordered by ID.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_assortments() -> Result<Vec<Assortment>, InventoryError> {
+    require_reader("list_assortments", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().assortments.plans.values().cloned().collect()))
+}
+
+// Compares an assortment with current stock and open reorder suggestions: the gap and excess
+// per item, the orders that would close the gap and, once the season has ended, the items to
+// mark down.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_assortment_report(id: u64) -> Result<AssortmentReport, InventoryError> {
+    require_reader("get_assortment_report", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().assortment_report(id, ic_cdk::api::time()))
+}
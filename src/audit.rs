@@ -0,0 +1,254 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_ALERTS: usize = 1000; // Oldest alerts are dropped beyond this
+
+/// Endpoints that hand out the whole catalog or other bulk data
+const BULK_ENDPOINTS: &[&str] = &[
+    "export_inventory",
+    "export_logs",
+    "create_snapshot",
+    "create_differential_snapshot",
+    "download_snapshot",
+    "get_payments",
+];
+
+/// Thresholds for flagging unusual access
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AuditConfig {
+    pub window_secs: u64,      // Length of a counting window
+    pub spike_factor: u32,     // Calls in a window above this multiple of the usual rate are unusual
+    pub min_spike_calls: u32,  // Windows with no more calls than this are never flagged as spikes
+    pub max_denied_calls: u32, // Denied calls in a window above which the principal is flagged
+    pub auto_suspend: bool,    // Whether flagged principals are suspended automatically
+    pub suspension_secs: u64,  // How long an automatic suspension lasts
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            window_secs: 60 * 60,
+            spike_factor: 10,
+            min_spike_calls: 50,
+            max_denied_calls: 10,
+            auto_suspend: false,
+            suspension_secs: 60 * 60,
+        }
+    }
+}
+
+/// Call counts of one principal on one endpoint
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct AccessStats {
+    pub total_calls: u64,   // Calls ever made
+    pub denied_calls: u64,  // Calls ever refused for lack of a role
+    pub window_start: u64,  // Start of the current counting window in nanoseconds since the Unix epoch
+    pub window_calls: u32,  // Calls in the current window
+    pub window_denied: u32, // Denied calls in the current window
+    pub usual_calls: f64,   // Moving average of calls per window, the principal's usual rate
+    pub last_call: u64,     // Time of the latest call in nanoseconds since the Unix epoch
+}
+
+/// Why an access was flagged
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum AnomalyKind {
+    BulkAccessByLowRole { role: Option<Role> }, // Bulk data requested by a clerk or a principal without a role
+    CallSpike { calls: u32, usual: f64 },       // Far more calls than the principal usually makes
+    RepeatedDenials { denied: u32 },            // Many calls refused for lack of a role
+}
+
+/// A flagged access
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AccessAlert {
+    pub id: u64,                      // Sequential ID of the alert
+    pub principal: Principal,         // Who made the call
+    pub endpoint: String,             // Endpoint that was called
+    pub kind: AnomalyKind,            // Why the access was flagged
+    pub at: u64,                      // Time of the call in nanoseconds since the Unix epoch
+    pub suspended_until: Option<u64>, // End of the automatic suspension, if one was applied
+}
+
+/// Per-principal, per-endpoint call counts, alerts and suspensions
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct AccessAudit {
+    pub config: AuditConfig,                              // Anomaly thresholds
+    pub stats: HashMap<(Principal, String), AccessStats>, // Counts keyed by principal and endpoint
+    pub alerts: BTreeMap<u64, AccessAlert>,               // Alerts keyed by ID
+    pub next_alert_id: u64,                               // ID handed to the next alert
+    pub suspensions: HashMap<Principal, u64>,             // End of each suspension keyed by principal
+}
+
+impl AccessAudit {
+    /// Checks that a principal is not suspended
+    pub fn ensure_not_suspended(&self, principal: &Principal, now: u64) -> Result<(), InventoryError> {
+        match self.suspensions.get(principal) {
+            Some(&until) if until > now => Err(InventoryError::Unauthorized {
+                msg: format!("{} is suspended until {}", principal, until),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Counts a call and flags it if it looks unusual
+    /// - `principal`: The caller
+    /// - `endpoint`: The endpoint being called
+    /// - `denied`: Whether the call was refused for lack of a role
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn record_access(&mut self, principal: Principal, endpoint: &str, denied: bool, now: u64) {
+        let config = self.audit.config.clone();
+        let role = self.access.effective_role(&principal, now);
+        let stats = self.audit.stats.entry((principal, endpoint.to_string())).or_default();
+        let window = config.window_secs.saturating_mul(NANOS_PER_SEC);
+        if now.saturating_sub(stats.window_start) >= window {
+            if stats.total_calls > 0 {
+                stats.usual_calls = 0.75 * stats.usual_calls + 0.25 * stats.window_calls as f64;
+            }
+            stats.window_start = now;
+            stats.window_calls = 0;
+            stats.window_denied = 0;
+        }
+        stats.total_calls += 1;
+        stats.window_calls += 1;
+        stats.last_call = now;
+        if denied {
+            stats.denied_calls += 1;
+            stats.window_denied += 1;
+        }
+
+        let mut anomalies = Vec::new();
+        if BULK_ENDPOINTS.contains(&endpoint) && role.is_none_or(|role| role < Role::Manager) && stats.window_calls == 1 {
+            anomalies.push(AnomalyKind::BulkAccessByLowRole { role });
+        }
+        let spike_threshold = ((config.spike_factor as f64 * stats.usual_calls.max(1.0)).ceil() as u32).max(config.min_spike_calls);
+        if stats.window_calls == spike_threshold.saturating_add(1) { // Flag once per window, when the threshold is crossed
+            anomalies.push(AnomalyKind::CallSpike { calls: stats.window_calls, usual: stats.usual_calls });
+        }
+        if denied && stats.window_denied == config.max_denied_calls + 1 {
+            anomalies.push(AnomalyKind::RepeatedDenials { denied: stats.window_denied });
+        }
+        for kind in anomalies {
+            self.raise_access_alert(principal, endpoint, kind, role, now);
+        }
+    }
+
+    fn raise_access_alert(&mut self, principal: Principal, endpoint: &str, kind: AnomalyKind, role: Option<Role>, now: u64) {
+        let config = &self.audit.config;
+        let suspended_until = (config.auto_suspend && role != Some(Role::Owner)) // Never lock out the owner
+            .then(|| now.saturating_add(config.suspension_secs.saturating_mul(NANOS_PER_SEC)));
+        if let Some(until) = suspended_until {
+            self.audit.suspensions.insert(principal, until);
+        }
+        let id = self.audit.next_alert_id;
+        self.audit.next_alert_id += 1;
+        self.audit.alerts.insert(id, AccessAlert {
+            id,
+            principal,
+            endpoint: endpoint.to_string(),
+            kind: kind.clone(),
+            at: now,
+            suspended_until,
+        });
+        while self.audit.alerts.len() > MAX_ALERTS {
+            self.audit.alerts.pop_first();
+        }
+        let log = format!(
+            "Unusual access by {} to {}: {:?}{} at {}",
+            principal,
+            endpoint,
+            kind,
+            if suspended_until.is_some() { " (suspended)" } else { "" },
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        self.notify_webhooks(WebhookEvent::AccessAnomaly {
+            principal,
+            endpoint: endpoint.to_string(),
+            reason: format!("{:?}", kind),
+        });
+    }
+}
+
+/// Records a call in the access audit with the outcome of its access check, which it passes on
+/// - `endpoint`: Name of the endpoint being called
+/// - `result`: Outcome of the caller's role or permission check
+pub fn record_call(endpoint: &str, result: Result<(), InventoryError>) -> Result<(), InventoryError> {
+    let denied = matches!(result, Err(InventoryError::Unauthorized { .. }));
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().record_access(ic_cdk::caller(), endpoint, denied, ic_cdk::api::time());
+    });
+    result
+}
+
+// Retrieves call counts per principal and endpoint, busiest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_access_report() -> Result<Vec<(Principal, String, AccessStats)>, InventoryError> {
+    require_reader("get_access_report", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let mut report: Vec<(Principal, String, AccessStats)> = inventory.audit.stats
+            .iter()
+            .map(|((principal, endpoint), stats)| (*principal, endpoint.clone(), stats.clone()))
+            .collect();
+        report.sort_by_key(|(_, _, stats)| std::cmp::Reverse(stats.total_calls));
+        Ok(report)
+    })
+}
+
+// Retrieves flagged accesses, newest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_access_alerts() -> Result<Vec<AccessAlert>, InventoryError> {
+    require_reader("get_access_alerts", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().audit.alerts.values().rev().cloned().collect())
+    })
+}
+
+// Retrieves active suspensions as (principal, suspended until).
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_suspensions() -> Result<Vec<(Principal, u64)>, InventoryError> {
+    require_reader("get_suspensions", Role::Manager)?;
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().audit.suspensions.iter().filter(|(_, until)| **until > now).map(|(p, u)| (*p, *u)).collect())
+    })
+}
+
+// Lifts a principal's suspension early.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn lift_suspension(principal: Principal) -> Result<(), InventoryError> {
+    require_caller("lift_suspension", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().audit.suspensions.remove(&principal)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("{} is not suspended", principal) })
+    })
+}
+
+// Replaces the anomaly thresholds and automatic suspension settings.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_audit_config(config: AuditConfig) -> Result<(), InventoryError> {
+    require_caller("set_audit_config", Role::Owner)?;
+    if config.window_secs == 0 || config.spike_factor == 0 {
+        return Err(InventoryError::InvalidInput { msg: "window_secs and spike_factor must be positive".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().audit.config = config;
+    });
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const DEFAULT_TTL_DAYS: u32 = 30;                // Days a subscription waits for stock unless the subscriber asks otherwise
+const MAX_TTL_DAYS: u32 = 365;
+const MAX_SUBSCRIPTIONS_PER_ITEM: usize = 10_000; // Anyone may subscribe, so each item's waiting list is capped
+
+/// Who is told when an item is back in stock
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum Subscriber {
+    Principal(Principal), // A customer who subscribed through their own identity
+    Contact(String),      // An email address or phone number taken down by staff
+}
+
+/// A request to be told when an out-of-stock item comes back
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StockSubscription {
+    pub id: u64,
+    pub item_id: u32,
+    pub subscriber: Subscriber,
+    pub min_quantity: u32, // Stock a delivery must bring the item to before the subscriber is told
+    pub created_at: u64,   // Time of subscribing in nanoseconds since the Unix epoch
+    pub expires_at: u64,   // Time the subscription lapses if the item has not come back by then
+}
+
+/// Open back-in-stock subscriptions
+///
+/// A subscription ends when its notification is queued or when it expires.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct BackInStock {
+    pub subscriptions: BTreeMap<u64, StockSubscription>, // Open subscriptions keyed by ID
+    pub next_subscription_id: u64,                       // ID handed to the next subscription
+}
+
+impl SupermarketManager {
+    /// Opens a subscription to an out-of-stock item and returns its ID
+    /// - `ttl_days`: Days until the subscription expires
+    pub fn subscribe_back_in_stock(
+        &mut self,
+        item_id: u32,
+        subscriber: Subscriber,
+        min_quantity: u32,
+        ttl_days: u32,
+        now: u64,
+    ) -> Result<u64, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        Validator::new()
+            .check(!matches!(item.unit, Unit::Pack { .. }), "item_id", "is a pack; subscribe to its base item")
+            .check(!self.bundles.definitions.contains_key(&item_id), "item_id", "is a bundle; subscribe to its components")
+            .check(!item.archived, "item_id", "is archived")
+            .check(item.quantity == 0, "item_id", "is in stock")
+            .finish()?;
+        let waiting = self.back_in_stock.subscriptions.values().filter(|s| s.item_id == item_id);
+        let mut count = 0;
+        for subscription in waiting {
+            if subscription.subscriber == subscriber {
+                return Err(InventoryError::Conflict {
+                    msg: format!("Already subscribed to item {} as subscription {}", item_id, subscription.id),
+                });
+            }
+            count += 1;
+        }
+        if count >= MAX_SUBSCRIPTIONS_PER_ITEM {
+            return Err(InventoryError::Conflict { msg: format!("Item {} has too many subscribers", item_id) });
+        }
+        let id = self.back_in_stock.next_subscription_id;
+        self.back_in_stock.next_subscription_id += 1;
+        self.back_in_stock.subscriptions.insert(id, StockSubscription {
+            id,
+            item_id,
+            subscriber,
+            min_quantity,
+            created_at: now,
+            expires_at: now.saturating_add(ttl_days as u64 * NANOS_PER_DAY),
+        });
+        Ok(id)
+    }
+
+    /// Queues notifications for the subscribers a delivery has brought an item back for, ending their subscriptions
+    pub fn notify_back_in_stock(&mut self, item_id: u32, now: u64) {
+        let Some(quantity) = self.items.get(&item_id).map(|item| item.quantity) else {
+            return;
+        };
+        let due: Vec<u64> = self.back_in_stock.subscriptions
+            .values()
+            .filter(|s| s.item_id == item_id && s.expires_at > now && quantity >= s.min_quantity)
+            .map(|s| s.id)
+            .collect();
+        for id in due {
+            if let Some(subscription) = self.back_in_stock.subscriptions.remove(&id) {
+                self.notify_webhooks(WebhookEvent::BackInStock { item_id, quantity, subscriber: subscription.subscriber });
+            }
+        }
+    }
+
+    /// Drops subscriptions past their expiry and returns how many were dropped
+    pub fn expire_back_in_stock(&mut self, now: u64) -> usize {
+        let before = self.back_in_stock.subscriptions.len();
+        self.back_in_stock.subscriptions.retain(|_, s| s.expires_at > now);
+        let expired = before - self.back_in_stock.subscriptions.len();
+        if expired > 0 {
+            let log = format!("{} back-in-stock subscriptions expired at {}", expired, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+        expired
+    }
+}
+
+/// Expires stale subscriptions once a day
+pub fn start_back_in_stock_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_nanos(NANOS_PER_DAY), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().expire_back_in_stock(ic_cdk::api::time());
+        });
+    });
+}
+
+// Subscribes to be told when an out-of-stock item is back. With a `contact` handle a clerk
+// subscribes on a customer's behalf; without one the caller subscribes themselves.
+// `min_quantity` defaults to 1 and `ttl_days` to 30.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn subscribe_back_in_stock(
+    item_id: u32,
+    contact: Option<String>,
+    min_quantity: Option<u32>,
+    ttl_days: Option<u32>,
+) -> Result<u64, InventoryError> {
+    let subscriber = match contact {
+        Some(contact) => {
+            require_caller("subscribe_back_in_stock", Role::Clerk)?;
+            Validator::new().name("contact", &contact).finish()?;
+            Subscriber::Contact(contact.trim().to_string())
+        }
+        None => {
+            let caller = ic_cdk::caller();
+            if caller == Principal::anonymous() {
+                return Err(InventoryError::Unauthorized { msg: "Sign in to subscribe, or leave a contact with staff".to_string() });
+            }
+            Subscriber::Principal(caller)
+        }
+    };
+    let min_quantity = min_quantity.unwrap_or(1);
+    let ttl_days = ttl_days.unwrap_or(DEFAULT_TTL_DAYS);
+    Validator::new()
+        .check(min_quantity > 0, "min_quantity", "must be positive")
+        .check((1..=MAX_TTL_DAYS).contains(&ttl_days), "ttl_days", format!("must be between 1 and {}", MAX_TTL_DAYS))
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().subscribe_back_in_stock(item_id, subscriber, min_quantity, ttl_days, ic_cdk::api::time())
+    })
+}
+
+// Cancels a back-in-stock subscription. Customers cancel their own; clerks may cancel any.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn unsubscribe_back_in_stock(id: u64) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    let own = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let subscription = inventory.back_in_stock.subscriptions.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Subscription {} not found", id),
+        })?;
+        Ok::<_, InventoryError>(subscription.subscriber == Subscriber::Principal(caller))
+    })?;
+    if !own {
+        require_caller("unsubscribe_back_in_stock", Role::Clerk)?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().back_in_stock.subscriptions.remove(&id);
+    });
+    Ok(())
+}
+
+// Retrieves the caller's own back-in-stock subscriptions.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_my_back_in_stock_subscriptions() -> Vec<StockSubscription> {
+    let subscriber = Subscriber::Principal(ic_cdk::caller());
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        inventory.back_in_stock.subscriptions.values().filter(|s| s.subscriber == subscriber).cloned().collect()
+    })
+}
+
+// Retrieves open back-in-stock subscriptions, optionally for one item only.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_back_in_stock_subscriptions(item_id: Option<u32>) -> Result<Vec<StockSubscription>, InventoryError> {
+    require_reader("get_back_in_stock_subscriptions", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        Ok(inventory.back_in_stock.subscriptions
+            .values()
+            .filter(|s| item_id.is_none_or(|item_id| s.item_id == item_id))
+            .cloned()
+            .collect())
+    })
+}
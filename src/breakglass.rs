@@ -0,0 +1,218 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+
+use crate::access::{check_caller, require_caller, Role};
+use crate::audit::record_call;
+use crate::ratelimit::rate_limit;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MIN_ACTIVATION_DELAY_SECS: u64 = 24 * 60 * 60; // Staff always get at least a day to cancel a request
+
+/// Emergency access for when the owner key is lost
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BreakGlassConfig {
+    pub recovery_principal: Principal, // Pre-registered principal allowed to request access
+    pub activation_delay_secs: u64,    // Wait between a request and access being granted
+    pub access_secs: u64,              // How long access lasts once granted
+}
+
+/// A pending or granted break-glass request
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BreakGlassRequest {
+    pub requested_at: u64, // Time of the request in nanoseconds since the Unix epoch
+    pub activates_at: u64, // Time read-only access starts unless staff cancel the request first
+    pub expires_at: u64,   // Time read-only access ends
+}
+
+/// Break-glass settings and the current request
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct BreakGlass {
+    pub config: Option<BreakGlassConfig>,   // Unset until the owner registers a recovery principal
+    pub request: Option<BreakGlassRequest>, // The latest request, if it has not been cancelled
+}
+
+impl BreakGlass {
+    /// Whether a principal currently holds break-glass read access
+    pub fn is_active(&self, principal: &Principal, now: u64) -> bool {
+        match (&self.config, &self.request) {
+            (Some(config), Some(request)) => {
+                config.recovery_principal == *principal && request.activates_at <= now && now < request.expires_at
+            }
+            _ => false,
+        }
+    }
+
+    /// Starts the activation delay for the recovery principal's access and returns the request,
+    /// with whether it is new; asking again while a request stands does not restart the delay
+    fn request_access(&mut self, caller: Principal, now: u64) -> Result<(BreakGlassRequest, bool), InventoryError> {
+        let config = self.config.as_ref().filter(|config| config.recovery_principal == caller).ok_or_else(|| {
+            InventoryError::Unauthorized { msg: format!("{} is not the recovery principal", caller) }
+        })?;
+        if let Some(request) = self.request.clone().filter(|request| now < request.expires_at) {
+            return Ok((request, false));
+        }
+        let activates_at = now.saturating_add(config.activation_delay_secs.saturating_mul(NANOS_PER_SEC));
+        let request = BreakGlassRequest {
+            requested_at: now,
+            activates_at,
+            expires_at: activates_at.saturating_add(config.access_secs.saturating_mul(NANOS_PER_SEC)),
+        };
+        self.request = Some(request.clone());
+        Ok((request, true))
+    }
+}
+
+/// Checks that a break-glass configuration is usable
+pub fn validate_config(config: &BreakGlassConfig) -> Result<(), InventoryError> {
+    if config.recovery_principal == Principal::anonymous() {
+        return Err(InventoryError::InvalidInput { msg: "The anonymous principal cannot be a recovery principal".to_string() });
+    }
+    if config.activation_delay_secs < MIN_ACTIVATION_DELAY_SECS {
+        return Err(InventoryError::InvalidInput {
+            msg: format!("activation_delay_secs must be at least {}", MIN_ACTIVATION_DELAY_SECS),
+        });
+    }
+    if config.access_secs == 0 {
+        return Err(InventoryError::InvalidInput { msg: "access_secs must be positive".to_string() });
+    }
+    Ok(())
+}
+
+impl SupermarketManager {
+    /// Replaces the break-glass settings, withdrawing any request made under the old ones
+    pub fn set_break_glass_config(&mut self, config: Option<BreakGlassConfig>) {
+        self.break_glass.config = config;
+        self.break_glass.request = None;
+        let log = format!("Break-glass recovery settings changed at {}", SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+}
+
+/// Checks that the caller may read data guarded by the given role, recording the call in the
+/// access audit
+/// - `endpoint`: Name of the endpoint being called
+///
+/// Besides staff holding the role, this admits the recovery principal while break-glass access
+/// is active. Use it only on endpoints that read or export data, never on ones that change it.
+/// Queries keep no state, so only reads made as update calls are counted.
+pub fn require_reader(endpoint: &str, minimum: Role) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    let active = INVENTORY_MANAGER.with(|inventory| inventory.borrow().break_glass.is_active(&caller, ic_cdk::api::time()));
+    record_call(endpoint, if active { Ok(()) } else { check_caller(minimum) })
+}
+
+// Registers or clears the recovery principal. Once governance is configured this requires a proposal.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_recovery_config(config: Option<BreakGlassConfig>) -> Result<(), InventoryError> {
+    require_caller("set_recovery_config", Role::Owner)?;
+    if let Some(config) = &config {
+        validate_config(config)?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        inventory.set_break_glass_config(config);
+        Ok(())
+    })
+}
+
+// Starts the activation delay for break-glass read access. Only the recovery principal may call
+// this; staff are alerted and can cancel the request until the delay has passed.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn request_break_glass() -> Result<BreakGlassRequest, InventoryError> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let (request, new) = inventory.break_glass.request_access(caller, now)?;
+        if new {
+            let activates_at = request.activates_at;
+            let log = format!(
+                "Break-glass access requested by {}, activating at {} at {}",
+                caller,
+                activates_at,
+                SupermarketManager::get_current_time()
+            );
+            inventory.logs.push(log);
+            inventory.notify_webhooks(WebhookEvent::BreakGlassRequested { recovery_principal: caller, activates_at });
+        }
+        Ok(request)
+    })
+}
+
+// Cancels a pending or active break-glass request.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_break_glass() -> Result<(), InventoryError> {
+    require_caller("cancel_break_glass", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.break_glass.request.take().ok_or_else(|| InventoryError::NotFound {
+            msg: "There is no break-glass request".to_string(),
+        })?;
+        let log = format!("Break-glass request cancelled by {} at {}", ic_cdk::caller(), SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the recovery settings and the current request, for staff and the recovery principal.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_break_glass_status() -> Result<(Option<BreakGlassConfig>, Option<BreakGlassRequest>), InventoryError> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let is_recovery = inventory.break_glass.config.as_ref().is_some_and(|config| config.recovery_principal == caller);
+        if !is_recovery {
+            inventory.access.require(&caller, Role::Manager, ic_cdk::api::time())?;
+        }
+        Ok((inventory.break_glass.config.clone(), inventory.break_glass.request.clone()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_NANOS: u64 = MIN_ACTIVATION_DELAY_SECS * NANOS_PER_SEC;
+
+    fn configured(recovery_principal: Principal) -> BreakGlass {
+        let config = BreakGlassConfig { recovery_principal, activation_delay_secs: MIN_ACTIVATION_DELAY_SECS, access_secs: 60 * 60 };
+        BreakGlass { config: Some(config), request: None }
+    }
+
+    #[test]
+    fn access_starts_after_the_delay_and_ends_after_its_window() {
+        let recovery = Principal::from_slice(&[9]);
+        let mut break_glass = configured(recovery);
+        assert!(break_glass.request_access(Principal::from_slice(&[1]), 0).is_err());
+
+        let (request, new) = break_glass.request_access(recovery, 0).unwrap();
+        assert!(new);
+        assert_eq!(request.activates_at, DAY_NANOS);
+        assert!(!break_glass.is_active(&recovery, DAY_NANOS - 1));
+        assert!(break_glass.is_active(&recovery, DAY_NANOS));
+        assert!(!break_glass.is_active(&Principal::from_slice(&[1]), DAY_NANOS));
+        assert!(!break_glass.is_active(&recovery, request.expires_at));
+
+        let (again, new) = break_glass.request_access(recovery, DAY_NANOS / 2).unwrap();
+        assert!(!new);
+        assert_eq!(again.activates_at, request.activates_at); // The delay is not restarted
+        let (later, new) = break_glass.request_access(recovery, request.expires_at).unwrap();
+        assert!(new && later.activates_at > request.expires_at);
+    }
+
+    #[test]
+    fn configurations_keep_a_day_for_staff_to_cancel() {
+        let config = |recovery_principal, activation_delay_secs| BreakGlassConfig { recovery_principal, activation_delay_secs, access_secs: 60 };
+        assert!(validate_config(&config(Principal::from_slice(&[9]), MIN_ACTIVATION_DELAY_SECS)).is_ok());
+        assert!(validate_config(&config(Principal::from_slice(&[9]), MIN_ACTIVATION_DELAY_SECS - 1)).is_err());
+        assert!(validate_config(&config(Principal::anonymous(), MIN_ACTIVATION_DELAY_SECS)).is_err());
+    }
+}
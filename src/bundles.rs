@@ -0,0 +1,136 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, Role};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_COMPONENTS: usize = 50; // Components in one bundle
+
+/// An item assembled from other items, e.g. a gift basket or a deli sandwich
+///
+/// Selling the bundle takes its components out of stock; the bundle item's own quantity is not
+/// used. Components may be packs, which draw on their base item, but not other bundles.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Bundle {
+    pub components: Vec<(u32, u32)>, // Pairs of (item ID, units per bundle), in grams or millilitres for weighed goods
+}
+
+/// Bundle definitions keyed by the bundle's item ID
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Bundles {
+    pub definitions: BTreeMap<u32, Bundle>,
+}
+
+impl SupermarketManager {
+    /// Stock taken by selling units of an item, as (stock item ID, stock units) pairs
+    ///
+    /// A plain item or pack takes from one stock item; a bundle takes from each component.
+    pub fn stock_demand(&self, item_id: u32, quantity: u32) -> Result<Vec<(u32, u32)>, InventoryError> {
+        let Some(bundle) = self.bundles.definitions.get(&item_id) else {
+            return Ok(vec![self.stock_units(item_id, quantity)?]);
+        };
+        self.sellable_item(item_id)?;
+        bundle.components
+            .iter()
+            .map(|&(component_id, units)| {
+                let units = units.checked_mul(quantity).ok_or_else(|| InventoryError::InvalidInput {
+                    msg: format!("{} bundles of item {} is too many", quantity, item_id),
+                })?;
+                self.stock_units(component_id, units)
+            })
+            .collect()
+    }
+
+    /// Defines or replaces the components of a bundle item
+    pub fn define_bundle(&mut self, item_id: u32, components: Vec<(u32, u32)>) -> Result<(), InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        let mut validator = Validator::new();
+        validator
+            .check(item.unit == Unit::Each, "item_id", "must be sold by the piece to be a bundle")
+            .check(!self.bundles.definitions.values().any(|b| b.components.iter().any(|&(id, _)| id == item_id)), "item_id", "is a component of another bundle")
+            .check(!components.is_empty(), "components", "must contain at least one component")
+            .check(components.len() <= MAX_COMPONENTS, "components", format!("must have at most {} components", MAX_COMPONENTS));
+        for (i, &(component_id, units)) in components.iter().enumerate() {
+            let field = format!("components[{}]", i);
+            validator
+                .check(component_id != item_id, &field, "must not be the bundle itself")
+                .check(self.items.contains_key(&component_id), &field, "is not a known item")
+                .check(!self.bundles.definitions.contains_key(&component_id), &field, "must not be another bundle")
+                .check(units > 0, &field, "must have a positive quantity");
+        }
+        validator.finish()?;
+        self.recount_item(item_id, |inventory| inventory.bundles.definitions.insert(item_id, Bundle { components }));
+        let log = format!("Item {} defined as a bundle at {}", item_id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// How many bundles the components in stock are enough for
+    pub fn assemblable_bundles(&self, item_id: u32) -> Result<u32, InventoryError> {
+        let bundle = self.bundles.definitions.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} is not a bundle", item_id),
+        })?;
+        let mut needed: HashMap<u32, u64> = HashMap::new();
+        for &(component_id, units) in &bundle.components { // Sum repeats and packs sharing a base item
+            let (stock_item_id, stock_units) = self.stock_units(component_id, units)?;
+            *needed.entry(stock_item_id).or_insert(0) += stock_units as u64;
+        }
+        let count = needed
+            .iter()
+            .map(|(stock_item_id, &units)| self.items.get(stock_item_id).expect("stock_units only returns existing items").quantity as u64 / units)
+            .min()
+            .unwrap_or(0);
+        Ok(count.min(u32::MAX as u64) as u32)
+    }
+}
+
+// Makes an item a bundle of other items given as (item ID, units per bundle) pairs, or replaces
+// its components.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn define_bundle(item_id: u32, components: Vec<(u32, u32)>) -> Result<(), InventoryError> {
+    require_caller("define_bundle", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().define_bundle(item_id, components)
+    })
+}
+
+// Turns a bundle back into a plain item that is sold from its own stock.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_bundle(item_id: u32) -> Result<(), InventoryError> {
+    require_caller("remove_bundle", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if inventory.recount_item(item_id, |inventory| inventory.bundles.definitions.remove(&item_id)).is_none() {
+            return Err(InventoryError::NotFound { msg: format!("Item {} is not a bundle", item_id) });
+        }
+        let log = format!("Item {} is no longer a bundle at {}", item_id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the components of a bundle item, or None if the item is not a bundle.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_bundle(item_id: u32) -> Option<Bundle> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().bundles.definitions.get(&item_id).cloned()
+    })
+}
+
+// Computes how many units of a bundle can be assembled from the components in stock.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_assemblable_bundles(item_id: u32) -> Result<u32, InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().assemblable_bundles(item_id)
+    })
+}
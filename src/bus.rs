@@ -0,0 +1,521 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::back_in_stock::Subscriber;
+use crate::breakglass::require_reader;
+use crate::events::{InventoryEventKind, InventoryEventPayload};
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::webhooks::{post_notification, WebhookEvent, WebhookEventKind};
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const DELIVERY_INTERVAL_SECS: u64 = 15;    // How often the outbox is flushed
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_OUTBOX: usize = 20_000;          // Queued messages across destinations; the oldest are dead-lettered beyond this
+const MAX_DEAD_LETTERS: usize = 5000;      // Oldest dead letters are dropped beyond this
+const CANISTER_METHOD_DEFAULT: &str = "on_integration_message";
+
+/// What kind of system a destination is, so operators can tell destinations apart
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum DestinationKind {
+    Erp,
+    Webshop,
+    Esl,
+    Notifier,
+    Other,
+}
+
+/// How messages reach a destination
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum Transport {
+    Https { url: String },                                       // JSON body POSTed through an HTTPS outcall
+    Canister { canister_id: Principal, method: Option<String> }, // Call with a `BusEnvelope`, `on_integration_message` by default
+}
+
+/// How a message is rendered for a destination; messages a transformer has no rendering for are skipped
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum Transformer {
+    Envelope,            // Generic JSON with the topic, payload and timestamp
+    ErpStockMovement,    // Stock deltas, one per change, for ERP stock ledgers
+    WebshopAvailability, // Availability and price per item for online shops
+    EslPriceUpdate,      // Name and price changes for shelf label controllers
+    NotifierText,        // A one-line human-readable `text` field for chat or paging tools
+}
+
+/// A topic messages are published on: a critical alert or an inventory change
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum BusTopic {
+    Alert(WebhookEventKind),
+    Inventory(InventoryEventKind),
+}
+
+/// A message published to the bus before it is transformed for each destination
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum BusPayload {
+    Alert(WebhookEvent),
+    Inventory(InventoryEventPayload),
+}
+
+impl BusPayload {
+    pub fn topic(&self) -> BusTopic {
+        match self {
+            BusPayload::Alert(event) => BusTopic::Alert(event.kind()),
+            BusPayload::Inventory(payload) => BusTopic::Inventory(payload.kind()),
+        }
+    }
+}
+
+/// Retry settings of a destination
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct DeliveryPolicy {
+    pub max_attempts: u32,      // Attempts before a message is dead-lettered
+    pub base_backoff_secs: u64, // Delay before the first retry, doubled on each later one
+    pub max_backoff_secs: u64,  // Longest delay between retries
+    pub max_age_secs: u64,      // Messages still undelivered this long after publishing are dead-lettered
+}
+
+impl Default for DeliveryPolicy {
+    fn default() -> Self {
+        DeliveryPolicy {
+            max_attempts: 8,
+            base_backoff_secs: 30,
+            max_backoff_secs: 60 * 60,
+            max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// A named external system messages are forwarded to
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Destination {
+    pub name: String,             // Unique name, e.g. "erp" or "webshop"
+    pub kind: DestinationKind,    // What kind of system it is
+    pub transport: Transport,     // How messages are delivered
+    pub transformer: Transformer, // How messages are rendered
+    pub topics: Vec<BusTopic>,    // Topics forwarded to it
+    pub policy: DeliveryPolicy,   // Retry settings
+    pub paused: bool,             // Paused destinations keep queueing but are not delivered to
+}
+
+/// Body sent to canister destinations
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BusEnvelope {
+    pub message_id: u64, // Stable across retries so the receiver can drop duplicates
+    pub topic: BusTopic, // Topic the message was published on
+    pub body: String,    // Transformed JSON body
+}
+
+/// A transformed message waiting for delivery to one destination
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BusMessage {
+    pub id: u64,                    // Unique ID of the message
+    pub destination: String,        // Destination it is addressed to
+    pub topic: BusTopic,            // Topic it was published on
+    pub body: String,               // Transformed JSON body
+    pub published_at: u64,          // Time of publishing in nanoseconds since the Unix epoch
+    pub attempts: u32,              // Attempts made so far
+    pub next_attempt_at: u64,       // Earliest time of the next attempt in nanoseconds since the Unix epoch
+    pub in_flight: bool,            // Whether an attempt is outstanding
+    pub last_error: Option<String>, // Reason the most recent attempt failed
+}
+
+/// A message that could not be delivered, kept for inspection and replay
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct DeadLetter {
+    pub message: BusMessage, // The message as it was when it was given up on
+    pub reason: String,      // Why it was given up on
+    pub dead_at: u64,        // Time it was dead-lettered in nanoseconds since the Unix epoch
+}
+
+/// Delivery counts of a destination
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct DestinationStats {
+    pub delivered: u64,     // Messages delivered
+    pub dead_lettered: u64, // Messages given up on
+    pub skipped: u64,       // Messages the transformer had no rendering for
+}
+
+/// Store-and-forward bus for outbound integrations
+///
+/// Alerts and inventory changes are rendered per destination when they are published and kept
+/// in the outbox until delivered, so a destination that is down only delays its own messages.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct IntegrationBus {
+    pub destinations: BTreeMap<String, Destination>, // Destinations keyed by name
+    pub stats: BTreeMap<String, DestinationStats>,   // Delivery counts keyed by destination name
+    pub outbox: BTreeMap<u64, BusMessage>,           // Undelivered messages keyed by ID
+    pub dead_letters: BTreeMap<u64, DeadLetter>,     // Dead letters keyed by message ID
+    pub next_message_id: u64,                        // ID handed to the next message
+}
+
+impl IntegrationBus {
+    /// Moves a message to the dead-letter queue
+    fn dead_letter(&mut self, message: BusMessage, reason: String, now: u64) {
+        self.stats.entry(message.destination.clone()).or_default().dead_lettered += 1;
+        self.dead_letters.insert(message.id, DeadLetter { message, reason, dead_at: now });
+        while self.dead_letters.len() > MAX_DEAD_LETTERS {
+            self.dead_letters.pop_first();
+        }
+    }
+}
+
+/// Renders a payload for a transformer, or None if the transformer does not handle it
+/// - `timestamp`: Time of publishing in RFC3339 format
+pub fn transform(transformer: Transformer, payload: &BusPayload, timestamp: &str) -> Option<String> {
+    let body = match (transformer, payload) {
+        (Transformer::Envelope, _) => serde_json::json!({
+            "topic": payload.topic(),
+            "payload": payload,
+            "timestamp": timestamp,
+        }),
+        (Transformer::ErpStockMovement, BusPayload::Inventory(InventoryEventPayload::StockChanged { item_id, old_quantity, new_quantity })) => {
+            serde_json::json!({
+                "item_id": item_id,
+                "delta": *new_quantity as i64 - *old_quantity as i64, // Sales arrive as stock changes too, so they are not counted twice
+                "quantity": new_quantity,
+                "timestamp": timestamp,
+            })
+        }
+        (Transformer::WebshopAvailability, BusPayload::Inventory(InventoryEventPayload::StockChanged { item_id, new_quantity, .. })) => {
+            serde_json::json!({ "item_id": item_id, "available": *new_quantity > 0, "quantity": new_quantity })
+        }
+        (Transformer::WebshopAvailability, BusPayload::Inventory(InventoryEventPayload::ItemAdded { item_id, name, quantity, price })) => {
+            serde_json::json!({ "item_id": item_id, "name": name, "price": price, "available": *quantity > 0, "quantity": quantity })
+        }
+        (Transformer::EslPriceUpdate, BusPayload::Inventory(InventoryEventPayload::ItemAdded { item_id, name, price, .. })) => {
+            serde_json::json!({ "item_id": item_id, "name": name, "price": price })
+        }
+        (
+            Transformer::WebshopAvailability | Transformer::EslPriceUpdate,
+            BusPayload::Inventory(InventoryEventPayload::PriceChanged { item_id, new_price, .. }),
+        ) => serde_json::json!({ "item_id": item_id, "price": new_price }),
+        (Transformer::NotifierText, BusPayload::Alert(event)) => serde_json::json!({ "text": alert_text(event) }),
+        _ => return None,
+    };
+    Some(body.to_string())
+}
+
+fn alert_text(event: &WebhookEvent) -> String {
+    match event {
+        WebhookEvent::LowStock { item_id, quantity, threshold } => {
+            format!("Item {} is low on stock: {} left, threshold {}", item_id, quantity, threshold)
+        }
+        WebhookEvent::ExpiredItem { item_id, .. } => format!("Item {} has passed its expiration date", item_id),
+        WebhookEvent::LargeAdjustment { item_id, old_quantity, new_quantity } => {
+            format!("Item {} was adjusted from {} to {}", item_id, old_quantity, new_quantity)
+        }
+        WebhookEvent::AccessAnomaly { principal, endpoint, reason } => {
+            format!("Unusual access by {} to {}: {}", principal, endpoint, reason)
+        }
+        WebhookEvent::BreakGlassRequested { recovery_principal, activates_at } => {
+            format!("Break-glass access requested by {}, activating at {}", recovery_principal, activates_at)
+        }
+        WebhookEvent::PaymentDiscrepancy { payment_id, block_index, issue } => match payment_id {
+            Some(id) => format!("Payment {} does not match ledger block {}: {}", id, block_index, issue),
+            None => format!("Ledger block {} has no matching payment: {}", block_index, issue),
+        },
+        WebhookEvent::BackInStock { item_id, quantity, subscriber } => match subscriber {
+            Subscriber::Principal(principal) => format!("Item {} is back in stock ({} left) for {}", item_id, quantity, principal),
+            Subscriber::Contact(contact) => format!("Item {} is back in stock ({} left) for {}", item_id, quantity, contact),
+        },
+        WebhookEvent::PriceDrop { item_id, old_price, new_price, customer } => {
+            format!("Item {} dropped from {} to {} for {}", item_id, old_price, new_price, customer)
+        }
+        WebhookEvent::TemperatureBreach { location, celsius, min_celsius, max_celsius } => {
+            format!("{} is at {}°C, outside {}°C to {}°C", location, celsius, min_celsius, max_celsius)
+        }
+    }
+}
+
+/// Checks that a destination is usable
+fn validate_destination(destination: &Destination) -> Result<(), InventoryError> {
+    let mut validator = Validator::new();
+    validator
+        .name("name", &destination.name)
+        .check(!destination.topics.is_empty(), "topics", "must contain at least one topic")
+        .check(destination.policy.max_attempts > 0, "policy.max_attempts", "must be positive")
+        .check(destination.policy.base_backoff_secs > 0, "policy.base_backoff_secs", "must be positive")
+        .check(
+            destination.policy.max_backoff_secs >= destination.policy.base_backoff_secs,
+            "policy.max_backoff_secs",
+            "must be at least base_backoff_secs",
+        );
+    match &destination.transport {
+        Transport::Https { url } => {
+            validator.check(url.starts_with("https://"), "transport.url", "must use https://");
+        }
+        Transport::Canister { canister_id, .. } => {
+            validator.check(*canister_id != Principal::anonymous(), "transport.canister_id", "must not be anonymous");
+        }
+    }
+    validator.finish()
+}
+
+impl SupermarketManager {
+    /// Renders a payload for every destination subscribed to its topic and queues the results
+    /// - `payload`: The alert or inventory change to forward
+    /// - `now`: The time of publishing in nanoseconds since the Unix epoch
+    pub fn publish_to_bus(&mut self, payload: BusPayload, now: u64) {
+        let topic = payload.topic();
+        let bus = &mut self.bus;
+        if !bus.destinations.values().any(|d| d.topics.contains(&topic)) {
+            return;
+        }
+        let timestamp = SupermarketManager::get_current_time();
+        for destination in bus.destinations.values().filter(|d| d.topics.contains(&topic)) {
+            let Some(body) = transform(destination.transformer, &payload, &timestamp) else {
+                bus.stats.entry(destination.name.clone()).or_default().skipped += 1;
+                continue;
+            };
+            let id = bus.next_message_id;
+            bus.next_message_id += 1;
+            bus.outbox.insert(id, BusMessage {
+                id,
+                destination: destination.name.clone(),
+                topic,
+                body,
+                published_at: now,
+                attempts: 0,
+                next_attempt_at: now,
+                in_flight: false,
+                last_error: None,
+            });
+        }
+        while bus.outbox.len() > MAX_OUTBOX {
+            if let Some((_, message)) = bus.outbox.pop_first() {
+                bus.dead_letter(message, "Outbox full".to_string(), now);
+            }
+        }
+    }
+
+    /// Claims every message that is due, dead-lettering expired ones
+    ///
+    /// Returns (message ID, transport, envelope) for each attempt to make.
+    fn take_due_bus_messages(&mut self, now: u64) -> Vec<(u64, Transport, BusEnvelope)> {
+        let bus = &mut self.bus;
+        let mut due = Vec::new();
+        let mut expired = Vec::new();
+        for message in bus.outbox.values_mut().filter(|m| !m.in_flight && m.next_attempt_at <= now) {
+            let Some(destination) = bus.destinations.get(&message.destination) else {
+                expired.push((message.id, "Destination removed".to_string()));
+                continue;
+            };
+            if destination.paused {
+                continue;
+            }
+            let max_age = destination.policy.max_age_secs.saturating_mul(NANOS_PER_SEC);
+            if now.saturating_sub(message.published_at) > max_age {
+                expired.push((message.id, "Message expired before it could be delivered".to_string()));
+                continue;
+            }
+            message.in_flight = true;
+            message.attempts += 1;
+            let envelope = BusEnvelope { message_id: message.id, topic: message.topic, body: message.body.clone() };
+            due.push((message.id, destination.transport.clone(), envelope));
+        }
+        for (id, reason) in expired {
+            if let Some(message) = bus.outbox.remove(&id) {
+                bus.dead_letter(message, reason, now);
+            }
+        }
+        due
+    }
+
+    /// Records the outcome of an attempt, scheduling a retry or dead-lettering the message
+    fn finish_bus_message(&mut self, id: u64, result: Result<(), String>, now: u64) {
+        let bus = &mut self.bus;
+        let Some(message) = bus.outbox.get_mut(&id) else {
+            return;
+        };
+        message.in_flight = false;
+        let Err(error) = result else {
+            let message = bus.outbox.remove(&id).expect("message is in the outbox");
+            bus.stats.entry(message.destination).or_default().delivered += 1;
+            return;
+        };
+        let policy = bus.destinations.get(&message.destination).map(|d| d.policy.clone()).unwrap_or_default();
+        message.last_error = Some(error.clone());
+        if message.attempts >= policy.max_attempts {
+            let message = bus.outbox.remove(&id).expect("message is in the outbox");
+            bus.dead_letter(message, format!("Gave up after {} attempts: {}", policy.max_attempts, error), now);
+            return;
+        }
+        let backoff = policy.base_backoff_secs
+            .saturating_mul(1u64 << (message.attempts - 1).min(16))
+            .min(policy.max_backoff_secs);
+        message.next_attempt_at = now.saturating_add(backoff.saturating_mul(NANOS_PER_SEC));
+    }
+
+    /// Moves a dead letter back into the outbox for another round of attempts
+    pub fn replay_dead_letter(&mut self, id: u64, now: u64) -> Result<(), InventoryError> {
+        let bus = &mut self.bus;
+        let letter = bus.dead_letters.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Dead letter {} not found", id),
+        })?;
+        if !bus.destinations.contains_key(&letter.message.destination) {
+            return Err(InventoryError::NotFound { msg: format!("Destination {} not found", letter.message.destination) });
+        }
+        let mut message = bus.dead_letters.remove(&id).expect("dead letter exists").message;
+        message.attempts = 0;
+        message.next_attempt_at = now;
+        message.published_at = now; // Replaying restarts the age limit
+        message.last_error = None;
+        bus.outbox.insert(message.id, message);
+        Ok(())
+    }
+}
+
+/// Sends one message over its destination's transport
+async fn deliver(transport: Transport, envelope: BusEnvelope) -> Result<(), String> {
+    match transport {
+        Transport::Https { url } => post_notification(url, envelope.body).await,
+        Transport::Canister { canister_id, method } => {
+            let method = method.unwrap_or_else(|| CANISTER_METHOD_DEFAULT.to_string());
+            ic_cdk::call::<_, ()>(canister_id, &method, (envelope,))
+                .await
+                .map_err(|(code, msg)| format!("{} failed: {:?} {}", method, code, msg))
+        }
+    }
+}
+
+/// Sends every due message and records the outcomes
+fn process_bus() {
+    let due = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().take_due_bus_messages(ic_cdk::api::time())
+    });
+    for (id, transport, envelope) in due {
+        ic_cdk::spawn(async move {
+            let result = deliver(transport, envelope).await;
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().finish_bus_message(id, result, ic_cdk::api::time());
+            });
+        });
+    }
+}
+
+/// Registers the timer that flushes the outbox
+pub fn start_bus_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(DELIVERY_INTERVAL_SECS), process_bus);
+}
+
+// Adds a destination or replaces the one with the same name. Messages already queued for it
+// keep their rendering; new ones use the new transformer.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_bus_destination(destination: Destination) -> Result<(), InventoryError> {
+    require_caller("set_bus_destination", Role::Manager)?;
+    validate_destination(&destination)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let log = format!("Integration destination {} set at {}", destination.name, SupermarketManager::get_current_time());
+        inventory.bus.destinations.insert(destination.name.clone(), destination);
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Removes a destination. Its queued messages are dead-lettered on the next delivery round.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_bus_destination(name: String) -> Result<(), InventoryError> {
+    require_caller("remove_bus_destination", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.bus.destinations.remove(&name).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Destination {} not found", name),
+        })?;
+        let log = format!("Integration destination {} removed at {}", name, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every destination with its delivery counts and number of queued messages.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_bus_destinations() -> Result<Vec<(Destination, DestinationStats, u64)>, InventoryError> {
+    require_reader("get_bus_destinations", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let bus = &inventory.borrow().bus;
+        Ok(bus.destinations.values()
+            .map(|d| {
+                let queued = bus.outbox.values().filter(|m| m.destination == d.name).count() as u64;
+                (d.clone(), bus.stats.get(&d.name).cloned().unwrap_or_default(), queued)
+            })
+            .collect())
+    })
+}
+
+// Retrieves queued messages, optionally for one destination, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_bus_outbox(destination: Option<String>) -> Result<Vec<BusMessage>, InventoryError> {
+    require_reader("get_bus_outbox", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().bus.outbox.values()
+            .filter(|m| destination.as_ref().is_none_or(|name| *name == m.destination))
+            .cloned()
+            .collect())
+    })
+}
+
+// Retrieves dead letters, optionally for one destination, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_dead_letters(destination: Option<String>) -> Result<Vec<DeadLetter>, InventoryError> {
+    require_reader("get_dead_letters", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().bus.dead_letters.values()
+            .filter(|l| destination.as_ref().is_none_or(|name| *name == l.message.destination))
+            .cloned()
+            .collect())
+    })
+}
+
+// Queues dead letters for delivery again: the given IDs, or every dead letter of a destination
+// when `ids` is empty. Returns how many were replayed.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn replay_dead_letters(ids: Vec<u64>, destination: Option<String>) -> Result<u64, InventoryError> {
+    require_caller("replay_dead_letters", Role::Manager)?;
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let ids = if ids.is_empty() {
+            let name = destination.ok_or_else(|| InventoryError::InvalidInput {
+                msg: "Give dead letter IDs or a destination".to_string(),
+            })?;
+            inventory.bus.dead_letters.values().filter(|l| l.message.destination == name).map(|l| l.message.id).collect()
+        } else {
+            ids
+        };
+        if let Some(missing) = ids.iter().find(|id| !inventory.bus.dead_letters.contains_key(id)) {
+            return Err(InventoryError::NotFound { msg: format!("Dead letter {} not found", missing) });
+        }
+        for &id in &ids {
+            inventory.replay_dead_letter(id, now)?;
+        }
+        let log = format!("Replayed {} dead letters at {}", ids.len(), SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(ids.len() as u64)
+    })
+}
+
+// Discards a dead letter for good.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn discard_dead_letter(id: u64) -> Result<(), InventoryError> {
+    require_caller("discard_dead_letter", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().bus.dead_letters.remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("Dead letter {} not found", id) })
+    })
+}
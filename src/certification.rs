@@ -0,0 +1,139 @@
+use sha2::{Digest, Sha256};
+
+/// A node of an IC hash tree, as checked by the HTTP gateway against the certified data
+pub enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned([u8; 32]),
+}
+
+fn domain_hash(separator: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([separator.len() as u8]);
+    hasher.update(separator.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+impl HashTree {
+    /// Root hash of the tree, which is what the canister certifies
+    pub fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()]),
+            HashTree::Labeled(label, subtree) => domain_hash("ic-hashtree-labeled", &[label, &subtree.digest()]),
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+            HashTree::Pruned(digest) => *digest,
+        }
+    }
+
+    /// Self-describing CBOR encoding of the tree, as expected in the IC-Certificate header
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut out = vec![0xd9, 0xd9, 0xf7]; // Self-describing CBOR tag
+        self.write_cbor(&mut out);
+        out
+    }
+
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => {
+                cbor_head(out, 4, 1);
+                cbor_head(out, 0, 0);
+            }
+            HashTree::Fork(left, right) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 1);
+                left.write_cbor(out);
+                right.write_cbor(out);
+            }
+            HashTree::Labeled(label, subtree) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 2);
+                cbor_bytes(out, label);
+                subtree.write_cbor(out);
+            }
+            HashTree::Leaf(value) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 3);
+                cbor_bytes(out, value);
+            }
+            HashTree::Pruned(digest) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 4);
+                cbor_bytes(out, digest);
+            }
+        }
+    }
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, len: u64) {
+    let major = major << 5;
+    match len {
+        0..=23 => out.push(major | len as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, len as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Builds the tree over sorted (label, leaf) pairs, pruning every branch that does not lead to `keep`
+///
+/// With `keep` set to None the full tree is built, which is only needed for its digest.
+pub fn labeled_tree(entries: &[(&[u8], &[u8])], keep: Option<&[u8]>) -> HashTree {
+    match entries {
+        [] => HashTree::Empty,
+        [(label, value)] => HashTree::Labeled(label.to_vec(), Box::new(HashTree::Leaf(value.to_vec()))),
+        _ => {
+            let (left, right) = entries.split_at(entries.len() / 2);
+            let branch = |half: &[(&[u8], &[u8])]| {
+                let tree = labeled_tree(half, keep);
+                match keep {
+                    Some(target) if !half.iter().any(|(label, _)| *label == target) => HashTree::Pruned(tree.digest()),
+                    _ => tree,
+                }
+            };
+            HashTree::Fork(Box::new(branch(left)), Box::new(branch(right)))
+        }
+    }
+}
+
+/// Root of everything the canister certifies: the HTTP assets, then the valuation snapshots
+pub fn certified_root(http_assets: HashTree, valuations: HashTree) -> HashTree {
+    HashTree::Fork(Box::new(http_assets), Box::new(valuations))
+}
+
+/// Standard base64 with padding, as used in the IC-Certificate header
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,421 @@
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_STORES: usize = 500;               // Stores one head office audits
+const MAX_AUDIT_ITEMS: usize = 1000;         // Items compared by one audit
+const MAX_SCHEDULED: usize = 1000;           // Price changes waiting for their effective date
+const SCHEDULE_INTERVAL_SECS: u64 = 15 * 60; // Effective dates are honoured to within this interval
+
+/// What a store's endpoint returned, or why the call to it failed
+pub type StoreReply<T> = Result<(Result<T, InventoryError>,), (RejectionCode, String)>;
+
+/// How a store's price for an item compares with head office's
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum PriceConsistency {
+    Consistent,                     // Within the tolerance of head office's price
+    Deviating,                      // Beyond the tolerance; harmonizing sets it to head office's price
+    NotStocked,                     // The store has no such item
+    Unreachable { reason: String }, // The store could not be asked
+}
+
+/// One store's price for an item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StorePrice {
+    pub store: Principal,           // The store's canister
+    pub name: String,
+    pub price: Option<f64>,         // None when the store does not stock the item or could not be asked
+    pub deviation_pct: Option<f64>, // How far the price is above head office's, negative when below
+    pub consistency: PriceConsistency,
+}
+
+/// An item's price at head office and at every store of the chain
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemPriceAudit {
+    pub item_id: u32,
+    pub name: String,
+    pub head_office_price: f64,
+    pub stores: Vec<StorePrice>, // Ordered by store canister
+    pub deviating: u32,          // Stores flagged as deviating
+}
+
+/// A set of new prices a store applies once they take effect
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ScheduledPriceChange {
+    pub id: u64,
+    pub prices: Vec<(u32, f64)>, // Pairs of (item ID, new price)
+    pub effective_at: u64,       // Time the prices take effect in nanoseconds since the Unix epoch
+    pub scheduled_by: Principal, // Head office canister that sent them
+    pub scheduled_at: u64,
+}
+
+/// What harmonizing did at one store
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreHarmonization {
+    pub store: Principal,
+    pub prices: Vec<(u32, f64)>,  // Head office prices sent to the store
+    pub schedule_id: Option<u64>, // The store's scheduled change; None when applied at once or when sending failed
+    pub error: Option<String>,    // Why the store did not accept the prices
+}
+
+/// The stores a head office audits, and for a store, the head office whose prices it takes
+///
+/// A canister can be both: a regional office may answer to head office and audit its own stores.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Chain {
+    pub stores: BTreeMap<Principal, String>,            // Store canisters audited from here, with their names
+    pub tolerance_pct: f64,                             // Deviation from head office's price still counted as consistent
+    pub head_office: Option<Principal>,                 // Canister allowed to read and schedule this store's prices
+    pub scheduled: BTreeMap<u64, ScheduledPriceChange>, // Price changes waiting for their effective date, by ID
+    pub next_schedule_id: u64,
+}
+
+impl Chain {
+    /// Compares a store's price with head office's
+    fn compare(&self, head_office_price: f64, price: f64) -> (Option<f64>, PriceConsistency) {
+        let deviation_pct = (head_office_price > 0.0).then(|| (price - head_office_price) / head_office_price * 100.0);
+        let within = match deviation_pct {
+            Some(deviation_pct) => deviation_pct.abs() <= self.tolerance_pct,
+            None => price == head_office_price,
+        };
+        (deviation_pct, if within { PriceConsistency::Consistent } else { PriceConsistency::Deviating })
+    }
+}
+
+/// Checks a store canister registered with head office
+pub fn validate_chain_store(store: Principal, name: &str) -> Result<(), InventoryError> {
+    Validator::new()
+        .name("name", name)
+        .check(store != ic_cdk::id(), "store", "must not be this canister")
+        .finish()
+}
+
+/// Checks the deviation an audit still counts as consistent
+pub fn validate_price_tolerance(tolerance_pct: f64) -> Result<(), InventoryError> {
+    Validator::new()
+        .check(tolerance_pct.is_finite() && (0.0..=100.0).contains(&tolerance_pct), "tolerance_pct", "must be between 0 and 100")
+        .finish()
+}
+
+impl SupermarketManager {
+    /// Registers a store canister with this head office, or renames one
+    pub fn add_chain_store(&mut self, store: Principal, name: String) -> Result<(), InventoryError> {
+        if self.chain.stores.len() >= MAX_STORES && !self.chain.stores.contains_key(&store) {
+            return Err(InventoryError::Conflict { msg: format!("At most {} stores can be registered", MAX_STORES) });
+        }
+        self.chain.stores.insert(store, name.trim().to_string());
+        let log = format!("Store {} registered with head office at {}", store, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Stops auditing a store
+    pub fn remove_chain_store(&mut self, store: Principal) -> Result<(), InventoryError> {
+        self.chain.stores.remove(&store).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Store {} is not registered", store),
+        })?;
+        let log = format!("Store {} removed from head office at {}", store, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Sets the deviation from head office's price an audit still counts as consistent
+    pub fn set_price_tolerance(&mut self, tolerance_pct: f64) {
+        self.chain.tolerance_pct = tolerance_pct;
+        let log = format!("Price tolerance set to {}% at {}", tolerance_pct, SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Sets or clears the head office allowed to read and schedule this store's prices
+    pub fn set_head_office(&mut self, head_office: Option<Principal>) {
+        self.chain.head_office = head_office;
+        let log = format!(
+            "Head office {} at {}",
+            head_office.map_or("cleared".to_string(), |head_office| format!("set to {}", head_office)),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+    }
+
+    /// Applies every scheduled price change that has taken effect
+    pub fn apply_scheduled_prices(&mut self, now: u64) {
+        let due: Vec<u64> = self.chain.scheduled
+            .values()
+            .filter(|change| change.effective_at <= now)
+            .map(|change| change.id)
+            .collect();
+        for id in due {
+            let change = self.chain.scheduled.remove(&id).expect("collected from the scheduled changes");
+            // Items can be removed while a change waits, so only those still stocked are repriced
+            let prices: Vec<(u32, f64)> = change.prices.into_iter().filter(|(item_id, _)| self.items.contains_key(item_id)).collect();
+            let log = match self.set_prices(&prices, change.scheduled_by, now) {
+                Ok(results) => format!("Scheduled price change {} applied to {} items at {}", id, results.len(), SupermarketManager::get_current_time()),
+                Err(error) => format!("Scheduled price change {} failed: {:?} at {}", id, error, SupermarketManager::get_current_time()),
+            };
+            self.logs.push(log);
+        }
+    }
+
+    /// Takes new prices from head office, applying them at once when they are already in effect
+    ///
+    /// Returns the ID of the scheduled change, or None when the prices were applied.
+    pub fn schedule_prices(&mut self, prices: Vec<(u32, f64)>, effective_at: u64, scheduled_by: Principal, now: u64) -> Result<Option<u64>, InventoryError> {
+        self.plan_prices(&prices)?;
+        if effective_at <= now {
+            self.set_prices(&prices, scheduled_by, now)?;
+            return Ok(None);
+        }
+        if self.chain.scheduled.len() >= MAX_SCHEDULED {
+            return Err(InventoryError::Conflict { msg: format!("At most {} price changes can be scheduled", MAX_SCHEDULED) });
+        }
+        let id = self.chain.next_schedule_id;
+        self.chain.next_schedule_id += 1;
+        let count = prices.len();
+        self.chain.scheduled.insert(id, ScheduledPriceChange { id, prices, effective_at, scheduled_by, scheduled_at: now });
+        let log = format!("{} head office prices scheduled as change {} at {}", count, id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(Some(id))
+    }
+}
+
+/// Applies scheduled price changes as they take effect
+pub fn start_price_schedule_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(SCHEDULE_INTERVAL_SECS), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().apply_scheduled_prices(ic_cdk::api::time());
+        });
+    });
+}
+
+/// Checks the caller is this store's head office
+pub fn require_head_office() -> Result<Principal, InventoryError> {
+    let caller = ic_cdk::caller();
+    let head_office = INVENTORY_MANAGER.with(|inventory| inventory.borrow().chain.head_office);
+    match head_office {
+        Some(head_office) if head_office == caller => Ok(caller),
+        _ => Err(InventoryError::Unauthorized { msg: format!("{} is not this store's head office", caller) }),
+    }
+}
+
+/// Asks every store for its prices of the given items and compares them with head office's
+async fn audit_prices(item_ids: Vec<u32>) -> Result<Vec<ItemPriceAudit>, InventoryError> {
+    let mut validator = Validator::new();
+    validator
+        .check(!item_ids.is_empty(), "item_ids", "must contain at least one item")
+        .check(item_ids.len() <= MAX_AUDIT_ITEMS, "item_ids", format!("must have at most {} entries", MAX_AUDIT_ITEMS));
+    let mut audits: Vec<ItemPriceAudit> = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        for (i, item_id) in item_ids.iter().enumerate() {
+            validator.check(inventory.items.contains_key(item_id), &format!("item_ids[{}]", i), "is not a known item");
+        }
+        item_ids
+            .iter()
+            .filter_map(|item_id| inventory.items.get(item_id))
+            .map(|item| ItemPriceAudit { item_id: item.id, name: item.name, head_office_price: item.price, stores: Vec::new(), deviating: 0 })
+            .collect()
+    });
+    validator.finish()?;
+    let stores: Vec<(Principal, String)> = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().chain.stores.iter().map(|(store, name)| (*store, name.clone())).collect()
+    });
+    if stores.is_empty() {
+        return Err(InventoryError::InvalidInput { msg: "No stores are registered with this head office".to_string() });
+    }
+
+    for (store, name) in stores {
+        let result: StoreReply<Vec<(u32, Option<f64>)>> =
+            ic_cdk::call(store, "get_store_prices", (item_ids.clone(),)).await;
+        let prices: Result<BTreeMap<u32, Option<f64>>, String> = match result {
+            Ok((Ok(prices),)) => Ok(prices.into_iter().collect()),
+            Ok((Err(error),)) => Err(format!("Store refused: {:?}", error)),
+            Err((code, msg)) => Err(format!("Store call failed: {:?} {}", code, msg)),
+        };
+        INVENTORY_MANAGER.with(|inventory| {
+            let chain = &inventory.borrow().chain;
+            for audit in &mut audits {
+                let (price, deviation_pct, consistency) = match &prices {
+                    Err(reason) => (None, None, PriceConsistency::Unreachable { reason: reason.clone() }),
+                    Ok(prices) => match prices.get(&audit.item_id).copied().flatten() {
+                        None => (None, None, PriceConsistency::NotStocked),
+                        Some(price) => {
+                            let (deviation_pct, consistency) = chain.compare(audit.head_office_price, price);
+                            (Some(price), deviation_pct, consistency)
+                        }
+                    },
+                };
+                if consistency == PriceConsistency::Deviating {
+                    audit.deviating += 1;
+                }
+                audit.stores.push(StorePrice { store, name: name.clone(), price, deviation_pct, consistency });
+            }
+        });
+    }
+    Ok(audits)
+}
+
+// Registers a store canister whose prices this head office audits, or renames one. Once a
+// governance canister is set, this takes an executed `AddChainStore` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn add_chain_store(store: Principal, name: String) -> Result<(), InventoryError> {
+    require_caller("add_chain_store", Role::Owner)?;
+    validate_chain_store(store, &name)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.add_chain_store(store, name)
+    })
+}
+
+// Stops auditing a store. Once a governance canister is set, this takes an executed
+// `RemoveChainStore` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_chain_store(store: Principal) -> Result<(), InventoryError> {
+    require_caller("remove_chain_store", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.remove_chain_store(store)
+    })
+}
+
+// Sets how far, in percent, a store's price may stray from head office's before an audit flags it.
+// Once a governance canister is set, this takes an executed `SetPriceTolerance` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_price_tolerance(tolerance_pct: f64) -> Result<(), InventoryError> {
+    require_caller("set_price_tolerance", Role::Manager)?;
+    validate_price_tolerance(tolerance_pct)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_price_tolerance(tolerance_pct);
+        Ok(())
+    })
+}
+
+// Sets the head office canister allowed to read this store's prices and schedule new ones, or
+// clears it. Once a governance canister is set, this takes an executed `SetHeadOffice` proposal
+// instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_head_office(head_office: Option<Principal>) -> Result<(), InventoryError> {
+    require_caller("set_head_office", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_head_office(head_office);
+        Ok(())
+    })
+}
+
+// Compares the given items' prices at every registered store with this head office's own
+// prices, flagging those beyond the tolerance.
+// This function is marked as `#[update]` because it calls other canisters.
+#[update(guard = "rate_limit")]
+async fn audit_store_prices(item_ids: Vec<u32>) -> Result<Vec<ItemPriceAudit>, InventoryError> {
+    require_caller("audit_store_prices", Role::Manager)?;
+    audit_prices(item_ids).await
+}
+
+// Sets every deviating store price of the given items to head office's price, taking effect at
+// `effective_at` (nanoseconds since the Unix epoch) or at once when it is left out. Each store
+// is sent one change; stores that refuse it are reported and keep their prices.
+// This function is marked as `#[update]` because it calls other canisters.
+#[update(guard = "rate_limit")]
+async fn harmonize_store_prices(item_ids: Vec<u32>, effective_at: Option<u64>) -> Result<Vec<StoreHarmonization>, InventoryError> {
+    require_caller("harmonize_store_prices", Role::Manager)?;
+    let effective_at = effective_at.unwrap_or_else(ic_cdk::api::time);
+    let audits = audit_prices(item_ids).await?;
+    let mut changes: BTreeMap<Principal, Vec<(u32, f64)>> = BTreeMap::new();
+    for audit in &audits {
+        for store in audit.stores.iter().filter(|store| store.consistency == PriceConsistency::Deviating) {
+            changes.entry(store.store).or_default().push((audit.item_id, audit.head_office_price));
+        }
+    }
+    let mut outcomes = Vec::new();
+    for (store, prices) in changes {
+        let result: StoreReply<Option<u64>> =
+            ic_cdk::call(store, "schedule_head_office_prices", (prices.clone(), effective_at)).await;
+        let (schedule_id, error) = match result {
+            Ok((Ok(schedule_id),)) => (schedule_id, None),
+            Ok((Err(error),)) => (None, Some(format!("Store refused: {:?}", error))),
+            Err((code, msg)) => (None, Some(format!("Store call failed: {:?} {}", code, msg))),
+        };
+        outcomes.push(StoreHarmonization { store, prices, schedule_id, error });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let failed = outcomes.iter().filter(|outcome| outcome.error.is_some()).count();
+        let log = format!(
+            "Prices harmonized at {} stores, {} failed, at {}",
+            outcomes.len() - failed,
+            failed,
+            SupermarketManager::get_current_time()
+        );
+        inventory.borrow_mut().logs.push(log);
+    });
+    Ok(outcomes)
+}
+
+// Retrieves this store's prices of the given items, None for items it does not stock. Head
+// office calls this when auditing prices.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_store_prices(item_ids: Vec<u32>) -> Result<Vec<(u32, Option<f64>)>, InventoryError> {
+    if require_head_office().is_err() {
+        require_reader("get_store_prices", Role::Clerk)?;
+    }
+    Validator::new()
+        .check(item_ids.len() <= MAX_AUDIT_ITEMS, "item_ids", format!("must have at most {} entries", MAX_AUDIT_ITEMS))
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        Ok(item_ids.iter().map(|&item_id| (item_id, inventory.items.get(&item_id).map(|item| item.price))).collect())
+    })
+}
+
+// Takes new prices from this store's head office, given as (item ID, new price) pairs, to apply
+// at `effective_at` in nanoseconds since the Unix epoch. Returns the scheduled change's ID, or
+// None when the prices were already in effect and have been applied.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn schedule_head_office_prices(prices: Vec<(u32, f64)>, effective_at: u64) -> Result<Option<u64>, InventoryError> {
+    let head_office = require_head_office()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().schedule_prices(prices, effective_at, head_office, ic_cdk::api::time())
+    })
+}
+
+// Cancels a scheduled price change before it takes effect.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_scheduled_prices(id: u64) -> Result<ScheduledPriceChange, InventoryError> {
+    require_caller("cancel_scheduled_prices", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let change = inventory.chain.scheduled.remove(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Scheduled price change {} not found", id),
+        })?;
+        let log = format!("Scheduled price change {} cancelled at {}", id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(change)
+    })
+}
+
+// Retrieves the price changes waiting for their effective date, ordered by ID.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_scheduled_prices() -> Result<Vec<ScheduledPriceChange>, InventoryError> {
+    require_reader("list_scheduled_prices", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().chain.scheduled.values().cloned().collect()))
+}
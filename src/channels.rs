@@ -0,0 +1,237 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+/// Where a sale was made
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum SalesChannel {
+    #[default]
+    InStore,   // Tills and self-checkout
+    Online,    // The webshop
+    Wholesale, // Sales to business customers
+}
+
+impl SalesChannel {
+    pub const ALL: [SalesChannel; 3] = [SalesChannel::InStore, SalesChannel::Online, SalesChannel::Wholesale];
+}
+
+/// How tax applies to a channel's prices
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TaxTreatment {
+    pub rate_pct: f64,            // Tax rate, e.g. 20.0 for 20%
+    pub prices_include_tax: bool, // Whether prices already contain the tax, as shelf prices usually do
+}
+
+impl Default for TaxTreatment {
+    fn default() -> Self {
+        TaxTreatment { rate_pct: 0.0, prices_include_tax: true }
+    }
+}
+
+impl TaxTreatment {
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        Validator::new()
+            .check(self.rate_pct.is_finite() && (0.0..=100.0).contains(&self.rate_pct), "tax.rate_pct", "must be between 0 and 100")
+            .finish()
+    }
+}
+
+/// Price lists, tax treatment and stock buffers per sales channel
+///
+/// In-store sales always use the item's own price, which shelf labels show; other channels
+/// use it too unless their price list has an entry for the item.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Channels {
+    pub price_lists: BTreeMap<SalesChannel, BTreeMap<u32, f64>>, // Prices by item ID for the channels that override them
+    pub tax: BTreeMap<SalesChannel, TaxTreatment>,               // Channels without an entry charge no tax
+    pub buffers: BTreeMap<(u32, SalesChannel), u32>,             // Stock units kept back for one channel, by (item ID, channel)
+}
+
+/// How much of a channel's stock buffer is still backed by stock
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BufferUtilization {
+    pub item_id: u32,
+    pub channel: SalesChannel,
+    pub buffer: u32,               // Units kept back for the channel
+    pub on_hand: u32,              // The item's stock
+    pub covered: u32,              // Units of the buffer the stock still covers
+    pub covered_pct: f64,          // Covered units as a percentage of the buffer; below 100 only the channel can sell what is left
+    pub available_to_promise: u32, // Units the channel itself may sell: stock minus other channels' buffers
+}
+
+impl SupermarketManager {
+    /// Sets how tax is charged on a channel's sales
+    pub fn set_channel_tax(&mut self, channel: SalesChannel, tax: TaxTreatment) {
+        self.channels.tax.insert(channel, tax);
+        let log = format!("{:?} tax treatment changed at {}", channel, SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Stock units of an item a channel may sell: its stock less the buffers kept for other channels
+    pub fn available_to_promise(&self, item_id: u32, channel: SalesChannel) -> u32 {
+        let on_hand = self.items.get(&item_id).map_or(0, |item| item.quantity);
+        let held_for_others: u32 = SalesChannel::ALL
+            .into_iter()
+            .filter(|&other| other != channel)
+            .filter_map(|other| self.channels.buffers.get(&(item_id, other)))
+            .fold(0, |total: u32, &units| total.saturating_add(units));
+        on_hand.saturating_sub(held_for_others)
+    }
+
+    /// Utilization of every buffer, or only those of one item
+    pub fn buffer_utilization(&self, item_id: Option<u32>) -> Vec<BufferUtilization> {
+        self.channels.buffers
+            .iter()
+            .filter(|((id, _), _)| item_id.is_none_or(|item_id| *id == item_id))
+            .map(|(&(item_id, channel), &buffer)| {
+                let on_hand = self.items.get(&item_id).map_or(0, |item| item.quantity);
+                let covered = buffer.min(on_hand);
+                BufferUtilization {
+                    item_id,
+                    channel,
+                    buffer,
+                    on_hand,
+                    covered,
+                    covered_pct: if buffer == 0 { 100.0 } else { covered as f64 / buffer as f64 * 100.0 },
+                    available_to_promise: self.available_to_promise(item_id, channel),
+                }
+            })
+            .collect()
+    }
+
+    /// Prices a sale line for a channel as (unit price, total charged, tax contained in the total)
+    pub fn price_line(&self, item_id: u32, quantity: u32, channel: SalesChannel) -> (f64, f64, f64) {
+        let item = self.items.get(&item_id).expect("callers price existing items");
+        let unit_price = self.channels.price_lists
+            .get(&channel)
+            .and_then(|prices| prices.get(&item_id))
+            .copied()
+            .unwrap_or(item.price);
+        let price = unit_price * quantity as f64 / item.unit.stock_units_per_price_unit() as f64;
+        let tax = self.channels.tax.get(&channel).cloned().unwrap_or_default();
+        let (total, tax) = if tax.prices_include_tax {
+            let total = self.config.round_amount(price);
+            (total, self.config.round_amount(total * tax.rate_pct / (100.0 + tax.rate_pct)))
+        } else {
+            let tax = self.config.round_amount(price * tax.rate_pct / 100.0);
+            (self.config.round_amount(price) + tax, tax)
+        };
+        (unit_price, total, tax)
+    }
+}
+
+// Sets an item's price for the online or wholesale channel, or clears it with None so the
+// item's own price applies.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_channel_price(channel: SalesChannel, item_id: u32, price: Option<f64>) -> Result<(), InventoryError> {
+    require_permission("set_channel_price", Permission::ChangePrices)?;
+    let mut validator = Validator::new();
+    validator.check(channel != SalesChannel::InStore, "channel", "uses the item's own price; change that instead");
+    if let Some(price) = price {
+        validator.price("price", price);
+    }
+    validator.finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        let prices = inventory.channels.price_lists.entry(channel).or_default();
+        match price {
+            Some(price) => prices.insert(item_id, price),
+            None => prices.remove(&item_id),
+        };
+        let log = format!("Item {} {:?} price changed at {}", item_id, channel, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the price an item sells for on each channel.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_channel_prices(item_id: u32) -> Result<Vec<(SalesChannel, f64)>, InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let item = inventory.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        Ok(SalesChannel::ALL
+            .into_iter()
+            .map(|channel| {
+                let price = inventory.channels.price_lists.get(&channel).and_then(|prices| prices.get(&item_id));
+                (channel, price.copied().unwrap_or(item.price))
+            })
+            .collect())
+    })
+}
+
+// Keeps `units` of an item's stock back for one channel, e.g. 5 units for in-store however
+// much sells online, or removes the buffer with None. Packs and bundles draw on their
+// components' buffers, so set it on the item that holds the stock.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_channel_buffer(item_id: u32, channel: SalesChannel, units: Option<u32>) -> Result<(), InventoryError> {
+    require_caller("set_channel_buffer", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let item = inventory.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        Validator::new()
+            .check(!matches!(item.unit, Unit::Pack { .. }), "item_id", "is a pack; set the buffer on its base item")
+            .check(!inventory.bundles.definitions.contains_key(&item_id), "item_id", "is a bundle; set buffers on its components")
+            .finish()?;
+        match units {
+            Some(units) => inventory.channels.buffers.insert((item_id, channel), units),
+            None => inventory.channels.buffers.remove(&(item_id, channel)),
+        };
+        let log = format!("Item {} {:?} stock buffer changed at {}", item_id, channel, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves how far each stock buffer is backed by stock, optionally for one item only.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_buffer_utilization(item_id: Option<u32>) -> Result<Vec<BufferUtilization>, InventoryError> {
+    require_reader("get_buffer_utilization", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().buffer_utilization(item_id)))
+}
+
+// Sets how tax is charged on a channel's sales. Once a governance canister is set, this takes an
+// executed `SetChannelTax` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_channel_tax(channel: SalesChannel, tax: TaxTreatment) -> Result<(), InventoryError> {
+    require_caller("set_channel_tax", Role::Manager)?;
+    tax.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_channel_tax(channel, tax);
+        Ok(())
+    })
+}
+
+// Retrieves the tax treatment of every channel.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_channel_tax() -> Vec<(SalesChannel, TaxTreatment)> {
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        SalesChannel::ALL
+            .into_iter()
+            .map(|channel| (channel, inventory.channels.tax.get(&channel).cloned().unwrap_or_default()))
+            .collect()
+    })
+}
@@ -0,0 +1,352 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::costing::CostBatch;
+use crate::journal::JournalEvent;
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_READINGS: usize = 100_000;    // Temperature readings kept; the oldest is dropped first
+const MAX_BREACHES: usize = 10_000;     // Breaches kept; the oldest is dropped first
+const MAX_LOCATIONS: usize = 200;       // Locations monitored at once
+const MIN_READING_CELSIUS: f64 = -60.0; // Colder or warmer readings are taken to be sensor faults
+const MAX_READING_CELSIUS: f64 = 80.0;
+
+/// How an item has to be kept
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum StorageClass {
+    Ambient, // Shelf-stable at room temperature
+    Chilled, // Kept in a fridge or chiller cabinet
+    Frozen,  // Kept in a freezer
+}
+
+impl StorageClass {
+    /// The food-safety range a location of this class is held to unless it sets its own
+    pub fn default_range(self) -> TemperatureRange {
+        match self {
+            StorageClass::Ambient => TemperatureRange { min_celsius: 5.0, max_celsius: 25.0 },
+            StorageClass::Chilled => TemperatureRange { min_celsius: 0.0, max_celsius: 5.0 },
+            StorageClass::Frozen => TemperatureRange { min_celsius: -30.0, max_celsius: -18.0 },
+        }
+    }
+}
+
+/// Temperatures a location must stay within
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub struct TemperatureRange {
+    pub min_celsius: f64,
+    pub max_celsius: f64,
+}
+
+impl TemperatureRange {
+    fn contains(&self, celsius: f64) -> bool {
+        celsius >= self.min_celsius && celsius <= self.max_celsius
+    }
+
+    /// How far outside the range a reading is; 0 inside it
+    fn excess(&self, celsius: f64) -> f64 {
+        (self.min_celsius - celsius).max(celsius - self.max_celsius).max(0.0)
+    }
+}
+
+/// A fridge, freezer or aisle whose temperature is logged
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct MonitoredLocation {
+    pub name: String,                // Matches the aisle of the items shelved there, e.g. "Frozen"
+    pub storage_class: StorageClass,
+    pub range: TemperatureRange,
+}
+
+/// A temperature logged at a location
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TemperatureReading {
+    pub location: String,
+    pub celsius: f64,
+    pub in_range: bool,
+    pub recorded_by: Principal,
+    pub recorded_at: u64,
+}
+
+/// A run of readings outside a location's range, from the first bad reading to the first good one
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TemperatureBreach {
+    pub id: u64,
+    pub location: String,
+    pub storage_class: StorageClass,
+    pub range: TemperatureRange,
+    pub started_at: u64,
+    pub ended_at: Option<u64>, // Time of the first reading back in range; None while the breach lasts
+    pub worst_celsius: f64,    // The reading furthest outside the range
+    pub readings: u32,         // Readings outside the range during the breach
+}
+
+/// An item shelved at a breached location and its stock that was on hand during the breach
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AffectedItem {
+    pub item_id: u32,
+    pub name: String,
+    pub storage_class: Option<StorageClass>,
+    pub expiration_date: u64,
+    pub quantity: u32,
+    pub batches: Vec<CostBatch>, // Batches received before the breach ended that still have stock
+}
+
+/// A breach with the stock it may have spoiled, for food-safety audits
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BreachImpact {
+    pub breach: TemperatureBreach,
+    pub affected: Vec<AffectedItem>, // In ID order
+}
+
+/// Monitored locations, their temperature readings and the breaches found in them
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ColdChain {
+    pub locations: BTreeMap<String, MonitoredLocation>, // Locations keyed by name
+    pub readings: VecDeque<TemperatureReading>,          // Oldest first
+    pub breaches: BTreeMap<u64, TemperatureBreach>,      // Breaches keyed by ID, oldest first
+    pub open_breaches: HashMap<String, u64>,             // Breach still running per location
+    pub next_breach_id: u64,
+}
+
+impl SupermarketManager {
+    /// Sets how an item has to be kept, or clears it
+    pub fn set_item_storage_class(&mut self, item_id: u32, storage_class: Option<StorageClass>) -> Result<(), InventoryError> {
+        if !self.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        self.journal_event(JournalEvent::StorageClassSet { item_id, storage_class });
+        let log = format!("Item {} storage class set to {:?} at {}", item_id, storage_class, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Logs a temperature and opens or closes the location's breach, alerting webhooks when one opens
+    pub fn record_temperature(&mut self, location: &str, celsius: f64, recorded_by: Principal, now: u64) -> Result<TemperatureReading, InventoryError> {
+        let monitored = self.cold_chain.locations.get(location).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Location {} is not monitored", location),
+        })?;
+        let in_range = monitored.range.contains(celsius);
+        let reading = TemperatureReading { location: monitored.name.clone(), celsius, in_range, recorded_by, recorded_at: now };
+        self.cold_chain.readings.push_back(reading.clone());
+        while self.cold_chain.readings.len() > MAX_READINGS {
+            self.cold_chain.readings.pop_front();
+        }
+
+        let open = self.cold_chain.open_breaches.get(location).copied();
+        match (in_range, open) {
+            (true, Some(id)) => {
+                self.cold_chain.open_breaches.remove(location);
+                if let Some(breach) = self.cold_chain.breaches.get_mut(&id) {
+                    breach.ended_at = Some(now);
+                }
+                let log = format!("Temperature breach {} at {} ended at {}", id, location, SupermarketManager::get_current_time());
+                self.logs.push(log);
+            }
+            (false, Some(id)) => {
+                if let Some(breach) = self.cold_chain.breaches.get_mut(&id) {
+                    breach.readings += 1;
+                    if monitored.range.excess(celsius) > monitored.range.excess(breach.worst_celsius) {
+                        breach.worst_celsius = celsius;
+                    }
+                }
+            }
+            (false, None) => {
+                let id = self.cold_chain.next_breach_id;
+                self.cold_chain.next_breach_id += 1;
+                self.cold_chain.breaches.insert(id, TemperatureBreach {
+                    id,
+                    location: monitored.name.clone(),
+                    storage_class: monitored.storage_class,
+                    range: monitored.range,
+                    started_at: now,
+                    ended_at: None,
+                    worst_celsius: celsius,
+                    readings: 1,
+                });
+                while self.cold_chain.breaches.len() > MAX_BREACHES {
+                    self.cold_chain.breaches.pop_first();
+                }
+                self.cold_chain.open_breaches.insert(monitored.name.clone(), id);
+                let log = format!(
+                    "Temperature breach {} at {}: {}°C outside {}°C to {}°C at {}",
+                    id,
+                    location,
+                    celsius,
+                    monitored.range.min_celsius,
+                    monitored.range.max_celsius,
+                    SupermarketManager::get_current_time()
+                );
+                self.logs.push(log);
+                self.notify_webhooks(WebhookEvent::TemperatureBreach {
+                    location: monitored.name,
+                    celsius,
+                    min_celsius: monitored.range.min_celsius,
+                    max_celsius: monitored.range.max_celsius,
+                });
+            }
+            (true, None) => {}
+        }
+        Ok(reading)
+    }
+
+    /// Breaches that overlap the window, each with the stock shelved at its location that was
+    /// on hand while it lasted
+    ///
+    /// Batches are the ones still holding stock; batches sold through since are not listed.
+    pub fn breach_report(&self, from: u64, to: u64, now: u64) -> Vec<BreachImpact> {
+        let breaches: Vec<&TemperatureBreach> = self.cold_chain.breaches
+            .values()
+            .filter(|breach| breach.started_at < to && breach.ended_at.is_none_or(|ended_at| ended_at >= from))
+            .collect();
+        if breaches.is_empty() {
+            return Vec::new();
+        }
+        let items: Vec<InventoryItem> = self.items.values().filter(|item| !item.archived).collect();
+        let mut shelved: HashMap<&str, Vec<&InventoryItem>> = HashMap::new();
+        for item in &items {
+            if let Some(location) = &item.location {
+                shelved.entry(location.aisle.as_str()).or_default().push(item);
+            }
+        }
+
+        breaches
+            .into_iter()
+            .map(|breach| {
+                let ended_at = breach.ended_at.unwrap_or(now);
+                let affected = shelved
+                    .get(breach.location.as_str())
+                    .into_iter()
+                    .flatten()
+                    .map(|item| AffectedItem {
+                        item_id: item.id,
+                        name: item.name.clone(),
+                        storage_class: item.storage_class,
+                        expiration_date: item.expiration_date,
+                        quantity: item.quantity,
+                        batches: self.costing.batches
+                            .get(&item.id)
+                            .into_iter()
+                            .flatten()
+                            .filter(|batch| batch.remaining > 0 && batch.received_at <= ended_at)
+                            .cloned()
+                            .collect(),
+                    })
+                    .collect();
+                BreachImpact { breach: breach.clone(), affected }
+            })
+            .collect()
+    }
+}
+
+// Sets how an item has to be kept (ambient, chilled or frozen), or clears it.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_item_storage_class(item_id: u32, storage_class: Option<StorageClass>) -> Result<(), InventoryError> {
+    require_caller("set_item_storage_class", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_item_storage_class(item_id, storage_class)
+    })
+}
+
+// Starts or changes temperature monitoring of a location, named like the aisle its items are
+// shelved in. The range defaults to the storage class's food-safety range.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_monitored_location(location: String, storage_class: StorageClass, range: Option<TemperatureRange>) -> Result<MonitoredLocation, InventoryError> {
+    require_caller("set_monitored_location", Role::Manager)?;
+    let range = range.unwrap_or_else(|| storage_class.default_range());
+    Validator::new()
+        .name("location", &location)
+        .check(range.min_celsius.is_finite() && range.max_celsius.is_finite(), "range", "must be finite")
+        .check(range.min_celsius < range.max_celsius, "range.max_celsius", "must be above min_celsius")
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let name = location.trim().to_string();
+        if !inventory.cold_chain.locations.contains_key(&name) && inventory.cold_chain.locations.len() >= MAX_LOCATIONS {
+            return Err(InventoryError::Conflict { msg: format!("At most {} locations can be monitored", MAX_LOCATIONS) });
+        }
+        let monitored = MonitoredLocation { name: name.clone(), storage_class, range };
+        inventory.cold_chain.locations.insert(name.clone(), monitored.clone());
+        let log = format!("Location {} monitored as {:?} at {}", name, storage_class, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(monitored)
+    })
+}
+
+// Stops monitoring a location. Its readings and breaches are kept for audits.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_monitored_location(location: String) -> Result<(), InventoryError> {
+    require_caller("remove_monitored_location", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.cold_chain.locations.remove(&location).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Location {} is not monitored", location),
+        })?;
+        inventory.cold_chain.open_breaches.remove(&location);
+        let log = format!("Location {} no longer monitored at {}", location, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every monitored location, ordered by name.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_monitored_locations() -> Result<Vec<MonitoredLocation>, InventoryError> {
+    require_reader("list_monitored_locations", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().cold_chain.locations.values().cloned().collect()))
+}
+
+// Logs a temperature reading at a monitored location, e.g. from a probe or a staff check.
+// A reading outside the location's range opens a breach and alerts webhooks; the next reading
+// back in range closes it.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn record_temperature(location: String, celsius: f64) -> Result<TemperatureReading, InventoryError> {
+    require_caller("record_temperature", Role::Clerk)?;
+    Validator::new()
+        .check(celsius.is_finite(), "celsius", "must be a number")
+        .check(
+            (MIN_READING_CELSIUS..=MAX_READING_CELSIUS).contains(&celsius),
+            "celsius",
+            format!("must be between {} and {}", MIN_READING_CELSIUS, MAX_READING_CELSIUS),
+        )
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().record_temperature(&location, celsius, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Retrieves the readings logged at a location between `from` and `to` (nanoseconds since the
+// Unix epoch), oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_temperature_readings(location: String, from: u64, to: u64) -> Result<Vec<TemperatureReading>, InventoryError> {
+    require_reader("get_temperature_readings", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().cold_chain.readings
+            .iter()
+            .filter(|reading| reading.location == location && reading.recorded_at >= from && reading.recorded_at < to)
+            .cloned()
+            .collect())
+    })
+}
+
+// Retrieves the temperature breaches that overlap `from` to `to`, each with the items shelved at
+// the location and their batches on hand during the breach, for food-safety audits.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_temperature_breach_report(from: u64, to: u64) -> Result<Vec<BreachImpact>, InventoryError> {
+    require_reader("get_temperature_breach_report", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().breach_report(from, to, ic_cdk::api::time())))
+}
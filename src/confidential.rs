@@ -0,0 +1,276 @@
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// Kinds of sensitive data kept in confidential storage
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfidentialKind {
+    SupplierContract, // Contract terms agreed with a supplier
+    CustomerContact,  // Contact details of a customer
+}
+
+/// A sensitive field stored only as ciphertext
+///
+/// The canister never sees the plaintext: clients encrypt with a key obtained from
+/// `get_confidential_key` and decrypt the stored ciphertext the same way.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ConfidentialRecord {
+    pub kind: ConfidentialKind,  // What the record holds
+    pub subject_id: String,      // Supplier or customer the record is about
+    pub ciphertext: Vec<u8>,     // Client-encrypted contents
+    pub key_epoch: u32,          // Key epoch the ciphertext was encrypted under
+    pub readers: Vec<Principal>, // Principals besides managers allowed to decrypt
+    pub updated_by: Principal,   // Who stored the current ciphertext
+    pub updated_at: u64,         // When the ciphertext was stored in nanoseconds since the Unix epoch
+}
+
+/// Settings for calls to the vetKD management canister API
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VetKdConfig {
+    pub key_name: String,       // Name of the vetKD master key (e.g. "key_1", or "dfx_test_key" locally)
+    pub derive_key_cycles: u64, // Cycles attached to each vetkd_derive_key call
+}
+
+impl Default for VetKdConfig {
+    fn default() -> Self {
+        VetKdConfig {
+            key_name: "key_1".to_string(),
+            derive_key_cycles: 26_153_846_153,
+        }
+    }
+}
+
+/// A record key encrypted to the caller's transport key
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct EncryptedRecordKey {
+    pub encrypted_key: Vec<u8>, // vetKD key encrypted to the transport public key
+    pub key_epoch: u32,         // Epoch the key belongs to; ciphertexts must be tagged with it
+}
+
+/// Confidential records and the current key epoch
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ConfidentialStore {
+    pub config: VetKdConfig,                                               // vetKD settings
+    pub records: BTreeMap<(ConfidentialKind, String), ConfidentialRecord>, // Records keyed by kind and subject
+    pub key_epoch: u32,                                                    // Bumped on every key rotation
+}
+
+#[derive(CandidType, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+/// Derivation context for a key epoch, so rotating the epoch yields unrelated keys
+fn derivation_context(epoch: u32) -> Vec<u8> {
+    format!("supermarket_inventory confidential v{}", epoch).into_bytes()
+}
+
+/// Derivation input for a record, so each record has its own key
+fn derivation_input(kind: ConfidentialKind, subject_id: &str) -> Vec<u8> {
+    format!("{:?}/{}", kind, subject_id).into_bytes()
+}
+
+impl SupermarketManager {
+    /// Checks that a principal may decrypt a record
+    /// - `principal`: The principal asking for the record or its key
+    /// - `kind`, `subject_id`: The record being accessed
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    ///
+    /// Managers and an active break-glass recovery principal may access every record; other
+    /// principals only records listing them as readers.
+    pub fn check_confidential_access(&self, principal: &Principal, kind: ConfidentialKind, subject_id: &str, now: u64) -> Result<(), InventoryError> {
+        if self.access.require(principal, Role::Manager, now).is_ok() || self.break_glass.is_active(principal, now) {
+            return Ok(());
+        }
+        match self.confidential.records.get(&(kind, subject_id.to_string())) {
+            Some(record) if record.readers.contains(principal) => Ok(()),
+            _ => Err(InventoryError::Unauthorized { msg: format!("{} may not read this record", principal) }),
+        }
+    }
+}
+
+// Derives the key for a record and returns it encrypted to the caller's transport key.
+// Managers use it to encrypt new contents; authorized readers use it to decrypt. Pass the
+// record's `key_epoch` to decrypt a record stored before a rotation, or None for the current epoch.
+// Access is checked per record against its readers rather than by role.
+// This function is marked as `#[update]` because it calls the management canister.
+#[update(guard = "rate_limit")]
+async fn get_confidential_key(kind: ConfidentialKind, subject_id: String, transport_public_key: Vec<u8>, key_epoch: Option<u32>) -> Result<EncryptedRecordKey, InventoryError> {
+    let caller = ic_cdk::caller();
+    let (config, key_epoch) = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        inventory.check_confidential_access(&caller, kind, &subject_id, ic_cdk::api::time())?;
+        let current = inventory.confidential.key_epoch;
+        let epoch = key_epoch.unwrap_or(current);
+        if epoch > current {
+            return Err(InventoryError::InvalidInput { msg: format!("Key epoch {} does not exist yet", epoch) });
+        }
+        Ok((inventory.confidential.config.clone(), epoch))
+    })?;
+    let args = VetKdDeriveKeyArgs {
+        input: derivation_input(kind, &subject_id),
+        context: derivation_context(key_epoch),
+        transport_public_key,
+        key_id: VetKdKeyId { curve: VetKdCurve::Bls12381G2, name: config.key_name },
+    };
+    let (result,): (VetKdDeriveKeyResult,) = call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        config.derive_key_cycles as u128,
+    )
+    .await
+    .map_err(|(code, msg)| InventoryError::CallFailed { msg: format!("vetkd_derive_key failed: {:?} {}", code, msg) })?;
+    Ok(EncryptedRecordKey { encrypted_key: result.encrypted_key, key_epoch })
+}
+
+// Retrieves the vetKD public key for the current epoch, which clients use to verify derived keys.
+// Open to anyone, as the key is public.
+// This function is marked as `#[update]` because it calls the management canister.
+#[update(guard = "rate_limit")]
+async fn get_confidential_public_key() -> Result<Vec<u8>, InventoryError> {
+    let (config, key_epoch) = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        (inventory.confidential.config.clone(), inventory.confidential.key_epoch)
+    });
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: derivation_context(key_epoch),
+        key_id: VetKdKeyId { curve: VetKdCurve::Bls12381G2, name: config.key_name },
+    };
+    let (result,): (VetKdPublicKeyResult,) = ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+        .await
+        .map_err(|(code, msg)| InventoryError::CallFailed { msg: format!("vetkd_public_key failed: {:?} {}", code, msg) })?;
+    Ok(result.public_key)
+}
+
+// Stores client-encrypted contents for a record, replacing any previous version.
+// The ciphertext must be encrypted under the current key epoch.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn put_confidential_record(kind: ConfidentialKind, subject_id: String, ciphertext: Vec<u8>, key_epoch: u32, readers: Vec<Principal>) -> Result<(), InventoryError> {
+    require_caller("put_confidential_record", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if key_epoch != inventory.confidential.key_epoch {
+            return Err(InventoryError::Conflict {
+                msg: format!("Ciphertext uses key epoch {} but the current epoch is {}", key_epoch, inventory.confidential.key_epoch),
+            });
+        }
+        let record = ConfidentialRecord {
+            kind,
+            subject_id: subject_id.clone(),
+            ciphertext,
+            key_epoch,
+            readers,
+            updated_by: ic_cdk::caller(),
+            updated_at: ic_cdk::api::time(),
+        };
+        inventory.confidential.records.insert((kind, subject_id.clone()), record);
+        let log = format!(
+            "Confidential {:?} record for {} stored at {}",
+            kind,
+            subject_id,
+            SupermarketManager::get_current_time()
+        );
+        inventory.logs.push(log); // Log the change without its contents
+        Ok(())
+    })
+}
+
+// Retrieves the ciphertext of a record for an authorized reader.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_confidential_record(kind: ConfidentialKind, subject_id: String) -> Result<ConfidentialRecord, InventoryError> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        inventory.check_confidential_access(&caller, kind, &subject_id, ic_cdk::api::time())?;
+        inventory.confidential.records.get(&(kind, subject_id.clone())).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("No {:?} record for {}", kind, subject_id),
+        })
+    })
+}
+
+// Starts a new key epoch. Existing records stay readable with their old epoch's key until a
+// manager re-encrypts them under the new one.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn rotate_confidential_key() -> Result<u32, InventoryError> {
+    require_caller("rotate_confidential_key", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        inventory.confidential.key_epoch += 1;
+        let epoch = inventory.confidential.key_epoch;
+        let log = format!("Confidential key rotated to epoch {} at {}", epoch, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(epoch)
+    })
+}
+
+// Retrieves (kind, subject ID, key epoch) of records still encrypted under an old key epoch.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_stale_confidential_records() -> Result<Vec<(ConfidentialKind, String, u32)>, InventoryError> {
+    require_reader("list_stale_confidential_records", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let current = inventory.confidential.key_epoch;
+        Ok(inventory.confidential.records.values()
+            .filter(|record| record.key_epoch < current)
+            .map(|record| (record.kind, record.subject_id.clone(), record.key_epoch))
+            .collect())
+    })
+}
+
+// Replaces the vetKD key name and cycle settings.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_vetkd_config(config: VetKdConfig) -> Result<(), InventoryError> {
+    require_caller("set_vetkd_config", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().governance.ensure_direct_change_allowed())?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().confidential.config = config;
+    });
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::HashMap;
+
+use crate::{SupermarketManager, INVENTORY_MANAGER};
+
+const INSTRUCTIONS_PER_ROUND: u64 = 2_000_000_000;      // Instructions a message executes per round before it is sliced
+const MAX_INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000; // Instructions after which an update message traps
+const SECS_PER_ROUND: f64 = 1.0;                        // Typical round duration on an application subnet
+const UPDATE_BASE_CYCLES: u128 = 590_000;               // Base fee of an update message on a 13-node subnet
+const CYCLES_PER_10_INSTRUCTIONS: u128 = 4;             // Execution fee on a 13-node subnet
+
+/// Operations whose cost grows with the size of the store
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeavyOperation {
+    Export,      // Serializing items, sales and logs for export_inventory
+    ReorderJob,  // The daily reorder suggestion run
+    ExpiredScan, // The daily scan for expired items
+    Snapshot,    // Encoding the store for create_snapshot
+}
+
+impl HeavyOperation {
+    /// Instructions assumed per unit of input before any run has been measured
+    fn default_instructions_per_unit(&self) -> u64 {
+        match self {
+            HeavyOperation::Export => 20_000,
+            HeavyOperation::ReorderJob => 50_000,
+            HeavyOperation::ExpiredScan => 2_000,
+            HeavyOperation::Snapshot => 10_000,
+        }
+    }
+}
+
+/// Measurements of past runs of an operation
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct OperationStats {
+    pub samples: u64,            // Number of measured runs
+    pub total_instructions: u64, // Instructions used across all measured runs
+    pub total_units: u64,        // Input units processed across all measured runs
+    pub last_instructions: u64,  // Instructions used by the most recent run
+}
+
+/// Predicted cost of running an operation
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct CostEstimate {
+    pub operation: HeavyOperation,
+    pub input_size: u64,              // Units of input the estimate is for
+    pub instructions: u64,            // Predicted instruction count
+    pub cycles: u128,                 // Predicted cycles charged
+    pub rounds: u64,                  // Rounds the message is expected to span
+    pub expected_duration_secs: f64,  // Predicted wall-clock duration
+    pub exceeds_message_limit: bool,  // Whether the run would trap on the instruction limit
+    pub samples: u64,                 // Measured runs the prediction is based on; 0 means a default guess
+}
+
+/// Instruction counts measured for heavy operations
+#[derive(Default)]
+pub struct CostTracker {
+    pub stats: HashMap<HeavyOperation, OperationStats>, // Measurements keyed by operation
+}
+
+impl CostTracker {
+    /// Records a measured run of an operation
+    /// - `input_size`: Units of input the run processed
+    /// - `instructions`: Instructions the run used
+    pub fn record(&mut self, operation: HeavyOperation, input_size: u64, instructions: u64) {
+        let stats = self.stats.entry(operation).or_default();
+        stats.samples += 1;
+        stats.total_instructions = stats.total_instructions.saturating_add(instructions);
+        stats.total_units = stats.total_units.saturating_add(input_size);
+        stats.last_instructions = instructions;
+    }
+
+    /// Predicts the cost of running an operation over `input_size` units
+    ///
+    /// Uses the average instructions per unit of past runs, or a conservative default before
+    /// the operation has been measured.
+    pub fn estimate(&self, operation: HeavyOperation, input_size: u64) -> CostEstimate {
+        let stats = self.stats.get(&operation).cloned().unwrap_or_default();
+        let per_unit = match stats.total_units {
+            0 => operation.default_instructions_per_unit(),
+            units => stats.total_instructions.div_ceil(units),
+        };
+        let instructions = per_unit.saturating_mul(input_size.max(1));
+        let rounds = instructions.div_ceil(INSTRUCTIONS_PER_ROUND).max(1);
+        CostEstimate {
+            operation,
+            input_size,
+            instructions,
+            cycles: UPDATE_BASE_CYCLES + instructions as u128 * CYCLES_PER_10_INSTRUCTIONS / 10,
+            rounds,
+            expected_duration_secs: rounds as f64 * SECS_PER_ROUND,
+            exceeds_message_limit: instructions > MAX_INSTRUCTIONS_PER_MESSAGE,
+            samples: stats.samples,
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Current input size of an operation: the number of records it has to walk
+    pub fn operation_input_size(&self, operation: HeavyOperation) -> u64 {
+        match operation {
+            HeavyOperation::Export | HeavyOperation::Snapshot => self.items.len() as u64 + self.sales.len() + self.logs.len() as u64,
+            HeavyOperation::ReorderJob => self.reorder.rules.len() as u64,
+            HeavyOperation::ExpiredScan => self.items.len() as u64,
+        }
+    }
+}
+
+/// Runs `f` and records the instructions it used against the operation's current input size
+///
+/// `f` must not hold a borrow of the inventory when it returns.
+pub fn measured<T>(operation: HeavyOperation, f: impl FnOnce() -> T) -> T {
+    let input_size = INVENTORY_MANAGER.with(|inventory| inventory.borrow().operation_input_size(operation));
+    let start = ic_cdk::api::performance_counter(0);
+    let result = f();
+    let instructions = ic_cdk::api::performance_counter(0).saturating_sub(start);
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().costs.record(operation, input_size, instructions);
+    });
+    result
+}
+
+// Predicts the instructions, cycles and duration of a heavy operation so it can be scheduled.
+// Pass `input_size` to plan for a store of a different size, or None for the current one.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn estimate_operation_cost(operation: HeavyOperation, input_size: Option<u64>) -> CostEstimate {
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let input_size = input_size.unwrap_or_else(|| inventory.operation_input_size(operation));
+        inventory.costs.estimate(operation, input_size)
+    })
+}
+
+// Retrieves the measurements behind the estimates.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_operation_stats() -> Vec<(HeavyOperation, OperationStats)> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().costs.stats.iter().map(|(op, stats)| (*op, stats.clone())).collect()
+    })
+}
@@ -0,0 +1,337 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::history::ItemHistoryEvent;
+use crate::idempotency::run_once;
+use crate::journal::JournalEvent;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{AdjustmentReason, InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+pub const UNCATEGORIZED: &str = "Uncategorized"; // Category reported for items without one
+
+/// Stock received in one delivery, with what it cost
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct CostBatch {
+    pub batch_id: u64,
+    pub item_id: u32,
+    pub received_at: u64, // Time of receipt in nanoseconds since the Unix epoch
+    pub received: u32,    // Units received, in grams or millilitres for weighed goods
+    pub remaining: u32,   // Units of the batch not yet sold or written off
+    pub unit_cost: f64,   // Cost per unit, per kilogram or litre for weighed goods, like the selling price
+}
+
+/// Cost price of an item and its stock still on hand
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemCost {
+    pub item_id: u32,
+    pub cost_price: Option<f64>,  // Latest cost per unit; used for stock not covered by a batch
+    pub batches: Vec<CostBatch>,  // Batches with units remaining, oldest first
+    pub stock_value: Option<f64>, // Cost of the units on hand, if every unit can be costed
+}
+
+/// Gross margin over a set of sales
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct MarginLine {
+    pub revenue: f64,          // Takings from costed sales
+    pub cost: f64,             // Cost of the goods in those sales
+    pub gross_margin: f64,     // Revenue minus cost
+    pub margin_pct: f64,       // Gross margin as a percentage of revenue
+    pub units_sold: u64,       // Units sold in costed sales
+    pub uncosted_revenue: f64, // Takings from sales whose cost is unknown; left out of the margin
+}
+
+impl MarginLine {
+    fn add(&mut self, revenue: f64, cost: Option<f64>, units: u32) {
+        match cost {
+            Some(cost) => {
+                self.revenue += revenue;
+                self.cost += cost;
+                self.units_sold += units as u64;
+            }
+            None => self.uncosted_revenue += revenue,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.gross_margin = self.revenue - self.cost;
+        self.margin_pct = if self.revenue > 0.0 { self.gross_margin / self.revenue * 100.0 } else { 0.0 };
+    }
+
+    fn convert(&mut self, convert: &impl Fn(f64) -> f64) {
+        self.revenue = convert(self.revenue);
+        self.cost = convert(self.cost);
+        self.gross_margin = convert(self.gross_margin);
+        self.uncosted_revenue = convert(self.uncosted_revenue);
+    }
+}
+
+/// Margin of one item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemMargin {
+    pub item_id: u32,
+    pub name: String,
+    pub category: String,
+    pub margin: MarginLine,
+}
+
+/// Gross margin per item and per category over a period
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct MarginReport {
+    pub since: u64,                                   // Start of the period in nanoseconds since the Unix epoch
+    pub items: Vec<ItemMargin>,                       // Items sold in the period, highest gross margin first
+    pub categories: BTreeMap<String, MarginLine>,     // Totals per category
+    pub channels: BTreeMap<SalesChannel, MarginLine>, // Totals per sales channel
+    pub total: MarginLine,                            // Totals over every sale in the period
+    pub currency: String,                             // Currency of every amount in the report
+}
+
+impl MarginReport {
+    /// Restates every amount in another currency; margin percentages are unchanged
+    pub fn convert(&mut self, currency: String, convert: impl Fn(f64) -> f64) {
+        self.items.iter_mut().for_each(|line| line.margin.convert(&convert));
+        self.categories.values_mut().chain(self.channels.values_mut()).for_each(|line| line.convert(&convert));
+        self.total.convert(&convert);
+        self.currency = currency;
+    }
+}
+
+/// Cost prices, received batches and the cost of every sale
+///
+/// Kept apart from items and sales because those are readable by anyone, while cost and
+/// margin figures are for managers only. Sales consume batches oldest first.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Costing {
+    pub cost_prices: HashMap<u32, f64>,             // Latest cost per unit by item ID
+    pub batches: HashMap<u32, VecDeque<CostBatch>>, // Batches with units remaining by item ID, oldest first
+    pub sale_costs: HashMap<u64, f64>,              // Cost of goods by sale ID; sales without an entry are uncosted
+    pub next_batch_id: u64,
+}
+
+impl Costing {
+    /// Takes units out of an item's batches, oldest first, and returns their cost
+    ///
+    /// Units beyond the batches are costed at the item's cost price; the result is None if
+    /// there is none.
+    /// - `units_per_cost_unit`: Stock units per unit the cost is quoted in
+    pub fn consume(&mut self, item_id: u32, units: u32, units_per_cost_unit: u32) -> Option<f64> {
+        let per_unit = |unit_cost: f64| unit_cost / units_per_cost_unit as f64;
+        let mut left = units;
+        let mut cost = 0.0;
+        if let Some(batches) = self.batches.get_mut(&item_id) {
+            while left > 0 {
+                let Some(batch) = batches.front_mut() else { break };
+                let taken = batch.remaining.min(left);
+                batch.remaining -= taken;
+                left -= taken;
+                cost += per_unit(batch.unit_cost) * taken as f64;
+                if batch.remaining == 0 {
+                    batches.pop_front();
+                }
+            }
+        }
+        if left > 0 {
+            cost += per_unit(*self.cost_prices.get(&item_id)?) * left as f64;
+        }
+        Some(cost)
+    }
+
+    /// Writes off the oldest batch units so the batches never hold more than the stock on hand
+    pub fn trim_to(&mut self, item_id: u32, quantity: u32) {
+        let Some(batches) = self.batches.get_mut(&item_id) else { return };
+        let mut excess = batches.iter().map(|b| b.remaining as u64).sum::<u64>().saturating_sub(quantity as u64);
+        while excess > 0 {
+            let Some(batch) = batches.front_mut() else { break };
+            let taken = (batch.remaining as u64).min(excess) as u32;
+            batch.remaining -= taken;
+            excess -= taken as u64;
+            if batch.remaining == 0 {
+                batches.pop_front();
+            }
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Books a delivery: adds the units to stock and records their cost as a new batch
+    /// - `unit_cost`: Cost per unit, per kilogram or litre for weighed goods
+    ///
+    /// Returns the batch ID.
+    pub fn receive_stock(&mut self, item_id: u32, quantity: u32, unit_cost: f64, now: u64) -> Result<u64, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        let new_quantity = item.quantity.checked_add(quantity).ok_or_else(|| InventoryError::InvalidInput {
+            msg: "Received quantity overflows the stock level".to_string(),
+        })?;
+        self.validate_stock_level(item_id, new_quantity)?;
+        let batch_id = self.costing.next_batch_id;
+        self.costing.next_batch_id += 1;
+        self.costing.cost_prices.insert(item_id, unit_cost);
+        self.adjust_item_quantity(item_id, new_quantity, AdjustmentReason::Received);
+        self.costing.batches.entry(item_id).or_default().push_back(CostBatch {
+            batch_id,
+            item_id,
+            received_at: now,
+            received: quantity,
+            remaining: quantity,
+            unit_cost,
+        });
+        self.record_history(item_id, ItemHistoryEvent::BatchReceived { batch_id, quantity });
+        self.notify_back_in_stock(item_id, now);
+        Ok(batch_id)
+    }
+
+    /// Cost price and batches of an item
+    pub fn item_cost(&self, item_id: u32) -> Result<ItemCost, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        let batches: Vec<CostBatch> = self.costing.batches.get(&item_id).map(|b| b.iter().cloned().collect()).unwrap_or_default();
+        let cost_price = self.costing.cost_prices.get(&item_id).copied();
+        let per_unit = item.unit.stock_units_per_price_unit() as f64;
+        let in_batches: u64 = batches.iter().map(|b| b.remaining as u64).sum();
+        let batch_value: f64 = batches.iter().map(|b| b.unit_cost / per_unit * b.remaining as f64).sum();
+        let uncovered = (item.quantity as u64).saturating_sub(in_batches);
+        let stock_value = match cost_price {
+            _ if uncovered == 0 => Some(batch_value),
+            Some(cost) => Some(batch_value + cost / per_unit * uncovered as f64),
+            None => None,
+        };
+        Ok(ItemCost { item_id, cost_price, batches, stock_value })
+    }
+
+    /// Gross margin of every sale at or after `since`, per item and per category
+    /// - `include_test`: Whether to count test sales
+    pub fn margin_report(&self, since: u64, include_test: bool) -> MarginReport {
+        let mut items: BTreeMap<u32, ItemMargin> = BTreeMap::new();
+        let mut total = MarginLine::default();
+        let mut channels: BTreeMap<SalesChannel, MarginLine> = BTreeMap::new();
+        for sale in self.reportable_sales(include_test).filter(|sale| sale.timestamp >= since) {
+            let cost = self.costing.sale_costs.get(&sale.id).copied();
+            total.add(sale.net_total(), cost, sale.quantity);
+            channels.entry(sale.channel).or_default().add(sale.net_total(), cost, sale.quantity);
+            let line = items.entry(sale.item_id).or_insert_with(|| {
+                let item = self.items.get(&sale.item_id);
+                let item = item.as_ref();
+                ItemMargin {
+                    item_id: sale.item_id,
+                    name: item.map_or_else(|| format!("Item {}", sale.item_id), |item| item.name.clone()),
+                    category: item.and_then(|item| item.category.clone()).unwrap_or_else(|| UNCATEGORIZED.to_string()),
+                    margin: MarginLine::default(),
+                }
+            });
+            line.margin.add(sale.net_total(), cost, sale.quantity);
+        }
+        let mut categories: BTreeMap<String, MarginLine> = BTreeMap::new();
+        for line in items.values() {
+            let category = categories.entry(line.category.clone()).or_default();
+            category.revenue += line.margin.revenue;
+            category.cost += line.margin.cost;
+            category.units_sold += line.margin.units_sold;
+            category.uncosted_revenue += line.margin.uncosted_revenue;
+        }
+        categories.values_mut().for_each(MarginLine::finish);
+        channels.values_mut().for_each(MarginLine::finish);
+        total.finish();
+        let mut items: Vec<ItemMargin> = items.into_values().collect();
+        items.iter_mut().for_each(|line| line.margin.finish());
+        items.sort_by(|a, b| b.margin.gross_margin.total_cmp(&a.margin.gross_margin));
+        MarginReport { since, items, categories, channels, total, currency: self.config.currency_code.clone() }
+    }
+
+    /// Sets or clears the category an item is reported under
+    pub fn set_item_category(&mut self, item_id: u32, category: Option<String>) -> Result<(), InventoryError> {
+        if !self.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        self.journal_event(JournalEvent::CategorySet { item_id, category });
+        let log = format!("Item {} category changed at {}", item_id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+}
+
+// Books a delivery of an item at a cost per unit (per kilogram or litre for weighed goods),
+// adding it to stock. Returns the batch ID.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn receive_stock(item_id: u32, quantity: u32, unit_cost: f64, idempotency_key: Option<String>) -> Result<u64, InventoryError> {
+    require_permission("receive_stock", Permission::ReceiveStock)?;
+    run_once("receive_stock", idempotency_key, || {
+        Validator::new()
+            .check(quantity > 0, "quantity", "must be positive")
+            .price("unit_cost", unit_cost)
+            .finish()?;
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().receive_stock(item_id, quantity, unit_cost, ic_cdk::api::time())
+        })
+    })
+}
+
+// Sets an item's cost price without a delivery, e.g. for opening stock. It costs any units
+// not covered by a received batch.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_cost_price(item_id: u32, cost_price: f64) -> Result<(), InventoryError> {
+    require_caller("set_cost_price", Role::Manager)?;
+    Validator::new().price("cost_price", cost_price).finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        inventory.costing.cost_prices.insert(item_id, cost_price);
+        let log = format!("Item {} cost price changed at {}", item_id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Sets or clears the category an item is reported under in margin reports.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_item_category(item_id: u32, category: Option<String>) -> Result<(), InventoryError> {
+    require_caller("set_item_category", Role::Manager)?;
+    if let Some(category) = &category {
+        Validator::new().name("category", category).finish()?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_item_category(item_id, category)
+    })
+}
+
+// Retrieves an item's cost price, the batches still on hand and the cost value of its stock.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_cost(item_id: u32) -> Result<ItemCost, InventoryError> {
+    require_reader("get_item_cost", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().item_cost(item_id)
+    })
+}
+
+// Retrieves the gross margin per item and per category of the sales since `since`
+// (nanoseconds since the Unix epoch), or of every sale when it is None. Test sales are left
+// out unless `include_test` is set. Amounts are in the store's currency, or in `currency` at
+// its latest cached exchange rate.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_margin_report(since: Option<u64>, include_test: Option<bool>, currency: Option<String>) -> Result<MarginReport, InventoryError> {
+    require_reader("get_margin_report", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let mut report = inventory.margin_report(since.unwrap_or(0), include_test.unwrap_or(false));
+        if let Some(currency) = currency {
+            let rate = inventory.exchange_rate(&currency, ic_cdk::api::time())?;
+            report.convert(currency, |amount| inventory.convert_amount(amount, &rate));
+        }
+        Ok(report)
+    })
+}
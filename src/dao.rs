@@ -0,0 +1,179 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+
+use crate::access::{require_caller, Role};
+use crate::allocation::AllocationStrategy;
+use crate::chain::{validate_chain_store, validate_price_tolerance};
+use crate::channels::{SalesChannel, TaxTreatment};
+use crate::exchange::ExchangeRateConfig;
+use crate::hooks::BusinessRule;
+use crate::loyalty::LoyaltyConfig;
+use crate::markdowns::{validate_markdown_rule, MarkdownFollowUp};
+use crate::ratelimit::rate_limit;
+use crate::royalties::RoyaltyFormula;
+use crate::self_checkout::SelfCheckoutConfig;
+use crate::settings::StoreConfig;
+use crate::stocktake::VarianceTolerance;
+use crate::webhooks::WebhookConfig;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// An operational setting that a DAO may control
+///
+/// Security-critical changes (ownership, controllers, keys, payments) stay under M-of-N admin
+/// approval in `governance`; these are the day-to-day parameters a community votes on.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ParameterChange {
+    SetSelfCheckoutConfig { config: SelfCheckoutConfig },                                                                 // Change the audit rate and trust score steps
+    SetWebhookConfig { config: WebhookConfig },                                                                           // Change event thresholds and delivery attempts
+    SetStoreConfig { config: StoreConfig },                                                                               // Change the currency, rounding, timezone and low-stock default
+    SetGovernanceCanister { canister_id: Option<Principal> },                                                             // Hand control to another canister, or back to staff
+    SetChannelTax { channel: SalesChannel, tax: TaxTreatment },                                                           // Change a channel's tax rate and whether its prices include tax
+    SetLoyaltyConfig { config: LoyaltyConfig },                                                                           // Change how points are earned and what they are worth
+    AddMarkdownRule { category: Option<String>, days_before_expiry: u32, discount_pct: u8, follow_up: MarkdownFollowUp }, // Mark down stock close to expiry
+    RemoveMarkdownRule { id: u64 },                                                                                       // Stop a markdown rule; markdowns it applied run their course
+    SetBusinessRule { rule: BusinessRule, enabled: bool, limit: Option<u32> },                                            // Switch a built-in business rule on or off
+    AddChainStore { store: Principal, name: String },                                                                     // Audit a store's prices from this head office, or rename it
+    RemoveChainStore { store: Principal },                                                                                // Stop auditing a store
+    SetPriceTolerance { tolerance_pct: f64 },                                                                             // Change how far a store's price may stray before an audit flags it
+    SetHeadOffice { head_office: Option<Principal> },                                                                     // Change or clear the head office that reads and schedules prices
+    SetExchangeRateConfig { config: ExchangeRateConfig },                                                                 // Change the exchange-rate canister and the currencies tracked
+    SetRoyaltyFormula { formula: RoyaltyFormula },                                                                        // Change the formula royalties are worked out with
+    SetVarianceTolerance { category: Option<String>, tolerance: Option<VarianceTolerance> },                              // Change or remove the stocktake variance a manager need not approve
+    SetDefaultAllocationStrategy { strategy: AllocationStrategy },                                                        // Change how orders that name no strategy are spread over warehouses
+}
+
+/// Optional DAO control of operational parameters
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct DaoGovernance {
+    pub governance_canister: Option<Principal>, // Unset while staff change parameters directly
+}
+
+impl DaoGovernance {
+    /// Checks that an operational parameter may be changed directly by staff
+    ///
+    /// Once a governance canister is set such changes must arrive as an executed proposal instead.
+    pub fn ensure_direct_change_allowed(&self) -> Result<(), InventoryError> {
+        match self.governance_canister {
+            Some(canister_id) => Err(InventoryError::Unauthorized {
+                msg: format!("This parameter is controlled by governance canister {}", canister_id),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Checks a parameter change's payload before it is voted on or applied
+    pub fn validate_change(&self, change: &ParameterChange) -> Result<(), InventoryError> {
+        match change {
+            ParameterChange::SetSelfCheckoutConfig { config } => config.validate(),
+            ParameterChange::SetWebhookConfig { config } => config.validate(),
+            ParameterChange::SetStoreConfig { config } => config.validate(),
+            ParameterChange::SetGovernanceCanister { canister_id: Some(canister_id) } if *canister_id == Principal::anonymous() => {
+                Err(InventoryError::InvalidInput { msg: "The anonymous principal cannot be a governance canister".to_string() })
+            }
+            ParameterChange::SetGovernanceCanister { .. } => Ok(()),
+            ParameterChange::SetChannelTax { tax, .. } => tax.validate(),
+            ParameterChange::SetLoyaltyConfig { config } => config.validate(),
+            ParameterChange::AddMarkdownRule { category, days_before_expiry, discount_pct, follow_up } => {
+                validate_markdown_rule(category.as_deref(), *days_before_expiry, *discount_pct, follow_up)
+            }
+            ParameterChange::RemoveMarkdownRule { .. } => Ok(()),
+            ParameterChange::SetBusinessRule { rule, enabled, limit } => rule.validate(*enabled, *limit),
+            ParameterChange::AddChainStore { store, name } => validate_chain_store(*store, name),
+            ParameterChange::RemoveChainStore { .. } | ParameterChange::SetHeadOffice { .. } => Ok(()),
+            ParameterChange::SetPriceTolerance { tolerance_pct } => validate_price_tolerance(*tolerance_pct),
+            ParameterChange::SetExchangeRateConfig { config } => config.validate(&self.config.currency_code),
+            ParameterChange::SetRoyaltyFormula { formula } => formula.validate(),
+            ParameterChange::SetVarianceTolerance { tolerance, .. } => tolerance.as_ref().map_or(Ok(()), VarianceTolerance::validate),
+            ParameterChange::SetDefaultAllocationStrategy { .. } => Ok(()),
+        }
+    }
+
+    /// Applies a parameter change executed by the governance canister
+    /// - `caller`: The principal executing the change
+    /// - `change`: The change carried by the adopted proposal
+    pub fn execute_parameter_change(&mut self, caller: Principal, change: ParameterChange) -> Result<(), InventoryError> {
+        if self.dao.governance_canister != Some(caller) {
+            return Err(InventoryError::Unauthorized { msg: format!("{} is not the governance canister", caller) });
+        }
+        self.validate_change(&change)?;
+        match &change {
+            ParameterChange::SetSelfCheckoutConfig { config } => self.self_checkout.config = config.clone(),
+            ParameterChange::SetWebhookConfig { config } => self.webhooks.config = config.clone(),
+            ParameterChange::SetStoreConfig { config } => {
+                self.config = config.clone();
+                self.rebuild_inventory_summary(ic_cdk::api::time()); // The low-stock default may have changed
+            }
+            ParameterChange::SetGovernanceCanister { canister_id } => self.dao.governance_canister = *canister_id,
+            ParameterChange::SetChannelTax { channel, tax } => self.set_channel_tax(*channel, tax.clone()),
+            ParameterChange::SetLoyaltyConfig { config } => self.set_loyalty_config(config.clone()),
+            ParameterChange::AddMarkdownRule { category, days_before_expiry, discount_pct, follow_up } => {
+                self.add_markdown_rule(category.clone(), *days_before_expiry, *discount_pct, follow_up.clone())?;
+            }
+            ParameterChange::RemoveMarkdownRule { id } => self.remove_markdown_rule(*id)?,
+            ParameterChange::SetBusinessRule { rule, enabled, limit } => {
+                self.set_business_rule(*rule, *enabled, *limit);
+            }
+            ParameterChange::AddChainStore { store, name } => self.add_chain_store(*store, name.clone())?,
+            ParameterChange::RemoveChainStore { store } => self.remove_chain_store(*store)?,
+            ParameterChange::SetPriceTolerance { tolerance_pct } => self.set_price_tolerance(*tolerance_pct),
+            ParameterChange::SetHeadOffice { head_office } => self.set_head_office(*head_office),
+            ParameterChange::SetExchangeRateConfig { config } => self.set_exchange_rate_config(config.clone()),
+            ParameterChange::SetRoyaltyFormula { formula } => self.set_royalty_formula(formula.clone(), caller),
+            ParameterChange::SetVarianceTolerance { category, tolerance } => {
+                self.set_variance_tolerance(category.clone(), tolerance.clone());
+            }
+            ParameterChange::SetDefaultAllocationStrategy { strategy } => self.set_default_allocation_strategy(*strategy),
+        }
+        let log = format!("Governance canister executed {:?} at {}", change, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+}
+
+// Hands control of operational parameters to a governance canister such as an SNS. Only the
+// owner can do this, and only once; afterwards only the governance canister can change it.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_governance_canister(canister_id: Principal) -> Result<(), InventoryError> {
+    require_caller("set_governance_canister", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.validate_change(&ParameterChange::SetGovernanceCanister { canister_id: Some(canister_id) })?;
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.dao.governance_canister = Some(canister_id);
+        let log = format!("Governance canister set to {} at {}", canister_id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Validates a proposal payload before it is put to a vote, returning a human-readable
+// rendering of the change. Follows the SNS generic function validator signature.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn validate_parameter_change(change: ParameterChange) -> Result<String, String> {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().validate_change(&change)).map_err(|err| format!("{:?}", err))?;
+    Ok(format!("{:?}", change))
+}
+
+// Applies an adopted proposal's payload. Only the governance canister may call this.
+// Follows the SNS generic function target signature.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn execute_parameter_change(change: ParameterChange) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().execute_parameter_change(caller, change)
+    })
+    .map_err(|err| format!("{:?}", err))
+}
+
+// Retrieves the governance canister controlling operational parameters, if any.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_governance_canister() -> Option<Principal> {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().dao.governance_canister)
+}
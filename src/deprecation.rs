@@ -0,0 +1,183 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+use crate::access::{require_caller, Role};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Notice that an endpoint, field or HTTP route will be removed or changed
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Deprecation {
+    pub target: String,              // Endpoint name such as "get_inventory_logs", field path such as "InventoryItem.unit", or HTTP route such as "GET /low-stock"
+    pub replacement: Option<String>, // What integrators should move to, if anything
+    pub note: String,                // Why, and what changes at the sunset
+    pub deprecated_at: u64,          // Time the deprecation was announced in nanoseconds since the Unix epoch
+    pub sunset_at: u64,              // Time after which the target may break, in nanoseconds since the Unix epoch
+}
+
+/// A deprecation as reported to an integrator
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct DeprecationWarning {
+    pub target: String,
+    pub replacement: Option<String>,
+    pub sunset_at: u64,
+    pub sunset_passed: bool, // The target may already have changed
+    pub message: String,     // Human-readable summary
+}
+
+impl Deprecation {
+    fn warning(&self, now: u64) -> DeprecationWarning {
+        let mut message = format!("{} is deprecated and may break after {}", self.target, http_date(self.sunset_at));
+        if let Some(replacement) = &self.replacement {
+            message.push_str(&format!("; use {} instead", replacement));
+        }
+        if !self.note.is_empty() {
+            message.push_str(&format!(". {}", self.note));
+        }
+        DeprecationWarning {
+            target: self.target.clone(),
+            replacement: self.replacement.clone(),
+            sunset_at: self.sunset_at,
+            sunset_passed: now >= self.sunset_at,
+            message,
+        }
+    }
+}
+
+/// Deprecated parts of the API
+///
+/// Candid replies carry no metadata, so canister callers learn about deprecations from
+/// `get_deprecations` and `get_deprecation_warnings`. HTTP routes additionally answer with
+/// `Deprecation`, `Sunset` and `Link` headers.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Deprecations {
+    pub entries: BTreeMap<String, Deprecation>, // Deprecations keyed by target
+}
+
+impl Deprecations {
+    /// Response headers announcing the deprecation of an HTTP route, if it is deprecated
+    /// - `path`: The request path without its query string
+    pub fn http_headers(&self, method: &str, path: &str) -> Vec<(String, String)> {
+        let Some(deprecation) = self.entries.get(&format!("{} {}", method, path)) else {
+            return Vec::new();
+        };
+        let mut headers = vec![
+            ("Deprecation".to_string(), format!("@{}", deprecation.deprecated_at / NANOS_PER_SEC)),
+            ("Sunset".to_string(), http_date(deprecation.sunset_at)),
+        ];
+        if let Some(replacement) = &deprecation.replacement {
+            headers.push(("Link".to_string(), format!("<{}>; rel=\"successor-version\"", replacement)));
+        }
+        headers
+    }
+}
+
+/// Formats a time as an HTTP date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+/// - `nanos`: Nanoseconds since the Unix epoch
+fn http_date(nanos: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(nanos as i128)
+        .ok()
+        .map(|time| {
+            format!(
+                "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+                &time.weekday().to_string()[..3],
+                time.day(),
+                &time.month().to_string()[..3],
+                time.year(),
+                time.hour(),
+                time.minute(),
+                time.second()
+            )
+        })
+        .unwrap_or_default()
+}
+
+impl SupermarketManager {
+    /// Announces or updates a deprecation; the announcement time of an existing one is kept
+    pub fn set_deprecation(&mut self, target: String, replacement: Option<String>, note: String, sunset_at: u64, now: u64) -> Result<(), InventoryError> {
+        if sunset_at <= now {
+            return Err(InventoryError::InvalidInput { msg: "sunset_at must be in the future".to_string() });
+        }
+        let deprecated_at = self.deprecations.entries.get(&target).map_or(now, |d| d.deprecated_at);
+        let log = format!(
+            "{} deprecated with sunset {} at {}",
+            target,
+            http_date(sunset_at),
+            SupermarketManager::get_current_time()
+        );
+        self.deprecations.entries.insert(target.clone(), Deprecation { target, replacement, note, deprecated_at, sunset_at });
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Deprecations of endpoints the caller has used, according to the usage analytics
+    pub fn deprecation_warnings(&self, caller: &candid::Principal, now: u64) -> Vec<DeprecationWarning> {
+        let Some(usage) = self.usage.principals.get(caller) else {
+            return Vec::new();
+        };
+        self.deprecations.entries
+            .values()
+            .filter(|d| usage.by_endpoint.contains_key(&d.target))
+            .map(|d| d.warning(now))
+            .collect()
+    }
+}
+
+// Marks an endpoint, field or HTTP route as deprecated with a sunset time in nanoseconds, or
+// updates an existing deprecation.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_deprecation(target: String, replacement: Option<String>, note: String, sunset_at: u64) -> Result<(), InventoryError> {
+    require_caller("set_deprecation", Role::Manager)?;
+    let mut validator = Validator::new();
+    validator.name("target", &target);
+    if let Some(replacement) = &replacement {
+        validator.name("replacement", replacement);
+    }
+    validator.finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_deprecation(target, replacement, note, sunset_at, ic_cdk::api::time())
+    })
+}
+
+// Withdraws a deprecation, e.g. once the target has been removed.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_deprecation(target: String) -> Result<(), InventoryError> {
+    require_caller("remove_deprecation", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if inventory.deprecations.entries.remove(&target).is_none() {
+            return Err(InventoryError::NotFound { msg: format!("{} is not deprecated", target) });
+        }
+        let log = format!("Deprecation of {} withdrawn at {}", target, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every active deprecation so integrators can plan their migration.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_deprecations() -> Vec<DeprecationWarning> {
+    INVENTORY_MANAGER.with(|inventory| {
+        let now = ic_cdk::api::time();
+        inventory.borrow().deprecations.entries.values().map(|d| d.warning(now)).collect()
+    })
+}
+
+// Retrieves the deprecations that affect the caller: deprecated endpoints it has called, as
+// recorded by the usage analytics.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_deprecation_warnings() -> Vec<DeprecationWarning> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().deprecation_warnings(&ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
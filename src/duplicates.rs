@@ -0,0 +1,280 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::costing::CostBatch;
+use crate::history::ItemHistoryEvent;
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::stocktake::StocktakeStatus;
+use crate::watchlists::Watch;
+use crate::{AdjustmentReason, InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_CANDIDATES: usize = 500; // Possible duplicates one call reports
+const NAME_WINDOW: usize = 3;      // Neighbours in name order each item is compared with
+
+/// Why two items look like the same product
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum DuplicateReason {
+    SameBarcode { barcode: String },
+    SimilarName { distance: u32 }, // Characters that differ between the normalized names; 0 when they only differ in case, spacing or punctuation
+}
+
+/// A pair of items that may be the same product
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PossibleDuplicate {
+    pub item_id: u32,      // The lower of the two IDs
+    pub duplicate_id: u32,
+    pub name: String,
+    pub duplicate_name: String,
+    pub reason: DuplicateReason,
+}
+
+/// A merge of a duplicate into the item kept
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemMerge {
+    pub keep_id: u32,
+    pub merge_id: u32,        // The duplicate, archived by the merge
+    pub quantity_moved: u32,  // Stock of the duplicate added to the kept item
+    pub sales_repointed: u64, // Sales of the duplicate now recorded against the kept item
+    pub merged_by: Principal,
+    pub merged_at: u64,
+}
+
+/// Merges made so far, oldest first
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Duplicates {
+    pub merges: Vec<ItemMerge>,
+}
+
+/// Lowercase words of a name without punctuation, e.g. "Whole  Milk, 1L" as "whole milk 1l"
+fn normalize_name(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Edits needed to turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// How far apart two normalized names are, if close enough to be the same product
+///
+/// Names with different numbers, e.g. "milk 1l" and "milk 2l", are sizes or variants and never match.
+fn name_distance(a: &str, b: &str) -> Option<u32> {
+    let digits = |name: &str| name.chars().filter(char::is_ascii_digit).collect::<String>();
+    if digits(a) != digits(b) {
+        return None;
+    }
+    let allowed = match a.chars().count().min(b.chars().count()) {
+        0..=3 => 0,
+        4..=9 => 1,
+        _ => 2,
+    };
+    let distance = edit_distance(a, b);
+    (distance <= allowed).then_some(distance as u32)
+}
+
+impl SupermarketManager {
+    /// Pairs of items that share a barcode or have near-identical names
+    ///
+    /// Names are compared with the few names that sort next to them, so catalogs of any size are
+    /// checked in one pass. Archived items are left out.
+    pub fn find_possible_duplicates(&self) -> Vec<PossibleDuplicate> {
+        let items: Vec<InventoryItem> = self.items.values().filter(|item| !item.archived).collect();
+        let mut found: BTreeMap<(u32, u32), PossibleDuplicate> = BTreeMap::new();
+        let mut report = |a: &InventoryItem, b: &InventoryItem, reason: DuplicateReason| {
+            let (first, second) = if a.id < b.id { (a, b) } else { (b, a) };
+            found.entry((first.id, second.id)).or_insert_with(|| PossibleDuplicate {
+                item_id: first.id,
+                duplicate_id: second.id,
+                name: first.name.clone(),
+                duplicate_name: second.name.clone(),
+                reason,
+            });
+        };
+
+        let mut by_barcode: BTreeMap<&str, Vec<&InventoryItem>> = BTreeMap::new();
+        for item in &items {
+            if let Some(barcode) = item.barcode.as_deref() {
+                by_barcode.entry(barcode).or_default().push(item);
+            }
+        }
+        for (barcode, group) in &by_barcode {
+            for (i, a) in group.iter().enumerate() {
+                for b in &group[i + 1..] {
+                    report(a, b, DuplicateReason::SameBarcode { barcode: barcode.to_string() });
+                }
+            }
+        }
+
+        let mut by_name: Vec<(String, &InventoryItem)> = items.iter().map(|item| (normalize_name(&item.name), item)).collect();
+        by_name.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (i, (name, item)) in by_name.iter().enumerate() {
+            for (other_name, other) in by_name.iter().skip(i + 1).take(NAME_WINDOW) {
+                if let Some(distance) = name_distance(name, other_name) {
+                    report(item, other, DuplicateReason::SimilarName { distance });
+                }
+            }
+        }
+        found.into_values().take(MAX_CANDIDATES).collect()
+    }
+
+    /// Folds a duplicate into the item kept: its stock and cost batches are added to the kept
+    /// item, its sales, subscriptions and watches are moved over, and the duplicate is archived
+    pub fn merge_items(&mut self, keep_id: u32, merge_id: u32, merged_by: Principal, now: u64) -> Result<ItemMerge, InventoryError> {
+        if keep_id == merge_id {
+            return Err(InventoryError::InvalidInput { msg: "An item cannot be merged into itself".to_string() });
+        }
+        let keep = self.sellable_item(keep_id)?;
+        let merge = self.sellable_item(merge_id)?;
+        if keep.unit != merge.unit {
+            return Err(InventoryError::InvalidInput {
+                msg: format!("Items {} and {} are sold in different units and cannot be merged", keep_id, merge_id),
+            });
+        }
+        if self.bundles.definitions.contains_key(&keep_id) || self.bundles.definitions.contains_key(&merge_id) {
+            return Err(InventoryError::InvalidInput { msg: "Bundles cannot be merged".to_string() });
+        }
+        // Packs and bundles built on the duplicate would lose their stock once it is archived
+        let dependant = self.items.values().find(|item| matches!(item.unit, Unit::Pack { base_item_id, .. } if base_item_id == merge_id));
+        if let Some(pack) = dependant {
+            return Err(InventoryError::Conflict { msg: format!("Item {} is a pack of item {}; repoint it first", pack.id, merge_id) });
+        }
+        if let Some((&bundle_id, _)) = self.bundles.definitions.iter().find(|(_, bundle)| bundle.components.iter().any(|&(id, _)| id == merge_id)) {
+            return Err(InventoryError::Conflict { msg: format!("Bundle {} contains item {}; change it first", bundle_id, merge_id) });
+        }
+        let counting = self.stocktakes.sessions.values().any(|session| {
+            session.status == StocktakeStatus::Open && (session.expected.contains_key(&keep_id) || session.expected.contains_key(&merge_id))
+        });
+        if counting {
+            return Err(InventoryError::Conflict { msg: "An open stocktake is counting one of the items".to_string() });
+        }
+
+        let holds_stock = !matches!(keep.unit, Unit::Pack { .. }); // A pack's stock is its base item's
+        let quantity_moved = if holds_stock { merge.quantity } else { 0 };
+        if quantity_moved > 0 {
+            self.adjust_item_quantity(keep_id, keep.quantity.saturating_add(quantity_moved), AdjustmentReason::Merged);
+            self.adjust_item_quantity(merge_id, 0, AdjustmentReason::Merged);
+        }
+        if let Some(batches) = self.costing.batches.remove(&merge_id) {
+            let kept = self.costing.batches.entry(keep_id).or_default();
+            kept.extend(batches.into_iter().map(|batch| CostBatch { item_id: keep_id, ..batch }));
+            kept.make_contiguous().sort_by_key(|batch| batch.received_at);
+        }
+        if let Some(cost) = self.costing.cost_prices.remove(&merge_id) {
+            self.costing.cost_prices.entry(keep_id).or_insert(cost);
+        }
+        for subscription in self.back_in_stock.subscriptions.values_mut().filter(|s| s.item_id == merge_id) {
+            subscription.item_id = keep_id;
+        }
+        let watchers: Vec<Principal> = self.watchlists.watches.keys().filter(|(id, _)| *id == merge_id).map(|&(_, customer)| customer).collect();
+        for customer in watchers {
+            let watch = self.watchlists.watches.remove(&(merge_id, customer)).expect("collected from the watches");
+            self.watchlists.watches.entry((keep_id, customer)).or_insert(Watch { item_id: keep_id, ..watch });
+        }
+        let sales_repointed = self.sales.repoint_item(merge_id, keep_id);
+        self.archive_item(merge_id)?;
+
+        let merge_record = ItemMerge { keep_id, merge_id, quantity_moved, sales_repointed, merged_by, merged_at: now };
+        self.duplicates.merges.push(merge_record.clone());
+        let log = format!(
+            "Item {} merged into item {}, moving {} units and {} sales, at {}",
+            merge_id,
+            keep_id,
+            quantity_moved,
+            sales_repointed,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        self.record_history(keep_id, ItemHistoryEvent::Merged { merged_id: merge_id });
+        self.record_history(merge_id, ItemHistoryEvent::MergedInto { keep_id });
+        Ok(merge_record)
+    }
+}
+
+// Retrieves pairs of items that share a barcode or have near-identical names, e.g. the same
+// product imported twice from different spreadsheets.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn find_possible_duplicates() -> Result<Vec<PossibleDuplicate>, InventoryError> {
+    require_reader("find_possible_duplicates", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().find_possible_duplicates()))
+}
+
+// Folds the item `merge_id` into `keep_id`: stock, cost batches and sales move to the kept item
+// and the duplicate is archived. Both items must be sold in the same unit.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn merge_items(keep_id: u32, merge_id: u32) -> Result<ItemMerge, InventoryError> {
+    require_permission("merge_items", Permission::RemoveItems)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().merge_items(keep_id, merge_id, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Retrieves every merge made, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_item_merges() -> Result<Vec<ItemMerge>, InventoryError> {
+    require_reader("list_item_merges", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().duplicates.merges.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::SalesChannel;
+    use crate::sales::Sale;
+
+    #[test]
+    fn names_are_compared_without_case_spacing_or_punctuation() {
+        assert_eq!(normalize_name("Whole  Milk, 1L"), "whole milk 1l");
+        assert_eq!(name_distance(&normalize_name("Whole Milk 1L"), &normalize_name("whole-milk 1l")), Some(0));
+        assert_eq!(name_distance("whole milk 1l", "whole mlk 1l"), Some(1));
+        assert_eq!(name_distance("whole milk 1l", "whole silk 2l"), None); // Other sizes are other products
+        assert_eq!(name_distance("tea", "tee"), None);                     // Short names must match exactly
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn merging_repoints_the_duplicates_sales() {
+        let sale = |id: u64, item_id: u32, stock_item_id: u32| Sale {
+            id,
+            item_id,
+            quantity: 1,
+            unit_price: 1.0,
+            total: 1.0,
+            tax: 0.0,
+            channel: SalesChannel::default(),
+            stock_item_id,
+            stock_units: 1,
+            timestamp: 0,
+            test: false,
+        };
+        let mut manager = SupermarketManager::new();
+        manager.sales.extend(vec![sale(0, 2, 2), sale(1, 1, 1), sale(2, 5, 2), sale(3, 3, 3)]);
+
+        assert_eq!(manager.sales.repoint_item(2, 1), 2);
+        let moved: Vec<(u32, u32)> = manager.sales.iter_from(0).map(|sale| (sale.item_id, sale.stock_item_id)).collect();
+        assert_eq!(moved, vec![(1, 1), (1, 1), (5, 1), (3, 3)]);
+    }
+}
@@ -0,0 +1,116 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::access::{require_caller, Role};
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, INVENTORY_MANAGER};
+
+/// Name of the hybrid scheme, included in every encrypted payload so clients know how to open it
+pub const EXPORT_SCHEME: &str = "X25519-HKDF-SHA256-ChaCha20Poly1305";
+const HKDF_INFO: &[u8] = b"supermarket_inventory export v1";
+
+/// A payload sealed to the registered export key
+///
+/// To decrypt, compute the X25519 shared secret of the recipient's private key and
+/// `ephemeral_public_key`, expand it with HKDF-SHA256 (salt: ephemeral key followed by the
+/// recipient's public key, info: `supermarket_inventory export v1`) into a 32-byte key and a
+/// 12-byte nonce, then open `ciphertext` with ChaCha20-Poly1305.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct EncryptedPayload {
+    pub scheme: String,                // Always EXPORT_SCHEME
+    pub ephemeral_public_key: Vec<u8>, // One-time X25519 public key used for this payload
+    pub ciphertext: Vec<u8>,           // ChaCha20-Poly1305 ciphertext including the authentication tag
+}
+
+/// Data returned by export and backup endpoints
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ExportPayload {
+    Plain(Vec<u8>),              // Returned only when no export key is registered
+    Encrypted(EncryptedPayload), // Sealed to the registered export key
+}
+
+/// Export encryption settings
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ExportEncryption {
+    pub public_key: Option<[u8; 32]>, // X25519 public key exports are sealed to
+}
+
+/// Seals bytes to an X25519 public key using a fresh ephemeral key
+/// - `recipient`: The recipient's X25519 public key
+/// - `ephemeral_secret`: 32 random bytes used once as the ephemeral private key
+/// - `plaintext`: The bytes to encrypt
+pub fn seal(recipient: [u8; 32], ephemeral_secret: [u8; 32], plaintext: &[u8]) -> EncryptedPayload {
+    let recipient = PublicKey::from(recipient);
+    let secret = StaticSecret::from(ephemeral_secret);
+    let ephemeral_public = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&recipient);
+
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral_public.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    let mut okm = [0u8; 44]; // 32-byte key followed by a 12-byte nonce
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(HKDF_INFO, &mut okm)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&okm[..32]));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&okm[32..]), plaintext)
+        .expect("ChaCha20-Poly1305 encryption of an in-memory buffer cannot fail");
+    EncryptedPayload {
+        scheme: EXPORT_SCHEME.to_string(),
+        ephemeral_public_key: ephemeral_public.as_bytes().to_vec(),
+        ciphertext,
+    }
+}
+
+/// Wraps exported bytes for the caller, encrypting them if an export key is registered
+///
+/// This makes a management canister call for randomness, so it can only be used from update calls.
+pub async fn protect_export(plaintext: Vec<u8>) -> Result<ExportPayload, InventoryError> {
+    let Some(recipient) = INVENTORY_MANAGER.with(|inventory| inventory.borrow().export_encryption.public_key) else {
+        return Ok(ExportPayload::Plain(plaintext));
+    };
+    let (random,) = raw_rand().await.map_err(|(code, msg)| InventoryError::CallFailed {
+        msg: format!("Could not obtain randomness for encryption: {:?} {}", code, msg),
+    })?;
+    let ephemeral_secret: [u8; 32] = random
+        .get(..32)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| InventoryError::CallFailed { msg: "raw_rand returned too few bytes".to_string() })?;
+    Ok(ExportPayload::Encrypted(seal(recipient, ephemeral_secret, &plaintext)))
+}
+
+// Registers the X25519 public key exports are encrypted to, or clears it with None.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_export_public_key(public_key: Option<Vec<u8>>) -> Result<(), InventoryError> {
+    require_caller("set_export_public_key", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().governance.ensure_direct_change_allowed())?;
+    let key = match public_key {
+        Some(bytes) => Some(<[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| InventoryError::InvalidInput {
+            msg: "An X25519 public key is exactly 32 bytes".to_string(),
+        })?),
+        None => None,
+    };
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().export_encryption.public_key = key;
+    });
+    Ok(())
+}
+
+// Retrieves the registered export public key, if any.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_export_public_key() -> Option<Vec<u8>> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().export_encryption.public_key.map(|key| key.to_vec())
+    })
+}
@@ -0,0 +1,22 @@
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+
+use crate::hooks::BusinessRule;
+use crate::validation::ValidationError;
+
+/// Errors returned by inventory endpoints that can fail
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum InventoryError {
+    NotFound { msg: String },                                       // The referenced record does not exist
+    InvalidInput { msg: String },                                   // An argument is out of range or malformed
+    Unauthorized { msg: String },                                   // The caller lacks the required role
+    CallFailed { msg: String },                                     // A call to another canister failed
+    Conflict { msg: String },                                       // The request clashes with another one
+    ItemArchived { item_id: u32 },                                  // The item is archived and cannot be sold
+    InsufficientStock { item_id: u32, available: u32, requested: u32 }, // Not enough units on hand
+    VersionConflict { item_id: u32, expected: u64, current: u64 },  // The item changed since the caller last read it
+    Overloaded { retry_after_secs: u32 },                           // The canister is shedding load; retry later
+    RateLimited { retry_after_secs: u32 },                          // The caller made too many update calls; retry later
+    Validation { errors: Vec<ValidationError> },                    // One or more arguments failed validation
+    RuleViolation { rule: BusinessRule, msg: String },              // An enabled business rule refused the change
+}
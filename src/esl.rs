@@ -0,0 +1,176 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// What an electronic shelf label should currently display
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct EslUpdate {
+    pub label_id: String,     // Label the update is addressed to
+    pub item_id: u32,         // Item bound to the label
+    pub name: Option<String>, // Item name, or None if the item has been removed
+    pub price: Option<f64>,   // Item price, or None if the item has been removed
+    pub seq: u64,             // Sequence number of the change this update reflects
+}
+
+/// A label whose display is behind the current item data
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct UnsyncedLabel {
+    pub label_id: String,        // Label that has not confirmed the latest change
+    pub item_id: u32,            // Item bound to the label
+    pub acked_seq: Option<u64>,  // Last sequence number the gateway acknowledged, if any
+    pub current_seq: u64,        // Sequence number of the latest change to the item
+}
+
+/// Label bindings and change tracking for ESL gateways
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct EslFeed {
+    pub bindings: BTreeMap<String, u32>,  // Item ID keyed by label ID
+    pub item_seq: HashMap<u32, u64>,      // Sequence number of the latest displayed-field change per item
+    pub acks: HashMap<String, u64>,       // Last acknowledged sequence number per label
+    pub seq: u64,                         // Sequence number of the latest change overall
+    pub changed_at: HashMap<u32, u64>,    // Time of the latest displayed-field change per item, for printed labels
+}
+
+impl EslFeed {
+    /// Records that an item's name, price, barcode or promotion changed so bound labels are resent
+    /// and printed labels reprinted
+    /// - `item_id`: The item whose displayed fields changed
+    pub fn mark_changed(&mut self, item_id: u32) {
+        self.seq += 1;
+        self.item_seq.insert(item_id, self.seq);
+        self.changed_at.insert(item_id, ic_cdk::api::time());
+    }
+
+    /// Sequence number a label must acknowledge to be in sync
+    fn required_seq(&self, item_id: u32) -> u64 {
+        self.item_seq.get(&item_id).copied().unwrap_or(0)
+    }
+}
+
+impl SupermarketManager {
+    /// Label updates needed since a sequence number
+    /// - `since_seq`: The last sequence number the gateway has processed
+    ///
+    /// Returns one update per bound label whose item changed after `since_seq`, ordered by sequence number.
+    pub fn esl_updates_since(&self, since_seq: u64) -> Vec<EslUpdate> {
+        let mut updates: Vec<EslUpdate> = self.esl.bindings
+            .iter()
+            .filter_map(|(label_id, &item_id)| {
+                let seq = self.esl.required_seq(item_id);
+                if seq <= since_seq {
+                    return None;
+                }
+                let item = self.items.get(&item_id);
+                let item = item.as_ref();
+                Some(EslUpdate {
+                    label_id: label_id.clone(),
+                    item_id,
+                    name: item.map(|i| i.name.clone()),
+                    price: item.map(|i| i.price),
+                    seq,
+                })
+            })
+            .collect();
+        updates.sort_by_key(|u| u.seq);
+        updates
+    }
+
+    /// Labels that have not acknowledged the latest change to their item
+    pub fn unsynced_labels(&self) -> Vec<UnsyncedLabel> {
+        self.esl.bindings
+            .iter()
+            .filter_map(|(label_id, &item_id)| {
+                let acked_seq = self.esl.acks.get(label_id).copied();
+                let current_seq = self.esl.required_seq(item_id);
+                match acked_seq {
+                    Some(acked) if acked >= current_seq => None,
+                    _ => Some(UnsyncedLabel { label_id: label_id.clone(), item_id, acked_seq, current_seq }),
+                }
+            })
+            .collect()
+    }
+}
+
+// Binds a shelf label to an item, replacing any previous binding for the label.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn bind_esl_label(label_id: String, item_id: u32) -> Result<(), InventoryError> {
+    require_caller("bind_esl_label", Role::Clerk)?;
+    Validator::new().name("label_id", &label_id).finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        inventory.esl.acks.remove(&label_id); // A rebound label has to show the new item before it is in sync
+        inventory.esl.bindings.insert(label_id, item_id);
+        inventory.esl.mark_changed(item_id); // Make sure the new binding appears in the next feed
+        Ok(())
+    })
+}
+
+// Removes a shelf label binding.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn unbind_esl_label(label_id: String) -> Result<(), InventoryError> {
+    require_caller("unbind_esl_label", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.esl.acks.remove(&label_id);
+        inventory.esl.bindings.remove(&label_id)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("Label {} not bound", label_id) })
+    })
+}
+
+// Retrieves every label binding as (label ID, item ID) pairs.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_esl_bindings() -> Vec<(String, u32)> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().esl.bindings.iter().map(|(label, &item)| (label.clone(), item)).collect()
+    })
+}
+
+// Retrieves the label updates a gateway needs after the given sequence number.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_esl_updates(since_seq: u64) -> Vec<EslUpdate> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().esl_updates_since(since_seq)
+    })
+}
+
+// Records that labels have displayed the updates up to the given sequence numbers. Gateways
+// need the `SyncLabels` permission.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn ack_esl_updates(acks: Vec<(String, u64)>) {
+    if let Err(error) = require_permission("ack_esl_updates", Permission::SyncLabels) {
+        ic_cdk::trap(&format!("{:?}", error)); // Nothing is returned, so a refused gateway gets a reject instead
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        for (label_id, seq) in acks {
+            if inventory.esl.bindings.contains_key(&label_id) { // Ignore acks for labels that are no longer bound
+                let acked = inventory.esl.acks.entry(label_id).or_insert(0);
+                *acked = (*acked).max(seq);
+            }
+        }
+    });
+}
+
+// Retrieves labels that have not acknowledged the latest change to their item.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_unsynced_esl_labels() -> Vec<UnsyncedLabel> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().unsynced_labels()
+    })
+}
@@ -0,0 +1,251 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::bus::BusPayload;
+use crate::channels::SalesChannel;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const DELIVERY_INTERVAL_SECS: u64 = 10;            // How often subscriber queues are flushed
+const MAX_BATCH: usize = 100;                      // Events sent to a subscriber per call
+const MAX_QUEUED: usize = 10_000;                  // Events kept per subscriber; the oldest are dropped beyond this
+const BASE_BACKOFF_NANOS: u64 = 10_000_000_000;    // Delay before the first retry, doubled on each later one
+const MAX_BACKOFF_NANOS: u64 = 3_600_000_000_000;  // Longest delay between retries
+const SUBSCRIBER_METHOD: &str = "on_inventory_event";
+
+/// Kinds of event a canister can subscribe to
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InventoryEventKind {
+    ItemAdded,
+    StockChanged,
+    SaleRecorded,
+    PriceChanged,
+}
+
+/// A change published to subscribed canisters
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum InventoryEventPayload {
+    ItemAdded { item_id: u32, name: String, quantity: u32, price: f64 },                           // An item was added or replaced
+    StockChanged { item_id: u32, old_quantity: u32, new_quantity: u32 },                           // An item's stock level changed
+    SaleRecorded { sale_id: u64, item_id: u32, quantity: u32, total: f64, channel: SalesChannel }, // A sale line was recorded
+    PriceChanged { item_id: u32, old_price: f64, new_price: f64 },                                 // An item's price was changed on its own
+}
+
+impl InventoryEventPayload {
+    pub fn kind(&self) -> InventoryEventKind {
+        match self {
+            InventoryEventPayload::ItemAdded { .. } => InventoryEventKind::ItemAdded,
+            InventoryEventPayload::StockChanged { .. } => InventoryEventKind::StockChanged,
+            InventoryEventPayload::SaleRecorded { .. } => InventoryEventKind::SaleRecorded,
+            InventoryEventPayload::PriceChanged { .. } => InventoryEventKind::PriceChanged,
+        }
+    }
+}
+
+/// A published event as delivered to subscribers
+///
+/// Delivery is at-least-once: a subscriber may see an event again after a failed call and
+/// should ignore sequence numbers it has already processed.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InventoryEvent {
+    pub seq: u64,                       // Sequence number, increasing across all events
+    pub timestamp: u64,                 // Time of the change in nanoseconds since the Unix epoch
+    pub payload: InventoryEventPayload, // What changed
+}
+
+/// A canister receiving events, and its delivery queue
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Subscription {
+    pub subscriber: Principal,                // Canister that is called with `on_inventory_event`
+    pub event_types: Vec<InventoryEventKind>, // Events it wants
+    pub queue: VecDeque<InventoryEvent>,      // Events not yet acknowledged, oldest first
+    pub dropped: u64,                         // Events dropped because the queue was full; a resync is needed
+    pub failures: u32,                        // Consecutive failed calls
+    pub next_attempt_at: u64,                 // Earliest time of the next call in nanoseconds since the Unix epoch
+    pub in_flight: bool,                      // Whether a call is outstanding
+    pub last_error: Option<String>,           // Error of the latest failed call
+}
+
+/// Canister subscriptions and the event sequence
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct EventBus {
+    pub subscriptions: BTreeMap<Principal, Subscription>, // Subscriptions keyed by subscriber
+    pub next_seq: u64,                                    // Sequence number handed to the next event
+}
+
+/// Whether a principal is a canister rather than a user
+fn is_canister(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[9] == 0x01 // Opaque IDs, which the system assigns to canisters
+}
+
+impl SupermarketManager {
+    /// Queues an event for every canister subscribed to its kind
+    /// - `payload`: What changed
+    /// - `now`: The time of the change in nanoseconds since the Unix epoch
+    pub fn publish_event(&mut self, payload: InventoryEventPayload, now: u64) {
+        self.publish_to_bus(BusPayload::Inventory(payload.clone()), now);
+        let kind = payload.kind();
+        let bus = &mut self.events;
+        if !bus.subscriptions.values().any(|s| s.event_types.contains(&kind)) {
+            return;
+        }
+        let event = InventoryEvent { seq: bus.next_seq, timestamp: now, payload };
+        bus.next_seq += 1;
+        for subscription in bus.subscriptions.values_mut().filter(|s| s.event_types.contains(&kind)) {
+            subscription.queue.push_back(event.clone());
+            if subscription.queue.len() > MAX_QUEUED {
+                subscription.queue.pop_front();
+                subscription.dropped += 1;
+            }
+        }
+    }
+
+    /// Claims a batch for every subscriber that is due and has nothing in flight
+    ///
+    /// Returns (subscriber, batch) for each call to make.
+    fn take_due_batches(&mut self, now: u64) -> Vec<(Principal, Vec<InventoryEvent>)> {
+        self.events.subscriptions
+            .values_mut()
+            .filter(|s| !s.in_flight && !s.queue.is_empty() && s.next_attempt_at <= now)
+            .map(|s| {
+                s.in_flight = true;
+                (s.subscriber, s.queue.iter().take(MAX_BATCH).cloned().collect())
+            })
+            .collect()
+    }
+
+    /// Records the outcome of a call, dropping acknowledged events or scheduling a retry
+    /// - `last_seq`: Sequence number of the last event in the batch
+    fn finish_batch(&mut self, subscriber: Principal, last_seq: u64, result: Result<(), String>, now: u64) {
+        let Some(subscription) = self.events.subscriptions.get_mut(&subscriber) else {
+            return; // Unsubscribed while the call was outstanding
+        };
+        subscription.in_flight = false;
+        match result {
+            Ok(()) => {
+                while subscription.queue.front().is_some_and(|event| event.seq <= last_seq) {
+                    subscription.queue.pop_front();
+                }
+                subscription.failures = 0;
+                subscription.next_attempt_at = now;
+                subscription.last_error = None;
+            }
+            Err(error) => {
+                let backoff = BASE_BACKOFF_NANOS.saturating_mul(1u64 << subscription.failures.min(16)).min(MAX_BACKOFF_NANOS);
+                subscription.failures += 1;
+                subscription.next_attempt_at = now.saturating_add(backoff);
+                subscription.last_error = Some(error);
+            }
+        }
+    }
+}
+
+/// Sends every due batch and records the outcomes
+fn deliver_events() {
+    let batches = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().take_due_batches(ic_cdk::api::time())
+    });
+    for (subscriber, events) in batches {
+        let last_seq = events.last().map_or(0, |event| event.seq);
+        ic_cdk::spawn(async move {
+            let result: Result<(), String> = ic_cdk::call(subscriber, SUBSCRIBER_METHOD, (events,))
+                .await
+                .map_err(|(code, msg)| format!("{} failed: {:?} {}", SUBSCRIBER_METHOD, code, msg));
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().finish_batch(subscriber, last_seq, result, ic_cdk::api::time());
+            });
+        });
+    }
+}
+
+/// Registers the timer that flushes subscriber queues
+pub fn start_event_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(DELIVERY_INTERVAL_SECS), deliver_events);
+}
+
+// Subscribes the calling canister to inventory events. Events are delivered in batches to its
+// `on_inventory_event : (vec InventoryEvent) -> ()` method. Subscribing again replaces the event types.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn subscribe(event_types: Vec<InventoryEventKind>) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    if !is_canister(&caller) {
+        return Err(InventoryError::Unauthorized { msg: "Only canisters can subscribe to events".to_string() });
+    }
+    if event_types.is_empty() {
+        return Err(InventoryError::InvalidInput { msg: "Subscribe to at least one event type".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let subscription = inventory.events.subscriptions.entry(caller).or_insert_with(|| Subscription {
+            subscriber: caller,
+            event_types: Vec::new(),
+            queue: VecDeque::new(),
+            dropped: 0,
+            failures: 0,
+            next_attempt_at: 0,
+            in_flight: false,
+            last_error: None,
+        });
+        subscription.event_types = event_types;
+        let log = format!("Canister {} subscribed to events at {}", caller, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Unsubscribes the calling canister, discarding its undelivered events. Open to any caller, as
+// it only removes the caller's own subscription.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn unsubscribe() -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().events.subscriptions.remove(&caller)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("{} is not subscribed", caller) })
+    })
+}
+
+// Removes a subscriber, e.g. one that has stopped accepting events.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_subscription(subscriber: Principal) -> Result<(), InventoryError> {
+    require_caller("remove_subscription", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().events.subscriptions.remove(&subscriber)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("{} is not subscribed", subscriber) })
+    })
+}
+
+// Retrieves every subscription with the number of events waiting for it; the queued events
+// themselves are left out.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_subscriptions() -> Result<Vec<(Subscription, u64)>, InventoryError> {
+    require_reader("get_subscriptions", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().events.subscriptions.values()
+            .map(|s| {
+                let summary = Subscription {
+                    subscriber: s.subscriber,
+                    event_types: s.event_types.clone(),
+                    queue: VecDeque::new(),
+                    dropped: s.dropped,
+                    failures: s.failures,
+                    next_attempt_at: s.next_attempt_at,
+                    in_flight: s.in_flight,
+                    last_error: s.last_error.clone(),
+                };
+                (summary, s.queue.len() as u64)
+            })
+            .collect())
+    })
+}
@@ -0,0 +1,318 @@
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";  // The exchange-rate canister on mainnet
+const XRC_CALL_CYCLES: u128 = 1_000_000_000;                  // Fee attached to each rate request; unused cycles are refunded
+const REFRESH_INTERVAL_SECS: u64 = 60 * 60;                   // How often tracked rates are fetched
+const MAX_RATE_AGE_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // Older rates are not used for conversions
+const MAX_CURRENCIES: usize = 10;                             // Secondary currencies a store can track
+const MAX_DECIMAL_PLACES: u8 = 8;
+
+/// Kind of asset the exchange-rate canister prices
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Default)]
+pub enum AssetClass {
+    Cryptocurrency, // e.g. ICP
+    #[default]
+    FiatCurrency,   // e.g. EUR
+}
+
+/// An asset as the exchange-rate canister names it
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Asset {
+    pub symbol: String,
+    pub class: AssetClass,
+}
+
+#[derive(CandidType)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>, // Seconds since the Unix epoch; None for the latest rate
+}
+
+// Only the fields read here; Candid skips the others when decoding
+#[derive(Deserialize, CandidType, Debug)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+#[derive(Deserialize, CandidType, Debug)]
+struct ExchangeRate {
+    timestamp: u64,
+    rate: u64, // Scaled by 10^`metadata.decimals`
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(Deserialize, CandidType, Debug)]
+struct OtherError {
+    code: u32,
+    description: String,
+}
+
+#[derive(Deserialize, CandidType, Debug)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+/// A currency prices and report totals can be converted to
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TrackedCurrency {
+    pub symbol: String,     // Code the exchange-rate canister knows it by, e.g. "ICP" or "CHF"
+    pub class: AssetClass,
+    pub decimal_places: u8, // Digits converted amounts are rounded to, e.g. 8 for ICP
+}
+
+/// Which exchange-rate canister to ask, and for which currencies
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ExchangeRateConfig {
+    pub xrc_canister: Option<Principal>, // None for the exchange-rate canister on mainnet
+    pub base_class: AssetClass,          // Kind of the store's own currency; fiat unless the store prices in a token
+    pub currencies: Vec<TrackedCurrency>,
+}
+
+/// The latest rate fetched for a tracked currency
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct CachedRate {
+    pub currency: String,
+    pub rate: f64,           // Units of the currency one unit of the store's currency buys
+    pub rate_timestamp: u64, // Time the rate is for, in seconds since the Unix epoch
+    pub fetched_at: u64,     // Time it was fetched in nanoseconds since the Unix epoch
+}
+
+/// An item's price in another currency
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ConvertedPrice {
+    pub item_id: u32,
+    pub price: f64,           // The item's own price in the store's currency
+    pub currency: String,
+    pub converted_price: f64, // The price in `currency`, rounded to its decimal places
+    pub rate: CachedRate,     // The rate used
+}
+
+/// Tracked currencies, their cached rates and why the latest fetch of any of them failed
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ExchangeRates {
+    pub config: ExchangeRateConfig,
+    pub rates: BTreeMap<String, CachedRate>, // Latest rate by currency symbol
+    pub errors: BTreeMap<String, String>,    // Failure of the latest fetch by currency symbol; cleared on success
+}
+
+impl ExchangeRateConfig {
+    /// Checks the tracked currencies against the store's own
+    pub fn validate(&self, store_currency: &str) -> Result<(), InventoryError> {
+        let mut validator = Validator::new();
+        validator.check(self.currencies.len() <= MAX_CURRENCIES, "currencies", format!("must have at most {} entries", MAX_CURRENCIES));
+        let mut seen = HashSet::new();
+        for (i, tracked) in self.currencies.iter().enumerate() {
+            let symbol = &tracked.symbol;
+            validator
+                .check(
+                    (2..=10).contains(&symbol.len()) && symbol.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()),
+                    &format!("currencies[{}].symbol", i),
+                    "must be 2 to 10 upper-case letters or digits",
+                )
+                .check(symbol != store_currency, &format!("currencies[{}].symbol", i), "must not be the store's own currency")
+                .check(seen.insert(symbol), &format!("currencies[{}].symbol", i), "appears more than once")
+                .check(
+                    tracked.decimal_places <= MAX_DECIMAL_PLACES,
+                    &format!("currencies[{}].decimal_places", i),
+                    format!("must be at most {}", MAX_DECIMAL_PLACES),
+                );
+        }
+        validator.finish()
+    }
+}
+
+impl ExchangeRates {
+    fn xrc_canister(&self) -> Principal {
+        self.config.xrc_canister.unwrap_or_else(|| Principal::from_text(XRC_CANISTER_ID).expect("valid principal"))
+    }
+
+    fn tracked(&self, currency: &str) -> Option<&TrackedCurrency> {
+        self.config.currencies.iter().find(|tracked| tracked.symbol == currency)
+    }
+}
+
+impl SupermarketManager {
+    /// Replaces the exchange-rate settings, dropping rates of currencies no longer tracked
+    pub fn set_exchange_rate_config(&mut self, config: ExchangeRateConfig) {
+        let exchange = &mut self.exchange;
+        exchange.rates.retain(|currency, _| config.currencies.iter().any(|tracked| tracked.symbol == *currency));
+        exchange.errors.retain(|currency, _| config.currencies.iter().any(|tracked| tracked.symbol == *currency));
+        exchange.config = config;
+        let log = format!("Exchange rate settings changed at {}", SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// The rate for converting amounts in the store's currency to `currency`
+    ///
+    /// The store's own currency converts at 1.
+    pub fn exchange_rate(&self, currency: &str, now: u64) -> Result<CachedRate, InventoryError> {
+        if currency == self.config.currency_code {
+            return Ok(CachedRate { currency: currency.to_string(), rate: 1.0, rate_timestamp: now / 1_000_000_000, fetched_at: now });
+        }
+        if self.exchange.tracked(currency).is_none() {
+            return Err(InventoryError::NotFound { msg: format!("Currency {} is not tracked", currency) });
+        }
+        let rate = self.exchange.rates.get(currency).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("No rate for {} has been fetched yet", currency),
+        })?;
+        if now.saturating_sub(rate.fetched_at) > MAX_RATE_AGE_NANOS {
+            return Err(InventoryError::Conflict { msg: format!("The rate for {} is more than a day old", currency) });
+        }
+        Ok(rate.clone())
+    }
+
+    /// Converts an amount in the store's currency, rounding it to the target currency's decimal places
+    pub fn convert_amount(&self, amount: f64, rate: &CachedRate) -> f64 {
+        let decimal_places = self.exchange.tracked(&rate.currency).map_or(self.config.decimal_places, |tracked| tracked.decimal_places);
+        let scale = 10f64.powi(decimal_places as i32);
+        (amount * rate.rate * scale).round() / scale
+    }
+
+    /// Records the outcome of fetching a currency's rate
+    fn finish_rate_fetch(&mut self, currency: String, result: Result<CachedRate, String>) {
+        if self.exchange.tracked(&currency).is_none() {
+            return; // Untracked while the call was in flight
+        }
+        match result {
+            Ok(rate) => {
+                self.exchange.errors.remove(&currency);
+                self.exchange.rates.insert(currency, rate);
+            }
+            Err(reason) => {
+                let log = format!("Exchange rate for {} not fetched: {} at {}", currency, reason, SupermarketManager::get_current_time());
+                self.logs.push(log);
+                self.exchange.errors.insert(currency, reason);
+            }
+        }
+    }
+}
+
+/// Asks the exchange-rate canister for the latest rate from the store's currency to `quote`
+async fn fetch_rate(xrc_canister: Principal, base: Asset, quote: Asset) -> Result<CachedRate, String> {
+    let currency = quote.symbol.clone();
+    let request = GetExchangeRateRequest { base_asset: base, quote_asset: quote, timestamp: None };
+    let (result,): (Result<ExchangeRate, ExchangeRateError>,) =
+        call_with_payment128(xrc_canister, "get_exchange_rate", (request,), XRC_CALL_CYCLES)
+            .await
+            .map_err(|(code, msg)| format!("Exchange-rate call failed: {:?} {}", code, msg))?;
+    let rate = result.map_err(|error| format!("Exchange-rate canister refused: {:?}", error))?;
+    Ok(CachedRate {
+        currency,
+        rate: rate.rate as f64 / 10f64.powi(rate.metadata.decimals as i32),
+        rate_timestamp: rate.timestamp,
+        fetched_at: ic_cdk::api::time(),
+    })
+}
+
+/// Fetches the rate of every tracked currency, one call at a time
+async fn refresh_rates() {
+    let (xrc_canister, base, currencies) = INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let base = Asset { symbol: inventory.config.currency_code.clone(), class: inventory.exchange.config.base_class };
+        (inventory.exchange.xrc_canister(), base, inventory.exchange.config.currencies.clone())
+    });
+    for tracked in currencies {
+        let quote = Asset { symbol: tracked.symbol.clone(), class: tracked.class };
+        let result = fetch_rate(xrc_canister, base.clone(), quote).await;
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().finish_rate_fetch(tracked.symbol, result);
+        });
+    }
+}
+
+/// Registers the timer that keeps the cached rates fresh
+pub fn start_exchange_rate_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(REFRESH_INTERVAL_SECS), || ic_cdk::spawn(refresh_rates()));
+}
+
+// Sets the exchange-rate canister and the secondary currencies whose rates are fetched hourly.
+// Rates of currencies no longer tracked are dropped. Once a governance canister is set, this
+// takes an executed `SetExchangeRateConfig` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_exchange_rate_config(config: ExchangeRateConfig) -> Result<(), InventoryError> {
+    require_caller("set_exchange_rate_config", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        config.validate(&inventory.config.currency_code)?;
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_exchange_rate_config(config);
+        Ok(())
+    })
+}
+
+// Retrieves the exchange-rate settings.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_exchange_rate_config() -> Result<ExchangeRateConfig, InventoryError> {
+    require_reader("get_exchange_rate_config", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().exchange.config.clone()))
+}
+
+// Fetches every tracked rate now instead of waiting for the hourly run. Each request pays the
+// exchange-rate canister's fee in cycles.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+async fn refresh_exchange_rates() -> Result<Vec<CachedRate>, InventoryError> {
+    require_caller("refresh_exchange_rates", Role::Manager)?;
+    refresh_rates().await;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().exchange.rates.values().cloned().collect()))
+}
+
+// Retrieves the cached rates and the failures of the latest fetches, by currency.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_exchange_rates() -> (Vec<CachedRate>, Vec<(String, String)>) {
+    INVENTORY_MANAGER.with(|inventory| {
+        let exchange = &inventory.borrow().exchange;
+        (
+            exchange.rates.values().cloned().collect(),
+            exchange.errors.iter().map(|(currency, reason)| (currency.clone(), reason.clone())).collect(),
+        )
+    })
+}
+
+// Retrieves an item's price converted to a tracked currency at the latest cached rate.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_price_in(id: u32, currency: String) -> Result<ConvertedPrice, InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let item = inventory.items.get(&id).ok_or_else(|| InventoryError::NotFound { msg: format!("Item {} not found", id) })?;
+        let rate = inventory.exchange_rate(&currency, ic_cdk::api::time())?;
+        Ok(ConvertedPrice {
+            item_id: id,
+            price: item.price,
+            converted_price: inventory.convert_amount(item.price, &rate),
+            currency,
+            rate,
+        })
+    })
+}
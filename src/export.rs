@@ -0,0 +1,39 @@
+use ic_cdk_macros::update;
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::cost::{measured, HeavyOperation};
+use crate::encryption::{protect_export, ExportPayload};
+use crate::load::admit_expensive_call;
+use crate::logs::LogEntry;
+use crate::ratelimit::rate_limit;
+use crate::sales::Sale;
+use crate::{InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+impl SupermarketManager {
+    /// Serializes items, sales and logs into a JSON document for off-chain use
+    pub fn export_json(&self) -> Vec<u8> {
+        let items: Vec<InventoryItem> = self.items.values().collect(); // In ID order, so exports can be diffed
+        let sales: Vec<Sale> = self.sales.iter_from(0).collect();
+        let logs: Vec<LogEntry> = self.logs.entries().collect();
+        serde_json::to_vec(&serde_json::json!({
+            "exported_at": SupermarketManager::get_current_time(),
+            "items": items,
+            "sales": sales,
+            "logs": logs,
+        }))
+        .expect("Inventory data always serializes to JSON")
+    }
+}
+
+// Exports the whole inventory as JSON, encrypted to the export key when one is registered.
+// This function is marked as `#[update]` because encryption needs fresh randomness.
+#[update(guard = "rate_limit")]
+async fn export_inventory() -> Result<ExportPayload, InventoryError> {
+    require_reader("export_inventory", Role::Manager)?;
+    admit_expensive_call()?;
+    let json = measured(HeavyOperation::Export, || {
+        INVENTORY_MANAGER.with(|inventory| inventory.borrow().export_json())
+    });
+    protect_export(json).await
+}
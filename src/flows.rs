@@ -0,0 +1,147 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Nat, Principal};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::payments::{Account, Payment, PaymentStatus};
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_IN_FLIGHT: usize = 100;                        // Flows awaiting a call at once; more are refused as overloaded
+const ABANDON_AFTER_NANOS: u64 = 30 * 60 * 1_000_000_000; // A flow still waiting by then trapped after its await
+const SWEEP_INTERVAL_SECS: u64 = 5 * 60;
+
+/// What an async flow was doing when it awaited, so an abandoned one can be compensated for
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum FlowKind {
+    Payment { payer: Account, ledger_canister_id: Principal, amount: Nat }, // A checkout's ledger transfer
+    Reconciliation,                                                         // A scan of the payment ledger
+    ProposalExecution { proposal_id: u64 },                                 // An approved proposal changing canister settings
+}
+
+/// An async flow waiting on an inter-canister call
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InFlight {
+    pub id: u64,
+    pub kind: FlowKind,
+    pub caller: Principal,
+    pub started_at: u64, // Time the flow began awaiting in nanoseconds since the Unix epoch
+}
+
+/// Flows that committed state before an await and have not yet committed their result
+///
+/// State changed before an await is kept even if the code after it traps, while the changes made
+/// after it are rolled back. Each flow records itself before awaiting and clears the record in
+/// the same step that applies its result, so a record left behind marks a flow that never
+/// finished. The sweep timer compensates for those once they are `ABANDON_AFTER_NANOS` old.
+#[derive(Default)]
+pub struct AsyncFlows {
+    pub in_flight: BTreeMap<u64, InFlight>, // Waiting flows by ID
+    pub next_id: u64,
+    pub abandoned: u64,                     // Flows compensated for by the sweep
+}
+
+/// Reads state in a borrow that ends with the closure, so it can never be held across an await
+pub fn read<R>(f: impl FnOnce(&SupermarketManager) -> R) -> R {
+    INVENTORY_MANAGER.with(|inventory| f(&inventory.borrow()))
+}
+
+/// Changes state in a borrow that ends with the closure, so it can never be held across an await
+pub fn mutate<R>(f: impl FnOnce(&mut SupermarketManager) -> R) -> R {
+    INVENTORY_MANAGER.with(|inventory| f(&mut inventory.borrow_mut()))
+}
+
+/// A flow's record while it awaits; finish it with `finish` once the call returns
+///
+/// Dropping it without finishing, e.g. by returning an error early, clears the record too.
+#[must_use]
+pub struct Flow {
+    id: u64,
+}
+
+impl Flow {
+    /// Records a flow about to await a call, in the same step as the state it changes first
+    pub fn begin(inventory: &mut SupermarketManager, kind: FlowKind, caller: Principal, now: u64) -> Result<Flow, InventoryError> {
+        if inventory.flows.in_flight.len() >= MAX_IN_FLIGHT {
+            return Err(InventoryError::Overloaded { retry_after_secs: SWEEP_INTERVAL_SECS as u32 });
+        }
+        let id = inventory.flows.next_id;
+        inventory.flows.next_id += 1;
+        inventory.flows.in_flight.insert(id, InFlight { id, kind, caller, started_at: now });
+        Ok(Flow { id })
+    }
+
+    /// Applies the flow's result and clears its record together, so either both are kept or neither is
+    pub fn finish<R>(self, f: impl FnOnce(&mut SupermarketManager) -> R) -> R {
+        mutate(|inventory| {
+            inventory.flows.in_flight.remove(&self.id);
+            f(inventory)
+        })
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        INVENTORY_MANAGER.with(|inventory| {
+            if let Ok(mut inventory) = inventory.try_borrow_mut() {
+                inventory.flows.in_flight.remove(&self.id);
+            }
+        });
+    }
+}
+
+impl SupermarketManager {
+    /// Compensates for flows that trapped after their await and never recorded a result
+    pub fn sweep_abandoned_flows(&mut self, now: u64) {
+        let abandoned: Vec<u64> = self
+            .flows
+            .in_flight
+            .values()
+            .filter(|flow| now.saturating_sub(flow.started_at) >= ABANDON_AFTER_NANOS)
+            .map(|flow| flow.id)
+            .collect();
+        for id in abandoned {
+            let flow = self.flows.in_flight.remove(&id).expect("collected from the flows in flight");
+            self.flows.abandoned += 1;
+            match flow.kind {
+                FlowKind::Payment { payer, ledger_canister_id, amount } => {
+                    // Whether the tokens moved is settled by reconciliation, which reports an unrecorded transfer
+                    let reason = "The checkout stopped after its ledger transfer was sent".to_string();
+                    self.push_payment(Payment {
+                        id: 0,
+                        payer,
+                        ledger_canister_id,
+                        amount,
+                        block_index: None,
+                        status: PaymentStatus::Unresolved { reason },
+                        sale_ids: Vec::new(),
+                        timestamp: flow.started_at,
+                    });
+                }
+                FlowKind::Reconciliation => self.reconciliation.running = false,
+                FlowKind::ProposalExecution { proposal_id } => {
+                    self.finish_proposal(proposal_id, Err("Execution stopped before its result was recorded".to_string()), now);
+                }
+            }
+            let log = format!("Abandoned async flow {} compensated at {}", id, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+    }
+}
+
+/// Registers the timer that compensates for abandoned flows
+pub fn start_flow_sweep_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(SWEEP_INTERVAL_SECS), || {
+        mutate(|inventory| inventory.sweep_abandoned_flows(ic_cdk::api::time()));
+    });
+}
+
+// Retrieves the async flows waiting on an inter-canister call, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_flows_in_flight() -> Result<Vec<InFlight>, InventoryError> {
+    require_reader("list_flows_in_flight", Role::Manager)?;
+    Ok(read(|inventory| inventory.flows.in_flight.values().cloned().collect()))
+}
@@ -0,0 +1,442 @@
+use ic_cdk::api::management_canister::main::{update_settings, CanisterSettings, UpdateSettingsArgument};
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::{self, require_reader, BreakGlassConfig};
+use crate::confidential::VetKdConfig;
+use crate::flows::{mutate, Flow, FlowKind};
+use crate::payments::PaymentConfig;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Who may approve security-critical changes and how many approvals they need
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct GovernanceConfig {
+    pub admins: Vec<Principal>, // The N principals allowed to propose and approve
+    pub threshold: u32,         // The M approvals a proposal needs before it executes
+    pub window_secs: u64,       // How long a proposal stays open for approvals
+}
+
+/// A security-critical change that needs M-of-N admin approval once governance is configured
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ProposalAction {
+    TransferOwnership { new_owner: Principal },               // Make another principal the store owner
+    SetControllers { controllers: Vec<Principal> },           // Replace the canister's controllers
+    SetExportPublicKey { public_key: Option<Vec<u8>> },       // Change or clear the export encryption key
+    SetPaymentConfig { config: PaymentConfig },               // Change the payment ledger or conversion
+    SetVetKdConfig { config: VetKdConfig },                   // Change the vetKD key settings
+    RotateConfidentialKey,                                    // Start a new confidential key epoch
+    UpdateGovernance { config: GovernanceConfig },            // Change the admins, threshold or window
+    SetRecoveryConfig { config: Option<BreakGlassConfig> },   // Change or clear the break-glass recovery principal
+}
+
+/// Lifecycle state of a proposal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum ProposalStatus {
+    Open,                      // Collecting approvals
+    Executing,                 // Reached the threshold and is being applied
+    Executed,                  // Reached the threshold and was applied
+    Failed { reason: String }, // Reached the threshold but could not be applied
+    Expired,                   // The window closed before enough approvals arrived
+    Cancelled,                 // Withdrawn by the proposer
+}
+
+/// One step in the life of a proposal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ProposalEventKind {
+    Proposed,
+    Approved,
+    Executed,
+    ExecutionFailed { reason: String },
+    Expired,
+    Cancelled,
+}
+
+/// An audit entry of a proposal's lifecycle
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ProposalEvent {
+    pub kind: ProposalEventKind,
+    pub by: Option<Principal>, // Admin who caused the event, or None for automatic transitions
+    pub at: u64,               // Time of the event in nanoseconds since the Unix epoch
+}
+
+/// A pending or decided security-critical change
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Proposal {
+    pub id: u64,                     // Unique ID of the proposal
+    pub action: ProposalAction,      // Change to apply once approved
+    pub proposer: Principal,         // Admin who made the proposal
+    pub approvals: Vec<Principal>,   // Admins who approved, including the proposer
+    pub status: ProposalStatus,      // Lifecycle state
+    pub expires_at: u64,             // End of the approval window in nanoseconds since the Unix epoch
+    pub history: Vec<ProposalEvent>, // Full audit of the proposal's lifecycle
+}
+
+/// Governance settings and every proposal ever made
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Governance {
+    pub config: Option<GovernanceConfig>,    // Unset until the owner enables threshold approval
+    pub proposals: BTreeMap<u64, Proposal>,  // Proposals keyed by ID
+}
+
+impl Governance {
+    /// Checks that a security setting may be changed directly by the owner
+    ///
+    /// Once governance is configured such changes must go through a proposal instead.
+    pub fn ensure_direct_change_allowed(&self) -> Result<(), InventoryError> {
+        match self.config {
+            Some(_) => Err(InventoryError::Unauthorized {
+                msg: "This change requires an approved governance proposal".to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn require_admin(&self, principal: &Principal) -> Result<&GovernanceConfig, InventoryError> {
+        let config = self.config.as_ref().ok_or_else(|| InventoryError::InvalidInput {
+            msg: "Governance is not configured".to_string(),
+        })?;
+        if !config.admins.contains(principal) {
+            return Err(InventoryError::Unauthorized { msg: format!("{} is not a governance admin", principal) });
+        }
+        Ok(config)
+    }
+}
+
+/// Checks that a governance configuration is usable
+pub fn validate_config(config: &GovernanceConfig) -> Result<(), InventoryError> {
+    let mut admins = config.admins.clone();
+    admins.sort();
+    admins.dedup();
+    if admins.len() != config.admins.len() {
+        return Err(InventoryError::InvalidInput { msg: "Governance admins must be distinct".to_string() });
+    }
+    if config.threshold == 0 || config.threshold as usize > admins.len() {
+        return Err(InventoryError::InvalidInput { msg: "threshold must be between 1 and the number of admins".to_string() });
+    }
+    if config.window_secs == 0 {
+        return Err(InventoryError::InvalidInput { msg: "window_secs must be positive".to_string() });
+    }
+    Ok(())
+}
+
+/// Checks an action's payload before it is put to a vote
+fn validate_action(action: &ProposalAction) -> Result<(), InventoryError> {
+    match action {
+        ProposalAction::SetExportPublicKey { public_key: Some(key) } if key.len() != 32 => {
+            Err(InventoryError::InvalidInput { msg: "An X25519 public key is exactly 32 bytes".to_string() })
+        }
+        ProposalAction::SetControllers { controllers } if controllers.is_empty() => {
+            Err(InventoryError::InvalidInput { msg: "A canister needs at least one controller".to_string() })
+        }
+        ProposalAction::SetPaymentConfig { config } if config.units_per_price_unit == 0 => {
+            Err(InventoryError::InvalidInput { msg: "units_per_price_unit must be positive".to_string() })
+        }
+        ProposalAction::UpdateGovernance { config } => validate_config(config),
+        ProposalAction::SetRecoveryConfig { config: Some(config) } => breakglass::validate_config(config),
+        _ => Ok(()),
+    }
+}
+
+impl SupermarketManager {
+    /// Opens a proposal, counting the proposer as its first approval
+    /// - `proposer`: The admin making the proposal
+    /// - `action`: The change to apply once approved
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn propose(&mut self, proposer: Principal, action: ProposalAction, now: u64) -> Result<u64, InventoryError> {
+        let window = self.governance.require_admin(&proposer)?.window_secs;
+        validate_action(&action)?;
+        let id = self.governance.proposals.len() as u64;
+        self.governance.proposals.insert(id, Proposal {
+            id,
+            action,
+            proposer,
+            approvals: vec![proposer],
+            status: ProposalStatus::Open,
+            expires_at: now.saturating_add(window.saturating_mul(NANOS_PER_SEC)),
+            history: vec![ProposalEvent { kind: ProposalEventKind::Proposed, by: Some(proposer), at: now }],
+        });
+        let log = format!("Governance proposal {} opened by {} at {}", id, proposer, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(id)
+    }
+
+    /// Adds an admin's approval to an open proposal
+    /// - `approver`: The admin approving
+    /// - `id`: The proposal being approved
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    ///
+    /// Returns the action to execute if this approval reached the threshold.
+    pub fn approve(&mut self, approver: Principal, id: u64, now: u64) -> Result<Option<ProposalAction>, InventoryError> {
+        let threshold = self.governance.require_admin(&approver)?.threshold;
+        let proposal = self.open_proposal(id, now)?;
+        if !proposal.approvals.contains(&approver) {
+            proposal.approvals.push(approver);
+            proposal.history.push(ProposalEvent { kind: ProposalEventKind::Approved, by: Some(approver), at: now });
+        } else if (proposal.approvals.len() as u32) < threshold {
+            return Err(InventoryError::Conflict { msg: format!("{} already approved proposal {}", approver, id) });
+        }
+        // A repeated approval is accepted only to execute a proposal that already has enough approvals,
+        // which happens when the threshold is 1 or was lowered after the proposal was made
+        if proposal.approvals.len() as u32 >= threshold {
+            proposal.status = ProposalStatus::Executing; // Later approvals must not execute it again
+            return Ok(Some(proposal.action.clone()));
+        }
+        Ok(None)
+    }
+
+    /// Withdraws an open proposal; only its proposer may do this
+    pub fn cancel_proposal(&mut self, caller: Principal, id: u64, now: u64) -> Result<(), InventoryError> {
+        let proposal = self.open_proposal(id, now)?;
+        if proposal.proposer != caller {
+            return Err(InventoryError::Unauthorized { msg: "Only the proposer can cancel a proposal".to_string() });
+        }
+        proposal.status = ProposalStatus::Cancelled;
+        proposal.history.push(ProposalEvent { kind: ProposalEventKind::Cancelled, by: Some(caller), at: now });
+        Ok(())
+    }
+
+    /// Looks up a proposal that is still open, expiring it if its window has closed
+    fn open_proposal(&mut self, id: u64, now: u64) -> Result<&mut Proposal, InventoryError> {
+        let proposal = self.governance.proposals.get_mut(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Proposal {} not found", id),
+        })?;
+        if proposal.status == ProposalStatus::Open && now > proposal.expires_at {
+            proposal.status = ProposalStatus::Expired;
+            proposal.history.push(ProposalEvent { kind: ProposalEventKind::Expired, by: None, at: now });
+        }
+        if proposal.status != ProposalStatus::Open {
+            return Err(InventoryError::Conflict { msg: format!("Proposal {} is {:?}", id, proposal.status) });
+        }
+        Ok(proposal)
+    }
+
+    /// Applies an approved action that only touches canister state
+    ///
+    /// Controller changes need a management canister call and are applied by the endpoint instead.
+    fn apply_action(&mut self, action: &ProposalAction) {
+        match action {
+            ProposalAction::TransferOwnership { new_owner } => self.access.transfer_owner(*new_owner),
+            ProposalAction::SetExportPublicKey { public_key } => {
+                self.export_encryption.public_key = public_key.as_ref().and_then(|key| key.as_slice().try_into().ok());
+            }
+            ProposalAction::SetPaymentConfig { config } => self.payments.config = Some(config.clone()),
+            ProposalAction::SetVetKdConfig { config } => self.confidential.config = config.clone(),
+            ProposalAction::RotateConfidentialKey => self.confidential.key_epoch += 1,
+            ProposalAction::UpdateGovernance { config } => self.governance.config = Some(config.clone()),
+            ProposalAction::SetRecoveryConfig { config } => self.set_break_glass_config(config.clone()),
+            ProposalAction::SetControllers { .. } => {}
+        }
+    }
+
+    /// Records the outcome of executing an approved proposal
+    pub fn finish_proposal(&mut self, id: u64, result: Result<(), String>, now: u64) {
+        if let Some(proposal) = self.governance.proposals.get_mut(&id) {
+            let (status, kind) = match result {
+                Ok(()) => (ProposalStatus::Executed, ProposalEventKind::Executed),
+                Err(reason) => (
+                    ProposalStatus::Failed { reason: reason.clone() },
+                    ProposalEventKind::ExecutionFailed { reason },
+                ),
+            };
+            proposal.status = status;
+            proposal.history.push(ProposalEvent { kind, by: None, at: now });
+            let log = format!(
+                "Governance proposal {} {:?} at {}",
+                id,
+                proposal.status,
+                SupermarketManager::get_current_time()
+            );
+            self.logs.push(log);
+        }
+    }
+}
+
+// Enables threshold approval. Only the owner can do this, and only once; afterwards the
+// governance settings themselves can only change through an UpdateGovernance proposal.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn configure_governance(config: GovernanceConfig) -> Result<(), InventoryError> {
+    require_caller("configure_governance", Role::Owner)?;
+    validate_config(&config)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        inventory.governance.config = Some(config);
+        let log = format!("Governance enabled at {}", SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Opens a proposal for a security-critical change. Only governance admins may propose.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn propose_change(action: ProposalAction) -> Result<u64, InventoryError> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().propose(caller, action, ic_cdk::api::time())
+    })
+}
+
+// Approves a proposal, executing it once the threshold of approvals is reached. Only governance
+// admins may approve.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+async fn approve_proposal(id: u64) -> Result<ProposalStatus, InventoryError> {
+    let caller = ic_cdk::caller();
+    let (ready, flow) = mutate(|inventory| {
+        let now = ic_cdk::api::time();
+        let ready = inventory.approve(caller, id, now)?;
+        // Only a change of controllers awaits a call, so only it is recorded as a flow
+        let flow = match &ready {
+            Some(ProposalAction::SetControllers { .. }) => match Flow::begin(inventory, FlowKind::ProposalExecution { proposal_id: id }, caller, now) {
+                Ok(flow) => Some(flow),
+                Err(error) => {
+                    inventory.finish_proposal(id, Err(format!("{:?}", error)), now);
+                    return Err(error);
+                }
+            },
+            _ => None,
+        };
+        Ok((ready, flow))
+    })?;
+    let Some(action) = ready else {
+        return Ok(ProposalStatus::Open);
+    };
+
+    let result = match &action {
+        ProposalAction::SetControllers { controllers } => {
+            let arg = UpdateSettingsArgument {
+                canister_id: ic_cdk::id(),
+                settings: CanisterSettings {
+                    controllers: Some(controllers.clone()),
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                },
+            };
+            update_settings(arg).await.map_err(|(code, msg)| format!("update_settings failed: {:?} {}", code, msg))
+        }
+        _ => {
+            mutate(|inventory| inventory.apply_action(&action));
+            Ok(())
+        }
+    };
+    let finish = |inventory: &mut SupermarketManager| {
+        inventory.finish_proposal(id, result, ic_cdk::api::time());
+        Ok(inventory.governance.proposals[&id].status.clone())
+    };
+    match flow {
+        Some(flow) => flow.finish(finish),
+        None => mutate(finish),
+    }
+}
+
+// Withdraws an open proposal made by the caller; nobody else may withdraw it.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_proposal(id: u64) -> Result<(), InventoryError> {
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().cancel_proposal(caller, id, ic_cdk::api::time())
+    })
+}
+
+// Retrieves a proposal with its full audit history.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_proposal(id: u64) -> Result<Proposal, InventoryError> {
+    require_reader("get_proposal", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().governance.proposals.get(&id).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Proposal {} not found", id),
+        })
+    })
+}
+
+// Retrieves every proposal, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_proposals() -> Result<Vec<Proposal>, InventoryError> {
+    require_reader("list_proposals", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().governance.proposals.values().cloned().collect())
+    })
+}
+
+// Retrieves the governance settings, if threshold approval is enabled.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_governance_config() -> Result<Option<GovernanceConfig>, InventoryError> {
+    require_reader("get_governance_config", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().governance.config.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    /// A store with three admins needing two approvals, and a proposal by the first to hand
+    /// ownership to the fourth principal
+    fn governed() -> SupermarketManager {
+        let mut manager = SupermarketManager::new();
+        manager.governance.config = Some(GovernanceConfig { admins: vec![admin(1), admin(2), admin(3)], threshold: 2, window_secs: 60 });
+        manager.governance.proposals.insert(0, Proposal {
+            id: 0,
+            action: ProposalAction::TransferOwnership { new_owner: admin(4) },
+            proposer: admin(1),
+            approvals: vec![admin(1)],
+            status: ProposalStatus::Open,
+            expires_at: 60 * NANOS_PER_SEC,
+            history: Vec::new(),
+        });
+        manager
+    }
+
+    #[test]
+    fn a_proposal_executes_once_at_its_threshold() {
+        let mut manager = governed();
+        assert!(matches!(manager.approve(admin(9), 0, 0), Err(InventoryError::Unauthorized { .. })));
+        assert!(matches!(manager.approve(admin(1), 0, 0), Err(InventoryError::Conflict { .. }))); // Already counted
+
+        let action = manager.approve(admin(2), 0, 0).unwrap().expect("the second approval reaches the threshold");
+        assert_eq!(manager.governance.proposals[&0].status, ProposalStatus::Executing);
+        assert!(manager.approve(admin(3), 0, 0).is_err()); // No second execution
+
+        manager.apply_action(&action);
+        assert_eq!(manager.access.role_of(&admin(4)), Some(Role::Owner));
+        assert!(manager.governance.ensure_direct_change_allowed().is_err());
+    }
+
+    #[test]
+    fn a_proposal_expires_when_its_window_closes() {
+        let mut manager = governed();
+        assert!(manager.approve(admin(2), 0, 61 * NANOS_PER_SEC).is_err());
+        let proposal = &manager.governance.proposals[&0];
+        assert_eq!(proposal.status, ProposalStatus::Expired);
+        assert!(matches!(proposal.history.last().map(|event| &event.kind), Some(ProposalEventKind::Expired)));
+        assert!(manager.cancel_proposal(admin(1), 0, 0).is_err());
+    }
+
+    #[test]
+    fn configurations_and_actions_are_checked_before_a_vote() {
+        let config = |admins: Vec<Principal>, threshold| GovernanceConfig { admins, threshold, window_secs: 60 };
+        assert!(validate_config(&config(vec![admin(1), admin(2)], 2)).is_ok());
+        assert!(validate_config(&config(vec![admin(1), admin(1)], 1)).is_err()); // Duplicate admins
+        assert!(validate_config(&config(vec![admin(1)], 2)).is_err());           // Threshold above the admins
+        assert!(validate_action(&ProposalAction::SetControllers { controllers: Vec::new() }).is_err());
+        assert!(validate_action(&ProposalAction::SetExportPublicKey { public_key: Some(vec![0; 31]) }).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::HashMap;
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::costing::UNCATEGORIZED;
+use crate::load::admit_expensive_call;
+use crate::{InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const EXPIRY_HORIZON_DAYS: f64 = 90.0; // Expiry further out than this carries no risk
+const MIN_SUPPLY_DAYS: f64 = 7.0;      // Fewer days of supply than this risk a stockout
+const MAX_SUPPLY_DAYS: f64 = 30.0;     // More days of supply than this tie up stock
+const OVERSTOCK_DAYS: f64 = 180.0;     // Days of supply that score zero
+const TARGET_MARGIN_PCT: f64 = 30.0;   // Margin that scores full marks
+const FLAG_PENALTY: u8 = 25;           // Data-quality points lost per flag
+const MAX_PAGE_ITEMS: u32 = 500;       // Most items one health listing returns
+
+/// One of the measures an item's health score is made of
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthFactor {
+    ExpiryRisk,   // Stock likely to expire before it sells
+    DaysOfSupply, // Stock on hand against the sales rate, low or high
+    Margin,       // Price against cost
+    Velocity,     // Sales rate against the rest of the category
+    DataQuality,  // Missing master data
+}
+
+impl HealthFactor {
+    /// Share of the overall score, out of 100
+    fn weight(self) -> u8 {
+        match self {
+            HealthFactor::ExpiryRisk => 25,
+            HealthFactor::DaysOfSupply => 25,
+            HealthFactor::Margin => 20,
+            HealthFactor::Velocity => 20,
+            HealthFactor::DataQuality => 10,
+        }
+    }
+}
+
+/// Master data an item is missing
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataQualityFlag {
+    NoCategory, // Reported as uncategorized and left out of category comparisons
+    NoBarcode,  // Cannot be scanned
+    NoLocation, // Cannot be found on a pick route
+    NoCost,     // No cost price or batch, so its margin is unknown
+}
+
+/// How one factor scored
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct HealthComponent {
+    pub factor: HealthFactor,
+    pub score: Option<u8>, // 0 to 100; None if the factor does not apply or cannot be measured
+    pub weight: u8,        // Share of the overall score, out of 100, before unscored factors are left out
+    pub detail: String,    // Why it scored what it did
+}
+
+/// Health score of an item and what it is made of
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemHealth {
+    pub item_id: u32,
+    pub name: String,
+    pub category: String,
+    pub score: u8,                        // Weighted average of the scored components, 0 worst to 100 best
+    pub components: Vec<HealthComponent>, // One per factor
+    pub flags: Vec<DataQualityFlag>,
+}
+
+impl ItemHealth {
+    fn component(&self, factor: HealthFactor) -> Option<u8> {
+        self.components.iter().find(|c| c.factor == factor).and_then(|c| c.score)
+    }
+}
+
+/// What a health listing is ordered by
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthSort {
+    Score,
+    Factor(HealthFactor), // Items that factor does not apply to come last
+}
+
+/// A page of items ordered by health
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemHealthPage {
+    pub items: Vec<ItemHealth>, // Items from the requested offset
+    pub total: u64,             // Items in the whole listing
+}
+
+/// Scores a fraction of the way from 0 to 1
+fn percent(fraction: f64) -> u8 {
+    (fraction.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+/// Units per day of each category's items, for ranking an item against its peers
+type CategoryRates = HashMap<String, Vec<f64>>;
+
+impl SupermarketManager {
+    /// Whether an item holds its own stock, rather than drawing on a base item or components
+    fn holds_stock(&self, item: &InventoryItem) -> bool {
+        !matches!(item.unit, Unit::Pack { .. }) && !self.bundles.definitions.contains_key(&item.id)
+    }
+
+    /// Sales rate used for health: the 7-day average, or the 30-day one if nothing sold this week
+    fn health_rate(&self, item_id: u32, now: u64) -> f64 {
+        let velocity = self.velocity.velocity(item_id, now);
+        if velocity.per_day_7 > 0.0 { velocity.per_day_7 } else { velocity.per_day_30 }
+    }
+
+    fn category_rates(&self, now: u64) -> CategoryRates {
+        let mut rates: CategoryRates = HashMap::new();
+        for item in self.items.values().filter(|item| !item.archived && self.holds_stock(item)) {
+            let Some(category) = &item.category else { continue };
+            rates.entry(category.clone()).or_default().push(self.health_rate(item.id, now));
+        }
+        rates
+    }
+
+    fn expiry_component(&self, item: &InventoryItem, rate: f64, now: u64) -> HealthComponent {
+        let (score, detail) = if !self.holds_stock(item) {
+            (None, "Stock is held by the items it is made of".to_string())
+        } else if item.quantity == 0 {
+            (Some(100), "Nothing on hand to expire".to_string())
+        } else {
+            let days_left = item.expiration_date.saturating_sub(now / 1_000_000_000) as f64 / SECS_PER_DAY as f64;
+            let sell_through = rate * days_left / item.quantity as f64;
+            let score = percent(sell_through.max(days_left / EXPIRY_HORIZON_DAYS));
+            let detail = if days_left <= 0.0 {
+                format!("{} units have expired", item.quantity)
+            } else {
+                format!("Expires in {:.0} days; about {:.0}% of stock sells by then", days_left, sell_through.min(1.0) * 100.0)
+            };
+            (Some(score), detail)
+        };
+        HealthComponent { factor: HealthFactor::ExpiryRisk, score, weight: HealthFactor::ExpiryRisk.weight(), detail }
+    }
+
+    fn supply_component(&self, item: &InventoryItem, rate: f64) -> HealthComponent {
+        let (score, detail) = if !self.holds_stock(item) {
+            (None, "Stock is held by the items it is made of".to_string())
+        } else if item.quantity == 0 {
+            (Some(0), "Out of stock".to_string())
+        } else if rate == 0.0 {
+            (Some(0), format!("{} units on hand and none sold in 30 days", item.quantity))
+        } else {
+            let days = item.quantity as f64 / rate;
+            let score = if days < MIN_SUPPLY_DAYS {
+                percent(days / MIN_SUPPLY_DAYS)
+            } else {
+                percent((OVERSTOCK_DAYS - days) / (OVERSTOCK_DAYS - MAX_SUPPLY_DAYS))
+            };
+            (Some(score), format!("{:.1} days of supply at {:.1} units per day", days, rate))
+        };
+        HealthComponent { factor: HealthFactor::DaysOfSupply, score, weight: HealthFactor::DaysOfSupply.weight(), detail }
+    }
+
+    fn margin_component(&self, item: &InventoryItem) -> HealthComponent {
+        let oldest_batch = self.costing.batches.get(&item.id).and_then(|batches| batches.front()).map(|b| b.unit_cost);
+        let cost = self.costing.cost_prices.get(&item.id).copied().or(oldest_batch);
+        let (score, detail) = match cost {
+            None => (None, "No cost price or batch".to_string()),
+            Some(_) if item.price <= 0.0 => (Some(0), "Priced at zero".to_string()),
+            Some(cost) => {
+                let margin_pct = (item.price - cost) / item.price * 100.0;
+                (Some(percent(margin_pct / TARGET_MARGIN_PCT)), format!("{:.1}% margin at cost {:.2}", margin_pct, cost))
+            }
+        };
+        HealthComponent { factor: HealthFactor::Margin, score, weight: HealthFactor::Margin.weight(), detail }
+    }
+
+    fn velocity_component(&self, item: &InventoryItem, rate: f64, rates: &CategoryRates) -> HealthComponent {
+        let peers = item.category.as_ref().and_then(|category| rates.get(category)).filter(|peers| peers.len() > 1);
+        let (score, detail) = if !self.holds_stock(item) {
+            (None, "Sales are counted against the items it is made of".to_string())
+        } else if let Some(peers) = peers {
+            let slower = peers.iter().filter(|&&peer| peer < rate).count();
+            let fraction = slower as f64 / (peers.len() - 1) as f64;
+            let detail = format!("Sells faster than {:.0}% of its category at {:.1} units per day", fraction * 100.0, rate);
+            (Some(percent(fraction)), detail)
+        } else {
+            (None, "No other items in its category to compare with".to_string())
+        };
+        HealthComponent { factor: HealthFactor::Velocity, score, weight: HealthFactor::Velocity.weight(), detail }
+    }
+
+    fn data_quality_flags(&self, item: &InventoryItem) -> Vec<DataQualityFlag> {
+        let uncosted = !self.costing.cost_prices.contains_key(&item.id)
+            && self.costing.batches.get(&item.id).is_none_or(|batches| batches.is_empty());
+        [
+            (item.category.is_none(), DataQualityFlag::NoCategory),
+            (item.barcode.is_none(), DataQualityFlag::NoBarcode),
+            (item.location.is_none(), DataQualityFlag::NoLocation),
+            (uncosted, DataQualityFlag::NoCost),
+        ]
+        .into_iter()
+        .filter_map(|(missing, flag)| missing.then_some(flag))
+        .collect()
+    }
+
+    fn score_item(&self, item: &InventoryItem, rates: &CategoryRates, now: u64) -> ItemHealth {
+        let rate = self.health_rate(item.id, now);
+        let flags = self.data_quality_flags(item);
+        let quality = HealthComponent {
+            factor: HealthFactor::DataQuality,
+            score: Some(100u8.saturating_sub(FLAG_PENALTY.saturating_mul(flags.len() as u8))),
+            weight: HealthFactor::DataQuality.weight(),
+            detail: if flags.is_empty() { "Complete".to_string() } else { format!("Missing {:?}", flags) },
+        };
+        let components = vec![
+            self.expiry_component(item, rate, now),
+            self.supply_component(item, rate),
+            self.margin_component(item),
+            self.velocity_component(item, rate, rates),
+            quality,
+        ];
+        let (weighted, weights) = components
+            .iter()
+            .filter_map(|c| c.score.map(|score| (score as u32 * c.weight as u32, c.weight as u32)))
+            .fold((0, 0), |(weighted, weights), (w, weight)| (weighted + w, weights + weight));
+        ItemHealth {
+            item_id: item.id,
+            name: item.name.clone(),
+            category: item.category.clone().unwrap_or_else(|| UNCATEGORIZED.to_string()),
+            score: (weighted as f64 / weights.max(1) as f64).round() as u8,
+            components,
+            flags,
+        }
+    }
+
+    /// Health score of one item against its category
+    pub fn item_health(&self, item_id: u32, now: u64) -> Result<ItemHealth, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        Ok(self.score_item(&item, &self.category_rates(now), now))
+    }
+
+    /// Health of every item that is not archived, ordered by `sort`
+    /// - `descending`: Best first instead of worst first; either way ties go by item ID
+    pub fn list_item_health(&self, sort: HealthSort, descending: bool, now: u64) -> Vec<ItemHealth> {
+        let rates = self.category_rates(now);
+        let mut items: Vec<ItemHealth> = self.items
+            .values()
+            .filter(|item| !item.archived)
+            .map(|item| self.score_item(&item, &rates, now))
+            .collect();
+        let key = |health: &ItemHealth| match sort {
+            HealthSort::Score => Some(health.score),
+            HealthSort::Factor(factor) => health.component(factor),
+        };
+        items.sort_by(|a, b| {
+            let order = match (key(a), key(b)) {
+                (Some(a), Some(b)) if descending => b.cmp(&a),
+                (a, b) => a.is_none().cmp(&b.is_none()).then(a.cmp(&b)), // Unscored last
+            };
+            order.then(a.item_id.cmp(&b.item_id))
+        });
+        items
+    }
+}
+
+// Retrieves an item's health score with the components it is made of and why each scored
+// what it did.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_health(item_id: u32) -> Result<ItemHealth, InventoryError> {
+    require_reader("get_item_health", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().item_health(item_id, ic_cdk::api::time()))
+}
+
+// Retrieves up to `limit` items from `offset` ordered by health score, or by one of its
+// factors, worst first unless `descending` is set, to triage the catalog.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_item_health(sort: Option<HealthSort>, descending: Option<bool>, offset: u64, limit: u32) -> Result<ItemHealthPage, InventoryError> {
+    require_reader("list_item_health", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let items = inventory.borrow().list_item_health(sort.unwrap_or(HealthSort::Score), descending.unwrap_or(false), ic_cdk::api::time());
+        Ok(ItemHealthPage {
+            total: items.len() as u64,
+            items: items.into_iter().skip(offset as usize).take(limit.min(MAX_PAGE_ITEMS) as usize).collect(),
+        })
+    })
+}
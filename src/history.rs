@@ -0,0 +1,119 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{HashMap, VecDeque};
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::{AdjustmentReason, InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_ENTRIES_PER_ITEM: usize = 10_000; // Timeline entries kept per item; the oldest is dropped first
+const MAX_PAGE_ENTRIES: u32 = 1000;         // Most entries `get_item_history` returns per call
+
+/// Something that happened to one item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ItemHistoryEvent {
+    Created { name: String, quantity: u32, price: f64, replaced: bool },                // Added, or replaced by a new definition
+    QuantityChanged { old_quantity: u32, new_quantity: u32, reason: AdjustmentReason }, // Stock set by hand, by a recount or by a delivery
+    PriceChanged { old_price: f64, new_price: f64 },
+    BatchReceived { batch_id: u64, quantity: u32 },                                     // A delivery booked through `receive_stock`
+    Sold { sale_id: u64, quantity: u32, test: bool },                                   // The item itself was sold
+    StockTaken { sale_id: u64, sold_item_id: u32, units: u32 },                         // Stock left as part of a pack or bundle sold under another ID
+    Archived,
+    Unarchived,
+    Removed,
+    Merged { merged_id: u32 },                                                          // A duplicate's stock and sales were folded into the item
+    MergedInto { keep_id: u32 },                                                        // The item was a duplicate, folded into `keep_id` and archived
+}
+
+/// An entry of an item's timeline
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemHistoryEntry {
+    pub seq: u64,             // Position in the item's timeline; never reused, even once older entries are dropped
+    pub timestamp: u64,       // Time of the event in nanoseconds since the Unix epoch
+    pub log_seq: Option<u64>, // Change log entry written for it; None for entries rebuilt after a restore
+    pub event: ItemHistoryEvent,
+}
+
+/// A page of an item's timeline
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemHistoryPage {
+    pub entries: Vec<ItemHistoryEntry>, // Entries from the requested offset, oldest first
+    pub first_seq: u64,                 // Oldest entry still kept; anything before it was dropped
+    pub next_seq: u64,                  // Sequence number the item's next entry will get
+}
+
+/// Timeline of one item
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ItemTimeline {
+    pub entries: VecDeque<ItemHistoryEntry>, // Kept entries, oldest first
+    pub first_seq: u64,                      // Sequence number of the first kept entry
+}
+
+/// Per-item timelines, so an item's history is read without scanning the log and ledger
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ItemHistory {
+    pub timelines: HashMap<u32, ItemTimeline>, // Timelines keyed by item ID, kept after an item is removed
+}
+
+impl ItemHistory {
+    pub fn record(&mut self, item_id: u32, timestamp: u64, log_seq: Option<u64>, event: ItemHistoryEvent) {
+        let timeline = self.timelines.entry(item_id).or_default();
+        let seq = timeline.first_seq + timeline.entries.len() as u64;
+        timeline.entries.push_back(ItemHistoryEntry { seq, timestamp, log_seq, event });
+        if timeline.entries.len() > MAX_ENTRIES_PER_ITEM {
+            timeline.entries.pop_front();
+            timeline.first_seq += 1;
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Adds an event to an item's timeline, linked to the log entry pushed just before
+    pub fn record_history(&mut self, item_id: u32, event: ItemHistoryEvent) {
+        let log_seq = self.logs.last_seq();
+        self.item_history.record(item_id, ic_cdk::api::time(), log_seq, event);
+    }
+
+    /// Rebuilds timelines after a restore from what the snapshot still holds: the sales ledger
+    ///
+    /// Quantity, price and archival events from before the restore are not in a snapshot and are lost.
+    pub fn rebuild_item_history(&mut self) {
+        let mut history = ItemHistory::default();
+        for sale in self.sales.iter_from(0) {
+            history.record(sale.item_id, sale.timestamp, None, ItemHistoryEvent::Sold {
+                sale_id: sale.id,
+                quantity: sale.quantity,
+                test: sale.test,
+            });
+            if sale.stock_item_id != sale.item_id {
+                history.record(sale.stock_item_id, sale.timestamp, None, ItemHistoryEvent::StockTaken {
+                    sale_id: sale.id,
+                    sold_item_id: sale.item_id,
+                    units: sale.stock_units,
+                });
+            }
+        }
+        self.item_history = history;
+    }
+}
+
+// Retrieves up to `limit` entries of an item's timeline starting at its sequence number `offset`.
+// Pass the previous page's last `seq + 1` to read on. Removed items keep their history.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_history(id: u32, offset: u64, limit: u32) -> Result<ItemHistoryPage, InventoryError> {
+    require_reader("get_item_history", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let timeline = inventory.item_history.timelines.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} has no history", id),
+        })?;
+        let from = offset.saturating_sub(timeline.first_seq) as usize;
+        Ok(ItemHistoryPage {
+            entries: timeline.entries.iter().skip(from).take(limit.min(MAX_PAGE_ENTRIES) as usize).cloned().collect(),
+            first_seq: timeline.first_seq,
+            next_seq: timeline.first_seq + timeline.entries.len() as u64,
+        })
+    })
+}
@@ -0,0 +1,237 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{AdjustmentReason, InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A built-in business rule that operators can switch on
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BusinessRule {
+    BlockExpiredSales,    // Refuse to sell stock past its expiration date
+    RequireCategory,      // Refuse to add an item without a category
+    MaxSaleQuantity,      // Refuse a sale line of more than `limit` units
+    PriceBelowCostAlert,  // Log a price change that takes an item below its cost price
+    LargeAdjustmentAlert, // Log a manual or recount change of stock by more than `limit` units
+}
+
+impl BusinessRule {
+    const ALL: [BusinessRule; 5] = [
+        BusinessRule::BlockExpiredSales,
+        BusinessRule::RequireCategory,
+        BusinessRule::MaxSaleQuantity,
+        BusinessRule::PriceBelowCostAlert,
+        BusinessRule::LargeAdjustmentAlert,
+    ];
+
+    /// The mutation the rule runs around
+    pub fn hook_point(&self) -> HookPoint {
+        match self {
+            BusinessRule::BlockExpiredSales | BusinessRule::MaxSaleQuantity => HookPoint::BeforeSale,
+            BusinessRule::RequireCategory => HookPoint::BeforeItemAdded,
+            BusinessRule::PriceBelowCostAlert => HookPoint::AfterPriceChange,
+            BusinessRule::LargeAdjustmentAlert => HookPoint::AfterQuantitySet,
+        }
+    }
+
+    /// Whether the rule needs a `limit`
+    fn takes_limit(&self) -> bool {
+        matches!(self, BusinessRule::MaxSaleQuantity | BusinessRule::LargeAdjustmentAlert)
+    }
+
+    /// Checks that `limit` is given exactly when the rule takes one
+    pub fn validate(&self, enabled: bool, limit: Option<u32>) -> Result<(), InventoryError> {
+        let mut validator = Validator::new();
+        if self.takes_limit() {
+            validator.check(!enabled || limit.is_some_and(|limit| limit > 0), "limit", "must be positive for this rule");
+        } else {
+            validator.check(limit.is_none(), "limit", "does not apply to this rule");
+        }
+        validator.finish()
+    }
+}
+
+/// Where a rule runs; rules run before a mutation can refuse it, rules run after it only record
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum HookPoint {
+    BeforeSale,       // Each line of a basket, before any stock moves
+    BeforeItemAdded,  // An item being added or replaced
+    AfterPriceChange, // An item whose price was just set
+    AfterQuantitySet, // An item whose stock level was just set by hand
+}
+
+/// A rule's settings and how often it has fired
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RuleStatus {
+    pub rule: BusinessRule,
+    pub hook_point: HookPoint,
+    pub enabled: bool,
+    pub limit: Option<u32>,         // Threshold for rules that take one
+    pub fired: u64,                 // Times the rule refused or flagged a mutation
+    pub last_fired_at: Option<u64>, // Time it last fired in nanoseconds since the Unix epoch
+}
+
+/// Rules operators have configured, with their firing counts
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct HookRegistry {
+    pub rules: BTreeMap<BusinessRule, RuleStatus>, // Configured rules; the others are off
+}
+
+impl HookRegistry {
+    /// The rule's limit if it is enabled
+    fn enabled(&self, rule: BusinessRule) -> Option<Option<u32>> {
+        self.rules.get(&rule).filter(|status| status.enabled).map(|status| status.limit)
+    }
+
+    /// Every built-in rule with its settings, configured or not
+    pub fn list(&self) -> Vec<RuleStatus> {
+        BusinessRule::ALL
+            .iter()
+            .map(|&rule| {
+                self.rules.get(&rule).cloned().unwrap_or(RuleStatus {
+                    rule,
+                    hook_point: rule.hook_point(),
+                    enabled: false,
+                    limit: None,
+                    fired: 0,
+                    last_fired_at: None,
+                })
+            })
+            .collect()
+    }
+}
+
+impl SupermarketManager {
+    /// Counts and logs a rule firing
+    fn rule_fired(&mut self, rule: BusinessRule, item_id: u32, detail: &str, now: u64) {
+        if let Some(status) = self.hooks.rules.get_mut(&rule) {
+            status.fired += 1;
+            status.last_fired_at = Some(now);
+        }
+        let log = format!("Business rule {:?} fired on item {}: {} at {}", rule, item_id, detail, SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Refuses a mutation on behalf of a rule, logging the firing
+    fn rule_violation(&mut self, rule: BusinessRule, item_id: u32, msg: String, now: u64) -> InventoryError {
+        self.rule_fired(rule, item_id, &msg, now);
+        InventoryError::RuleViolation { rule, msg }
+    }
+
+    /// Runs the rules hooked before a sale against every line of a basket
+    /// - `lines`: Pairs of (item ID, quantity) being sold
+    pub fn enforce_sale_rules(&mut self, lines: &[(u32, u32)], now: u64) -> Result<(), InventoryError> {
+        let now_secs = now / NANOS_PER_SEC;
+        for &(item_id, quantity) in lines {
+            if let Some(Some(limit)) = self.hooks.enabled(BusinessRule::MaxSaleQuantity) {
+                if quantity > limit {
+                    let msg = format!("{} units exceed the limit of {} per sale line", quantity, limit);
+                    return Err(self.rule_violation(BusinessRule::MaxSaleQuantity, item_id, msg, now));
+                }
+            }
+            if self.hooks.enabled(BusinessRule::BlockExpiredSales).is_some() {
+                for (stock_item_id, _) in self.stock_demand(item_id, quantity)? { // Packs and bundles expire with their stock
+                    let expired = self.items.get(&stock_item_id).is_some_and(|stock| now_secs >= stock.expiration_date);
+                    if expired {
+                        let msg = format!("Item {} is past its expiration date", stock_item_id);
+                        return Err(self.rule_violation(BusinessRule::BlockExpiredSales, item_id, msg, now));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule hooked before an item is added that would refuse the item, and why, without
+    /// counting it as fired
+    pub fn item_rule_violation(&self, item: &InventoryItem) -> Option<(BusinessRule, String)> {
+        if self.hooks.enabled(BusinessRule::RequireCategory).is_some() && item.category.is_none() {
+            return Some((BusinessRule::RequireCategory, "Items must have a category".to_string()));
+        }
+        None
+    }
+
+    /// Runs the rules hooked before an item is added or replaced
+    pub fn enforce_item_rules(&mut self, item: &InventoryItem, now: u64) -> Result<(), InventoryError> {
+        match self.item_rule_violation(item) {
+            Some((rule, msg)) => Err(self.rule_violation(rule, item.id, msg, now)),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the rules hooked after an item's price was set
+    pub fn after_price_change(&mut self, item_id: u32, new_price: f64, now: u64) {
+        if self.hooks.enabled(BusinessRule::PriceBelowCostAlert).is_none() {
+            return;
+        }
+        if let Some(&cost) = self.costing.cost_prices.get(&item_id).filter(|&&cost| new_price < cost) {
+            let detail = format!(
+                "price {} is below cost {}",
+                self.config.format_amount(new_price),
+                self.config.format_amount(cost)
+            );
+            self.rule_fired(BusinessRule::PriceBelowCostAlert, item_id, &detail, now);
+        }
+    }
+
+    /// Switches a business rule on or off and returns its settings
+    pub fn set_business_rule(&mut self, rule: BusinessRule, enabled: bool, limit: Option<u32>) -> RuleStatus {
+        let status = self.hooks.rules.entry(rule).or_insert(RuleStatus {
+            rule,
+            hook_point: rule.hook_point(),
+            enabled,
+            limit: None,
+            fired: 0,
+            last_fired_at: None,
+        });
+        status.enabled = enabled;
+        status.limit = limit.or(status.limit);
+        let status = status.clone();
+        let log = format!(
+            "Business rule {:?} {} at {}",
+            rule,
+            if enabled { "enabled" } else { "disabled" },
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        status
+    }
+
+    /// Runs the rules hooked after an item's stock level was set by hand
+    pub fn after_quantity_set(&mut self, item_id: u32, old_quantity: u32, new_quantity: u32, reason: AdjustmentReason, now: u64) {
+        let Some(Some(limit)) = self.hooks.enabled(BusinessRule::LargeAdjustmentAlert) else { return };
+        if matches!(reason, AdjustmentReason::Manual | AdjustmentReason::Recount) && old_quantity.abs_diff(new_quantity) > limit {
+            let detail = format!("stock set from {} to {} ({:?})", old_quantity, new_quantity, reason);
+            self.rule_fired(BusinessRule::LargeAdjustmentAlert, item_id, &detail, now);
+        }
+    }
+}
+
+// Switches a built-in business rule on or off. `MaxSaleQuantity` and `LargeAdjustmentAlert`
+// take a `limit`; leaving it out when switching one off keeps the previous limit. Once a
+// governance canister is set, this takes an executed `SetBusinessRule` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_business_rule(rule: BusinessRule, enabled: bool, limit: Option<u32>) -> Result<RuleStatus, InventoryError> {
+    require_caller("set_business_rule", Role::Manager)?;
+    rule.validate(enabled, limit)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        Ok(inventory.set_business_rule(rule, enabled, limit))
+    })
+}
+
+// Retrieves every built-in business rule with its settings and how often it has fired.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_business_rules() -> Result<Vec<RuleStatus>, InventoryError> {
+    require_reader("list_business_rules", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().hooks.list()))
+}
@@ -0,0 +1,217 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::certification::{base64, certified_root, labeled_tree, HashTree};
+use crate::metrics::prometheus_text;
+use crate::ratelimit::rate_limit;
+use crate::valuation::ValuationStore;
+use crate::{SupermarketManager, INVENTORY_MANAGER};
+
+const CACHE_TTL_NANOS: u64 = 30_000_000_000; // How long a certified response is served before it is recomputed
+const MAX_CACHED_PATHS: usize = 256;         // Certified responses kept at once; the oldest is dropped first
+const JSON: &str = "application/json";
+
+/// A request received through the HTTP gateway
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,                 // HTTP method, e.g. "GET"
+    pub url: String,                    // Path and query string
+    pub headers: Vec<(String, String)>, // Request headers
+    pub body: Vec<u8>,                  // Request body
+}
+
+/// A response returned through the HTTP gateway
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,               // HTTP status code
+    pub headers: Vec<(String, String)>, // Response headers
+    pub body: Vec<u8>,                  // Response body
+    pub upgrade: Option<bool>,          // Asks the gateway to repeat the request as an update call
+}
+
+impl HttpResponse {
+    /// A response with the given status, content type and body
+    pub fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+            upgrade: None,
+        }
+    }
+
+    /// A plain-text error response
+    pub fn error(status_code: u16, msg: &str) -> Self {
+        HttpResponse::new(status_code, "text/plain; charset=utf-8", msg.as_bytes().to_vec())
+    }
+
+    fn upgrade() -> Self {
+        HttpResponse { upgrade: Some(true), ..HttpResponse::error(204, "") }
+    }
+}
+
+/// A response body whose hash is part of the canister's certified data
+pub struct CachedResponse {
+    pub content_type: &'static str, // Content type the body was rendered as
+    pub body: Vec<u8>,              // Response body
+    pub body_hash: [u8; 32],        // SHA-256 of the body, the leaf certified for its path
+    pub certified_at: u64,          // Time the body was rendered in nanoseconds since the Unix epoch
+}
+
+/// Recently rendered responses and their certification
+///
+/// Responses are rendered and certified by update calls, then served from queries with an
+/// IC-Certificate header until they are `CACHE_TTL_NANOS` old.
+#[derive(Default)]
+pub struct HttpCache {
+    pub responses: BTreeMap<String, CachedResponse>, // Certified responses keyed by path
+}
+
+impl HttpCache {
+    pub fn tree(&self, keep: Option<&str>) -> HashTree {
+        let entries: Vec<(&[u8], &[u8])> = self.responses
+            .iter()
+            .map(|(path, response)| (path.as_bytes(), response.body_hash.as_slice()))
+            .collect();
+        let assets = labeled_tree(&entries, keep.map(str::as_bytes));
+        HashTree::Labeled(b"http_assets".to_vec(), Box::new(assets))
+    }
+
+    /// Stores a freshly rendered response; the caller certifies the new set of bodies
+    fn certify(&mut self, path: &str, content_type: &'static str, body: Vec<u8>, now: u64) {
+        let body_hash = Sha256::digest(&body).into();
+        self.responses.insert(path.to_string(), CachedResponse { content_type, body, body_hash, certified_at: now });
+        while self.responses.len() > MAX_CACHED_PATHS {
+            let oldest = self.responses.iter().min_by_key(|(_, r)| r.certified_at).map(|(p, _)| p.clone());
+            if let Some(path) = oldest {
+                self.responses.remove(&path);
+            }
+        }
+    }
+
+    /// Serves a cached response with its certificate, if it is still fresh
+    /// - `valuations`: The other half of the certified tree, pruned from the witness
+    fn serve(&self, path: &str, now: u64, valuations: &ValuationStore) -> Option<HttpResponse> {
+        let cached = self.responses.get(path).filter(|r| now.saturating_sub(r.certified_at) < CACHE_TTL_NANOS)?;
+        let certificate = ic_cdk::api::data_certificate()?;
+        let witness = certified_root(self.tree(Some(path)), HashTree::Pruned(valuations.tree(None).digest())).to_cbor();
+        let mut response = HttpResponse::new(200, cached.content_type, cached.body.clone());
+        response.headers.push((
+            "IC-Certificate".to_string(),
+            format!("certificate=:{}:, tree=:{}:", base64(&certificate), base64(&witness)),
+        ));
+        response.headers.push(("Cache-Control".to_string(), format!("max-age={}", CACHE_TTL_NANOS / 1_000_000_000)));
+        Some(response)
+    }
+}
+
+/// An item at or below its reorder threshold, or the store's default one, as served by `GET /low-stock`
+#[derive(Serialize)]
+struct LowStockEntry {
+    item_id: u32,
+    name: String,
+    quantity: u32,
+    threshold: u32,
+}
+
+fn json<T: Serialize>(value: &T) -> (u16, &'static str, Vec<u8>) {
+    (200, JSON, serde_json::to_vec(value).expect("Responses always serialize to JSON"))
+}
+
+impl SupermarketManager {
+    /// Certifies the cached HTTP responses and the valuation snapshots together
+    pub fn certify_state(&self) {
+        ic_cdk::api::set_certified_data(&certified_root(self.http.tree(None), self.valuations.tree(None)).digest());
+    }
+
+    /// Renders a GET route as (status, content type, body), or None if the path is unknown
+    /// - `path`: The request path without its query string
+    fn render_route(&self, path: &str, now: u64) -> Option<(u16, &'static str, Vec<u8>)> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["items"] => Some(json(&self.list_items())),
+            ["items", id] => Some(match id.parse().ok().and_then(|id| self.get_item(id)).filter(|item| !item.archived) {
+                Some(item) => json(&item),
+                None => (404, JSON, br#"{"error":"Item not found"}"#.to_vec()),
+            }),
+            ["low-stock"] => {
+                let low: Vec<LowStockEntry> = self.items
+                    .values()
+                    .filter(|item| !item.archived)
+                    .filter_map(|item| {
+                        let rule = self.reorder.rules.get(&item.id).map(|rule| rule.threshold);
+                        let threshold = rule.or(self.config.default_low_stock_threshold).filter(|&threshold| item.quantity <= threshold)?;
+                        Some(LowStockEntry { item_id: item.id, name: item.name, quantity: item.quantity, threshold })
+                    })
+                    .collect(); // The catalog is in ID order
+                Some(json(&low))
+            }
+            ["metrics"] => Some((200, "text/plain; version=0.0.4", prometheus_text(&self.metrics(now)).into_bytes())),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a path names one of the API routes, without rendering it
+fn is_route(path: &str) -> bool {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    matches!(segments.as_slice(), ["items"] | ["items", _] | ["low-stock"] | ["metrics"])
+}
+
+fn request_path(request: &HttpRequest) -> &str {
+    request.url.split('?').next().unwrap_or_default()
+}
+
+// Serves the read-only JSON API over the HTTP gateway: `GET /items`, `GET /items/{id}`,
+// `GET /low-stock` and `GET /metrics` (Prometheus text format). Certified responses rendered
+// in the last few seconds are served directly; anything else is upgraded to an update call.
+// Deprecated routes carry `Deprecation` and `Sunset` headers.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "Method not allowed");
+    }
+    let path = request_path(&request);
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let now = ic_cdk::api::time();
+        if let Some(mut response) = inventory.http.serve(path, now, &inventory.valuations) {
+            response.headers.extend(inventory.deprecations.http_headers(&request.method, path));
+            return response;
+        }
+        if is_route(path) {
+            HttpResponse::upgrade()
+        } else {
+            HttpResponse::error(404, "Not found")
+        }
+    })
+}
+
+// Renders an API response through consensus and certifies it so later queries can serve it.
+// Open to anyone, like the public routes it renders.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "Method not allowed");
+    }
+    let path = request_path(&request);
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let now = ic_cdk::api::time();
+        let Some((status, content_type, body)) = inventory.render_route(path, now) else {
+            return HttpResponse::error(404, "Not found");
+        };
+        if status == 200 {
+            inventory.http.certify(path, content_type, body.clone(), now);
+            inventory.certify_state();
+        }
+        let mut response = HttpResponse::new(status, content_type, body);
+        response.headers.extend(inventory.deprecations.http_headers(&request.method, path));
+        response
+    })
+}
@@ -0,0 +1,169 @@
+use candid::{CandidType, Principal};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use crate::{InventoryError, INVENTORY_MANAGER};
+
+const CAPACITY: usize = 1_000;                                // Number of recent keys remembered before the least recently used is evicted
+const IN_FLIGHT_TIMEOUT_NANOS: u64 = 10 * 60 * 1_000_000_000; // A call in flight for longer is taken to have trapped after an await
+
+/// Identifies one logical request: the caller, the endpoint and the caller-chosen key
+type RequestKey = (Principal, String, String);
+
+/// What is known about a request key
+enum Entry {
+    InFlight(u64), // An async call with this key started at this time, in nanoseconds since the Unix epoch, and has not finished
+    Done(Vec<u8>), // Candid-encoded result of the finished call
+}
+
+/// Bounded least-recently-used cache of results keyed by idempotency key
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: HashMap<RequestKey, (u64, Entry)>, // Entry and the tick it was last used
+    recency: BTreeMap<u64, RequestKey>,         // Keys ordered from least to most recently used
+    tick: u64,                                  // Counter used to order uses
+}
+
+impl IdempotencyCache {
+    /// Marks a key as just used
+    fn touch(&mut self, key: &RequestKey) {
+        if let Some((last_used, _)) = self.entries.get_mut(key) {
+            self.recency.remove(last_used);
+            self.tick += 1;
+            *last_used = self.tick;
+            self.recency.insert(self.tick, key.clone());
+        }
+    }
+
+    /// Stores the state of a key, evicting the least recently used key when full
+    fn put(&mut self, key: RequestKey, entry: Entry) {
+        if let Some((last_used, _)) = self.entries.remove(&key) {
+            self.recency.remove(&last_used);
+        }
+        while self.entries.len() >= CAPACITY {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, entry));
+    }
+
+    /// Looks up a key, refreshing its recency
+    fn lookup(&mut self, key: &RequestKey) -> Option<&Entry> {
+        self.touch(key);
+        self.entries.get(key).map(|(_, entry)| entry)
+    }
+
+    /// Starts an async call with a key: returns the encoded result of a finished call with it,
+    /// refuses while one is still in flight, or otherwise marks the key in flight from `now`
+    fn begin(&mut self, key: &RequestKey, now: u64) -> Result<Option<Vec<u8>>, InventoryError> {
+        match self.lookup(key) {
+            Some(Entry::Done(bytes)) => Ok(Some(bytes.clone())),
+            Some(Entry::InFlight(started_at)) if now.saturating_sub(*started_at) < IN_FLIGHT_TIMEOUT_NANOS => {
+                Err(InventoryError::Conflict {
+                    msg: format!("A call with idempotency key {} is still in progress", key.2),
+                })
+            }
+            _ => {
+                self.put(key.clone(), Entry::InFlight(now));
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn request_key(endpoint: &str, idempotency_key: String) -> RequestKey {
+    (ic_cdk::caller(), endpoint.to_string(), idempotency_key)
+}
+
+/// Runs a synchronous endpoint body at most once per idempotency key
+/// - `endpoint`: Name of the endpoint, so the same key can be reused across endpoints
+/// - `idempotency_key`: Caller-chosen key, or None to always run the body
+/// - `body`: The endpoint logic
+///
+/// A replay with a key that has already been seen returns the original result without running `body`.
+pub fn run_once<T, F>(endpoint: &str, idempotency_key: Option<String>, body: F) -> T
+where
+    T: CandidType + DeserializeOwned,
+    F: FnOnce() -> T,
+{
+    let Some(idempotency_key) = idempotency_key else {
+        return body();
+    };
+    let key = request_key(endpoint, idempotency_key);
+    let cached = INVENTORY_MANAGER.with(|inventory| match inventory.borrow_mut().idempotency.lookup(&key) {
+        Some(Entry::Done(bytes)) => Some(bytes.clone()),
+        _ => None, // Synchronous bodies cannot be in flight when another message runs
+    });
+    if let Some(bytes) = cached {
+        return candid::decode_one(&bytes).expect("Cached result has the endpoint's result type");
+    }
+    let result = body();
+    let bytes = candid::encode_one(&result).expect("Endpoint results are Candid-encodable");
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().idempotency.put(key, Entry::Done(bytes)));
+    result
+}
+
+/// Runs an asynchronous endpoint body at most once per idempotency key
+/// - `endpoint`: Name of the endpoint, so the same key can be reused across endpoints
+/// - `idempotency_key`: Caller-chosen key, or None to always run the body
+/// - `body`: The endpoint logic
+///
+/// A replay while the first call is still awaiting returns a `Conflict` error; a replay after it
+/// finished returns the original result. A call that trapped after an await never finishes, so
+/// once it has been in flight for `IN_FLIGHT_TIMEOUT_NANOS` a replay runs `body` again.
+pub async fn run_once_async<T, F>(endpoint: &str, idempotency_key: Option<String>, body: F) -> Result<T, InventoryError>
+where
+    T: CandidType + DeserializeOwned,
+    F: Future<Output = Result<T, InventoryError>>,
+{
+    let Some(idempotency_key) = idempotency_key else {
+        return body.await;
+    };
+    let key = request_key(endpoint, idempotency_key);
+    let now = ic_cdk::api::time();
+    let cached = INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().idempotency.begin(&key, now))?;
+    if let Some(bytes) = cached {
+        return candid::decode_one(&bytes).expect("Cached result has the endpoint's result type");
+    }
+    let result = body.await;
+    let bytes = candid::encode_one(&result).expect("Endpoint results are Candid-encodable");
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().idempotency.put(key, Entry::Done(bytes)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: usize) -> RequestKey {
+        (Principal::anonymous(), "record_sale".to_string(), n.to_string())
+    }
+
+    #[test]
+    fn an_async_replay_waits_for_the_first_call_unless_it_trapped() {
+        let mut cache = IdempotencyCache::default();
+        assert_eq!(cache.begin(&key(0), 0).unwrap(), None);
+        assert!(matches!(cache.begin(&key(0), 1), Err(InventoryError::Conflict { .. })));
+        assert_eq!(cache.begin(&key(0), IN_FLIGHT_TIMEOUT_NANOS).unwrap(), None); // Taken to have trapped
+
+        cache.put(key(0), Entry::Done(vec![7]));
+        assert_eq!(cache.begin(&key(0), IN_FLIGHT_TIMEOUT_NANOS + 1).unwrap(), Some(vec![7]));
+    }
+
+    #[test]
+    fn the_least_recently_used_key_is_evicted() {
+        let mut cache = IdempotencyCache::default();
+        for n in 0..CAPACITY {
+            cache.put(key(n), Entry::Done(Vec::new()));
+        }
+        assert!(cache.lookup(&key(0)).is_some()); // Now the most recently used
+        cache.put(key(CAPACITY), Entry::Done(Vec::new()));
+
+        assert_eq!(cache.entries.len(), CAPACITY);
+        assert!(cache.lookup(&key(0)).is_some());
+        assert!(cache.lookup(&key(1)).is_none());
+    }
+}
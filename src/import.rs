@@ -0,0 +1,166 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::HashMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::idempotency::run_once;
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_IMPORT_ROWS: usize = 5000; // Items added or replaced by one import
+
+/// One item of a bulk import; an item with the same ID is replaced as `add_inventory_item` would
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemImport {
+    pub id: u32,
+    pub name: String,
+    pub quantity: u32,
+    pub price: f64,
+    pub expiration_date: u64,
+    pub unit: Option<Unit>,       // Counted individually when left out
+    pub category: Option<String>, // Kept from the item being replaced when left out
+}
+
+impl ItemImport {
+    fn to_item(&self) -> InventoryItem {
+        InventoryItem {
+            id: self.id,
+            name: self.name.clone(),
+            quantity: self.quantity,
+            price: self.price,
+            expiration_date: self.expiration_date,
+            archived: false,
+            unit: self.unit.unwrap_or(Unit::Each),
+            version: 0,     // Assigned when the item is stored
+            location: None, // Kept from the item being replaced, if any
+            barcode: None,
+            category: self.category.as_ref().map(|category| category.trim().to_string()),
+            storage_class: None,
+        }
+    }
+}
+
+/// What an import did, or in a preview would do, to one item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ImportChange {
+    pub item_id: u32,
+    pub before: Option<InventoryItem>, // The item replaced; None when the item is new
+    pub after: InventoryItem,          // The item as stored
+}
+
+/// Every change made by an import
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ImportReport {
+    pub created: u32,
+    pub replaced: u32,
+    pub changes: Vec<ImportChange>, // In the order of the import's rows
+}
+
+impl SupermarketManager {
+    /// Checks every row of an import and works out the items it would store, without storing them
+    ///
+    /// Problems with any row are reported together, with fields named e.g. `items[3].price`.
+    /// A pack's base item may be in the inventory or on an earlier row.
+    pub fn plan_import(&self, rows: &[ItemImport], now: u64) -> Result<ImportReport, InventoryError> {
+        let mut validator = Validator::new();
+        validator
+            .check(!rows.is_empty(), "items", "must contain at least one item")
+            .check(rows.len() <= MAX_IMPORT_ROWS, "items", format!("must have at most {} entries", MAX_IMPORT_ROWS));
+        let mut units: HashMap<u32, Unit> = HashMap::new(); // Units of the rows checked so far
+        for (i, row) in rows.iter().enumerate() {
+            let field = |name: &str| format!("items[{}].{}", i, name);
+            let unit = row.unit.unwrap_or(Unit::Each);
+            validator
+                .check(!units.contains_key(&row.id), &field("id"), "appears more than once")
+                .name(&field("name"), &row.name)
+                .price(&field("price"), row.price)
+                .quantity(&field("quantity"), row.quantity, unit)
+                .expiration_date(&field("expiration_date"), row.expiration_date, now);
+            if let Some(category) = &row.category {
+                validator.name(&field("category"), category);
+            }
+            if let Unit::Pack { base_item_id, size } = unit {
+                let base_unit = units.get(&base_item_id).copied().or_else(|| self.items.get(&base_item_id).map(|base| base.unit));
+                validator
+                    .check(size > 0, &field("unit"), "must be a pack of at least one unit")
+                    .check(base_item_id != row.id, &field("unit"), "must not be a pack of the item itself")
+                    .check(
+                        base_item_id == row.id || base_unit.is_some(),
+                        &field("unit"),
+                        format!("must be a pack of an item in the inventory or on an earlier row; {} is neither", base_item_id),
+                    )
+                    .check(!matches!(base_unit, Some(Unit::Pack { .. })), &field("unit"), "must not be a pack of another pack");
+            }
+            units.insert(row.id, unit);
+        }
+        validator.finish()?;
+
+        let mut report = ImportReport { created: 0, replaced: 0, changes: Vec::with_capacity(rows.len()) };
+        for row in rows {
+            let before = self.items.get(&row.id);
+            let after = row.to_item().replacing(before.as_ref());
+            if before.is_some() {
+                report.replaced += 1;
+            } else {
+                report.created += 1;
+            }
+            report.changes.push(ImportChange { item_id: row.id, before, after });
+        }
+        Ok(report)
+    }
+
+    /// Adds or replaces many items at once; every row is checked first and either all are
+    /// stored or none are
+    pub fn import_items(&mut self, rows: &[ItemImport], now: u64) -> Result<ImportReport, InventoryError> {
+        let report = self.plan_import(rows, now)?;
+        for change in &report.changes {
+            self.enforce_item_rules(&change.after, now)?; // Before anything is stored, so a refusal leaves no partial import
+        }
+        for row in rows {
+            self.add_item(row.to_item())?;
+        }
+        let log = format!(
+            "Imported {} new and {} replaced items at {}",
+            report.created,
+            report.replaced,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(report)
+    }
+}
+
+// Adds or replaces many items at once. Nothing is stored unless every row is valid; the
+// errors of all rows are returned together.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn import_items(items: Vec<ItemImport>, idempotency_key: Option<String>) -> Result<ImportReport, InventoryError> {
+    require_caller("import_items", Role::Manager)?;
+    run_once("import_items", idempotency_key, || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().import_items(&items, ic_cdk::api::time())
+        })
+    })
+}
+
+// Previews `import_items`: returns every item it would create or replace, or every validation
+// error, without storing anything.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn simulate_import_items(items: Vec<ItemImport>) -> Result<ImportReport, InventoryError> {
+    require_reader("simulate_import_items", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let report = inventory.plan_import(&items, ic_cdk::api::time())?;
+        if let Some((rule, msg)) = report.changes.iter().find_map(|change| inventory.item_rule_violation(&change.after)) {
+            return Err(InventoryError::RuleViolation { rule, msg });
+        }
+        Ok(report)
+    })
+}
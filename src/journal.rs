@@ -0,0 +1,433 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use ic_stable_structures::{StableBTreeMap, StableLog};
+use std::collections::HashMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::cold_chain::StorageClass;
+use crate::location::ShelfLocation;
+use crate::projections::{launch_projection_job, ProjectionJobMode, ProjectionKind};
+use crate::ratelimit::rate_limit;
+use crate::storage::{self, Memory};
+use crate::{AdjustmentReason, InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_PAGE_ENTRIES: u32 = 1000; // Most entries `get_journal` returns per call
+
+/// A change to the item catalog
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum JournalEvent {
+    ItemPut { item: InventoryItem },                                       // Added, or replaced by a new definition
+    QuantitySet { item_id: u32, quantity: u32, reason: AdjustmentReason }, // Stock set by hand, by a recount, a delivery or a write-off
+    StockSold { item_id: u32, sale_id: u64, units: u32 },                  // Stock taken by a sale of the item or of a pack or bundle holding it
+    PriceSet { item_id: u32, price: f64 },
+    ArchivedSet { item_id: u32, archived: bool },
+    CategorySet { item_id: u32, category: Option<String> },
+    LocationSet { item_id: u32, location: Option<ShelfLocation> },
+    BarcodeSet { item_id: u32, barcode: Option<String> },
+    StorageClassSet { item_id: u32, storage_class: Option<StorageClass> },
+    ItemRemoved { item_id: u32 },
+    CatalogRestored { items: Vec<InventoryItem> },                         // The whole catalog replaced from a snapshot
+}
+
+impl JournalEvent {
+    /// Whether the event changes the given item
+    fn touches(&self, id: u32) -> bool {
+        match self {
+            JournalEvent::ItemPut { item } => item.id == id,
+            JournalEvent::QuantitySet { item_id, .. }
+            | JournalEvent::StockSold { item_id, .. }
+            | JournalEvent::PriceSet { item_id, .. }
+            | JournalEvent::ArchivedSet { item_id, .. }
+            | JournalEvent::CategorySet { item_id, .. }
+            | JournalEvent::LocationSet { item_id, .. }
+            | JournalEvent::BarcodeSet { item_id, .. }
+            | JournalEvent::StorageClassSet { item_id, .. }
+            | JournalEvent::ItemRemoved { item_id } => *item_id == id,
+            JournalEvent::CatalogRestored { .. } => true,
+        }
+    }
+
+    /// The one item the event changes, or None when it replaces the whole catalog
+    fn item_id(&self) -> Option<u32> {
+        match self {
+            JournalEvent::ItemPut { item } => Some(item.id),
+            JournalEvent::QuantitySet { item_id, .. }
+            | JournalEvent::StockSold { item_id, .. }
+            | JournalEvent::PriceSet { item_id, .. }
+            | JournalEvent::ArchivedSet { item_id, .. }
+            | JournalEvent::CategorySet { item_id, .. }
+            | JournalEvent::LocationSet { item_id, .. }
+            | JournalEvent::BarcodeSet { item_id, .. }
+            | JournalEvent::StorageClassSet { item_id, .. }
+            | JournalEvent::ItemRemoved { item_id } => Some(*item_id),
+            JournalEvent::CatalogRestored { .. } => None,
+        }
+    }
+}
+
+/// An entry of the journal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct JournalEntry {
+    pub seq: u64,          // Position in the journal, starting at 0
+    pub timestamp: u64,    // Time of the change in nanoseconds since the Unix epoch
+    pub caller: Principal, // Principal whose call made the change; the canister itself for timer jobs
+    pub event: JournalEvent,
+}
+
+/// A page of the journal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct JournalPage {
+    pub entries: Vec<JournalEntry>, // Matching entries from the requested offset, oldest first
+    pub next_seq: u64,              // Sequence number the next entry will get
+}
+
+/// Where a projection of the journal keeps its items
+trait ItemStore {
+    fn get_item(&self, id: u32) -> Option<InventoryItem>;
+    fn put_item(&mut self, item: InventoryItem);
+    fn remove_item(&mut self, id: u32);
+    fn clear_items(&mut self);
+}
+
+impl ItemStore for StableBTreeMap<u32, InventoryItem, Memory> {
+    fn get_item(&self, id: u32) -> Option<InventoryItem> {
+        self.get(&id)
+    }
+
+    fn put_item(&mut self, item: InventoryItem) {
+        self.insert(item.id, item);
+    }
+
+    fn remove_item(&mut self, id: u32) {
+        self.remove(&id);
+    }
+
+    fn clear_items(&mut self) {
+        self.clear_new();
+    }
+}
+
+impl ItemStore for HashMap<u32, InventoryItem> {
+    fn get_item(&self, id: u32) -> Option<InventoryItem> {
+        self.get(&id).cloned()
+    }
+
+    fn put_item(&mut self, item: InventoryItem) {
+        self.insert(item.id, item);
+    }
+
+    fn remove_item(&mut self, id: u32) {
+        self.remove(&id);
+    }
+
+    fn clear_items(&mut self) {
+        self.clear();
+    }
+}
+
+/// Folds one event into a set of items; put and restored items carry their own version, other changes bump it
+fn apply(items: &mut impl ItemStore, event: &JournalEvent) {
+    let item_id = match event {
+        JournalEvent::ItemPut { item } => {
+            items.put_item(item.clone());
+            return;
+        }
+        JournalEvent::ItemRemoved { item_id } => {
+            items.remove_item(*item_id);
+            return;
+        }
+        JournalEvent::CatalogRestored { items: restored } => {
+            items.clear_items();
+            for item in restored {
+                items.put_item(item.clone());
+            }
+            return;
+        }
+        JournalEvent::QuantitySet { item_id, .. }
+        | JournalEvent::StockSold { item_id, .. }
+        | JournalEvent::PriceSet { item_id, .. }
+        | JournalEvent::ArchivedSet { item_id, .. }
+        | JournalEvent::CategorySet { item_id, .. }
+        | JournalEvent::LocationSet { item_id, .. }
+        | JournalEvent::BarcodeSet { item_id, .. }
+        | JournalEvent::StorageClassSet { item_id, .. } => *item_id,
+    };
+    let Some(mut item) = items.get_item(item_id) else { return };
+    match event {
+        JournalEvent::QuantitySet { quantity, .. } => item.quantity = *quantity,
+        JournalEvent::StockSold { units, .. } => item.quantity = item.quantity.saturating_sub(*units),
+        JournalEvent::PriceSet { price, .. } => item.price = *price,
+        JournalEvent::ArchivedSet { archived, .. } => item.archived = *archived,
+        JournalEvent::CategorySet { category, .. } => item.category = category.clone(),
+        JournalEvent::LocationSet { location, .. } => item.location = location.clone(),
+        JournalEvent::BarcodeSet { barcode, .. } => item.barcode = barcode.clone(),
+        JournalEvent::StorageClassSet { storage_class, .. } => item.storage_class = *storage_class,
+        JournalEvent::ItemPut { .. } | JournalEvent::ItemRemoved { .. } | JournalEvent::CatalogRestored { .. } => {}
+    }
+    item.version += 1;
+    items.put_item(item);
+}
+
+/// The item catalog, a projection of the journal
+///
+/// Items live in stable memory so the catalog is not bounded by the heap. Reads decode a copy
+/// of the item; the only way to change one is to append an event with
+/// `SupermarketManager::journal_event`, so no endpoint can alter an item without a record.
+pub struct Catalog {
+    items: StableBTreeMap<u32, InventoryItem, Memory>,
+}
+
+impl Catalog {
+    /// Opens the catalog in its stable memory region, keeping any items already there
+    pub fn init() -> Self {
+        Catalog { items: StableBTreeMap::init(storage::memory(storage::ITEMS)) }
+    }
+
+    pub fn get(&self, id: &u32) -> Option<InventoryItem> {
+        self.items.get(id)
+    }
+
+    pub fn contains_key(&self, id: &u32) -> bool {
+        self.items.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Item IDs in ascending order
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = u32> + '_ {
+        self.items.keys()
+    }
+
+    /// Every item in ascending ID order
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = InventoryItem> + '_ {
+        self.items.values()
+    }
+}
+
+/// Append-only record of every change to the catalog, from which the catalog can be rebuilt
+///
+/// Sales, receipts, payments and write-offs are append-only ledgers of their own; the journal
+/// covers the item state they draw on. It lives in stable memory next to the catalog.
+pub struct Journal {
+    entries: StableLog<JournalEntry, Memory, Memory>, // Every entry in the order it was appended; never edited or removed
+}
+
+impl Journal {
+    /// Opens the journal in its stable memory regions, keeping any entries already there
+    pub fn init() -> Self {
+        let entries = StableLog::init(storage::memory(storage::JOURNAL_INDEX), storage::memory(storage::JOURNAL_DATA))
+            .expect("the journal regions hold nothing but the journal");
+        Journal { entries }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries from sequence number `from` on, oldest first
+    pub fn entries_from(&self, from: u64) -> impl Iterator<Item = JournalEntry> + '_ {
+        (from..self.entries.len()).filter_map(|seq| self.entries.get(seq))
+    }
+}
+
+/// A catalog being rebuilt from the journal a chunk at a time
+///
+/// The replayed items are held on the heap until they are installed.
+#[derive(Default)]
+pub struct CatalogReplay {
+    items: HashMap<u32, InventoryItem>,
+    next_seq: u64, // Journal entry to apply next
+}
+
+impl CatalogReplay {
+    /// Applies up to `max` more entries and returns whether the replay has caught up with the journal
+    pub fn advance(&mut self, journal: &Journal, max: usize) -> bool {
+        for entry in journal.entries_from(self.next_seq).take(max) {
+            apply(&mut self.items, &entry.event);
+            self.next_seq += 1;
+        }
+        self.next_seq == journal.len()
+    }
+
+    pub fn items(&self) -> &HashMap<u32, InventoryItem> {
+        &self.items
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+impl SupermarketManager {
+    /// Appends an event to the journal and applies it to the catalog and the inventory summary
+    pub fn journal_event(&mut self, event: JournalEvent) {
+        match event.item_id() {
+            Some(item_id) => self.recount_item(item_id, |inventory| apply(&mut inventory.items.items, &event)),
+            None => {
+                apply(&mut self.items.items, &event);
+                self.rebuild_inventory_summary(ic_cdk::api::time());
+            }
+        }
+        let seq = self.journal.len();
+        let entry = JournalEntry { seq, timestamp: ic_cdk::api::time(), caller: ic_cdk::caller(), event };
+        self.journal.entries.append(&entry).expect("stable memory can grow to hold the journal");
+    }
+
+    /// Writes a replay's version of the given items over the catalog's, keeping the inventory
+    /// summary in step; the replay must have caught up with the journal
+    pub fn install_replayed_items(&mut self, replay: &CatalogReplay, ids: &[u32]) {
+        debug_assert_eq!(replay.next_seq, self.journal.len());
+        for &id in ids {
+            self.recount_item(id, |inventory| match replay.items.get(&id) {
+                Some(item) => inventory.items.items.insert(id, item.clone()),
+                None => inventory.items.items.remove(&id),
+            });
+        }
+    }
+}
+
+// Retrieves up to `limit` journal entries starting at sequence number `offset`, optionally only
+// those changing one item. Pass the previous page's last `seq + 1` to read on.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_journal(offset: u64, limit: u32, item_id: Option<u32>) -> Result<JournalPage, InventoryError> {
+    require_reader("get_journal", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        Ok(JournalPage {
+            entries: inventory.journal
+                .entries_from(offset)
+                .filter(|entry| item_id.is_none_or(|id| entry.event.touches(id)))
+                .take(limit.min(MAX_PAGE_ENTRIES) as usize)
+                .collect(),
+            next_seq: inventory.journal.len(),
+        })
+    })
+}
+
+// Starts a background job that replays the journal a chunk at a time and reports the items
+// whose current state differs from it, without changing anything. Returns the job ID; its
+// discrepancies are read with `get_projection_job`.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn verify_projection() -> Result<u64, InventoryError> {
+    require_caller("verify_projection", Role::Manager)?;
+    launch_projection_job(ProjectionKind::Catalog, ProjectionJobMode::Verify)
+}
+
+// Starts a background job that replays the journal a chunk at a time and writes the replayed
+// items over the catalog. Returns the job ID, as for `verify_projection`.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn rebuild_projection() -> Result<u64, InventoryError> {
+    require_caller("rebuild_projection", Role::Owner)?;
+    launch_projection_job(ProjectionKind::Catalog, ProjectionJobMode::Rebuild)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Unit;
+
+    fn item(id: u32, quantity: u32) -> InventoryItem {
+        InventoryItem {
+            id,
+            name: format!("Item {}", id),
+            quantity,
+            price: 2.5,
+            expiration_date: 0,
+            archived: false,
+            unit: Unit::Each,
+            version: 1,
+            location: None,
+            barcode: None,
+            category: None,
+            storage_class: None,
+        }
+    }
+
+    /// Appends an event and applies it to the catalog, as `journal_event` does inside a canister
+    fn record(manager: &mut SupermarketManager, event: JournalEvent) {
+        apply(&mut manager.items.items, &event);
+        let entry = JournalEntry { seq: manager.journal.len(), timestamp: 0, caller: Principal::anonymous(), event };
+        manager.journal.entries.append(&entry).unwrap();
+    }
+
+    fn sample(manager: &mut SupermarketManager) {
+        record(manager, JournalEvent::ItemPut { item: item(1, 10) });
+        record(manager, JournalEvent::ItemPut { item: item(2, 5) });
+        record(manager, JournalEvent::StockSold { item_id: 1, sale_id: 0, units: 3 });
+        record(manager, JournalEvent::PriceSet { item_id: 2, price: 4.0 });
+        record(manager, JournalEvent::ItemRemoved { item_id: 2 });
+        record(manager, JournalEvent::ItemPut { item: item(3, 1) });
+        record(manager, JournalEvent::QuantitySet { item_id: 3, quantity: 8, reason: AdjustmentReason::Recount });
+    }
+
+    /// Folds the whole journal into a fresh set of items
+    fn full_replay(manager: &SupermarketManager) -> HashMap<u32, InventoryItem> {
+        let mut replay = CatalogReplay::default();
+        assert!(replay.advance(&manager.journal, usize::MAX));
+        replay.items
+    }
+
+    /// Items whose current state differs from a replay of the journal, lowest ID first
+    fn mismatched(manager: &SupermarketManager) -> Vec<u32> {
+        let replayed = full_replay(manager);
+        let mut ids: Vec<u32> = replayed.keys().copied().chain(manager.items.keys()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.retain(|id| replayed.get(id) != manager.items.get(id).as_ref());
+        ids
+    }
+
+    #[test]
+    fn replay_matches_the_catalog() {
+        let mut manager = SupermarketManager::new();
+        sample(&mut manager);
+
+        assert!(mismatched(&manager).is_empty());
+        let replayed = full_replay(&manager);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[&1].quantity, 7);
+        assert_eq!(replayed[&3].quantity, 8);
+        assert!(replayed[&3].version > 1); // Changes other than puts bump the version
+        assert!(!replayed.contains_key(&2));
+    }
+
+    #[test]
+    fn chunked_replay_matches_a_full_one() {
+        let mut manager = SupermarketManager::new();
+        sample(&mut manager);
+
+        let mut replay = CatalogReplay::default();
+        let mut chunks = 0;
+        while !replay.advance(&manager.journal, 2) {
+            chunks += 1;
+        }
+        assert_eq!(chunks, 3);
+        assert_eq!(replay.next_seq(), 7);
+        assert_eq!(replay.items(), &full_replay(&manager));
+    }
+
+    #[test]
+    fn replay_shows_items_changed_outside_the_journal() {
+        let mut manager = SupermarketManager::new();
+        sample(&mut manager);
+        manager.items.items.insert(3, item(3, 99));
+        manager.items.items.insert(4, item(4, 1));
+
+        assert_eq!(mismatched(&manager), vec![3, 4]);
+    }
+}
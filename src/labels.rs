@@ -0,0 +1,130 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::promotions::PromotionStatus;
+use crate::validation::Validator;
+use crate::{InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_LABELS: usize = 1000;     // Labels one call produces
+const IN_STORE_PREFIX: &str = "20"; // GS1 prefix reserved for codes used only within the store
+
+/// What a unit price is quoted per
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum UnitPriceBasis {
+    PerKg,
+    PerLitre,
+}
+
+/// Price per kilogram or litre, which shelf labels must show for weighed and measured goods
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct UnitPrice {
+    pub price: f64,
+    pub basis: UnitPriceBasis,
+}
+
+/// What a printed shelf label shows for an item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LabelData {
+    pub item_id: u32,
+    pub name: String,
+    pub price: f64,                    // Shelf price per piece, pack, kilogram or litre
+    pub currency_code: String,
+    pub unit_price: Option<UnitPrice>, // None for goods sold by the piece
+    pub barcode_payload: String,       // The item's barcode, or an in-store EAN-13 for items without one
+    pub promo: bool,                   // Marked down or on an approved bundle promotion
+    pub regular_price: Option<f64>,    // Price before a markdown, to print as the was price
+    pub changed_at: Option<u64>,       // Time of the latest change to what the label shows; None if unchanged since the last upgrade
+}
+
+/// An in-store EAN-13 for an item: the reserved prefix, the zero-padded ID and a check digit
+fn in_store_barcode(item_id: u32) -> String {
+    let digits = format!("{}{:010}", IN_STORE_PREFIX, item_id);
+    let sum: u32 = digits
+        .bytes()
+        .enumerate()
+        .map(|(i, digit)| (digit - b'0') as u32 * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    format!("{}{}", digits, (10 - sum % 10) % 10)
+}
+
+impl SupermarketManager {
+    /// Price per kilogram or litre of an item, if it is sold by weight or volume or is a pack of such goods
+    fn unit_price(&self, item: &InventoryItem) -> Option<UnitPrice> {
+        let basis = |unit: Unit| match unit {
+            Unit::Kg => Some(UnitPriceBasis::PerKg),
+            Unit::Litre => Some(UnitPriceBasis::PerLitre),
+            Unit::Each | Unit::Pack { .. } => None,
+        };
+        match item.unit {
+            Unit::Pack { base_item_id, size } => {
+                let base = self.items.get(&base_item_id)?;
+                let basis = basis(base.unit)?;
+                let price = item.price / size as f64 * base.unit.stock_units_per_price_unit() as f64;
+                Some(UnitPrice { price: self.config.round_amount(price), basis })
+            }
+            unit => Some(UnitPrice { price: item.price, basis: basis(unit)? }),
+        }
+    }
+
+    /// The label of an item as it should be printed now
+    pub fn label_data(&self, item: &InventoryItem) -> LabelData {
+        let markdown = self.markdowns.active.get(&item.id);
+        let bundled = self.promotions.promotions
+            .values()
+            .any(|p| p.status == PromotionStatus::Approved && p.discounted_item_id == item.id);
+        LabelData {
+            item_id: item.id,
+            name: item.name.clone(),
+            price: item.price,
+            currency_code: self.config.currency_code.clone(),
+            unit_price: self.unit_price(item),
+            barcode_payload: item.barcode.clone().unwrap_or_else(|| in_store_barcode(item.id)),
+            promo: markdown.is_some() || bundled,
+            regular_price: markdown.map(|markdown| markdown.original_price),
+            changed_at: self.esl.changed_at.get(&item.id).copied(),
+        }
+    }
+
+    /// Labels of the items whose label changed after `since`, in ID order
+    pub fn labels_changed_since(&self, since: u64) -> Vec<LabelData> {
+        let mut changed: Vec<u32> = self.esl.changed_at.iter().filter(|(_, &at)| at > since).map(|(&id, _)| id).collect();
+        changed.sort_unstable();
+        changed
+            .into_iter()
+            .filter_map(|id| self.items.get(&id))
+            .filter(|item| !item.archived)
+            .take(MAX_LABELS)
+            .map(|item| self.label_data(&item))
+            .collect()
+    }
+}
+
+// Produces the data for printing shelf labels for the given items, in the order given.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn generate_labels(item_ids: Vec<u32>) -> Result<Vec<LabelData>, InventoryError> {
+    require_reader("generate_labels", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let mut validator = Validator::new();
+        validator.check(item_ids.len() <= MAX_LABELS, "item_ids", format!("must have at most {} entries", MAX_LABELS));
+        for (i, id) in item_ids.iter().enumerate() {
+            validator.check(inventory.items.contains_key(id), &format!("item_ids[{}]", i), "is not a known item");
+        }
+        validator.finish()?;
+        Ok(item_ids.iter().filter_map(|id| inventory.items.get(id)).map(|item| inventory.label_data(&item)).collect())
+    })
+}
+
+// Retrieves the labels of items whose name, price, barcode or promotion changed after
+// `timestamp`, in nanoseconds since the Unix epoch, so only those need reprinting. At most
+// `MAX_LABELS` are returned, in ID order.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn labels_changed_since(timestamp: u64) -> Result<Vec<LabelData>, InventoryError> {
+    require_reader("labels_changed_since", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().labels_changed_since(timestamp)))
+}
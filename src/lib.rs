@@ -1,31 +1,341 @@
-use ic_cdk_macros::{update, query};
+use ic_cdk_macros::{update, query, init, pre_upgrade, post_upgrade};
 use serde::{Serialize, Deserialize};
 use candid::CandidType;
-use std::collections::HashMap;
 use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 
+pub mod access;
+pub mod allocation;
+pub mod assortments;
+pub mod audit;
+pub mod back_in_stock;
+pub mod breakglass;
+pub mod bundles;
+pub mod bus;
+pub mod certification;
+pub mod chain;
+pub mod channels;
+pub mod cold_chain;
+pub mod confidential;
+pub mod cost;
+pub mod costing;
+pub mod dao;
+pub mod deprecation;
+pub mod duplicates;
+pub mod encryption;
+pub mod error;
+pub mod esl;
+pub mod events;
+pub mod exchange;
+pub mod export;
+pub mod flows;
+pub mod governance;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod idempotency;
+pub mod import;
+pub mod journal;
+pub mod labels;
+pub mod load;
+pub mod location;
+pub mod logs;
+pub mod loyalty;
+pub mod markdowns;
+pub mod metrics;
+pub mod payments;
+pub mod pricing;
+pub mod projections;
+pub mod promotions;
+pub mod ratelimit;
+pub mod receipts;
+pub mod reconciliation;
+pub mod reorder;
+pub mod returns;
+pub mod royalties;
+pub mod sales;
+pub mod self_checkout;
+pub mod settings;
+pub mod shifts;
+pub mod snapshot;
+pub mod stocktake;
+pub mod storage;
+pub mod summary;
+pub mod trends;
+pub mod trials;
+pub mod upgrade;
+pub mod usage;
+pub mod validation;
+pub mod valuation;
+pub mod velocity;
+pub mod versioning;
+pub mod waste;
+pub mod watchlists;
+pub mod webhooks;
+
+use access::{require_permission, AccessControl, Permission, Role};
+use allocation::Fulfillment;
+use assortments::Assortments;
+use audit::AccessAudit;
+use back_in_stock::BackInStock;
+use breakglass::BreakGlass;
+use bundles::Bundles;
+use bus::IntegrationBus;
+use chain::Chain;
+use channels::Channels;
+use cold_chain::{ColdChain, StorageClass};
+use confidential::ConfidentialStore;
+use cost::CostTracker;
+use costing::Costing;
+use dao::DaoGovernance;
+use deprecation::Deprecations;
+use duplicates::Duplicates;
+use encryption::ExportEncryption;
+use error::InventoryError;
+use esl::EslFeed;
+use events::{EventBus, InventoryEventPayload};
+use exchange::ExchangeRates;
+use flows::AsyncFlows;
+use history::{ItemHistory, ItemHistoryEvent};
+use hooks::HookRegistry;
+use http::HttpCache;
+use governance::Governance;
+use idempotency::{run_once, IdempotencyCache};
+use journal::{Catalog, Journal, JournalEvent};
+use load::{degrade_history, load_degraded, LoadShedder};
+use location::ShelfLocation;
+use logs::LogStore;
+use loyalty::Loyalty;
+use markdowns::Markdowns;
+use payments::Payments;
+use pricing::{PriceChange, PriceHistory};
+use projections::Projections;
+use promotions::Promotions;
+use ratelimit::{rate_limit, RateLimiter};
+use receipts::Receipts;
+use reconciliation::Reconciliation;
+use reorder::ReorderPlanner;
+use returns::ReturnsLedger;
+use royalties::Royalties;
+use sales::SalesLedger;
+use self_checkout::SelfCheckout;
+use settings::{InitArgs, StoreConfig};
+use shifts::Shifts;
+use snapshot::SnapshotStore;
+use stocktake::Stocktakes;
+use summary::SummaryCounters;
+use trials::Trials;
+use usage::{metered, UsageAnalytics};
+use valuation::ValuationStore;
+use velocity::SalesVelocity;
+use versioning::InventoryItemV1;
+use waste::WasteLedger;
+use watchlists::Watchlists;
+use webhooks::Webhooks;
+
+/// Unit an item is stocked and sold in
+///
+/// Weighed and measured goods keep integer stock in the smallest unit: an item sold by the
+/// `Kg` counts grams and one sold by the `Litre` counts millilitres, while its price is per
+/// kilogram or litre. A `Pack` holds no stock of its own; selling one pack takes `size` units
+/// from the base item.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Each,                                  // Counted individually
+    Kg,                                    // Stock in grams, price per kilogram
+    Litre,                                 // Stock in millilitres, price per litre
+    Pack { base_item_id: u32, size: u32 }, // A pack of `size` units of another item
+}
+
+impl Unit {
+    /// Number of stock units one unit of price is quoted for
+    pub fn stock_units_per_price_unit(&self) -> u32 {
+        match self {
+            Unit::Kg | Unit::Litre => 1000,
+            Unit::Each | Unit::Pack { .. } => 1,
+        }
+    }
+}
+
+/// Why an item's stock level was set by hand rather than by a sale
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum AdjustmentReason {
+    Manual,   // A quantity update by staff
+    Recount,  // A correction from a finalized stocktake
+    Received, // A delivery booked through `receive_stock`
+    Waste,    // A write-off booked through `record_waste`
+    Returned, // Units put back by `record_return`
+    Merged,   // Stock combined by `merge_items`
+}
+
 /// Represents an item in the supermarket's inventory
-#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
 pub struct InventoryItem {
-    pub id: u32,                // Unique ID for the item
-    pub name: String,           // Name of the item
-    pub quantity: u32,          // Quantity of the item in stock
-    pub price: f64,             // Price of the item
-    pub expiration_date: u64,   // Expiration date of the item as a Unix timestamp
+    pub id: u32,                             // Unique ID for the item
+    pub name: String,                        // Name of the item
+    pub quantity: u32,                       // Quantity of the item in stock, in grams or millilitres for weighed goods
+    pub price: f64,                          // Price of the item, per kilogram or litre for weighed goods
+    pub expiration_date: u64,                // Expiration date of the item as a Unix timestamp
+    pub archived: bool,                      // Archived items are kept for history but hidden from listings and sales
+    pub unit: Unit,                          // Unit the item is stocked and sold in
+    pub version: u64,                        // Bumped on every write so concurrent updates can be detected
+    pub location: Option<ShelfLocation>,     // Where the item is shelved, if known
+    pub barcode: Option<String>,             // Barcode printed on the item, e.g. an EAN-13
+    pub category: Option<String>,            // Category the item is reported under, e.g. "Dairy"
+    pub storage_class: Option<StorageClass>, // How the item has to be kept; None if not recorded
+}
+
+impl InventoryItem {
+    /// Price of selling a quantity of the item, taking its unit of measure into account
+    /// - `quantity`: Number of units sold, in grams or millilitres for weighed goods
+    pub fn line_total(&self, quantity: u32) -> f64 {
+        self.price * quantity as f64 / self.unit.stock_units_per_price_unit() as f64
+    }
+
+    /// The item as stored in place of `old`: it continues the old version count and keeps the
+    /// old location, barcode, category and storage class unless it sets its own
+    pub fn replacing(mut self, old: Option<&InventoryItem>) -> InventoryItem {
+        if let Some(old) = old {
+            self.version = old.version + 1; // Replacing an item is a write too
+            self.location = self.location.or_else(|| old.location.clone());
+            self.barcode = self.barcode.or_else(|| old.barcode.clone());
+            self.category = self.category.or_else(|| old.category.clone());
+            self.storage_class = self.storage_class.or(old.storage_class);
+        } else {
+            self.version = 0;
+        }
+        self
+    }
 }
 
 /// Manages the supermarket inventory and keeps a log of changes
 pub struct SupermarketManager {
-    pub items: HashMap<u32, InventoryItem>, // HashMap to store items by their ID
-    pub logs: Vec<String>,                  // Vector to keep logs of all changes made to inventory
+    pub items: Catalog,                      // Items by their ID, projected from the journal
+    pub logs: LogStore,                      // Log of all changes made to inventory and its retention policy
+    pub sales: SalesLedger,                  // Ledger of every sale recorded against the inventory
+    pub reorder: ReorderPlanner,             // Reorder rules and the suggestions produced from them
+    pub self_checkout: SelfCheckout,         // Self-checkout audit settings and customer trust scores
+    pub esl: EslFeed,                        // Electronic shelf label bindings and change tracking
+    pub access: AccessControl,               // Staff roles keyed by principal
+    pub webhooks: Webhooks,                  // Registered webhooks and their delivery log
+    pub export_encryption: ExportEncryption, // Public key that exports and backups are encrypted to
+    pub payments: Payments,                  // Token payment settings and payment records
+    pub idempotency: IdempotencyCache,       // Results of recent update calls keyed by idempotency key
+    pub confidential: ConfidentialStore,     // Client-encrypted sensitive fields and the vetKD key epoch
+    pub governance: Governance,              // M-of-N approval of security-critical changes
+    pub dao: DaoGovernance,                  // Optional governance canister controlling operational parameters
+    pub costs: CostTracker,                  // Instruction counts measured for heavy operations
+    pub load: LoadShedder,                   // Per-round call counts used to shed expensive queries
+    pub snapshots: SnapshotStore,            // Index of backups written to stable memory
+    pub audit: AccessAudit,                  // Per-principal call counts, access alerts and suspensions
+    pub http: HttpCache,                     // Certified responses of the HTTP API
+    pub break_glass: BreakGlass,             // Emergency read-only access for a recovery principal
+    pub events: EventBus,                    // Canisters subscribed to inventory events
+    pub reconciliation: Reconciliation,      // Cross-checks of token payments against the ledger
+    pub bus: IntegrationBus,                 // Outbound integrations with their outbox and dead letters
+    pub usage: UsageAnalytics,               // API call volume, sizes, errors and instructions per caller
+    pub stocktakes: Stocktakes,              // Stock counting sessions and their variance reports
+    pub deprecations: Deprecations,          // Deprecated endpoints, fields and routes with their sunset times
+    pub costing: Costing,                    // Cost prices, received batches and the cost of each sale
+    pub velocity: SalesVelocity,             // Daily units sold per item over the last 30 days
+    pub price_history: PriceHistory,         // Every recorded price change
+    pub promotions: Promotions,              // Clearance bundles suggested by the aging scan
+    pub bundles: Bundles,                    // Items assembled from other items
+    pub fulfillment: Fulfillment,            // Warehouses, their stock and B2B orders shipped from them
+    pub item_history: ItemHistory,           // Typed timeline of everything that happened to each item
+    pub config: StoreConfig,                 // Currency, rounding, timezone and low-stock defaults
+    pub channels: Channels,                  // Price lists, tax treatment and stock buffers per sales channel
+    pub receipts: Receipts,                  // Numbered receipts of every transaction
+    pub back_in_stock: BackInStock,          // Customers waiting to hear an out-of-stock item is back
+    pub watchlists: Watchlists,              // Items customers want to hear about when their price drops
+    pub rate_limits: RateLimiter,            // Per-principal limits on update calls
+    pub waste: WasteLedger,                  // Stock written off as expired, damaged, stolen or spilled
+    pub loyalty: Loyalty,                    // Loyalty accounts, their points and purchases
+    pub journal: Journal,                    // Every change to the items, in order
+    pub markdowns: Markdowns,                // Near-expiry markdown rules and the markdowns in force
+    pub projections: Projections,            // Background jobs rebuilding and verifying derived state
+    pub hooks: HookRegistry,                 // Business rules run before and after mutations
+    pub chain: Chain,                        // Stores audited from here, head office and scheduled price changes
+    pub exchange: ExchangeRates,             // Secondary currencies and their cached exchange rates
+    pub returns: ReturnsLedger,              // Units of sales brought back and refunded
+    pub royalties: Royalties,                // Franchise royalty formula and locked monthly statements
+    pub duplicates: Duplicates,              // Duplicate items merged into the item kept
+    pub valuations: ValuationStore,          // Certified stock valuations for insurers and auditors
+    pub flows: AsyncFlows,                   // Async flows awaiting a call, compensated for if they never finish
+    pub assortments: Assortments,            // Seasonal assortments and their target stock
+    pub trials: Trials,                      // New items on trial and their keep or drop recommendations
+    pub summary: SummaryCounters,            // Running inventory totals kept in step with the catalog
+    pub cold_chain: ColdChain,               // Temperature readings of monitored locations and their breaches
+    pub shifts: Shifts,                      // Cashier shifts and the sales and refunds attributed to them
+}
+
+impl Default for SupermarketManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SupermarketManager {
     /// Initializes a new SupermarketManager with an empty inventory and log
     pub fn new() -> Self {
         SupermarketManager {
-            items: HashMap::new(),
-            logs: Vec::new(),
+            items: Catalog::init(),
+            logs: LogStore::init(),
+            sales: SalesLedger::init(),
+            reorder: ReorderPlanner::default(),
+            self_checkout: SelfCheckout::default(),
+            esl: EslFeed::default(),
+            access: AccessControl::default(),
+            webhooks: Webhooks::default(),
+            export_encryption: ExportEncryption::default(),
+            payments: Payments::default(),
+            idempotency: IdempotencyCache::default(),
+            confidential: ConfidentialStore::default(),
+            governance: Governance::default(),
+            dao: DaoGovernance::default(),
+            costs: CostTracker::default(),
+            load: LoadShedder::default(),
+            snapshots: SnapshotStore::default(),
+            audit: AccessAudit::default(),
+            http: HttpCache::default(),
+            break_glass: BreakGlass::default(),
+            events: EventBus::default(),
+            reconciliation: Reconciliation::default(),
+            bus: IntegrationBus::default(),
+            usage: UsageAnalytics::default(),
+            stocktakes: Stocktakes::default(),
+            deprecations: Deprecations::default(),
+            costing: Costing::default(),
+            velocity: SalesVelocity::default(),
+            price_history: PriceHistory::default(),
+            promotions: Promotions::default(),
+            bundles: Bundles::default(),
+            fulfillment: Fulfillment::default(),
+            item_history: ItemHistory::default(),
+            config: StoreConfig::default(),
+            channels: Channels::default(),
+            receipts: Receipts::init(),
+            back_in_stock: BackInStock::default(),
+            watchlists: Watchlists::default(),
+            rate_limits: RateLimiter::default(),
+            waste: WasteLedger::default(),
+            loyalty: Loyalty::default(),
+            journal: Journal::init(),
+            markdowns: Markdowns::default(),
+            projections: Projections::default(),
+            hooks: HookRegistry::default(),
+            chain: Chain::default(),
+            exchange: ExchangeRates::default(),
+            returns: ReturnsLedger::default(),
+            royalties: Royalties::default(),
+            duplicates: Duplicates::default(),
+            valuations: ValuationStore::default(),
+            flows: AsyncFlows::default(),
+            assortments: Assortments::default(),
+            trials: Trials::default(),
+            summary: SummaryCounters::default(),
+            cold_chain: ColdChain::default(),
+            shifts: Shifts::default(),
         }
     }
 
@@ -38,56 +348,228 @@ impl SupermarketManager {
 
     /// Adds a new item to the inventory
     /// - `item`: The item to add
-    pub fn add_item(&mut self, item: InventoryItem) {
-        self.items.insert(item.id, item.clone()); // Add the item to the inventory HashMap
+    pub fn add_item(&mut self, item: InventoryItem) -> Result<(), InventoryError> {
+        let old_effective_price = self.effective_price(item.id);
+        let mut price_change = None;
+        let old = self.items.get(&item.id);
+        let item = item.replacing(old.as_ref());
+        self.enforce_item_rules(&item, ic_cdk::api::time())?;
+        if let Some(old) = old.filter(|old| old.price != item.price) {
+            self.price_history.record(PriceChange {
+                item_id: item.id,
+                old_price: old.price,
+                new_price: item.price,
+                changed_by: ic_cdk::caller(),
+                changed_at: ic_cdk::api::time(),
+            });
+            price_change = Some(old.price);
+        }
+        let replaced = self.items.contains_key(&item.id);
+        self.journal_event(JournalEvent::ItemPut { item: item.clone() }); // Add the item to the inventory
+        self.esl.mark_changed(item.id); // Shelf labels need the new name and price
+        let event = InventoryEventPayload::ItemAdded { item_id: item.id, name: item.name.clone(), quantity: item.quantity, price: item.price };
+        self.publish_event(event, ic_cdk::api::time());
         let log = format!(
             "Item {} added at {}",
             item.id,
             SupermarketManager::get_current_time()
         );
         self.logs.push(log); // Log the addition with the current timestamp
+        let created = ItemHistoryEvent::Created { name: item.name.clone(), quantity: item.quantity, price: item.price, replaced };
+        self.record_history(item.id, created);
+        if let Some(old_price) = price_change {
+            self.record_history(item.id, ItemHistoryEvent::PriceChanged { old_price, new_price: item.price });
+        }
+        self.alert_price_drop(item.id, old_effective_price);
+        Ok(())
     }
 
     /// Retrieves an item from the inventory by ID
     /// - `id`: The ID of the item to retrieve
-    /// Returns an Option<&InventoryItem> which is Some if the item exists, or None if it doesn't
-    pub fn get_item(&self, id: u32) -> Option<&InventoryItem> {
-        self.items.get(&id) // Lookup the item by ID in the HashMap
+    ///
+    /// Returns an Option<InventoryItem> which is Some if the item exists, or None if it doesn't
+    pub fn get_item(&self, id: u32) -> Option<InventoryItem> {
+        self.items.get(&id) // Lookup the item by ID in the catalog
     }
 
     /// Updates the quantity of an existing item in the inventory
     /// - `id`: The ID of the item to update
     /// - `quantity`: The new quantity of the item
     pub fn update_item_quantity(&mut self, id: u32, quantity: u32) {
-        if let Some(item) = self.items.get_mut(&id) { // Check if the item exists
-            item.quantity = quantity; // Update the quantity
+        self.adjust_item_quantity(id, quantity, AdjustmentReason::Manual);
+    }
+
+    /// Sets the quantity of an existing item, recording why it was changed
+    /// - `id`: The ID of the item to update
+    /// - `quantity`: The new quantity of the item
+    /// - `reason`: Why the stock level was set by hand
+    pub fn adjust_item_quantity(&mut self, id: u32, quantity: u32, reason: AdjustmentReason) {
+        if let Some(item) = self.items.get(&id) { // Check if the item exists
+            let old_quantity = item.quantity;
+            self.journal_event(JournalEvent::QuantitySet { item_id: id, quantity, reason }); // Update the quantity
             let log = format!(
-                "Item {} quantity updated to {} at {}",
+                "Item {} quantity updated to {}{} at {}",
                 id,
                 quantity,
+                if reason == AdjustmentReason::Manual { String::new() } else { format!(" ({:?})", reason) },
                 SupermarketManager::get_current_time()
             );
             self.logs.push(log); // Log the update with the current timestamp
+            self.record_history(id, ItemHistoryEvent::QuantityChanged { old_quantity, new_quantity: quantity, reason });
+            self.costing.trim_to(id, quantity); // Stock written off by hand leaves the oldest batches first
+            self.check_stock_events(id, old_quantity, quantity, true);
+            let event = InventoryEventPayload::StockChanged { item_id: id, old_quantity, new_quantity: quantity };
+            self.publish_event(event, ic_cdk::api::time());
+            self.after_quantity_set(id, old_quantity, quantity, reason, ic_cdk::api::time());
+        }
+    }
+
+    /// Updates the quantity of an item only if it has not changed since the caller read it
+    /// - `id`: The ID of the item to update
+    /// - `expected_version`: The version the caller's read of the item returned
+    /// - `quantity`: The new quantity of the item
+    ///
+    /// Returns the item's new version.
+    pub fn update_quantity_cas(&mut self, id: u32, expected_version: u64, quantity: u32) -> Result<u64, InventoryError> {
+        let current = self.items.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", id),
+        })?.version;
+        if current != expected_version {
+            return Err(InventoryError::VersionConflict { item_id: id, expected: expected_version, current });
+        }
+        self.update_item_quantity(id, quantity);
+        Ok(current + 1)
+    }
+
+    /// Checks that an item's unit refers to a usable base item when it is a pack
+    /// - `item`: The item about to be added
+    pub fn validate_unit(&self, item: &InventoryItem) -> Result<(), InventoryError> {
+        let Unit::Pack { base_item_id, size } = item.unit else {
+            return Ok(());
+        };
+        if size == 0 {
+            return Err(InventoryError::InvalidInput { msg: "A pack must contain at least one unit".to_string() });
+        }
+        match self.items.get(&base_item_id) {
+            _ if base_item_id == item.id => Err(InventoryError::InvalidInput { msg: "A pack cannot contain itself".to_string() }),
+            None => Err(InventoryError::NotFound { msg: format!("Base item {} not found", base_item_id) }),
+            Some(base) if matches!(base.unit, Unit::Pack { .. }) => {
+                Err(InventoryError::InvalidInput { msg: "A pack's base item cannot itself be a pack".to_string() })
+            }
+            Some(_) => Ok(()),
         }
     }
 
+    /// Checks a new stock level against the cap for the item's unit
+    /// - `id`: The ID of the item being updated; unknown items are checked as counted goods
+    /// - `quantity`: The new quantity of the item
+    pub fn validate_stock_level(&self, id: u32, quantity: u32) -> Result<(), InventoryError> {
+        let unit = self.items.get(&id).map_or(Unit::Each, |item| item.unit);
+        validation::validate_stock_level(quantity, unit)
+    }
+
+    /// Resolves the item and number of units a sale actually takes from stock
+    /// - `item_id`: The item being sold
+    /// - `quantity`: The number of units of that item being sold
+    ///
+    /// Returns (stock item ID, stock units); for packs this is the base item and `quantity * size`.
+    pub fn stock_units(&self, item_id: u32, quantity: u32) -> Result<(u32, u32), InventoryError> {
+        let item = self.sellable_item(item_id)?;
+        match item.unit {
+            Unit::Pack { base_item_id, size } => {
+                self.sellable_item(base_item_id)?;
+                let units = quantity.checked_mul(size).ok_or_else(|| InventoryError::InvalidInput {
+                    msg: format!("{} packs of item {} is too many", quantity, item_id),
+                })?;
+                Ok((base_item_id, units))
+            }
+            _ => Ok((item_id, quantity)),
+        }
+    }
+
+    fn sellable_item(&self, item_id: u32) -> Result<InventoryItem, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        if item.archived {
+            return Err(InventoryError::ItemArchived { item_id });
+        }
+        Ok(item)
+    }
+
+    /// Archives an item so it is hidden from listings and can no longer be sold
+    /// - `id`: The ID of the item to archive
+    pub fn archive_item(&mut self, id: u32) -> Result<(), InventoryError> {
+        self.set_archived(id, true)
+    }
+
+    /// Restores an archived item so it is listed and can be sold again
+    /// - `id`: The ID of the item to restore
+    pub fn restore_item(&mut self, id: u32) -> Result<(), InventoryError> {
+        self.set_archived(id, false)
+    }
+
+    fn set_archived(&mut self, id: u32, archived: bool) -> Result<(), InventoryError> {
+        let item = self.items.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", id),
+        })?;
+        if item.archived == archived {
+            return Ok(()); // Nothing to change
+        }
+        self.journal_event(JournalEvent::ArchivedSet { item_id: id, archived });
+        let log = format!(
+            "Item {} {} at {}",
+            id,
+            if archived { "archived" } else { "restored" },
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log); // Log the change with the current timestamp
+        self.record_history(id, if archived { ItemHistoryEvent::Archived } else { ItemHistoryEvent::Unarchived });
+        Ok(())
+    }
+
+    /// Lists items that are not archived, ordered by ID
+    pub fn list_items(&self) -> Vec<InventoryItem> {
+        self.sorted_items(false)
+    }
+
+    /// Lists archived items, ordered by ID
+    pub fn list_archived_items(&self) -> Vec<InventoryItem> {
+        self.sorted_items(true)
+    }
+
+    fn sorted_items(&self, archived: bool) -> Vec<InventoryItem> {
+        self.items.values().filter(|item| item.archived == archived).collect() // The catalog is in ID order
+    }
+
     /// Removes an item from the inventory by ID
     /// - `id`: The ID of the item to remove
     pub fn remove_item(&mut self, id: u32) {
-        if self.items.remove(&id).is_some() { // Remove the item if it exists
+        if self.items.contains_key(&id) { // Remove the item if it exists
+            self.journal_event(JournalEvent::ItemRemoved { item_id: id });
+            self.costing.batches.remove(&id);
+            self.costing.cost_prices.remove(&id);
+            self.bundles.definitions.remove(&id);
+            self.channels.price_lists.values_mut().for_each(|prices| { prices.remove(&id); });
+            self.channels.buffers.retain(|(item_id, _), _| *item_id != id);
+            self.back_in_stock.subscriptions.retain(|_, s| s.item_id != id);
+            self.watchlists.watches.retain(|(item_id, _), _| *item_id != id);
+            self.markdowns.active.remove(&id);
+            self.esl.mark_changed(id); // Shelf labels need to blank out the removed item
             let log = format!(
                 "Item {} removed at {}",
                 id,
                 SupermarketManager::get_current_time()
             );
             self.logs.push(log); // Log the removal with the current timestamp
+            self.record_history(id, ItemHistoryEvent::Removed);
         }
     }
 
     /// Retrieves all logs of changes made to the inventory
     /// Returns a vector of strings, each representing a log entry
     pub fn get_logs(&self) -> Vec<String> {
-        self.logs.clone() // Return a copy of the logs
+        self.logs.entries().map(|entry| entry.message).collect() // Return a copy of the logs
     }
 }
 
@@ -99,55 +581,226 @@ thread_local! {
     static INVENTORY_MANAGER: RefCell<SupermarketManager> = RefCell::new(SupermarketManager::new());
 }
 
-// Adds a new item to the inventory.
-// This function is marked as `#[update]` because it modifies state.
-#[update]
-fn add_inventory_item(id: u32, name: String, quantity: u32, price: f64, expiration_date: u64) {
-    let item = InventoryItem {
-        id,
-        name,
-        quantity,
-        price,
-        expiration_date,
-    };
+// Sets the store up from its installation arguments and starts the background jobs. Without
+// arguments the installing principal becomes the owner and the default settings apply.
+// Installation fails if the arguments are invalid.
+#[init]
+fn init(args: Option<InitArgs>) {
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        match args {
+            Some(args) => {
+                if let Err(error) = inventory.apply_init_args(args, ic_cdk::caller()) {
+                    ic_cdk::trap(&format!("Invalid init arguments: {:?}", error));
+                }
+            }
+            None => {
+                inventory.access.roles.insert(ic_cdk::caller(), Role::Owner);
+            }
+        }
+    });
+    start_timers();
+}
 
+// Saves the heap state to stable memory, where `post_upgrade` finds it. Items, logs, sales and
+// the journal live in stable memory already.
+#[pre_upgrade]
+fn pre_upgrade() {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().save_heap_state());
+}
+
+// Restores the heap state saved by `pre_upgrade` and registers the timers again, which do not
+// survive an upgrade. When upgrading from a version that saved no heap state, the sales figures
+// and inventory totals are rebuilt from stable memory instead.
+#[post_upgrade]
+fn post_upgrade() {
     INVENTORY_MANAGER.with(|inventory| {
-        inventory.borrow_mut().add_item(item);
+        let mut inventory = inventory.borrow_mut();
+        if !inventory.load_heap_state() {
+            inventory.rebuild_velocity(ic_cdk::api::time());
+            inventory.rebuild_item_history();
+            inventory.rebuild_inventory_summary(ic_cdk::api::time());
+        }
     });
+    start_timers();
 }
 
-// Retrieves an item by ID.
+fn start_timers() {
+    reorder::start_reorder_timer();
+    webhooks::start_webhook_timers();
+    events::start_event_timer();
+    reconciliation::start_reconciliation_timer();
+    logs::start_log_retention_timer();
+    bus::start_bus_timer();
+    promotions::start_promotion_timer();
+    back_in_stock::start_back_in_stock_timer();
+    markdowns::start_markdown_timer();
+    chain::start_price_schedule_timer();
+    exchange::start_exchange_rate_timer();
+    royalties::start_royalty_timer();
+    flows::start_flow_sweep_timer();
+    trials::start_trial_timer();
+}
+
+// Adds a new item to the inventory, optionally under a category.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+#[allow(clippy::too_many_arguments)] // Candid arguments, kept positional so existing callers still work
+fn add_inventory_item(
+    id: u32,
+    name: String,
+    quantity: u32,
+    price: f64,
+    expiration_date: u64,
+    unit: Option<Unit>,
+    idempotency_key: Option<String>,
+    category: Option<String>,
+) -> Result<(), InventoryError> {
+    require_permission("add_inventory_item", Permission::AddItems)?;
+    metered("add_inventory_item", || {
+        run_once("add_inventory_item", idempotency_key, || {
+            let item = InventoryItem {
+                id,
+                name,
+                quantity,
+                price,
+                expiration_date,
+                archived: false,
+                unit: unit.unwrap_or(Unit::Each), // Items are counted individually unless stated otherwise
+                version: 0,                       // Assigned by add_item
+                location: None,                   // Kept from the item being replaced, if any
+                barcode: None,
+                category: category.map(|category| category.trim().to_string()),
+                storage_class: None,
+            };
+
+            validation::validate_item(&item, ic_cdk::api::time())?;
+            INVENTORY_MANAGER.with(|inventory| {
+                let mut inventory = inventory.borrow_mut();
+                inventory.validate_unit(&item)?;
+                inventory.add_item(item)
+            })
+        })
+    })
+}
+
+// Retrieves an item by ID in the v1 shape; `get_inventory_item_v2` returns every field.
 // This function is marked as `#[query]` because it only reads state and does not modify it.
 #[query]
-fn get_inventory_item(id: u32) -> Option<InventoryItem> {
-    INVENTORY_MANAGER.with(|inventory| {
-        inventory.borrow().get_item(id).cloned()
+fn get_inventory_item(id: u32) -> Option<InventoryItemV1> {
+    metered("get_inventory_item", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow().get_item(id).map(InventoryItemV1::from)
+        })
     })
 }
 
 // Updates the quantity of an existing item in the inventory.
 // This function is marked as `#[update]` because it modifies state.
-#[update]
-fn update_inventory_quantity(id: u32, quantity: u32) {
-    INVENTORY_MANAGER.with(|inventory| {
-        inventory.borrow_mut().update_item_quantity(id, quantity);
-    });
+#[update(guard = "rate_limit")]
+fn update_inventory_quantity(id: u32, quantity: u32, idempotency_key: Option<String>) -> Result<(), InventoryError> {
+    require_permission("update_inventory_quantity", Permission::AdjustStock)?;
+    metered("update_inventory_quantity", || {
+        run_once("update_inventory_quantity", idempotency_key, || {
+            INVENTORY_MANAGER.with(|inventory| {
+                let mut inventory = inventory.borrow_mut();
+                inventory.validate_stock_level(id, quantity)?;
+                inventory.update_item_quantity(id, quantity);
+                Ok(())
+            })
+        })
+    })
+}
+
+// Updates the quantity of an item only if its version still matches `expected_version`,
+// so two cashiers updating the same item cannot overwrite each other's changes.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn update_quantity_cas(id: u32, expected_version: u64, new_qty: u32, idempotency_key: Option<String>) -> Result<u64, InventoryError> {
+    require_permission("update_quantity_cas", Permission::AdjustStock)?;
+    metered("update_quantity_cas", || {
+        run_once("update_quantity_cas", idempotency_key, || {
+            INVENTORY_MANAGER.with(|inventory| {
+                let mut inventory = inventory.borrow_mut();
+                inventory.validate_stock_level(id, new_qty)?;
+                inventory.update_quantity_cas(id, expected_version, new_qty)
+            })
+        })
+    })
 }
 
 // Removes an item from the inventory by ID.
 // This function is marked as `#[update]` because it modifies state.
-#[update]
-fn remove_inventory_item(id: u32) {
-    INVENTORY_MANAGER.with(|inventory| {
-        inventory.borrow_mut().remove_item(id);
-    });
+#[update(guard = "rate_limit")]
+fn remove_inventory_item(id: u32, idempotency_key: Option<String>) {
+    if let Err(error) = require_permission("remove_inventory_item", Permission::RemoveItems) {
+        ic_cdk::trap(&format!("{:?}", error)); // Nothing is returned, so a refused caller gets a reject instead
+    }
+    metered("remove_inventory_item", || {
+        run_once("remove_inventory_item", idempotency_key, || {
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().remove_item(id);
+            });
+        })
+    })
+}
+
+// Archives an item, keeping it for history but hiding it from listings and sales.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn archive_item(id: u32) -> Result<(), InventoryError> {
+    require_permission("archive_item", Permission::RemoveItems)?;
+    metered("archive_item", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().archive_item(id)
+        })
+    })
+}
+
+// Restores an archived item.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn restore_item(id: u32) -> Result<(), InventoryError> {
+    require_permission("restore_item", Permission::RemoveItems)?;
+    metered("restore_item", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().restore_item(id)
+        })
+    })
+}
+
+// Retrieves every item that is not archived, in the v1 shape.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_inventory_items() -> Vec<InventoryItemV1> {
+    metered("list_inventory_items", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow().list_items().into_iter().map(InventoryItemV1::from).collect()
+        })
+    })
 }
 
-// Retrieves all logs of changes made to the inventory.
+// Retrieves every archived item, in the v1 shape.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_archived_items() -> Vec<InventoryItemV1> {
+    metered("list_archived_items", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow().list_archived_items().into_iter().map(InventoryItemV1::from).collect()
+        })
+    })
+}
+
+// Retrieves all logs of changes made to the inventory, or only the most recent ones while
+// the canister is shedding load.
 // This function is marked as `#[query]` because it only reads state.
 #[query]
 fn get_inventory_logs() -> Vec<String> {
-    INVENTORY_MANAGER.with(|inventory| {
-        inventory.borrow().get_logs()
+    metered("get_inventory_logs", || {
+        let degraded = load_degraded();
+        INVENTORY_MANAGER.with(|inventory| {
+            degrade_history(inventory.borrow().logs.entries().map(|entry| entry.message), degraded)
+        })
     })
 }
@@ -0,0 +1,161 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, INVENTORY_MANAGER};
+
+pub const DEGRADED_HISTORY_LIMIT: usize = 100; // Entries returned by history queries while degraded
+
+/// Thresholds at which expensive queries are shed
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LoadConfig {
+    pub max_updates_per_round: u32, // Update calls in a round above which expensive queries degrade
+    pub retry_after_secs: u32,      // Suggested wait returned with an Overloaded error
+    pub force_degraded: bool,       // Lets operators degrade expensive queries ahead of a known spike
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            max_updates_per_round: 500,
+            retry_after_secs: 5,
+            force_degraded: false,
+        }
+    }
+}
+
+/// Current load as seen by the canister
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LoadStatus {
+    pub degraded: bool,          // Whether expensive queries are currently shed
+    pub updates_this_round: u32, // Update calls in the current round
+    pub updates_last_round: u32, // Update calls in the previous round
+}
+
+/// Per-round update call counters used to shed expensive queries under load
+///
+/// A round is identified by its block time, which is the same for every message in it. Only
+/// update calls are counted, by the `rate_limit` guard every update runs: a query call discards
+/// its state changes, so it cannot add to the counts and a spike of queries alone never sheds.
+/// Queries read the counts the updates left behind.
+#[derive(Default)]
+pub struct LoadShedder {
+    pub config: LoadConfig,      // Shedding thresholds
+    pub round_time: u64,         // Block time of the round being counted
+    pub updates_this_round: u32, // Update calls counted in that round
+    pub updates_last_round: u32, // Update calls counted in the round before it
+}
+
+impl LoadShedder {
+    /// Counts an update call against the current round
+    /// - `now`: The current block time in nanoseconds since the Unix epoch
+    pub fn record_update(&mut self, now: u64) {
+        if now != self.round_time {
+            self.updates_last_round = if self.round_time == 0 { 0 } else { self.updates_this_round };
+            self.updates_this_round = 0;
+            self.round_time = now;
+        }
+        self.updates_this_round = self.updates_this_round.saturating_add(1);
+    }
+
+    /// Whether expensive queries should currently return their degraded variant
+    pub fn is_degraded(&self) -> bool {
+        self.config.force_degraded
+            || self.updates_this_round.max(self.updates_last_round) > self.config.max_updates_per_round
+    }
+
+    /// Checks that an expensive call may run in full, or returns a Retry-After-style error
+    pub fn admit_expensive(&self) -> Result<(), InventoryError> {
+        if self.is_degraded() {
+            return Err(InventoryError::Overloaded { retry_after_secs: self.config.retry_after_secs });
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> LoadStatus {
+        LoadStatus {
+            degraded: self.is_degraded(),
+            updates_this_round: self.updates_this_round,
+            updates_last_round: self.updates_last_round,
+        }
+    }
+}
+
+/// Counts the current update call; called by the `rate_limit` guard
+pub fn track_update() {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().load.record_update(ic_cdk::api::time()));
+}
+
+/// Whether expensive work should currently be degraded
+pub fn load_degraded() -> bool {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().load.is_degraded())
+}
+
+/// Sheds an expensive call with an Overloaded error if the canister is under load
+pub fn admit_expensive_call() -> Result<(), InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().load.admit_expensive())
+}
+
+/// Collects a history, keeping only its most recent entries while degraded
+///
+/// Entries are read newest first when degraded, so the rest of the history is never decoded.
+pub fn degrade_history<T>(entries: impl DoubleEndedIterator<Item = T>, degraded: bool) -> Vec<T> {
+    if !degraded {
+        return entries.collect();
+    }
+    let mut recent: Vec<T> = entries.rev().take(DEGRADED_HISTORY_LIMIT).collect();
+    recent.reverse();
+    recent
+}
+
+// Replaces the load-shedding thresholds.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_load_config(config: LoadConfig) -> Result<(), InventoryError> {
+    require_caller("set_load_config", Role::Manager)?;
+    if config.max_updates_per_round == 0 {
+        return Err(InventoryError::InvalidInput { msg: "max_updates_per_round must be positive".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().load.config = config;
+    });
+    Ok(())
+}
+
+// Retrieves the load-shedding thresholds.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_load_config() -> Result<LoadConfig, InventoryError> {
+    require_reader("get_load_config", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().load.config.clone()))
+}
+
+// Retrieves the current update call counts and whether expensive queries are degraded.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_load_status() -> Result<LoadStatus, InventoryError> {
+    require_reader("get_load_status", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().load.status()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_on_the_busier_of_this_round_and_the_last() {
+        let mut load = LoadShedder { config: LoadConfig { max_updates_per_round: 2, ..LoadConfig::default() }, ..LoadShedder::default() };
+        for _ in 0..3 {
+            load.record_update(10);
+        }
+        assert!(load.is_degraded());
+        load.record_update(20); // The busy round is still the last one
+        assert!(load.admit_expensive().is_err());
+        load.record_update(30);
+        assert!(!load.is_degraded());
+        assert_eq!((load.updates_this_round, load.updates_last_round), (1, 1));
+    }
+}
@@ -0,0 +1,191 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::cmp::Ordering;
+
+use crate::access::{require_caller, Role};
+use crate::journal::JournalEvent;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_SEARCH_RESULTS: usize = 20; // Most matches `find_item_location` returns for a name search
+
+/// Where an item sits on the shop floor
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct ShelfLocation {
+    pub aisle: String, // Aisle label, e.g. "4" or "Frozen"
+    pub shelf: u32,    // Shelf within the aisle, counted from the bottom
+    pub bin: u32,      // Position along the shelf
+}
+
+impl ShelfLocation {
+    /// Walking order: numbered aisles first in numeric order, then named aisles alphabetically
+    fn route_order(&self, other: &ShelfLocation) -> Ordering {
+        let aisle_key = |aisle: &str| (aisle.trim().parse::<u64>().unwrap_or(u64::MAX), aisle.to_lowercase());
+        aisle_key(&self.aisle)
+            .cmp(&aisle_key(&other.aisle))
+            .then(self.shelf.cmp(&other.shelf))
+            .then(self.bin.cmp(&other.bin))
+    }
+}
+
+/// An item and where to find it
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemLocation {
+    pub item_id: u32,
+    pub name: String,
+    pub quantity: u32,
+    pub location: Option<ShelfLocation>, // None if the item has not been given a location yet
+}
+
+/// Sorts locations into walking order, with unlocated items last
+fn sort_by_route(locations: &mut [ItemLocation]) {
+    locations.sort_by(|a, b| match (&a.location, &b.location) {
+        (Some(x), Some(y)) => x.route_order(y).then(a.item_id.cmp(&b.item_id)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.item_id.cmp(&b.item_id),
+    });
+}
+
+impl SupermarketManager {
+    fn item_location(&self, id: u32) -> Option<ItemLocation> {
+        self.items.get(&id).map(|item| ItemLocation {
+            item_id: item.id,
+            name: item.name.clone(),
+            quantity: item.quantity,
+            location: item.location.clone(),
+        })
+    }
+
+    /// Sets or clears where an item is shelved
+    pub fn set_item_location(&mut self, id: u32, location: Option<ShelfLocation>) -> Result<(), InventoryError> {
+        if !self.items.contains_key(&id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", id) });
+        }
+        let log = match &location {
+            Some(loc) => format!(
+                "Item {} moved to aisle {}, shelf {}, bin {} at {}",
+                id,
+                loc.aisle,
+                loc.shelf,
+                loc.bin,
+                SupermarketManager::get_current_time()
+            ),
+            None => format!("Item {} location cleared at {}", id, SupermarketManager::get_current_time()),
+        };
+        self.journal_event(JournalEvent::LocationSet { item_id: id, location });
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Sets or clears an item's barcode; a barcode may belong to only one item
+    pub fn set_item_barcode(&mut self, id: u32, barcode: Option<String>) -> Result<(), InventoryError> {
+        if let Some(code) = &barcode {
+            if let Some(other) = self.items.values().find(|item| item.id != id && item.barcode.as_ref() == Some(code)) {
+                return Err(InventoryError::Conflict { msg: format!("Barcode {} already belongs to item {}", code, other.id) });
+            }
+        }
+        if !self.items.contains_key(&id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", id) });
+        }
+        self.journal_event(JournalEvent::BarcodeSet { item_id: id, barcode });
+        self.esl.mark_changed(id); // Printed labels carry the barcode
+        let log = format!("Item {} barcode changed at {}", id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Items shelved in an aisle that are not archived, in shelf and bin order
+    pub fn items_by_aisle(&self, aisle: &str) -> Vec<ItemLocation> {
+        let mut found: Vec<ItemLocation> = self.items
+            .values()
+            .filter(|item| !item.archived && item.location.as_ref().is_some_and(|loc| loc.aisle.eq_ignore_ascii_case(aisle.trim())))
+            .filter_map(|item| self.item_location(item.id))
+            .collect();
+        sort_by_route(&mut found);
+        found
+    }
+
+    /// Finds items by exact barcode, or failing that by a case-insensitive match on part of the name
+    pub fn find_item_location(&self, name_or_barcode: &str) -> Vec<ItemLocation> {
+        let query = name_or_barcode.trim();
+        if let Some(item) = self.items.values().find(|item| item.barcode.as_deref() == Some(query)) {
+            return self.item_location(item.id).into_iter().collect();
+        }
+        let needle = query.to_lowercase();
+        let mut found: Vec<ItemLocation> = self.items
+            .values()
+            .filter(|item| !item.archived && !needle.is_empty() && item.name.to_lowercase().contains(&needle))
+            .filter_map(|item| self.item_location(item.id))
+            .collect();
+        sort_by_route(&mut found);
+        found.truncate(MAX_SEARCH_RESULTS);
+        found
+    }
+
+    /// Items of an order in the order a picker walks the store; unknown IDs are skipped
+    pub fn pick_route(&self, item_ids: &[u32]) -> Vec<ItemLocation> {
+        let mut route: Vec<ItemLocation> = item_ids.iter().filter_map(|&id| self.item_location(id)).collect();
+        sort_by_route(&mut route);
+        route.dedup_by_key(|line| line.item_id);
+        route
+    }
+}
+
+// Sets where an item is shelved, or clears it with None.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_item_location(id: u32, location: Option<ShelfLocation>) -> Result<(), InventoryError> {
+    require_caller("set_item_location", Role::Clerk)?;
+    if let Some(loc) = &location {
+        Validator::new().name("location.aisle", &loc.aisle).finish()?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_item_location(id, location)
+    })
+}
+
+// Sets the barcode printed on an item, or clears it with None.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_item_barcode(id: u32, barcode: Option<String>) -> Result<(), InventoryError> {
+    require_caller("set_item_barcode", Role::Clerk)?;
+    if let Some(code) = &barcode {
+        Validator::new()
+            .name("barcode", code)
+            .check(code.trim() == code, "barcode", "must not have leading or trailing spaces")
+            .finish()?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_item_barcode(id, barcode)
+    })
+}
+
+// Retrieves the items shelved in an aisle, by shelf and bin.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_items_by_aisle(aisle: String) -> Vec<ItemLocation> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().items_by_aisle(&aisle)
+    })
+}
+
+// Finds where an item is shelved by its barcode or part of its name.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn find_item_location(name_or_barcode: String) -> Vec<ItemLocation> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().find_item_location(&name_or_barcode)
+    })
+}
+
+// Sorts the items of an order by aisle, shelf and bin so it can be picked in one walk.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_pick_route(item_ids: Vec<u32>) -> Vec<ItemLocation> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().pick_route(&item_ids)
+    })
+}
@@ -0,0 +1,265 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::encryption::{protect_export, ExportPayload};
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::storage::{self, Memory};
+use crate::usage::metered;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const RETENTION_INTERVAL_SECS: u64 = 60 * 60; // How often the retention policy is applied
+const MAX_PAGE_ENTRIES: u32 = 1000;           // Most entries `get_logs` returns per call
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;   // JSON bytes per `export_logs` chunk, well under the response size limit
+
+/// A line of the change log
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LogEntry {
+    pub seq: u64,        // Position in the log; never reused, even once older entries are pruned
+    pub timestamp: u64,  // Time of the entry in nanoseconds since the Unix epoch
+    pub message: String, // What changed
+}
+
+/// How long log entries are kept; entries beyond either limit are pruned
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RetentionPolicy {
+    pub max_entries: Option<u64>,  // Most entries kept, newest first
+    pub max_age_secs: Option<u64>, // Oldest entry kept, by age
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_entries: Some(100_000),
+            max_age_secs: None,
+        }
+    }
+}
+
+/// A page of log entries
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>, // Entries from the requested offset, oldest first
+    pub first_seq: u64,         // Oldest entry still kept; anything before it was pruned
+    pub next_seq: u64,          // Sequence number the next entry will get
+}
+
+/// Entries from the requested sequence range to be written to off-chain storage
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LogRange {
+    pub start: u64,       // First sequence number wanted
+    pub end: Option<u64>, // Sequence number to stop before, or None for everything after `start`
+}
+
+/// One chunk of a log export
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LogChunk {
+    pub entries: ExportPayload, // JSON array of the chunk's entries, oldest first, encrypted to the export key when one is registered
+    pub next: Option<u64>,      // Start of the next chunk, or None once the range is complete
+    pub pruned_before: u64,     // Oldest entry still kept; if above the requested start, entries were lost
+}
+
+/// The change log with its retention settings
+///
+/// Entries live in stable memory keyed by sequence number, so years of logs do not have to fit
+/// on the heap. The retention policy is kept next to them so it outlasts upgrades.
+pub struct LogStore {
+    entries: StableBTreeMap<u64, LogEntry, Memory>, // Kept entries by sequence number
+    first_seq: StableCell<u64, Memory>,             // Sequence number of the first kept entry
+    retention: StableCell<RetentionPolicy, Memory>, // Limits applied by the retention timer
+}
+
+impl LogStore {
+    /// Opens the log in its stable memory regions, keeping any entries already there
+    pub fn init() -> Self {
+        LogStore {
+            entries: StableBTreeMap::init(storage::memory(storage::LOGS)),
+            first_seq: StableCell::init(storage::memory(storage::LOG_FIRST_SEQ), 0)
+                .expect("the log's region holds nothing but its first sequence number"),
+            retention: StableCell::init(storage::memory(storage::LOG_RETENTION), RetentionPolicy::default())
+                .expect("the log's region holds nothing but its retention policy"),
+        }
+    }
+
+    pub fn retention(&self) -> &RetentionPolicy {
+        self.retention.get()
+    }
+
+    pub fn set_retention(&mut self, policy: RetentionPolicy) {
+        self.retention.set(policy).expect("a retention policy fits its region");
+    }
+
+    /// Appends a message, stamped with the current time
+    pub fn push(&mut self, message: String) {
+        let seq = self.next_seq();
+        self.entries.insert(seq, LogEntry { seq, timestamp: ic_cdk::api::time(), message });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Sequence number of the first kept entry
+    pub fn first_seq(&self) -> u64 {
+        *self.first_seq.get()
+    }
+
+    /// Sequence number of the newest kept entry
+    pub fn last_seq(&self) -> Option<u64> {
+        self.entries.keys().next_back()
+    }
+
+    /// Sequence number the next entry will get
+    pub fn next_seq(&self) -> u64 {
+        self.last_seq().map_or(self.first_seq(), |seq| seq + 1)
+    }
+
+    /// Every kept entry, oldest first
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = LogEntry> + '_ {
+        self.entries.values()
+    }
+
+    /// Replaces every entry, e.g. when a snapshot is restored
+    pub fn replace(&mut self, entries: Vec<LogEntry>) {
+        self.set_first_seq(entries.first().map_or(0, |entry| entry.seq));
+        self.entries.clear_new();
+        for entry in entries {
+            self.entries.insert(entry.seq, entry);
+        }
+    }
+
+    fn set_first_seq(&mut self, seq: u64) {
+        self.first_seq.set(seq).expect("a sequence number fits its region");
+    }
+
+    /// Kept entries whose sequence numbers fall in `start..end`, oldest first
+    fn range(&self, start: u64, end: u64) -> impl Iterator<Item = LogEntry> + '_ {
+        self.entries.values_range(start..end.max(start))
+    }
+
+    /// Drops entries beyond the retention limits and returns how many were dropped
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn prune(&mut self, now: u64) -> usize {
+        let mut first_kept = self.first_seq();
+        let retention = self.retention().clone();
+        if let Some(max_entries) = retention.max_entries {
+            first_kept = first_kept.max(self.next_seq().saturating_sub(max_entries));
+        }
+        if let Some(max_age_secs) = retention.max_age_secs {
+            let cutoff = now.saturating_sub(max_age_secs.saturating_mul(NANOS_PER_SEC));
+            let first_recent = self.entries.iter().find(|(_, entry)| entry.timestamp >= cutoff).map_or(self.next_seq(), |(seq, _)| seq);
+            first_kept = first_kept.max(first_recent);
+        }
+        let mut dropped = 0;
+        while self.entries.first_key_value().is_some_and(|(seq, _)| seq < first_kept) {
+            self.entries.pop_first();
+            dropped += 1;
+        }
+        self.set_first_seq(first_kept);
+        dropped
+    }
+}
+
+impl SupermarketManager {
+    /// Applies the log retention policy, noting the pruning in the log itself
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn apply_log_retention(&mut self, now: u64) {
+        let pruned = self.logs.prune(now);
+        if pruned > 0 {
+            let log = format!("Pruned {} log entries at {}", pruned, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+    }
+}
+
+/// Registers the timer that enforces the log retention policy
+pub fn start_log_retention_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(RETENTION_INTERVAL_SECS), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().apply_log_retention(ic_cdk::api::time());
+        });
+    });
+}
+
+// Retrieves up to `limit` log entries starting at sequence number `offset`. Pass the previous
+// page's last `seq + 1` to read on; an offset older than `first_seq` starts at the oldest kept entry.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_logs(offset: u64, limit: u32) -> Result<LogPage, InventoryError> {
+    require_reader("get_logs", Role::Manager)?;
+    metered("get_logs", || {
+        let limit = limit.min(MAX_PAGE_ENTRIES) as u64;
+        INVENTORY_MANAGER.with(|inventory| {
+            let logs = &inventory.borrow().logs;
+            Ok(LogPage {
+                entries: logs.range(offset, offset.saturating_add(limit)).collect(),
+                first_seq: logs.first_seq(),
+                next_seq: logs.next_seq(),
+            })
+        })
+    })
+}
+
+// Exports a range of log entries for off-chain archival, one chunk per call. Call again with
+// `start` set to the returned `next` until it is None. The entries are encrypted to the export
+// key when one is registered.
+// This function is marked as `#[update]` because encryption needs fresh randomness.
+#[update(guard = "rate_limit")]
+async fn export_logs(range: LogRange) -> Result<LogChunk, InventoryError> {
+    require_reader("export_logs", Role::Manager)?;
+    admit_expensive_call()?;
+    let (entries, next, pruned_before) = INVENTORY_MANAGER.with(|inventory| {
+        let logs = &inventory.borrow().logs;
+        let end = range.end.unwrap_or(u64::MAX).min(logs.next_seq());
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        for entry in logs.range(range.start, end) {
+            let size = serde_json::to_vec(&entry).map_or(0, |json| json.len() + 1); // Plus the separating comma
+            if !entries.is_empty() && bytes + size > MAX_CHUNK_BYTES {
+                break;
+            }
+            bytes += size;
+            entries.push(entry);
+        }
+        let next = entries.last().map(|entry| entry.seq + 1).filter(|&next| next < end);
+        (entries, next, logs.first_seq())
+    });
+    let json = serde_json::to_vec(&entries).expect("log entries always serialize");
+    Ok(LogChunk { entries: protect_export(json).await?, next, pruned_before })
+}
+
+// Replaces the log retention policy. It is applied by the hourly retention job.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_log_retention(policy: RetentionPolicy) -> Result<(), InventoryError> {
+    require_caller("set_log_retention", Role::Owner)?;
+    if policy.max_entries == Some(0) {
+        return Err(InventoryError::InvalidInput { msg: "max_entries must be positive".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.logs.set_retention(policy);
+        let log = format!("Log retention policy changed at {}", SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+    });
+    Ok(())
+}
+
+// Retrieves the log retention policy.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_log_retention() -> RetentionPolicy {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().logs.retention().clone()
+    })
+}
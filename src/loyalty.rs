@@ -0,0 +1,262 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::receipts::Receipt;
+use crate::sales::Sale;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// How a loyalty customer identifies themselves at the till
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CustomerKey {
+    Principal(Principal), // A customer signed in with their own identity
+    Card(String),         // The number on a physical loyalty card
+}
+
+/// A loyalty account
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Customer {
+    pub id: u64,
+    pub principal_or_card: CustomerKey,
+    pub points: u64,           // Points available to redeem
+    pub pending_discount: f64, // Redeemed value taken off the customer's next checkout
+    pub lifetime_points: u64,  // Every point ever earned
+    pub registered_at: u64,    // Time of registration in nanoseconds since the Unix epoch
+}
+
+/// How points are earned and what they are worth
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LoyaltyConfig {
+    pub points_per_currency_unit: f64, // Points earned per unit of currency spent, e.g. 1.0 for a point per dollar
+    pub point_value: f64,              // Discount one redeemed point is worth, e.g. 0.01
+}
+
+impl Default for LoyaltyConfig {
+    fn default() -> Self {
+        LoyaltyConfig {
+            points_per_currency_unit: 1.0,
+            point_value: 0.01,
+        }
+    }
+}
+
+impl LoyaltyConfig {
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        Validator::new()
+            .check(self.points_per_currency_unit.is_finite() && self.points_per_currency_unit >= 0.0, "points_per_currency_unit", "must not be negative")
+            .price("point_value", self.point_value)
+            .finish()
+    }
+}
+
+/// Loyalty accounts and the receipts of their purchases
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Loyalty {
+    pub config: LoyaltyConfig,
+    pub customers: BTreeMap<u64, Customer>, // Accounts keyed by ID
+    pub by_key: HashMap<CustomerKey, u64>,  // Account ID by principal or card number
+    pub purchases: HashMap<u64, Vec<u64>>,  // Receipt numbers by customer ID, oldest first
+    pub next_customer_id: u64,
+}
+
+impl SupermarketManager {
+    /// Replaces how points are earned and what they are worth
+    pub fn set_loyalty_config(&mut self, config: LoyaltyConfig) {
+        self.loyalty.config = config;
+        let log = format!("Loyalty settings changed at {}", SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Opens a loyalty account for a principal or card that has none yet
+    pub fn register_customer(&mut self, key: CustomerKey, now: u64) -> Result<Customer, InventoryError> {
+        if let Some(id) = self.loyalty.by_key.get(&key) {
+            return Err(InventoryError::Conflict { msg: format!("Already registered as customer {}", id) });
+        }
+        let id = self.loyalty.next_customer_id;
+        self.loyalty.next_customer_id += 1;
+        let customer = Customer {
+            id,
+            principal_or_card: key.clone(),
+            points: 0,
+            pending_discount: 0.0,
+            lifetime_points: 0,
+            registered_at: now,
+        };
+        self.loyalty.by_key.insert(key, id);
+        self.loyalty.customers.insert(id, customer.clone());
+        let log = format!("Customer {} registered at {}", id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(customer)
+    }
+
+    /// Turns points into a discount on the customer's next checkout
+    pub fn redeem_points(&mut self, customer_id: u64, points: u64) -> Result<Customer, InventoryError> {
+        let point_value = self.loyalty.config.point_value;
+        let customer = self.loyalty.customers.get_mut(&customer_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Customer {} not found", customer_id),
+        })?;
+        if customer.points < points {
+            return Err(InventoryError::InvalidInput {
+                msg: format!("Customer {} has {} points, fewer than {}", customer_id, customer.points, points),
+            });
+        }
+        customer.points -= points;
+        customer.pending_discount = self.config.round_amount(customer.pending_discount + points as f64 * point_value);
+        let customer = customer.clone();
+        let log = format!("Customer {} redeemed {} points at {}", customer_id, points, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(customer)
+    }
+
+    /// Splits a customer's pending discount over the lines of a basket in proportion to their totals
+    ///
+    /// Returns the discount of each line; all zero without a customer or a pending discount.
+    pub fn loyalty_discounts(&self, customer_id: Option<u64>, line_totals: &[f64]) -> Result<Vec<f64>, InventoryError> {
+        let Some(customer_id) = customer_id else { return Ok(vec![0.0; line_totals.len()]) };
+        let customer = self.loyalty.customers.get(&customer_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Customer {} not found", customer_id),
+        })?;
+        let basket: f64 = line_totals.iter().sum();
+        let mut left = customer.pending_discount.min(basket);
+        Ok(line_totals
+            .iter()
+            .enumerate()
+            .map(|(i, &total)| {
+                let share = if i + 1 == line_totals.len() {
+                    left // The last line takes what rounding left over
+                } else {
+                    self.config.round_amount(customer.pending_discount.min(basket) * total / basket).min(left)
+                };
+                left -= share;
+                share
+            })
+            .collect())
+    }
+
+    /// Books a customer's checkout: uses up the discount it received, earns points on the
+    /// amount paid and adds the receipt to their purchases
+    pub fn settle_loyalty(&mut self, customer_id: u64, sales: &[Sale], receipt_number: u64, discount: f64) {
+        let paid: f64 = sales.iter().map(|sale| sale.total).sum();
+        let earned = (paid * self.loyalty.config.points_per_currency_unit).floor().max(0.0) as u64;
+        if let Some(customer) = self.loyalty.customers.get_mut(&customer_id) {
+            customer.pending_discount = self.config.round_amount((customer.pending_discount - discount).max(0.0));
+            customer.points += earned;
+            customer.lifetime_points += earned;
+        }
+        self.loyalty.purchases.entry(customer_id).or_default().push(receipt_number);
+    }
+}
+
+/// Checks that the caller is the customer or at least a clerk
+fn require_customer_or(endpoint: &str, customer_id: u64, minimum: Role, reader: bool) -> Result<(), InventoryError> {
+    let caller = CustomerKey::Principal(ic_cdk::caller());
+    let own = INVENTORY_MANAGER.with(|inventory| inventory.borrow().loyalty.by_key.get(&caller) == Some(&customer_id));
+    match (own, reader) {
+        (true, _) => Ok(()),
+        (false, true) => require_reader(endpoint, minimum),
+        (false, false) => require_caller(endpoint, minimum),
+    }
+}
+
+// Opens a loyalty account. With a `card` number a clerk registers a card customer; without
+// one the caller registers themselves.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn register_customer(card: Option<String>) -> Result<Customer, InventoryError> {
+    let key = match card {
+        Some(card) => {
+            require_caller("register_customer", Role::Clerk)?;
+            Validator::new().name("card", &card).finish()?;
+            CustomerKey::Card(card.trim().to_string())
+        }
+        None => {
+            let caller = ic_cdk::caller();
+            if caller == Principal::anonymous() {
+                return Err(InventoryError::Unauthorized { msg: "Sign in to join, or ask staff for a card".to_string() });
+            }
+            CustomerKey::Principal(caller)
+        }
+    };
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().register_customer(key, ic_cdk::api::time()))
+}
+
+// Redeems points for a discount on the customer's next checkout. Customers redeem their own;
+// clerks may redeem for anyone at the till.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn redeem_points(customer_id: u64, points: u64) -> Result<Customer, InventoryError> {
+    require_customer_or("redeem_points", customer_id, Role::Clerk, false)?;
+    Validator::new().check(points > 0, "points", "must be positive").finish()?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().redeem_points(customer_id, points))
+}
+
+// Retrieves a customer's points balance and pending discount.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_customer(customer_id: u64) -> Result<Customer, InventoryError> {
+    require_customer_or("get_customer", customer_id, Role::Clerk, true)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().loyalty.customers.get(&customer_id).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Customer {} not found", customer_id),
+        })
+    })
+}
+
+// Looks a customer up by their principal or card number, e.g. when a card is scanned.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn find_customer(key: CustomerKey) -> Result<Customer, InventoryError> {
+    if key != CustomerKey::Principal(ic_cdk::caller()) {
+        require_reader("find_customer", Role::Clerk)?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        inventory.loyalty.by_key
+            .get(&key)
+            .and_then(|id| inventory.loyalty.customers.get(id))
+            .cloned()
+            .ok_or_else(|| InventoryError::NotFound { msg: "No customer is registered with that key".to_string() })
+    })
+}
+
+// Retrieves the receipts of a customer's purchases, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_customer_purchases(customer_id: u64) -> Result<Vec<Receipt>, InventoryError> {
+    require_customer_or("get_customer_purchases", customer_id, Role::Clerk, true)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        if !inventory.loyalty.customers.contains_key(&customer_id) {
+            return Err(InventoryError::NotFound { msg: format!("Customer {} not found", customer_id) });
+        }
+        let numbers = inventory.loyalty.purchases.get(&customer_id).map(Vec::as_slice).unwrap_or_default();
+        Ok(numbers.iter().filter_map(|&number| inventory.receipts.get(number)).collect())
+    })
+}
+
+// Replaces how points are earned and what they are worth. Once a governance canister is set,
+// this takes an executed `SetLoyaltyConfig` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_loyalty_config(config: LoyaltyConfig) -> Result<(), InventoryError> {
+    require_caller("set_loyalty_config", Role::Manager)?;
+    config.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_loyalty_config(config);
+        Ok(())
+    })
+}
+
+// Retrieves how points are earned and what they are worth.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_loyalty_config() -> LoyaltyConfig {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().loyalty.config.clone())
+}
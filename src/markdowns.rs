@@ -0,0 +1,275 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const MAX_RULES: usize = 100;
+
+/// What happens to a markdown that has not cleared the stock
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum MarkdownFollowUp {
+    Revert,                                         // Keep the markdown until the stock expires or sells out, then restore the price
+    Escalate { after_days: u32, discount_pct: u8 }, // Deepen it to `discount_pct` once it has run `after_days` with stock left
+}
+
+/// A manager's rule for marking down stock close to its expiration date
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct MarkdownRule {
+    pub id: u64,
+    pub category: Option<String>, // Category the rule applies to; None for every category
+    pub days_before_expiry: u32,  // The markdown starts once the item expires within this many days
+    pub discount_pct: u8,         // Taken off the item's own price
+    pub follow_up: MarkdownFollowUp,
+}
+
+/// A markdown currently applied to an item's price
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ActiveMarkdown {
+    pub item_id: u32,
+    pub rule_id: u64,
+    pub original_price: f64, // Price restored when the markdown ends
+    pub price: f64,          // Marked-down price now in force
+    pub discount_pct: u8,
+    pub escalated: bool,     // Whether the rule's escalation has been applied
+    pub applied_at: u64,     // Time the markdown started in nanoseconds since the Unix epoch
+    pub expires_at: u64,     // The item's expiration date, as a Unix timestamp in seconds
+}
+
+/// Markdown rules and the markdowns they have applied
+///
+/// Items carry one expiration date for all their stock, so a markdown covers everything on hand.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Markdowns {
+    pub rules: BTreeMap<u64, MarkdownRule>,    // Rules keyed by ID
+    pub active: BTreeMap<u32, ActiveMarkdown>, // Markdowns in force keyed by item ID
+    pub next_rule_id: u64,
+}
+
+fn marked_down(price: f64, discount_pct: u8) -> f64 {
+    price * (100 - discount_pct as u32) as f64 / 100.0
+}
+
+/// Checks the settings of a new markdown rule
+pub fn validate_markdown_rule(category: Option<&str>, days_before_expiry: u32, discount_pct: u8, follow_up: &MarkdownFollowUp) -> Result<(), InventoryError> {
+    let mut validator = Validator::new();
+    validator
+        .check(days_before_expiry > 0, "days_before_expiry", "must be positive")
+        .check((1..100).contains(&discount_pct), "discount_pct", "must be between 1 and 99");
+    if let Some(category) = category {
+        validator.name("category", category);
+    }
+    if let MarkdownFollowUp::Escalate { after_days, discount_pct: escalated_pct } = *follow_up {
+        validator
+            .check(after_days > 0, "follow_up.after_days", "must be positive")
+            .check(escalated_pct > discount_pct && escalated_pct < 100, "follow_up.discount_pct", "must be above discount_pct and below 100");
+    }
+    validator.finish()
+}
+
+impl SupermarketManager {
+    /// Adds a markdown rule and returns its ID
+    pub fn add_markdown_rule(&mut self, category: Option<String>, days_before_expiry: u32, discount_pct: u8, follow_up: MarkdownFollowUp) -> Result<u64, InventoryError> {
+        if self.markdowns.rules.len() >= MAX_RULES {
+            return Err(InventoryError::Conflict { msg: format!("At most {} markdown rules can be defined", MAX_RULES) });
+        }
+        let id = self.markdowns.next_rule_id;
+        self.markdowns.next_rule_id += 1;
+        let category = category.map(|category| category.trim().to_string());
+        self.markdowns.rules.insert(id, MarkdownRule { id, category, days_before_expiry, discount_pct, follow_up });
+        let log = format!("Markdown rule {} added at {}", id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(id)
+    }
+
+    /// Removes a markdown rule; markdowns it already applied run their course
+    pub fn remove_markdown_rule(&mut self, id: u64) -> Result<(), InventoryError> {
+        self.markdowns.rules.remove(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Markdown rule {} not found", id),
+        })?;
+        let log = format!("Markdown rule {} removed at {}", id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Sets a price on the canister's own behalf, as the markdown job does
+    fn set_markdown_price(&mut self, item_id: u32, price: f64, now: u64) {
+        let price = self.config.round_amount(price);
+        if let Err(error) = self.set_prices(&[(item_id, price)], ic_cdk::id(), now) {
+            let log = format!("Markdown of item {} failed: {:?} at {}", item_id, error, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+    }
+
+    /// Ends markdowns whose stock expired or sold out, escalates those that are due and starts
+    /// new ones for items entering a rule's window
+    pub fn run_markdown_job(&mut self, now: u64) {
+        let now_secs = now / NANOS_PER_SEC;
+        let ended: Vec<u32> = self.markdowns.active
+            .values()
+            .filter(|markdown| {
+                self.items.get(&markdown.item_id).is_none_or(|item| {
+                    item.quantity == 0 || item.archived || now_secs >= markdown.expires_at || item.expiration_date != markdown.expires_at
+                })
+            })
+            .map(|markdown| markdown.item_id)
+            .collect();
+        for item_id in ended {
+            let markdown = self.markdowns.active.remove(&item_id).expect("collected from the active markdowns");
+            let Some(item) = self.items.get(&item_id) else { continue };
+            if item.price != markdown.price {
+                continue; // Repriced by staff in the meantime; their price stands
+            }
+            self.set_markdown_price(item_id, markdown.original_price, now);
+            let log = format!("Markdown of item {} reverted at {}", item_id, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+
+        let mut escalations = Vec::new();
+        for markdown in self.markdowns.active.values().filter(|markdown| !markdown.escalated) {
+            let Some(rule) = self.markdowns.rules.get(&markdown.rule_id) else { continue };
+            if let MarkdownFollowUp::Escalate { after_days, discount_pct } = rule.follow_up {
+                let due = markdown.applied_at.saturating_add(after_days as u64 * SECS_PER_DAY * NANOS_PER_SEC);
+                if now >= due && discount_pct > markdown.discount_pct {
+                    escalations.push((markdown.item_id, discount_pct));
+                }
+            }
+        }
+        for (item_id, discount_pct) in escalations {
+            let markdown = &self.markdowns.active[&item_id];
+            if self.items.get(&item_id).is_some_and(|item| item.price != markdown.price) {
+                self.markdowns.active.remove(&item_id); // Repriced by staff; stop managing it
+                continue;
+            }
+            let price = self.config.round_amount(marked_down(markdown.original_price, discount_pct));
+            self.set_markdown_price(item_id, price, now);
+            let markdown = self.markdowns.active.get_mut(&item_id).expect("checked above");
+            markdown.price = price;
+            markdown.discount_pct = discount_pct;
+            markdown.escalated = true;
+            let log = format!("Markdown of item {} escalated to {}% at {}", item_id, discount_pct, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+
+        let mut starts = Vec::new();
+        for item in self.items.values() {
+            if item.archived
+                || item.quantity == 0
+                || matches!(item.unit, Unit::Pack { .. })
+                || self.bundles.definitions.contains_key(&item.id)
+                || self.markdowns.active.contains_key(&item.id)
+                || now_secs >= item.expiration_date
+            {
+                continue;
+            }
+            let secs_left = item.expiration_date - now_secs;
+            let best = self.markdowns.rules
+                .values()
+                .filter(|rule| rule.category.is_none() || rule.category == item.category)
+                .filter(|rule| secs_left <= rule.days_before_expiry as u64 * SECS_PER_DAY)
+                .max_by_key(|rule| (rule.discount_pct, std::cmp::Reverse(rule.id)));
+            if let Some(rule) = best {
+                starts.push((item.id, item.price, item.expiration_date, rule.id, rule.discount_pct));
+            }
+        }
+        for (item_id, original_price, expires_at, rule_id, discount_pct) in starts {
+            let price = self.config.round_amount(marked_down(original_price, discount_pct));
+            self.set_markdown_price(item_id, price, now);
+            self.markdowns.active.insert(item_id, ActiveMarkdown {
+                item_id,
+                rule_id,
+                original_price,
+                price,
+                discount_pct,
+                escalated: false,
+                applied_at: now,
+                expires_at,
+            });
+            let log = format!(
+                "Item {} marked down {}% by rule {} at {}",
+                item_id,
+                discount_pct,
+                rule_id,
+                SupermarketManager::get_current_time()
+            );
+            self.logs.push(log);
+        }
+    }
+}
+
+/// Evaluates markdown rules once a day
+pub fn start_markdown_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(SECS_PER_DAY), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().run_markdown_job(ic_cdk::api::time());
+        });
+    });
+}
+
+// Adds a markdown rule, e.g. 30% off once an item expires within 2 days, for one category or
+// all of them. Returns the rule ID. Once a governance canister is set, this takes an executed
+// `AddMarkdownRule` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn add_markdown_rule(
+    category: Option<String>,
+    days_before_expiry: u32,
+    discount_pct: u8,
+    follow_up: MarkdownFollowUp,
+) -> Result<u64, InventoryError> {
+    require_caller("add_markdown_rule", Role::Manager)?;
+    validate_markdown_rule(category.as_deref(), days_before_expiry, discount_pct, &follow_up)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.add_markdown_rule(category, days_before_expiry, discount_pct, follow_up)
+    })
+}
+
+// Removes a markdown rule. Markdowns it already applied run their course. Once a governance
+// canister is set, this takes an executed `RemoveMarkdownRule` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_markdown_rule(id: u64) -> Result<(), InventoryError> {
+    require_caller("remove_markdown_rule", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.remove_markdown_rule(id)
+    })
+}
+
+// Retrieves every markdown rule.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_markdown_rules() -> Result<Vec<MarkdownRule>, InventoryError> {
+    require_reader("get_markdown_rules", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().markdowns.rules.values().cloned().collect()))
+}
+
+// Evaluates the markdown rules now instead of waiting for the daily run.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn run_markdowns() -> Result<Vec<ActiveMarkdown>, InventoryError> {
+    require_caller("run_markdowns", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.run_markdown_job(ic_cdk::api::time());
+        Ok(inventory.markdowns.active.values().cloned().collect())
+    })
+}
+
+// Retrieves the markdowns in force, ordered by item ID.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_active_markdowns() -> Vec<ActiveMarkdown> {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().markdowns.active.values().cloned().collect())
+}
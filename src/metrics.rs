@@ -0,0 +1,142 @@
+use ic_cdk::api::stable::stable64_size;
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::fmt::Write;
+
+use crate::channels::SalesChannel;
+use crate::cost::HeavyOperation;
+use crate::{SupermarketManager, INVENTORY_MANAGER};
+
+const SALES_HISTORY_DAYS: u64 = 7; // Days of sales counts included in the metrics
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Sales recorded on one day
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct DailySales {
+    pub day: u64,                                  // Days since the Unix epoch in the store's timezone
+    pub sales: u64,                                // Sale lines recorded that day
+    pub revenue: f64,                              // Total of those sale lines after tax
+    pub by_channel: Vec<(SalesChannel, u64, f64)>, // (channel, sale lines, revenue) for each channel
+}
+
+/// Canister health and domain metrics for monitoring
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Metrics {
+    pub item_count: u64,                               // Items that are not archived
+    pub archived_item_count: u64,                      // Items kept only for history
+    pub log_entries: u64,                              // Entries in the change log
+    pub sales_count: u64,                              // Entries in the sales ledger
+    pub heap_memory_bytes: u64,                        // Size of the Wasm heap
+    pub stable_memory_bytes: u64,                      // Size of stable memory
+    pub cycles_balance: u128,                          // Cycles left on the canister
+    pub last_instructions: Vec<(HeavyOperation, u64)>, // Instructions used by the latest run of each heavy operation
+    pub sales_per_day: Vec<DailySales>,                // Sales of the last few days, oldest first
+    pub last_reorder_run: Option<u64>,                 // Time the reorder timer last ran
+    pub last_expired_scan: Option<u64>,                // Time the expired-item timer last ran
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+impl SupermarketManager {
+    /// Collects the current metrics
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn metrics(&self, now: u64) -> Metrics {
+        let archived = self.items.values().filter(|item| item.archived).count() as u64;
+        let today = self.config.local_day(now);
+        let first_day = today.saturating_sub(SALES_HISTORY_DAYS - 1);
+        let mut sales_per_day: Vec<DailySales> = (first_day..=today)
+            .map(|day| DailySales {
+                day,
+                sales: 0,
+                revenue: 0.0,
+                by_channel: SalesChannel::ALL.into_iter().map(|channel| (channel, 0, 0.0)).collect(),
+            })
+            .collect();
+        for sale in self.reportable_sales(false).rev() {
+            let day = self.config.local_day(sale.timestamp);
+            if day < first_day {
+                break; // The ledger is in time order
+            }
+            if let Some(bucket) = sales_per_day.get_mut((day - first_day) as usize) {
+                bucket.sales += 1;
+                bucket.revenue += sale.net_total();
+                if let Some(channel) = bucket.by_channel.iter_mut().find(|(channel, _, _)| *channel == sale.channel) {
+                    channel.1 += 1;
+                    channel.2 += sale.net_total();
+                }
+            }
+        }
+        let mut last_instructions: Vec<(HeavyOperation, u64)> = self.costs.stats
+            .iter()
+            .map(|(op, stats)| (*op, stats.last_instructions))
+            .collect();
+        last_instructions.sort_by_key(|(op, _)| format!("{:?}", op));
+        Metrics {
+            item_count: self.items.len() as u64 - archived,
+            archived_item_count: archived,
+            log_entries: self.logs.len() as u64,
+            sales_count: self.reportable_sales(false).count() as u64,
+            heap_memory_bytes: heap_memory_bytes(),
+            stable_memory_bytes: stable64_size() * WASM_PAGE_SIZE,
+            cycles_balance: ic_cdk::api::canister_balance128(),
+            last_instructions,
+            sales_per_day,
+            last_reorder_run: self.reorder.last_run,
+            last_expired_scan: self.webhooks.last_expired_scan,
+        }
+    }
+}
+
+/// Renders metrics in the Prometheus text exposition format
+pub fn prometheus_text(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+    };
+    gauge("inventory_items", "Items that are not archived", metrics.item_count.to_string());
+    gauge("inventory_archived_items", "Archived items", metrics.archived_item_count.to_string());
+    gauge("inventory_log_entries", "Entries in the change log", metrics.log_entries.to_string());
+    gauge("inventory_sales", "Entries in the sales ledger", metrics.sales_count.to_string());
+    gauge("canister_heap_memory_bytes", "Size of the Wasm heap", metrics.heap_memory_bytes.to_string());
+    gauge("canister_stable_memory_bytes", "Size of stable memory", metrics.stable_memory_bytes.to_string());
+    gauge("canister_cycles_balance", "Cycles left on the canister", metrics.cycles_balance.to_string());
+    let timestamp = |time: Option<u64>| time.map_or("0".to_string(), |t| (t / 1_000_000_000).to_string());
+    gauge("inventory_last_reorder_run_seconds", "Unix time the reorder job last ran", timestamp(metrics.last_reorder_run));
+    gauge("inventory_last_expired_scan_seconds", "Unix time the expired-item scan last ran", timestamp(metrics.last_expired_scan));
+
+    let _ = writeln!(out, "# HELP inventory_last_instructions Instructions used by the latest run of a heavy operation");
+    let _ = writeln!(out, "# TYPE inventory_last_instructions gauge");
+    for (op, instructions) in &metrics.last_instructions {
+        let _ = writeln!(out, "inventory_last_instructions{{operation=\"{:?}\"}} {}", op, instructions);
+    }
+    let _ = writeln!(out, "# HELP inventory_daily_sales Sale lines recorded per day");
+    let _ = writeln!(out, "# TYPE inventory_daily_sales gauge");
+    for day in &metrics.sales_per_day {
+        let _ = writeln!(out, "inventory_daily_sales{{day=\"{}\"}} {}", day.day, day.sales);
+    }
+    let _ = writeln!(out, "# HELP inventory_daily_channel_revenue Revenue after tax per day and sales channel");
+    let _ = writeln!(out, "# TYPE inventory_daily_channel_revenue gauge");
+    for day in &metrics.sales_per_day {
+        for (channel, _, revenue) in &day.by_channel {
+            let _ = writeln!(out, "inventory_daily_channel_revenue{{day=\"{}\",channel=\"{:?}\"}} {}", day.day, channel, revenue);
+        }
+    }
+    out
+}
+
+// Retrieves canister health and domain metrics.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_metrics() -> Metrics {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().metrics(ic_cdk::api::time()))
+}
@@ -0,0 +1,285 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Nat, Principal};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::flows::{mutate, Flow, FlowKind};
+use crate::idempotency::run_once_async;
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::shifts::Tender;
+use crate::validation::validate_lines;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// An ICRC-1 account: an owner principal and an optional 32-byte subaccount
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+/// Arguments of the ICRC-2 `icrc2_transfer_from` ledger method
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// Errors returned by the ICRC-2 `icrc2_transfer_from` ledger method
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Which ledger checkout payments are taken on and how prices convert to tokens
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PaymentConfig {
+    pub ledger_canister_id: Principal, // ICRC-1/ICRC-2 ledger the payments are made on
+    pub units_per_price_unit: u64,     // Smallest token units per 1.0 of item price (e.g. 100_000_000 for e8s)
+}
+
+/// Outcome of a token payment
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum PaymentStatus {
+    Completed,                     // Tokens were transferred and the sale was recorded
+    Failed { reason: String },     // The transfer did not happen, so no stock was sold
+    RefundDue { reason: String },  // Tokens were transferred but the sale could not be recorded
+    Unresolved { reason: String }, // The checkout never finished; reconciliation shows whether tokens moved
+}
+
+/// A checkout paid for in tokens
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Payment {
+    pub id: u64,                       // Sequential ID of the payment
+    pub payer: Account,                // Account the tokens were taken from
+    pub ledger_canister_id: Principal, // Ledger the transfer was made on
+    pub amount: Nat,                   // Amount charged in the smallest token unit
+    pub block_index: Option<Nat>,      // Ledger block of the transfer, if it succeeded
+    pub status: PaymentStatus,         // Outcome of the payment
+    pub sale_ids: Vec<u64>,            // Sales recorded for the checkout
+    pub timestamp: u64,                // Time of the checkout in nanoseconds since the Unix epoch
+}
+
+/// Token payment settings and the record of every payment attempt
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Payments {
+    pub config: Option<PaymentConfig>, // Payment settings, unset until a ledger is configured
+    pub records: Vec<Payment>,         // Every payment attempt in order
+}
+
+impl SupermarketManager {
+    /// Price of a basket in the smallest token unit, checking that every line is in stock
+    /// - `lines`: Pairs of (item ID, quantity) being bought
+    /// - `channel`: Channel whose prices and tax apply
+    /// - `units_per_price_unit`: Smallest token units per 1.0 of item price
+    pub fn basket_token_amount(&self, lines: &[(u32, u32)], channel: SalesChannel, units_per_price_unit: u64) -> Result<u128, InventoryError> {
+        self.check_stock(lines, channel)?;
+        let total: f64 = lines
+            .iter()
+            .map(|&(item_id, quantity)| self.price_line(item_id, quantity, channel).1) // Matches the recorded sale totals
+            .sum();
+        Ok((total * units_per_price_unit as f64).round() as u128)
+    }
+
+    /// Records the outcome of a checkout's ledger transfer, selling the basket if it went through
+    /// - `payment`: The payment as charged, stored with the outcome
+    /// - `transfer`: Ledger block of the transfer, or why it did not happen
+    pub fn settle_payment(
+        &mut self,
+        mut payment: Payment,
+        transfer: Result<Nat, String>,
+        lines: &[(u32, u32)],
+        channel: SalesChannel,
+        now: u64,
+    ) -> Result<Payment, InventoryError> {
+        match transfer {
+            Err(reason) => {
+                payment.status = PaymentStatus::Failed { reason: reason.clone() };
+                self.push_payment(payment);
+                Err(InventoryError::CallFailed { msg: reason })
+            }
+            Ok(block_index) => {
+                payment.block_index = Some(block_index);
+                // Stock can change while the ledger call is in flight, so the sale may still fail here
+                match self.record_sales(lines, channel, Tender::Token, None, now) {
+                    Ok(sales) => {
+                        payment.sale_ids = sales.iter().map(|sale| sale.id).collect();
+                        Ok(self.push_payment(payment))
+                    }
+                    Err(error) => {
+                        payment.status = PaymentStatus::RefundDue { reason: format!("{:?}", error) };
+                        let payment = self.push_payment(payment);
+                        let log = format!(
+                            "Payment {} taken but sale failed, refund due, at {}",
+                            payment.id,
+                            SupermarketManager::get_current_time()
+                        );
+                        self.logs.push(log);
+                        Err(error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores a payment record and returns it
+    pub fn push_payment(&mut self, mut payment: Payment) -> Payment {
+        payment.id = self.payments.records.len() as u64;
+        self.payments.records.push(payment.clone());
+        payment
+    }
+}
+
+/// Takes the basket total from the payer through an ICRC-2 approval
+async fn transfer_from(config: &PaymentConfig, payer: &Account, amount: u128) -> Result<Nat, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: payer.clone(),
+        to: Account { owner: ic_cdk::id(), subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<(Result<Nat, TransferFromError>,), _> =
+        ic_cdk::call(config.ledger_canister_id, "icrc2_transfer_from", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(error),)) => Err(format!("Ledger rejected the transfer: {:?}", error)),
+        Err((code, msg)) => Err(format!("Ledger call failed: {:?} {}", code, msg)),
+    }
+}
+
+// Charges the payer for a basket in tokens and, once the transfer succeeds, sells the stock.
+// The payer must have approved this canister to spend at least the basket total. Prices are
+// those of `channel`, in-store by default.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+async fn checkout_with_payment(
+    lines: Vec<(u32, u32)>,
+    payer: Account,
+    idempotency_key: Option<String>,
+    channel: Option<SalesChannel>,
+) -> Result<Payment, InventoryError> {
+    require_permission("checkout_with_payment", Permission::RecordSales)?;
+    run_once_async("checkout_with_payment", idempotency_key, pay_and_record(lines, payer, channel.unwrap_or_default())).await
+}
+
+/// Takes payment for a basket and records the sale once the ledger transfer succeeds
+async fn pay_and_record(lines: Vec<(u32, u32)>, payer: Account, channel: SalesChannel) -> Result<Payment, InventoryError> {
+    validate_lines(&lines)?;
+    let (config, amount, flow) = mutate(|inventory| {
+        let config = inventory.payments.config.clone().ok_or_else(|| InventoryError::InvalidInput {
+            msg: "Token payments are not configured".to_string(),
+        })?;
+        let now = ic_cdk::api::time();
+        inventory.enforce_sale_rules(&lines, now)?; // Before the payer is charged
+        let amount = inventory.basket_token_amount(&lines, channel, config.units_per_price_unit)?;
+        let kind = FlowKind::Payment { payer: payer.clone(), ledger_canister_id: config.ledger_canister_id, amount: Nat::from(amount) };
+        let flow = Flow::begin(inventory, kind, ic_cdk::caller(), now)?;
+        Ok::<_, InventoryError>((config, amount, flow))
+    })?;
+
+    let transfer = transfer_from(&config, &payer, amount).await;
+    let now = ic_cdk::api::time();
+    let payment = Payment {
+        id: 0,
+        payer,
+        ledger_canister_id: config.ledger_canister_id,
+        amount: Nat::from(amount),
+        block_index: None,
+        status: PaymentStatus::Completed,
+        sale_ids: Vec::new(),
+        timestamp: now,
+    };
+    flow.finish(|inventory| inventory.settle_payment(payment, transfer, &lines, channel, now))
+}
+
+// Sets the ledger canister and price conversion used for token payments.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_payment_config(config: PaymentConfig) -> Result<(), InventoryError> {
+    require_caller("set_payment_config", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().governance.ensure_direct_change_allowed())?;
+    if config.units_per_price_unit == 0 {
+        return Err(InventoryError::InvalidInput { msg: "units_per_price_unit must be positive".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().payments.config = Some(config);
+    });
+    Ok(())
+}
+
+// Retrieves the token payment settings.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_payment_config() -> Result<Option<PaymentConfig>, InventoryError> {
+    require_reader("get_payment_config", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().payments.config.clone())
+    })
+}
+
+// Retrieves every token payment attempt.
+// This function is marked as `#[update]` so the access audit keeps a record of the call.
+#[update(guard = "rate_limit")]
+fn get_payments() -> Result<Vec<Payment>, InventoryError> {
+    require_reader("get_payments", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().payments.records.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(owner: Principal) -> Payment {
+        Payment {
+            id: 0,
+            payer: Account { owner, subaccount: None },
+            ledger_canister_id: Principal::anonymous(),
+            amount: Nat::from(250u32),
+            block_index: None,
+            status: PaymentStatus::Completed,
+            sale_ids: Vec::new(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn a_failed_transfer_is_recorded_without_selling() {
+        let mut manager = SupermarketManager::new();
+        let err = manager.settle_payment(payment(Principal::anonymous()), Err("rejected".to_string()), &[(1, 1)], SalesChannel::InStore, 0);
+        assert!(matches!(err, Err(InventoryError::CallFailed { .. })));
+
+        let recorded = &manager.payments.records[0];
+        assert_eq!(recorded.status, PaymentStatus::Failed { reason: "rejected".to_string() });
+        assert!(recorded.block_index.is_none() && recorded.sale_ids.is_empty());
+        assert!(manager.sales.is_empty());
+    }
+
+    #[test]
+    fn payments_are_numbered_in_order() {
+        let mut manager = SupermarketManager::new();
+        let ids: Vec<u64> = (1..=3).map(|n| manager.push_payment(payment(Principal::from_slice(&[n]))).id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert!(manager.basket_token_amount(&[(1, 1)], SalesChannel::InStore, 100).is_err()); // No such item to price
+    }
+}
@@ -0,0 +1,188 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{HashSet, VecDeque};
+
+use crate::access::{require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::events::InventoryEventPayload;
+use crate::history::ItemHistoryEvent;
+use crate::idempotency::run_once;
+use crate::journal::JournalEvent;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_PRICE_CHANGES: usize = 100_000; // Price history entries kept; the oldest is dropped first
+const MAX_BULK_UPDATES: usize = 5000;     // Price changes applied by one call
+
+/// A recorded change of an item's selling price
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PriceChange {
+    pub item_id: u32,
+    pub old_price: f64,
+    pub new_price: f64,
+    pub changed_by: Principal, // Who made the change
+    pub changed_at: u64,       // Time of the change in nanoseconds since the Unix epoch
+}
+
+/// Outcome of one line of a bulk price update
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PriceUpdateResult {
+    pub item_id: u32,
+    pub old_price: f64,
+    pub new_price: f64,
+}
+
+/// Every price change, oldest first
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct PriceHistory {
+    pub changes: VecDeque<PriceChange>,
+}
+
+impl PriceHistory {
+    pub fn record(&mut self, change: PriceChange) {
+        self.changes.push_back(change);
+        while self.changes.len() > MAX_PRICE_CHANGES {
+            self.changes.pop_front();
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Checks a set of price updates and works out what each would change, without applying them
+    /// - `updates`: Pairs of (item ID, new price)
+    pub fn plan_prices(&self, updates: &[(u32, f64)]) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+        let mut validator = Validator::new();
+        validator.check(updates.len() <= MAX_BULK_UPDATES, "updates", format!("must have at most {} entries", MAX_BULK_UPDATES));
+        let mut seen = HashSet::new();
+        for (i, &(item_id, price)) in updates.iter().enumerate() {
+            validator
+                .check(self.items.contains_key(&item_id), &format!("updates[{}].item_id", i), "is not a known item")
+                .check(seen.insert(item_id), &format!("updates[{}].item_id", i), "appears more than once")
+                .price(&format!("updates[{}].price", i), price);
+        }
+        validator.finish()?;
+        Ok(updates
+            .iter()
+            .map(|&(item_id, new_price)| {
+                let old_price = self.items.get(&item_id).expect("checked above").price;
+                PriceUpdateResult { item_id, old_price, new_price }
+            })
+            .collect())
+    }
+
+    /// Sets many prices at once; every update is checked first and either all apply or none do
+    /// - `updates`: Pairs of (item ID, new price)
+    pub fn set_prices(&mut self, updates: &[(u32, f64)], changed_by: Principal, now: u64) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+        self.plan_prices(updates)?;
+        Ok(updates.iter().map(|&(item_id, price)| self.set_price(item_id, price, changed_by, now)).collect())
+    }
+
+    /// The new price of every item in a category that is not archived, changed by a percentage
+    ///
+    /// New prices are rounded to the currency's minor unit.
+    pub fn category_price_updates(&self, category: &str, percent: i32) -> Result<Vec<(u32, f64)>, InventoryError> {
+        let factor = (100.0 + percent as f64) / 100.0;
+        let mut updates: Vec<(u32, f64)> = self.items
+            .values()
+            .filter(|item| !item.archived && item.category.as_deref() == Some(category))
+            .map(|item| (item.id, self.config.round_amount(item.price * factor)))
+            .collect();
+        if updates.is_empty() {
+            return Err(InventoryError::NotFound { msg: format!("No items in category {}", category) });
+        }
+        updates.sort_by_key(|&(item_id, _)| item_id);
+        Ok(updates)
+    }
+
+    /// Changes the price of every item in a category that is not archived by a percentage
+    pub fn adjust_category_prices(&mut self, category: &str, percent: i32, changed_by: Principal, now: u64) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+        let updates = self.category_price_updates(category, percent)?;
+        self.set_prices(&updates, changed_by, now)
+    }
+
+    fn set_price(&mut self, item_id: u32, new_price: f64, changed_by: Principal, now: u64) -> PriceUpdateResult {
+        let old_effective_price = self.effective_price(item_id);
+        let old_price = self.items.get(&item_id).expect("plan_prices checked the item exists").price;
+        self.journal_event(JournalEvent::PriceSet { item_id, price: new_price });
+        self.esl.mark_changed(item_id); // Shelf labels need the new price
+        self.price_history.record(PriceChange { item_id, old_price, new_price, changed_by, changed_at: now });
+        self.publish_event(InventoryEventPayload::PriceChanged { item_id, old_price, new_price }, now);
+        let log = format!(
+            "Item {} price changed from {} to {} at {}",
+            item_id,
+            self.config.format_amount(old_price),
+            self.config.format_amount(new_price),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        self.record_history(item_id, ItemHistoryEvent::PriceChanged { old_price, new_price });
+        self.alert_price_drop(item_id, old_effective_price);
+        self.after_price_change(item_id, new_price, now);
+        PriceUpdateResult { item_id, old_price, new_price }
+    }
+}
+
+// Sets the prices of many items at once, given as (item ID, new price) pairs. Nothing changes
+// unless every pair is valid.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn bulk_update_prices(updates: Vec<(u32, f64)>) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+    require_permission("bulk_update_prices", Permission::ChangePrices)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().set_prices(&updates, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Previews `bulk_update_prices`: checks the pairs and returns the changes they would make, or
+// every validation error, without changing any price.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn simulate_bulk_update_prices(updates: Vec<(u32, f64)>) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+    require_reader("simulate_bulk_update_prices", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().plan_prices(&updates))
+}
+
+// Raises or lowers the price of every item in a category by `percent`, e.g. 5 for a 5% increase.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn adjust_prices_by_category(category: String, percent: i32, idempotency_key: Option<String>) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+    require_permission("adjust_prices_by_category", Permission::ChangePrices)?;
+    run_once("adjust_prices_by_category", idempotency_key, || {
+        Validator::new()
+            .check(percent > -100, "percent", "must be greater than -100")
+            .check(percent <= 1000, "percent", "must be at most 1000")
+            .finish()?;
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().adjust_category_prices(&category, percent, ic_cdk::caller(), ic_cdk::api::time())
+        })
+    })
+}
+
+// Previews `adjust_prices_by_category`: returns the price changes it would make without
+// applying them.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn simulate_adjust_prices_by_category(category: String, percent: i32) -> Result<Vec<PriceUpdateResult>, InventoryError> {
+    require_reader("simulate_adjust_prices_by_category", Role::Manager)?;
+    Validator::new()
+        .check(percent > -100, "percent", "must be greater than -100")
+        .check(percent <= 1000, "percent", "must be at most 1000")
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let updates = inventory.category_price_updates(&category, percent)?;
+        inventory.plan_prices(&updates)
+    })
+}
+
+// Retrieves the price changes of an item, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_price_history(item_id: u32) -> Vec<PriceChange> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().price_history.changes.iter().filter(|change| change.item_id == item_id).cloned().collect()
+    })
+}
@@ -0,0 +1,453 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::journal::CatalogReplay;
+use crate::ratelimit::rate_limit;
+use crate::velocity::{SalesVelocity, LONG_WINDOW_DAYS};
+use crate::{InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CHUNK_SIZE: usize = 5000;         // Source entries processed per timer tick
+const MAX_DISCREPANCIES: usize = 1000; // Discrepancies kept per job; the rest are only counted
+const MAX_JOBS: usize = 50;             // Finished jobs kept; the oldest is dropped first
+
+/// State derived from a ledger or the journal, which can be rebuilt from it
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectionKind {
+    Catalog,       // Items with their stock balances and prices, from the journal
+    SalesVelocity, // Daily units sold per item and channel, from the sales ledger
+    ReceiptIndex,  // Receipt number by sale ID, from the receipts
+}
+
+/// Whether a job only compares or also replaces the projection
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectionJobMode {
+    Verify,  // Reports discrepancies and leaves the projection alone
+    Rebuild, // Reports discrepancies, then replaces the projection with the rebuilt one
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectionJobStatus {
+    Running,   // Replaying the source, then comparing and, for a rebuild, installing a chunk at a time
+    Completed,
+    Cancelled, // By a manager, or by an upgrade that interrupted it
+}
+
+/// A value of a projection that differs from what its source says
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Discrepancy {
+    pub key: String,              // What differs, e.g. "item 12" or "sale 40"
+    pub field: String,            // Field that differs, or "entry" when one side is missing it
+    pub expected: Option<String>, // Value rebuilt from the source; None if the source has no such entry
+    pub actual: Option<String>,   // Value in the live projection; None if it has no such entry
+}
+
+/// A chunked rebuild or verification of one projection
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ProjectionJob {
+    pub id: u64,
+    pub kind: ProjectionKind,
+    pub mode: ProjectionJobMode,
+    pub status: ProjectionJobStatus,
+    pub processed: u64,                  // Source entries processed so far
+    pub total: u64,                      // Source entries to process, growing if the source does while the job runs
+    pub started_by: Principal,
+    pub started_at: u64,                 // Time the job started in nanoseconds since the Unix epoch
+    pub finished_at: Option<u64>,
+    pub discrepancy_count: u64,          // Discrepancies found, including those not kept
+    pub discrepancies: Vec<Discrepancy>, // The first discrepancies found
+}
+
+/// A projection being rebuilt between timer ticks
+///
+/// The keys to compare are collected once the source has been replayed.
+enum Rebuild {
+    Catalog { replay: CatalogReplay, ids: Vec<u32> },
+    SalesVelocity { velocity: SalesVelocity, first_sale: u64, next_sale: u64, keys: Vec<(u32, SalesChannel)> },
+    ReceiptIndex { by_sale: HashMap<u64, u64>, next_receipt: u64, sale_ids: Vec<u64> }, // Receipt number to index next
+}
+
+impl Rebuild {
+    fn key_count(&self) -> usize {
+        match self {
+            Rebuild::Catalog { ids, .. } => ids.len(),
+            Rebuild::SalesVelocity { keys, .. } => keys.len(),
+            Rebuild::ReceiptIndex { sale_ids, .. } => sale_ids.len(),
+        }
+    }
+}
+
+/// How far the running job has got
+#[derive(Clone, Copy)]
+enum Phase {
+    Replay,                  // Folding the source into the rebuilt projection
+    Compare { next: usize }, // Comparing rebuilt and live values, from the key at `next`
+    Install { next: usize }, // Writing rebuilt values over the live ones, from the key at `next`
+}
+
+/// The job in progress and what it has rebuilt so far
+struct RunningJob {
+    id: u64,
+    rebuild: Rebuild,
+    phase: Phase,
+}
+
+/// Projection jobs and the one in progress
+#[derive(Default)]
+pub struct Projections {
+    pub jobs: BTreeMap<u64, ProjectionJob>, // Recent jobs keyed by ID
+    running: Option<RunningJob>,
+    pub next_job_id: u64,
+}
+
+/// Field-by-field differences between an item as rebuilt and as it is
+fn item_discrepancies(id: u32, expected: Option<&InventoryItem>, actual: Option<&InventoryItem>) -> Vec<Discrepancy> {
+    let key = format!("item {}", id);
+    let (expected, actual) = match (expected, actual) {
+        (Some(expected), Some(actual)) => (expected, actual),
+        (expected, actual) => {
+            let describe = |item: Option<&InventoryItem>| item.map(|item| format!("{:?}", item));
+            return vec![Discrepancy { key, field: "entry".to_string(), expected: describe(expected), actual: describe(actual) }];
+        }
+    };
+    let fields = [
+        ("name", format!("{:?}", expected.name), format!("{:?}", actual.name)),
+        ("quantity", expected.quantity.to_string(), actual.quantity.to_string()),
+        ("price", expected.price.to_string(), actual.price.to_string()),
+        ("expiration_date", expected.expiration_date.to_string(), actual.expiration_date.to_string()),
+        ("archived", expected.archived.to_string(), actual.archived.to_string()),
+        ("unit", format!("{:?}", expected.unit), format!("{:?}", actual.unit)),
+        ("version", expected.version.to_string(), actual.version.to_string()),
+        ("location", format!("{:?}", expected.location), format!("{:?}", actual.location)),
+        ("barcode", format!("{:?}", expected.barcode), format!("{:?}", actual.barcode)),
+        ("category", format!("{:?}", expected.category), format!("{:?}", actual.category)),
+        ("storage_class", format!("{:?}", expected.storage_class), format!("{:?}", actual.storage_class)),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, expected, actual)| expected != actual)
+        .map(|(field, expected, actual)| Discrepancy { key: key.clone(), field: field.to_string(), expected: Some(expected), actual: Some(actual) })
+        .collect()
+}
+
+/// The discrepancy between a rebuilt and a live value, if they differ
+fn value_discrepancy<V: PartialEq + Debug>(key: String, field: &str, expected: Option<V>, actual: Option<V>) -> Option<Discrepancy> {
+    (expected != actual).then(|| Discrepancy {
+        key,
+        field: field.to_string(),
+        expected: expected.map(|v| format!("{:?}", v)),
+        actual: actual.map(|v| format!("{:?}", v)),
+    })
+}
+
+/// An item's daily totals on a channel still inside the velocity window, which is all the live
+/// totals are compared on
+fn recent_days(velocity: &SalesVelocity, key: &(u32, SalesChannel), today: u64) -> Option<Vec<(u64, u64)>> {
+    let recent: Vec<(u64, u64)> = velocity.daily.get(key)?.iter().copied().filter(|&(day, _)| day + LONG_WINDOW_DAYS > today).collect();
+    (!recent.is_empty()).then_some(recent)
+}
+
+/// Keys found on either side, in ascending order
+fn key_union<K: Ord + Copy>(rebuilt: impl Iterator<Item = K>, live: impl Iterator<Item = K>) -> Vec<K> {
+    rebuilt.chain(live).collect::<BTreeSet<K>>().into_iter().collect()
+}
+
+/// Counts a chunk's discrepancies and keeps them while there is room
+fn record_discrepancies(job: &mut ProjectionJob, found: Vec<Discrepancy>) {
+    job.discrepancy_count += found.len() as u64;
+    let room = MAX_DISCREPANCIES.saturating_sub(job.discrepancies.len());
+    job.discrepancies.extend(found.into_iter().take(room));
+}
+
+impl SupermarketManager {
+    /// Starts a chunked job rebuilding or verifying a projection and returns its ID
+    pub fn start_projection_job(&mut self, kind: ProjectionKind, mode: ProjectionJobMode, started_by: Principal, now: u64) -> Result<u64, InventoryError> {
+        if let Some(RunningJob { id, .. }) = &self.projections.running {
+            return Err(InventoryError::Conflict { msg: format!("Projection job {} is still running", id) });
+        }
+        let (rebuild, total) = match kind {
+            ProjectionKind::Catalog => (Rebuild::Catalog { replay: CatalogReplay::default(), ids: Vec::new() }, self.journal.len()),
+            ProjectionKind::SalesVelocity => {
+                let since = now.saturating_sub(LONG_WINDOW_DAYS * NANOS_PER_DAY);
+                let next_sale = self.sales.first_since(since);
+                let rebuild = Rebuild::SalesVelocity { velocity: SalesVelocity::default(), first_sale: next_sale, next_sale, keys: Vec::new() };
+                (rebuild, self.sales.len() - next_sale)
+            }
+            ProjectionKind::ReceiptIndex => {
+                let first = self.receipts.linked_from;
+                (Rebuild::ReceiptIndex { by_sale: HashMap::new(), next_receipt: first, sale_ids: Vec::new() }, self.receipts.len() + 1 - first)
+            }
+        };
+        let id = self.projections.next_job_id;
+        self.projections.next_job_id += 1;
+        self.projections.jobs.insert(id, ProjectionJob {
+            id,
+            kind,
+            mode,
+            status: ProjectionJobStatus::Running,
+            processed: 0,
+            total,
+            started_by,
+            started_at: now,
+            finished_at: None,
+            discrepancy_count: 0,
+            discrepancies: Vec::new(),
+        });
+        while self.projections.jobs.len() > MAX_JOBS {
+            self.projections.jobs.pop_first();
+        }
+        self.projections.running = Some(RunningJob { id, rebuild, phase: Phase::Replay });
+        let log = format!("Projection job {} started to {:?} {:?} at {}", id, mode, kind, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(id)
+    }
+
+    /// Folds up to a chunk more of the source into a rebuild and returns whether it has caught
+    /// up, with the entries processed and to process
+    fn advance_rebuild(&self, rebuild: &mut Rebuild) -> (bool, u64, u64) {
+        match rebuild {
+            Rebuild::Catalog { replay, .. } => {
+                let done = replay.advance(&self.journal, CHUNK_SIZE);
+                (done, replay.next_seq(), self.journal.len())
+            }
+            Rebuild::SalesVelocity { velocity, first_sale, next_sale, .. } => {
+                let end = (*next_sale + CHUNK_SIZE as u64).min(self.sales.len());
+                for sale in self.sales.iter_from(*next_sale).take((end - *next_sale) as usize).filter(|sale| !sale.test) {
+                    self.record_velocity(velocity, &sale);
+                }
+                *next_sale = end;
+                (end == self.sales.len(), end - *first_sale, self.sales.len() - *first_sale)
+            }
+            Rebuild::ReceiptIndex { by_sale, next_receipt, .. } => {
+                let first = self.receipts.linked_from;
+                let end = (*next_receipt + CHUNK_SIZE as u64).min(self.receipts.len() + 1);
+                for receipt in self.receipts.iter_from(*next_receipt).take((end - *next_receipt) as usize) {
+                    by_sale.extend(receipt.lines.iter().map(|line| (line.sale_id, receipt.number)));
+                }
+                *next_receipt = end;
+                (end == self.receipts.len() + 1, end - first, self.receipts.len() + 1 - first)
+            }
+        }
+    }
+
+    /// Collects the keys of a caught-up rebuild and of the live projection
+    ///
+    /// Only the keys are gathered in one go; their values are compared a chunk at a time.
+    fn collect_keys(&self, rebuild: &mut Rebuild) {
+        match rebuild {
+            Rebuild::Catalog { replay, ids } => *ids = key_union(replay.items().keys().copied(), self.items.keys()),
+            Rebuild::SalesVelocity { velocity, keys, .. } => {
+                *keys = key_union(velocity.daily.keys().copied(), self.velocity.daily.keys().copied());
+            }
+            Rebuild::ReceiptIndex { by_sale, sale_ids, .. } => {
+                *sale_ids = key_union(by_sale.keys().copied(), self.receipts.by_sale.keys().copied());
+            }
+        }
+    }
+
+    /// Compares the rebuilt and live values of the keys from `from` on, up to a chunk of them;
+    /// returns the discrepancies found and the key to go on from
+    fn compare_chunk(&self, rebuild: &Rebuild, from: usize, now: u64) -> (Vec<Discrepancy>, usize) {
+        let end = (from + CHUNK_SIZE).min(rebuild.key_count());
+        let found = match rebuild {
+            Rebuild::Catalog { replay, ids } => ids[from..end]
+                .iter()
+                .flat_map(|&id| item_discrepancies(id, replay.items().get(&id), self.items.get(&id).as_ref()))
+                .collect(),
+            Rebuild::SalesVelocity { velocity, keys, .. } => {
+                let today = now / NANOS_PER_DAY;
+                keys[from..end]
+                    .iter()
+                    .filter_map(|key| {
+                        let (item_id, channel) = key;
+                        let key_name = format!("item {} on {:?}", item_id, channel);
+                        value_discrepancy(key_name, "daily_units", recent_days(velocity, key, today), recent_days(&self.velocity, key, today))
+                    })
+                    .collect()
+            }
+            Rebuild::ReceiptIndex { by_sale, sale_ids, .. } => sale_ids[from..end]
+                .iter()
+                .filter_map(|sale_id| {
+                    let key_name = format!("sale {}", sale_id);
+                    value_discrepancy(key_name, "receipt_number", by_sale.get(sale_id), self.receipts.by_sale.get(sale_id))
+                })
+                .collect(),
+        };
+        (found, end)
+    }
+
+    /// Processes the next chunk of the running job: the source first, then the comparison, then
+    /// for a rebuild the installation; returns whether work remains
+    ///
+    /// The rebuild catches up with its source before every chunk, so changes made while the job
+    /// runs reach both sides before they are compared or installed.
+    pub fn step_projection_job(&mut self, now: u64) -> bool {
+        let Some(mut running) = self.projections.running.take() else { return false };
+        let (caught_up, processed, total) = self.advance_rebuild(&mut running.rebuild);
+        let Some(job) = self.projections.jobs.get_mut(&running.id) else { return false }; // Cancelled and dropped meanwhile
+        job.processed = processed;
+        job.total = total;
+        let mode = job.mode;
+        if !caught_up {
+            self.projections.running = Some(running);
+            return true;
+        }
+        let next_phase = match running.phase {
+            Phase::Replay => {
+                self.collect_keys(&mut running.rebuild);
+                Some(Phase::Compare { next: 0 })
+            }
+            Phase::Compare { next } => {
+                let (found, end) = self.compare_chunk(&running.rebuild, next, now);
+                record_discrepancies(self.projections.jobs.get_mut(&running.id).expect("looked up above"), found);
+                if end < running.rebuild.key_count() {
+                    Some(Phase::Compare { next: end })
+                } else if mode == ProjectionJobMode::Rebuild {
+                    Some(Phase::Install { next: 0 })
+                } else {
+                    None
+                }
+            }
+            Phase::Install { next } => match running.rebuild {
+                Rebuild::Catalog { ref replay, ref ids } => {
+                    let end = (next + CHUNK_SIZE).min(ids.len());
+                    self.install_replayed_items(replay, &ids[next..end]);
+                    (end < ids.len()).then_some(Phase::Install { next: end })
+                }
+                Rebuild::SalesVelocity { velocity, .. } => {
+                    self.velocity = velocity;
+                    self.finish_projection_job(running.id, now);
+                    return false;
+                }
+                Rebuild::ReceiptIndex { by_sale, .. } => {
+                    self.receipts.by_sale = by_sale;
+                    self.finish_projection_job(running.id, now);
+                    return false;
+                }
+            },
+        };
+        match next_phase {
+            Some(phase) => {
+                running.phase = phase;
+                self.projections.running = Some(running);
+                true
+            }
+            None => {
+                self.finish_projection_job(running.id, now);
+                false
+            }
+        }
+    }
+
+    fn finish_projection_job(&mut self, id: u64, now: u64) {
+        let job = self.projections.jobs.get_mut(&id).expect("checked by the caller");
+        job.status = ProjectionJobStatus::Completed;
+        job.finished_at = Some(now);
+        let (mode, kind, count) = (job.mode, job.kind, job.discrepancy_count);
+        let log = format!(
+            "Projection job {} finished {:?} of {:?} with {} discrepancies at {}",
+            id,
+            mode,
+            kind,
+            count,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+    }
+
+    /// Marks the jobs an upgrade interrupted as cancelled; what they had rebuilt did not survive it
+    pub fn cancel_interrupted_projection_jobs(&mut self, now: u64) {
+        for job in self.projections.jobs.values_mut().filter(|job| job.status == ProjectionJobStatus::Running) {
+            job.status = ProjectionJobStatus::Cancelled;
+            job.finished_at = Some(now);
+            let log = format!("Projection job {} cancelled by an upgrade at {}", job.id, SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+    }
+
+    /// Stops the running job, leaving its projection as it was
+    pub fn cancel_projection_job(&mut self, id: u64, now: u64) -> Result<(), InventoryError> {
+        match &self.projections.running {
+            Some(running) if running.id == id => {}
+            _ => return Err(InventoryError::NotFound { msg: format!("Projection job {} is not running", id) }),
+        }
+        self.projections.running = None;
+        if let Some(job) = self.projections.jobs.get_mut(&id) {
+            job.status = ProjectionJobStatus::Cancelled;
+            job.finished_at = Some(now);
+        }
+        let log = format!("Projection job {} cancelled at {}", id, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        Ok(())
+    }
+}
+
+/// Starts a job for the caller and schedules its first chunk; the caller's role is checked already
+pub fn launch_projection_job(kind: ProjectionKind, mode: ProjectionJobMode) -> Result<u64, InventoryError> {
+    let id = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().start_projection_job(kind, mode, ic_cdk::caller(), ic_cdk::api::time())
+    })?;
+    schedule_projection_step();
+    Ok(id)
+}
+
+/// Runs the job a chunk per timer tick, so no single message has to process the whole source
+fn schedule_projection_step() {
+    ic_cdk::timer::set_timer(Duration::ZERO, || {
+        let more = INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().step_projection_job(ic_cdk::api::time()));
+        if more {
+            schedule_projection_step();
+        }
+    });
+}
+
+// Starts a background job that rebuilds a projection from its source, or checks it against
+// the source without changing it, a chunk at a time. Returns the job ID; only one job runs at
+// a time. Verifying is for managers; rebuilding replaces live state and is for the owner.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn start_projection_job(kind: ProjectionKind, mode: ProjectionJobMode) -> Result<u64, InventoryError> {
+    require_caller("start_projection_job", if mode == ProjectionJobMode::Rebuild { Role::Owner } else { Role::Manager })?;
+    launch_projection_job(kind, mode)
+}
+
+// Stops the running projection job; a rebuild that is cancelled changes nothing.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_projection_job(id: u64) -> Result<(), InventoryError> {
+    require_caller("cancel_projection_job", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow_mut().cancel_projection_job(id, ic_cdk::api::time()))
+}
+
+// Retrieves a projection job with its progress and the discrepancies it found.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_projection_job(id: u64) -> Result<ProjectionJob, InventoryError> {
+    require_reader("get_projection_job", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().projections.jobs.get(&id).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Projection job {} not found", id),
+        })
+    })
+}
+
+// Retrieves recent projection jobs, newest first, without their discrepancy lists.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_projection_jobs() -> Result<Vec<ProjectionJob>, InventoryError> {
+    require_reader("list_projection_jobs", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().projections.jobs
+            .values()
+            .rev()
+            .map(|job| ProjectionJob { discrepancies: Vec::new(), ..job.clone() })
+            .collect())
+    })
+}
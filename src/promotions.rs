@@ -0,0 +1,200 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SLOW_COVER_DAYS: f64 = 60.0;          // Stock lasting longer than this at the 30-day rate counts as slow-moving
+const NEAR_EXPIRY_DAYS: u64 = 7;            // Stock expiring within this many days is a clearance candidate
+const NEAR_EXPIRY_DISCOUNT: u8 = 50;        // Discount suggested for stock that would otherwise expire on the shelf
+const SLOW_MOVER_DISCOUNT: u8 = 25;         // Discount suggested for stock that is merely slow to sell
+const MAX_DECIDED_PROMOTIONS: usize = 1000; // Approved and rejected drafts kept for reference
+
+/// Why an item was picked for clearance
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum ClearanceReason {
+    NearExpiry, // It expires soon and will not sell through before then at its current rate
+    SlowMoving, // It has far more stock than it sells
+}
+
+/// Where a promotion is in its approval workflow
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum PromotionStatus {
+    Draft,    // Suggested, waiting for a manager
+    Approved, // Accepted by a manager
+    Rejected, // Turned down by a manager
+}
+
+/// A suggested bundle: buy the fast mover, get the clearance item at a discount
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct BundlePromotion {
+    pub id: u64,
+    pub category: String,              // Category both items belong to
+    pub anchor_item_id: u32,           // Fast mover the customer buys at full price
+    pub discounted_item_id: u32,       // Slow or near-expiry item offered with the anchor
+    pub discount_pct: u8,              // Discount on the discounted item
+    pub reason: ClearanceReason,
+    pub days_of_cover: Option<f64>,    // How long the discounted item's stock would last unpromoted; None if it is not selling
+    pub status: PromotionStatus,
+    pub created_at: u64,               // Time of the suggestion in nanoseconds since the Unix epoch
+    pub decided_by: Option<Principal>, // Manager who approved or rejected it
+    pub decided_at: Option<u64>,
+}
+
+/// Bundle promotions suggested by the daily aging scan
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Promotions {
+    pub promotions: BTreeMap<u64, BundlePromotion>, // Promotions keyed by ID
+    pub next_id: u64,                               // ID handed to the next promotion
+}
+
+impl SupermarketManager {
+    /// Drafts a bundle for every clearance candidate without a draft or approved promotion
+    ///
+    /// Candidates are paired with the fastest-selling item in stock in the same category;
+    /// items without a category are skipped. Returns the IDs of the new drafts.
+    pub fn suggest_bundles(&mut self, now: u64) -> Vec<u64> {
+        let now_secs = now / NANOS_PER_SEC;
+        let mut fastest: HashMap<String, (u32, f64)> = HashMap::new();
+        let mut candidates = Vec::new();
+        for item in self.items.values().filter(|item| !item.archived && item.quantity > 0) {
+            let Some(category) = item.category else { continue };
+            let velocity = self.velocity.velocity(item.id, now);
+            if fastest.get(&category).is_none_or(|&(_, rate)| velocity.per_day_7 > rate) {
+                fastest.insert(category.clone(), (item.id, velocity.per_day_7));
+            }
+            let days_of_cover = (velocity.per_day_30 > 0.0).then(|| item.quantity as f64 / velocity.per_day_30);
+            let days_to_expiry = item.expiration_date.saturating_sub(now_secs) / (NANOS_PER_DAY / NANOS_PER_SEC);
+            let reason = if days_to_expiry <= NEAR_EXPIRY_DAYS && days_of_cover.is_none_or(|days| days > days_to_expiry as f64) {
+                ClearanceReason::NearExpiry
+            } else if days_of_cover.is_none_or(|days| days > SLOW_COVER_DAYS) {
+                ClearanceReason::SlowMoving
+            } else {
+                continue;
+            };
+            candidates.push((item.id, category, reason, days_of_cover));
+        }
+        let mut created = Vec::new();
+        let mut drafts = Vec::new();
+        for (item_id, category, reason, days_of_cover) in candidates {
+            let Some(&(anchor_item_id, rate)) = fastest.get(&category) else { continue };
+            if anchor_item_id == item_id || rate == 0.0 {
+                continue; // Nothing in the category sells well enough to carry it
+            }
+            let covered = self.promotions.promotions.values().any(|p| p.status != PromotionStatus::Rejected && p.discounted_item_id == item_id);
+            if !covered {
+                drafts.push((category, anchor_item_id, item_id, reason, days_of_cover));
+            }
+        }
+        for (category, anchor_item_id, discounted_item_id, reason, days_of_cover) in drafts {
+            let id = self.promotions.next_id;
+            self.promotions.next_id += 1;
+            self.promotions.promotions.insert(id, BundlePromotion {
+                id,
+                category,
+                anchor_item_id,
+                discounted_item_id,
+                discount_pct: if reason == ClearanceReason::NearExpiry { NEAR_EXPIRY_DISCOUNT } else { SLOW_MOVER_DISCOUNT },
+                reason,
+                days_of_cover,
+                status: PromotionStatus::Draft,
+                created_at: now,
+                decided_by: None,
+                decided_at: None,
+            });
+            created.push(id);
+        }
+        if !created.is_empty() {
+            let log = format!("{} bundle promotions suggested at {}", created.len(), SupermarketManager::get_current_time());
+            self.logs.push(log);
+        }
+        created
+    }
+
+    /// Approves or rejects a draft promotion
+    pub fn decide_promotion(&mut self, id: u64, approve: bool, decided_by: Principal, now: u64) -> Result<BundlePromotion, InventoryError> {
+        let item_id = self.promotions.promotions.get(&id).map(|p| p.discounted_item_id);
+        let old_effective_price = item_id.and_then(|item_id| self.effective_price(item_id));
+        let promotion = self.promotions.promotions.get_mut(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Promotion {} not found", id),
+        })?;
+        if promotion.status != PromotionStatus::Draft {
+            return Err(InventoryError::Conflict { msg: format!("Promotion {} is already {:?}", id, promotion.status) });
+        }
+        promotion.status = if approve { PromotionStatus::Approved } else { PromotionStatus::Rejected };
+        promotion.decided_by = Some(decided_by);
+        promotion.decided_at = Some(now);
+        let promotion = promotion.clone();
+        let log = format!("Promotion {} {:?} at {}", id, promotion.status, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        let decided: Vec<u64> = self.promotions.promotions.values().filter(|p| p.status != PromotionStatus::Draft).map(|p| p.id).collect();
+        for old in decided.iter().take(decided.len().saturating_sub(MAX_DECIDED_PROMOTIONS)) {
+            if let Some(old) = self.promotions.promotions.remove(old).filter(|p| p.status == PromotionStatus::Approved) {
+                self.esl.mark_changed(old.discounted_item_id); // Its labels lose the promotion flag
+            }
+        }
+        if promotion.status == PromotionStatus::Approved {
+            self.esl.mark_changed(promotion.discounted_item_id); // Its labels gain the promotion flag
+        }
+        self.alert_price_drop(promotion.discounted_item_id, old_effective_price);
+        Ok(promotion)
+    }
+}
+
+/// Registers the daily scan that drafts clearance bundles
+pub fn start_promotion_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_nanos(NANOS_PER_DAY), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().suggest_bundles(ic_cdk::api::time());
+        });
+    });
+}
+
+// Runs the clearance scan now instead of waiting for the daily run. Returns the new draft IDs.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn suggest_bundle_promotions() -> Result<Vec<u64>, InventoryError> {
+    require_caller("suggest_bundle_promotions", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow_mut().suggest_bundles(ic_cdk::api::time())))
+}
+
+// Retrieves promotions, optionally only those with the given status.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_promotions(status: Option<PromotionStatus>) -> Result<Vec<BundlePromotion>, InventoryError> {
+    require_reader("get_promotions", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().promotions.promotions
+            .values()
+            .filter(|p| status.is_none_or(|status| p.status == status))
+            .cloned()
+            .collect())
+    })
+}
+
+// Approves a draft promotion.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn approve_promotion(id: u64) -> Result<BundlePromotion, InventoryError> {
+    require_caller("approve_promotion", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().decide_promotion(id, true, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Rejects a draft promotion. The item may be suggested again by a later scan.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn reject_promotion(id: u64) -> Result<BundlePromotion, InventoryError> {
+    require_caller("reject_promotion", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().decide_promotion(id, false, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
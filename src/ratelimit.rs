@@ -0,0 +1,256 @@
+use ic_cdk_macros::{inspect_message, update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::load::track_update;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_MINUTE: f64 = 60.0 * 1_000_000_000.0;
+const MAX_BUCKETS: usize = 10_000; // Buckets kept before the least recently used is dropped
+
+/// Token-bucket limits on update calls per principal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub burst: u32,             // Update calls a principal may make back to back
+    pub refill_per_minute: u32, // Calls added back to each bucket per minute
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            burst: 60,
+            refill_per_minute: 120,
+        }
+    }
+}
+
+/// Calls left to one principal
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    pub tokens: f64,     // Calls available, fractional while refilling
+    pub updated_at: u64, // Time `tokens` was last brought up to date in nanoseconds since the Unix epoch
+}
+
+/// Rate limits, per-principal buckets and principals exempt from them
+///
+/// Anonymous callers share the anonymous principal's bucket.
+#[derive(Default)]
+pub struct RateLimiter {
+    pub config: RateLimitConfig,
+    buckets: HashMap<Principal, TokenBucket>,     // Buckets of principals that called recently
+    recency: BTreeSet<(u64, Principal)>,          // Bucket owners ordered from least to most recently used
+    pub exempt: BTreeSet<Principal>,              // Trusted frontends and integrations that are never limited
+    pub rejected: u64,                            // Update calls turned away since the canister was installed
+}
+
+impl RateLimiter {
+    fn is_limited(&self, principal: &Principal) -> bool {
+        self.config.enabled && !self.exempt.contains(principal)
+    }
+
+    /// Tokens a principal has at `now`, counting the refill since its last call
+    fn tokens(&self, principal: &Principal, now: u64) -> f64 {
+        let burst = self.config.burst as f64;
+        self.buckets.get(principal).map_or(burst, |bucket| {
+            let elapsed = now.saturating_sub(bucket.updated_at) as f64;
+            (bucket.tokens + elapsed * self.config.refill_per_minute as f64 / NANOS_PER_MINUTE).min(burst)
+        })
+    }
+
+    /// Seconds until a principal with no tokens gets its next one
+    fn retry_after_secs(&self, principal: &Principal, now: u64) -> u32 {
+        let missing = 1.0 - self.tokens(principal, now);
+        (missing * 60.0 / self.config.refill_per_minute as f64).ceil().max(1.0) as u32
+    }
+
+    /// Checks whether a principal may make an update call, without spending a token
+    pub fn check(&self, principal: &Principal, now: u64) -> Result<(), InventoryError> {
+        if !self.is_limited(principal) || self.tokens(principal, now) >= 1.0 {
+            return Ok(());
+        }
+        Err(InventoryError::RateLimited { retry_after_secs: self.retry_after_secs(principal, now) })
+    }
+
+    /// Spends one of a principal's tokens, or counts the call as rejected if it has none
+    pub fn take(&mut self, principal: Principal, now: u64) -> Result<(), InventoryError> {
+        if let Err(error) = self.check(&principal, now) {
+            self.rejected += 1;
+            return Err(error);
+        }
+        if !self.is_limited(&principal) {
+            return Ok(());
+        }
+        let tokens = self.tokens(&principal, now) - 1.0;
+        match self.buckets.get(&principal) {
+            Some(bucket) => {
+                self.recency.remove(&(bucket.updated_at, principal));
+            }
+            None => {
+                while self.buckets.len() >= MAX_BUCKETS {
+                    // The least recently used bucket has had the longest to refill, so dropping it loses the least
+                    let Some((_, oldest)) = self.recency.pop_first() else { break };
+                    self.buckets.remove(&oldest);
+                }
+            }
+        }
+        self.buckets.insert(principal, TokenBucket { tokens, updated_at: now });
+        self.recency.insert((now, principal));
+        Ok(())
+    }
+
+    /// Drops every bucket, so everyone starts again from a full one
+    pub fn reset_buckets(&mut self) {
+        self.buckets.clear();
+        self.recency.clear();
+    }
+}
+
+/// Guard of every update endpoint: counts the call for load shedding, then spends one of the
+/// caller's tokens or rejects the call
+pub fn rate_limit() -> Result<(), String> {
+    track_update(); // Every update counts towards load shedding, whoever makes it
+    let caller = ic_cdk::caller();
+    if caller == ic_cdk::id() {
+        return Ok(()); // The canister's calls to itself are never limited
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().rate_limits.take(caller, ic_cdk::api::time())
+    })
+    .map_err(|error| format!("{:?}", error))
+}
+
+// Turns away ingress update calls from principals that have used up their tokens before they
+// are executed, so spam costs the canister no cycles. Runs on a single replica and cannot
+// change state, so tokens are spent by the `rate_limit` guard once the call executes.
+#[inspect_message]
+fn inspect_message() {
+    let allowed = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().rate_limits.check(&ic_cdk::caller(), ic_cdk::api::time()).is_ok()
+    });
+    if allowed {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+// Replaces the update call limits.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_rate_limit_config(config: RateLimitConfig) -> Result<(), InventoryError> {
+    require_caller("set_rate_limit_config", Role::Manager)?;
+    Validator::new()
+        .check(config.burst > 0, "burst", "must be positive")
+        .check(config.refill_per_minute > 0, "refill_per_minute", "must be positive")
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.rate_limits.config = config;
+        inventory.rate_limits.reset_buckets(); // Everyone starts again from a full bucket under the new limits
+        let log = format!("Rate limits changed at {}", SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the update call limits.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_rate_limit_config() -> Result<RateLimitConfig, InventoryError> {
+    require_reader("get_rate_limit_config", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().rate_limits.config.clone()))
+}
+
+// Exempts a principal from rate limiting, e.g. a trusted frontend that calls on behalf of many
+// users, or ends its exemption.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_rate_limit_exempt(principal: Principal, exempt: bool) -> Result<(), InventoryError> {
+    require_caller("set_rate_limit_exempt", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if exempt {
+            inventory.rate_limits.exempt.insert(principal);
+        } else {
+            inventory.rate_limits.exempt.remove(&principal);
+        }
+        let log = format!(
+            "Rate limit exemption {} for {} at {}",
+            if exempt { "granted" } else { "removed" },
+            principal,
+            SupermarketManager::get_current_time()
+        );
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the principals exempt from rate limiting and the number of calls turned away.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_rate_limit_exempt() -> Result<(Vec<Principal>, u64), InventoryError> {
+    require_reader("get_rate_limit_exempt", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        Ok((inventory.rate_limits.exempt.iter().copied().collect(), inventory.rate_limits.rejected))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn limiter(burst: u32, refill_per_minute: u32) -> RateLimiter {
+        RateLimiter { config: RateLimitConfig { enabled: true, burst, refill_per_minute }, ..Default::default() }
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let caller = Principal::from_slice(&[1]);
+        let mut limiter = limiter(2, 60);
+        assert!(limiter.take(caller, 0).is_ok());
+        assert!(limiter.take(caller, 0).is_ok());
+        assert!(matches!(limiter.take(caller, 0), Err(InventoryError::RateLimited { retry_after_secs: 1 })));
+        assert_eq!(limiter.rejected, 1);
+        assert!(limiter.take(caller, SECOND).is_ok()); // One call a second comes back
+        assert!(limiter.take(caller, SECOND).is_err());
+        assert_eq!(limiter.tokens(&caller, 60 * SECOND), 2.0); // Never more than the burst
+    }
+
+    #[test]
+    fn exempt_and_disabled_callers_are_not_limited() {
+        let caller = Principal::from_slice(&[1]);
+        let mut limiter = limiter(1, 1);
+        limiter.exempt.insert(caller);
+        for _ in 0..5 {
+            assert!(limiter.take(caller, 0).is_ok());
+        }
+        limiter.exempt.clear();
+        limiter.config.enabled = false;
+        assert!(limiter.take(caller, 0).is_ok());
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_bucket_is_evicted() {
+        let mut limiter = limiter(10, 1);
+        for i in 0..MAX_BUCKETS as u32 {
+            limiter.take(Principal::from_slice(&i.to_be_bytes()), i as u64).unwrap();
+        }
+        let first = Principal::from_slice(&0u32.to_be_bytes());
+        let second = Principal::from_slice(&1u32.to_be_bytes());
+        limiter.take(first, MAX_BUCKETS as u64).unwrap(); // Now the most recently used
+
+        limiter.take(Principal::from_slice(&[0xff; 5]), MAX_BUCKETS as u64 + 1).unwrap();
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+        assert_eq!(limiter.recency.len(), MAX_BUCKETS);
+        assert!(limiter.buckets.contains_key(&first));
+        assert!(!limiter.buckets.contains_key(&second));
+    }
+}
@@ -0,0 +1,306 @@
+use ic_cdk_macros::query;
+use ic_stable_structures::StableBTreeMap;
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::HashMap;
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::sales::Sale;
+use crate::settings::StoreConfig;
+use crate::storage::{self, Memory};
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const RECEIPT_WIDTH: usize = 42; // Characters per line on an 80 mm thermal printer
+
+/// One line of a receipt
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ReceiptLine {
+    pub sale_id: u64,
+    pub item_id: u32,
+    pub name: String,
+    pub quantity: u32,   // Units sold, in grams or millilitres for weighed goods
+    pub unit: Unit,
+    pub list_price: f64, // The item's own price per unit, per kilogram or litre for weighed goods
+    pub unit_price: f64, // Price charged per unit
+    pub list_total: f64, // The line at the item's own price, before tax is added
+    pub discount: f64,   // Saving against the list total, e.g. from a channel price list
+    pub total: f64,      // Amount charged for the line, including tax
+    pub tax: f64,        // Tax contained in the total
+}
+
+/// A receipt for one transaction
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Receipt {
+    pub number: u64,           // Sequential and gap-free, starting at 1
+    pub lines: Vec<ReceiptLine>,
+    pub subtotal: f64,         // Sum of the lines' list totals
+    pub discount: f64,         // Sum of the line discounts
+    pub tax: f64,              // Tax contained in the total
+    pub tax_included: bool,    // Whether prices already contained the tax, or it was added on top
+    pub total: f64,            // Amount charged
+    pub currency_code: String, // Currency the amounts are in
+    pub channel: SalesChannel,
+    pub cashier: Principal,    // Principal that recorded the sale
+    pub timestamp: u64,        // Time of the transaction in nanoseconds since the Unix epoch
+    pub test: bool,            // Issued for a test sale
+}
+
+/// Every receipt issued, oldest first
+///
+/// Receipts live in stable memory keyed by number and are never removed, so their numbers stay
+/// gap-free across upgrades. The index by sale is kept on the heap and saved with the heap state.
+pub struct Receipts {
+    receipts: StableBTreeMap<u64, Receipt, Memory>, // Every receipt by number
+    pub by_sale: HashMap<u64, u64>,                 // Receipt number by sale ID
+    pub linked_from: u64,                           // First receipt whose sales are still in the ledger; older ones predate a restore
+}
+
+impl Receipts {
+    /// Opens the receipts in their stable memory region, keeping any already there
+    pub fn init() -> Self {
+        Receipts { receipts: StableBTreeMap::init(storage::memory(storage::RECEIPTS)), by_sale: HashMap::new(), linked_from: 1 }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.receipts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receipts.is_empty()
+    }
+
+    pub fn get(&self, number: u64) -> Option<Receipt> {
+        self.receipts.get(&number)
+    }
+
+    /// Every receipt from number `from` on, oldest first
+    pub fn iter_from(&self, from: u64) -> impl Iterator<Item = Receipt> + '_ {
+        self.receipts.values_range(from..)
+    }
+
+    /// Unlinks every receipt issued so far from the sales ledger, which a restore has replaced
+    ///
+    /// The receipts themselves are kept; the sale IDs on them now belong to other sales.
+    pub fn unlink_sales(&mut self) {
+        self.by_sale.clear();
+        self.linked_from = self.len() + 1;
+    }
+}
+
+impl SupermarketManager {
+    /// Issues the next receipt for the sales of one transaction and returns its number
+    /// - `cashier`: The principal that recorded the sales
+    pub fn issue_receipt(&mut self, sales: &[Sale], cashier: Principal, now: u64) -> u64 {
+        let number = self.receipts.len() + 1;
+        let channel = sales.first().map_or_else(SalesChannel::default, |sale| sale.channel);
+        let tax_included = self.channels.tax.get(&channel).is_none_or(|tax| tax.prices_include_tax);
+        let lines: Vec<ReceiptLine> = sales
+            .iter()
+            .map(|sale| {
+                let item = self.items.get(&sale.item_id);
+                let item = item.as_ref();
+                let list_price = item.map_or(sale.unit_price, |item| item.price);
+                let list_total = item.map_or(0.0, |item| self.config.round_amount(item.line_total(sale.quantity)));
+                let before_tax = if tax_included { sale.total } else { sale.total - sale.tax };
+                ReceiptLine {
+                    sale_id: sale.id,
+                    item_id: sale.item_id,
+                    name: item.map_or_else(|| format!("Item {}", sale.item_id), |item| item.name.clone()),
+                    quantity: sale.quantity,
+                    unit: item.map_or(Unit::Each, |item| item.unit),
+                    list_price,
+                    unit_price: sale.unit_price,
+                    list_total: list_total.max(before_tax), // A channel price above the item's own is no negative discount
+                    discount: self.config.round_amount((list_total - before_tax).max(0.0)),
+                    total: sale.total,
+                    tax: sale.tax,
+                }
+            })
+            .collect();
+        let sum = |field: fn(&ReceiptLine) -> f64| self.config.round_amount(lines.iter().map(field).sum());
+        let receipt = Receipt {
+            number,
+            subtotal: sum(|line| line.list_total),
+            discount: sum(|line| line.discount),
+            tax: sum(|line| line.tax),
+            tax_included,
+            total: sum(|line| line.total),
+            currency_code: self.config.currency_code.clone(),
+            channel,
+            cashier,
+            timestamp: now,
+            test: sales.iter().any(|sale| sale.test),
+            lines,
+        };
+        for sale in sales {
+            self.receipts.by_sale.insert(sale.id, number);
+        }
+        self.receipts.receipts.insert(number, receipt);
+        number
+    }
+}
+
+/// A receipt row with `left` and `right` pushed to the edges
+fn row(left: &str, right: &str) -> String {
+    let pad = RECEIPT_WIDTH.saturating_sub(left.chars().count() + right.chars().count()).max(1);
+    format!("{}{}{}", left, " ".repeat(pad), right)
+}
+
+fn centred(text: &str) -> String {
+    format!("{:^width$}", text, width = RECEIPT_WIDTH).trim_end().to_string()
+}
+
+/// Lays a receipt out as plain text for a thermal printer
+pub fn render_text(receipt: &Receipt, config: &StoreConfig) -> String {
+    let places = config.decimal_places as usize;
+    let amount = |value: f64| format!("{:.*}", places, value);
+    let offset = UtcOffset::from_whole_seconds(config.utc_offset_minutes * 60).unwrap_or(UtcOffset::UTC);
+    let date = OffsetDateTime::from_unix_timestamp_nanos(receipt.timestamp as i128)
+        .ok()
+        .map(|time| time.to_offset(offset))
+        .map(|time| format!("{}-{:02}-{:02} {:02}:{:02}", time.year(), time.month() as u8, time.day(), time.hour(), time.minute()))
+        .unwrap_or_default();
+
+    let mut rows = vec![
+        centred(&config.store_name.chars().take(RECEIPT_WIDTH).collect::<String>()),
+        centred(&format!("RECEIPT {:06}", receipt.number)),
+        centred(&date),
+    ];
+    if receipt.test {
+        rows.push(centred("*** TEST - NOT A SALE ***"));
+    }
+    rows.push(format!("Cashier: {}", receipt.cashier));
+    rows.push("-".repeat(RECEIPT_WIDTH));
+    for line in &receipt.lines {
+        rows.push(line.name.chars().take(RECEIPT_WIDTH).collect());
+        let quantity = match line.unit {
+            Unit::Kg => format!("  {:.3} kg x {}/kg", line.quantity as f64 / 1000.0, amount(line.list_price)),
+            Unit::Litre => format!("  {:.3} l x {}/l", line.quantity as f64 / 1000.0, amount(line.list_price)),
+            Unit::Each | Unit::Pack { .. } => format!("  {} x {}", line.quantity, amount(line.list_price)),
+        };
+        rows.push(row(&quantity, &amount(line.list_total)));
+        if line.discount > 0.0 {
+            rows.push(row("  Discount", &format!("-{}", amount(line.discount))));
+        }
+    }
+    rows.push("-".repeat(RECEIPT_WIDTH));
+    rows.push(row("Subtotal", &amount(receipt.subtotal)));
+    if receipt.discount > 0.0 {
+        rows.push(row("Discount", &format!("-{}", amount(receipt.discount))));
+    }
+    if receipt.tax_included {
+        rows.push(row("TOTAL", &config.format_amount(receipt.total)));
+        rows.push(row("  incl. tax", &amount(receipt.tax)));
+    } else {
+        rows.push(row("Tax", &amount(receipt.tax)));
+        rows.push(row("TOTAL", &config.format_amount(receipt.total)));
+    }
+    let mut text = rows.join("\n");
+    text.push('\n');
+    text
+}
+
+// Retrieves a receipt by its number.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_receipt(number: u64) -> Result<Receipt, InventoryError> {
+    require_reader("get_receipt", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().receipts.get(number).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Receipt {} not found", number),
+        })
+    })
+}
+
+// Retrieves the number of the receipt a sale was printed on.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_sale_receipt_number(sale_id: u64) -> Result<u64, InventoryError> {
+    require_reader("get_sale_receipt_number", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().receipts.by_sale.get(&sale_id).copied().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Sale {} has no receipt", sale_id),
+        })
+    })
+}
+
+// Renders a receipt as plain text, 42 characters wide, for a thermal printer.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn render_receipt_text(number: u64) -> Result<String, InventoryError> {
+    require_reader("render_receipt_text", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let receipt = inventory.receipts.get(number).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Receipt {} not found", number),
+        })?;
+        Ok(render_text(&receipt, &inventory.config))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sale(id: u64, total: f64) -> Sale {
+        Sale {
+            id,
+            item_id: 7,
+            quantity: 2,
+            unit_price: total / 2.0,
+            total,
+            tax: 0.0,
+            channel: SalesChannel::default(),
+            stock_item_id: 7,
+            stock_units: 2,
+            timestamp: 0,
+            test: false,
+        }
+    }
+
+    #[test]
+    fn receipts_are_numbered_from_one_without_gaps() {
+        let cashier = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        assert_eq!(manager.issue_receipt(&[sale(0, 4.0), sale(1, 6.0)], cashier, 0), 1);
+        assert_eq!(manager.issue_receipt(&[sale(2, 5.0)], cashier, 0), 2);
+
+        let first = manager.receipts.get(1).unwrap();
+        assert_eq!(first.lines.len(), 2);
+        assert_eq!(first.total, 10.0);
+        assert_eq!(first.cashier, cashier);
+        assert_eq!(manager.receipts.by_sale.get(&1), Some(&1));
+        assert_eq!(manager.receipts.by_sale.get(&2), Some(&2));
+        assert!(manager.receipts.get(0).is_none() && manager.receipts.get(3).is_none());
+    }
+
+    #[test]
+    fn unlinked_receipts_are_kept_and_numbering_goes_on() {
+        let cashier = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        manager.issue_receipt(&[sale(0, 4.0)], cashier, 0);
+        manager.receipts.unlink_sales();
+
+        assert!(manager.receipts.by_sale.is_empty());
+        assert_eq!(manager.receipts.linked_from, 2);
+        assert!(manager.receipts.get(1).is_some());
+        assert_eq!(manager.issue_receipt(&[sale(0, 3.0)], cashier, 0), 2);
+        assert_eq!(manager.receipts.by_sale.get(&0), Some(&2));
+    }
+
+    #[test]
+    fn text_layout_shows_the_local_date() {
+        let cashier = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        manager.config.utc_offset_minutes = 90;
+        let number = manager.issue_receipt(&[sale(0, 4.0)], cashier, 1_700_000_000 * 1_000_000_000); // 2023-11-14 22:13 UTC
+        let text = render_text(&manager.receipts.get(number).unwrap(), &manager.config);
+
+        assert!(text.contains("RECEIPT 000001"));
+        assert!(text.contains("2023-11-14 23:43"));
+        assert!(text.lines().all(|line| line.chars().count() <= RECEIPT_WIDTH));
+    }
+}
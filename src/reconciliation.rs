@@ -0,0 +1,329 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Nat, Principal};
+use candid::types::reference::Func;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::flows::{mutate, Flow, FlowKind};
+use crate::payments::{Account, PaymentStatus};
+use crate::ratelimit::rate_limit;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const RECONCILE_INTERVAL_SECS: u64 = 60 * 60;     // How often the ledger is cross-checked
+const PAGE_SIZE: u64 = 2000;                       // Blocks requested per ledger call, the ICRC ledger maximum
+const MAX_BLOCKS_PER_RUN: u64 = 20_000;            // Blocks scanned per run; later runs continue from the cursor
+const SETTLE_NANOS: u64 = 10 * 60 * 1_000_000_000; // Blocks younger than this are left for the next run
+const MAX_REPORTS: usize = 100;                    // Oldest reports are dropped beyond this
+
+/// Arguments of the ICRC ledger `get_transactions` method and its archive callbacks
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+struct GetTransactionsRequest {
+    start: Nat,
+    length: Nat,
+}
+
+/// A transfer as recorded by the ledger; fields this canister does not check are left out
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct LedgerTransfer {
+    from: Account,
+    to: Account,
+    amount: Nat,
+}
+
+/// A ledger transaction; mints, burns and approvals only matter through `kind`
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct LedgerTransaction {
+    kind: String,
+    transfer: Option<LedgerTransfer>,
+    timestamp: u64,
+}
+
+/// Blocks the ledger has moved to an archive canister
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct ArchivedRange {
+    start: Nat,
+    length: Nat,
+    callback: Func,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct GetTransactionsResponse {
+    log_length: Nat,
+    first_index: Nat,
+    transactions: Vec<LedgerTransaction>,
+    archived_transactions: Vec<ArchivedRange>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransactionRange {
+    transactions: Vec<LedgerTransaction>,
+}
+
+/// How a payment and the ledger disagree
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum ReconciliationIssue {
+    MissingOnLedger,                                   // The payment's block does not exist on the ledger
+    NotATransfer { kind: String },                     // The payment's block is a mint, burn or approval
+    PayerMismatch { ledger_from: Account },            // Tokens came from another account than the payment's payer
+    RecipientMismatch { ledger_to: Account },          // Tokens went to another account than this canister's
+    AmountMismatch { ledger_amount: Nat },             // The ledger moved another amount than the payment charged
+    UnrecordedTransfer { from: Account, amount: Nat }, // Tokens reached this canister without a completed payment
+}
+
+/// A payment or ledger block that failed reconciliation
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ReconciliationFinding {
+    pub payment_id: Option<u64>,    // Payment the block belongs to, None for unrecorded transfers
+    pub block_index: u64,           // Ledger block that was checked
+    pub issue: ReconciliationIssue, // What does not match
+}
+
+/// Outcome of one reconciliation run
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub started_at: u64,                      // Start of the run in nanoseconds since the Unix epoch
+    pub ledger_canister_id: Principal,        // Ledger that was checked
+    pub first_block: u64,                     // First block scanned
+    pub blocks_scanned: u64,                  // Settled blocks read from the ledger
+    pub payments_matched: u64,                // Payments whose block matched exactly
+    pub findings: Vec<ReconciliationFinding>, // Missing or mismatched payments
+    pub error: Option<String>,                // Ledger error that cut the run short
+}
+
+/// Reconciliation cursor and recent reports
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Reconciliation {
+    pub cursor: Option<(Principal, u64)>,        // Ledger and the next block to scan on it
+    pub reports: VecDeque<ReconciliationReport>, // Recent reports, oldest first
+    pub running: bool,                           // Whether a run is in progress
+}
+
+/// Whether two accounts are the same, treating a missing subaccount as the all-zero one
+fn same_account(a: &Account, b: &Account) -> bool {
+    let subaccount = |account: &Account| account.subaccount.clone().filter(|s| s.iter().any(|&byte| byte != 0));
+    a.owner == b.owner && subaccount(a) == subaccount(b)
+}
+
+fn nat_to_u64(n: &Nat) -> u64 {
+    u64::try_from(&n.0).unwrap_or(u64::MAX)
+}
+
+/// Reads up to `length` blocks from `start`, following archive callbacks for old blocks
+///
+/// Returns the ledger length and the blocks found as (index, transaction), in order.
+async fn fetch_blocks(ledger: Principal, start: u64, length: u64) -> Result<(u64, Vec<(u64, LedgerTransaction)>), String> {
+    let request = GetTransactionsRequest { start: Nat::from(start), length: Nat::from(length) };
+    let (response,): (GetTransactionsResponse,) = ic_cdk::call(ledger, "get_transactions", (request,))
+        .await
+        .map_err(|(code, msg)| format!("get_transactions failed: {:?} {}", code, msg))?;
+
+    let mut blocks = Vec::new();
+    for range in response.archived_transactions {
+        let range_start = nat_to_u64(&range.start);
+        let request = GetTransactionsRequest { start: range.start, length: range.length };
+        let (archived,): (TransactionRange,) = ic_cdk::call(range.callback.principal, &range.callback.method, (request,))
+            .await
+            .map_err(|(code, msg)| format!("Archive {} failed: {:?} {}", range.callback.principal, code, msg))?;
+        blocks.extend((range_start..).zip(archived.transactions));
+    }
+    blocks.extend((nat_to_u64(&response.first_index)..).zip(response.transactions));
+    blocks.sort_by_key(|(index, _)| *index);
+    Ok((nat_to_u64(&response.log_length), blocks))
+}
+
+/// What a completed payment should look like on the ledger
+struct ExpectedPayment {
+    payment_id: u64,
+    payer: Account,
+    amount: Nat,
+}
+
+impl SupermarketManager {
+    /// Payments on a ledger whose tokens were taken, keyed by block index
+    fn expected_payments(&self, ledger: Principal) -> BTreeMap<u64, ExpectedPayment> {
+        self.payments.records
+            .iter()
+            .filter(|p| p.ledger_canister_id == ledger && !matches!(p.status, PaymentStatus::Failed { .. }))
+            .filter_map(|p| {
+                let block_index = nat_to_u64(p.block_index.as_ref()?);
+                Some((block_index, ExpectedPayment { payment_id: p.id, payer: p.payer.clone(), amount: p.amount.clone() }))
+            })
+            .collect()
+    }
+
+    /// Stores a finished run, moves the cursor on and alerts on every finding
+    fn finish_reconciliation(&mut self, report: ReconciliationReport, next_block: u64) {
+        self.reconciliation.running = false;
+        self.reconciliation.cursor = Some((report.ledger_canister_id, next_block));
+        for finding in &report.findings {
+            self.notify_webhooks(WebhookEvent::PaymentDiscrepancy {
+                payment_id: finding.payment_id,
+                block_index: finding.block_index,
+                issue: format!("{:?}", finding.issue),
+            });
+        }
+        let log = format!(
+            "Reconciled {} ledger blocks from {}: {} payments matched, {} discrepancies at {}",
+            report.blocks_scanned,
+            report.first_block,
+            report.payments_matched,
+            report.findings.len(),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        self.reconciliation.reports.push_back(report);
+        while self.reconciliation.reports.len() > MAX_REPORTS {
+            self.reconciliation.reports.pop_front();
+        }
+    }
+}
+
+/// Compares a settled ledger block with the payment recorded for it, if any
+fn check_block(transaction: &LedgerTransaction, expected: Option<&ExpectedPayment>, store: &Account) -> Option<ReconciliationIssue> {
+    let Some(transfer) = transaction.transfer.as_ref() else {
+        return expected.map(|_| ReconciliationIssue::NotATransfer { kind: transaction.kind.clone() });
+    };
+    let Some(expected) = expected else {
+        // Transfers into the store's account that no completed payment accounts for
+        return same_account(&transfer.to, store).then(|| ReconciliationIssue::UnrecordedTransfer {
+            from: transfer.from.clone(),
+            amount: transfer.amount.clone(),
+        });
+    };
+    if !same_account(&transfer.from, &expected.payer) {
+        Some(ReconciliationIssue::PayerMismatch { ledger_from: transfer.from.clone() })
+    } else if !same_account(&transfer.to, store) {
+        Some(ReconciliationIssue::RecipientMismatch { ledger_to: transfer.to.clone() })
+    } else if transfer.amount != expected.amount {
+        Some(ReconciliationIssue::AmountMismatch { ledger_amount: transfer.amount.clone() })
+    } else {
+        None
+    }
+}
+
+/// Scans the ledger from the cursor and cross-checks every block against recorded payments
+///
+/// Each run reads settled blocks only, so a checkout whose transfer is still being recorded is
+/// never reported as unrecorded. The first run starts at the earliest recorded payment.
+async fn reconcile() -> Result<ReconciliationReport, InventoryError> {
+    let now = ic_cdk::api::time();
+    let (ledger, expected, first_block, flow) = mutate(|inventory| {
+        let ledger = inventory.payments.config.as_ref().map(|config| config.ledger_canister_id).ok_or_else(|| {
+            InventoryError::InvalidInput { msg: "Token payments are not configured".to_string() }
+        })?;
+        if inventory.reconciliation.running {
+            return Err(InventoryError::InvalidInput { msg: "A reconciliation run is already in progress".to_string() });
+        }
+        let expected = inventory.expected_payments(ledger);
+        let first_block = match inventory.reconciliation.cursor {
+            Some((cursor_ledger, next)) if cursor_ledger == ledger => next,
+            _ => expected.keys().next().copied().unwrap_or(0), // A new ledger starts at its first payment
+        };
+        let flow = Flow::begin(inventory, FlowKind::Reconciliation, ic_cdk::caller(), now)?;
+        inventory.reconciliation.running = true;
+        Ok((ledger, expected, first_block, flow))
+    })?;
+
+    let store = Account { owner: ic_cdk::id(), subaccount: None };
+    let mut report = ReconciliationReport {
+        started_at: now,
+        ledger_canister_id: ledger,
+        first_block,
+        blocks_scanned: 0,
+        payments_matched: 0,
+        findings: Vec::new(),
+        error: None,
+    };
+    let mut next_block = first_block;
+    'scan: while report.blocks_scanned < MAX_BLOCKS_PER_RUN {
+        let length = PAGE_SIZE.min(MAX_BLOCKS_PER_RUN - report.blocks_scanned);
+        let (log_length, blocks) = match fetch_blocks(ledger, next_block, length).await {
+            Ok(result) => result,
+            Err(error) => {
+                report.error = Some(error);
+                break;
+            }
+        };
+        for (index, transaction) in &blocks {
+            if *index != next_block || now.saturating_sub(transaction.timestamp) < SETTLE_NANOS {
+                break 'scan; // A gap or an unsettled block; the next run picks up from here
+            }
+            let payment = expected.get(index);
+            match check_block(transaction, payment, &store) {
+                Some(issue) => report.findings.push(ReconciliationFinding {
+                    payment_id: payment.map(|p| p.payment_id),
+                    block_index: *index,
+                    issue,
+                }),
+                None if payment.is_some() => report.payments_matched += 1,
+                None => {}
+            }
+            report.blocks_scanned += 1;
+            next_block += 1;
+        }
+        if next_block >= log_length {
+            // Payments pointing past the end of the ledger can never match
+            for (&index, payment) in expected.range(log_length..) {
+                report.findings.push(ReconciliationFinding {
+                    payment_id: Some(payment.payment_id),
+                    block_index: index,
+                    issue: ReconciliationIssue::MissingOnLedger,
+                });
+            }
+            break;
+        }
+        if blocks.is_empty() {
+            break;
+        }
+    }
+
+    flow.finish(|inventory| inventory.finish_reconciliation(report.clone(), next_block));
+    Ok(report)
+}
+
+/// Registers the timer that reconciles payments against the ledger
+pub fn start_reconciliation_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(RECONCILE_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            let _ = reconcile().await; // Failures are kept in the report; an unconfigured ledger is skipped
+        });
+    });
+}
+
+// Cross-checks recorded token payments against the ledger straight away instead of waiting for
+// the hourly job. Each run continues from where the previous one stopped.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+async fn run_reconciliation() -> Result<ReconciliationReport, InventoryError> {
+    require_caller("run_reconciliation", Role::Manager)?;
+    reconcile().await
+}
+
+// Rescans the ledger from a given block on the next run, e.g. after a payment was corrected.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn reset_reconciliation_cursor(block_index: u64) -> Result<(), InventoryError> {
+    require_caller("reset_reconciliation_cursor", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let ledger = inventory.payments.config.as_ref().map(|config| config.ledger_canister_id).ok_or_else(|| {
+            InventoryError::InvalidInput { msg: "Token payments are not configured".to_string() }
+        })?;
+        inventory.reconciliation.cursor = Some((ledger, block_index));
+        Ok(())
+    })
+}
+
+// Retrieves recent reconciliation reports, newest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_reconciliation_reports() -> Result<Vec<ReconciliationReport>, InventoryError> {
+    require_reader("get_reconciliation_reports", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().reconciliation.reports.iter().rev().cloned().collect())
+    })
+}
@@ -0,0 +1,143 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::cost::{measured, HeavyOperation};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const VELOCITY_WINDOW_DAYS: u64 = 7; // Sales history used to measure how fast an item moves
+const COVER_DAYS: u64 = 7;           // Days of expected demand a reorder should cover on top of the target level
+
+/// Reorder settings for a single item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ReorderRule {
+    pub threshold: u32,    // Stock level at or below which the item should be reordered
+    pub target_level: u32, // Stock level a reorder should bring the item back up to
+    pub supplier_id: u32,  // Supplier the item is ordered from
+}
+
+/// A proposed purchase order line produced by the daily reorder job
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ReorderSuggestion {
+    pub id: u64,            // Unique ID of the suggestion
+    pub item_id: u32,       // Item that should be reordered
+    pub suggested_qty: u32, // Number of units to order
+    pub supplier_id: u32,   // Supplier to order from
+    pub created_at: u64,    // Time the suggestion was produced in nanoseconds since the Unix epoch
+}
+
+/// Reorder rules per item and the open suggestions generated from them
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ReorderPlanner {
+    pub rules: HashMap<u32, ReorderRule>,                // Reorder rules keyed by item ID
+    pub suggestions: BTreeMap<u64, ReorderSuggestion>,   // Open suggestions keyed by suggestion ID
+    pub next_suggestion_id: u64,                         // ID handed to the next new suggestion
+    pub last_run: Option<u64>,                           // Time the reorder job last ran
+}
+
+impl SupermarketManager {
+    /// Examines every item with a reorder rule and refreshes the open suggestions
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    ///
+    /// An item is suggested when its stock is at or below the threshold, or when it would
+    /// run out within `COVER_DAYS` at its recent sales velocity. An item keeps at most one
+    /// open suggestion, which is updated in place on later runs.
+    pub fn run_reorder_job(&mut self, now: u64) {
+        for (&item_id, rule) in &self.reorder.rules {
+            let Some(item) = self.items.get(&item_id).filter(|item| !item.archived) else {
+                continue; // Rules for removed or archived items are ignored
+            };
+            let sold = self.velocity.velocity(item_id, now).units_last_7_days;
+            let expected_demand = (sold * COVER_DAYS).div_ceil(VELOCITY_WINDOW_DAYS);
+            let on_hand = item.quantity as u64;
+            if on_hand > rule.threshold as u64 && on_hand >= expected_demand {
+                continue; // Enough stock to cover the coming days
+            }
+            let wanted = (rule.target_level as u64 + expected_demand).saturating_sub(on_hand);
+            if wanted == 0 {
+                continue;
+            }
+            let suggested_qty = wanted.min(u32::MAX as u64) as u32;
+
+            let existing = self.reorder.suggestions.values_mut().find(|s| s.item_id == item_id);
+            if let Some(suggestion) = existing {
+                suggestion.suggested_qty = suggested_qty;
+                suggestion.supplier_id = rule.supplier_id;
+                suggestion.created_at = now;
+            } else {
+                let id = self.reorder.next_suggestion_id;
+                self.reorder.next_suggestion_id += 1;
+                self.reorder.suggestions.insert(id, ReorderSuggestion {
+                    id,
+                    item_id,
+                    suggested_qty,
+                    supplier_id: rule.supplier_id,
+                    created_at: now,
+                });
+            }
+        }
+        self.reorder.last_run = Some(now);
+    }
+}
+
+/// Registers the daily reorder job with the canister timer
+pub fn start_reorder_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_nanos(NANOS_PER_DAY), || {
+        measured(HeavyOperation::ReorderJob, || {
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().run_reorder_job(ic_cdk::api::time());
+            });
+        });
+    });
+}
+
+// Sets the reorder threshold, target level and supplier for an item.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_reorder_rule(item_id: u32, threshold: u32, target_level: u32, supplier_id: u32) -> Result<(), InventoryError> {
+    require_caller("set_reorder_rule", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let Some(item) = inventory.items.get(&item_id) else {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        };
+        Validator::new()
+            .quantity("threshold", threshold, item.unit)
+            .quantity("target_level", target_level, item.unit)
+            .check(target_level > threshold, "target_level", "must be above threshold")
+            .finish()?;
+        inventory.recount_item(item_id, |inventory| {
+            inventory.reorder.rules.insert(item_id, ReorderRule { threshold, target_level, supplier_id });
+        });
+        Ok(())
+    })
+}
+
+// Retrieves the open reorder suggestions.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_reorder_suggestions() -> Result<Vec<ReorderSuggestion>, InventoryError> {
+    require_reader("get_reorder_suggestions", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().reorder.suggestions.values().cloned().collect())
+    })
+}
+
+// Dismisses a reorder suggestion once it has been ordered or rejected.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn dismiss_suggestion(id: u64) -> Result<(), InventoryError> {
+    require_caller("dismiss_suggestion", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().reorder.suggestions.remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("Suggestion {} not found", id) })
+    })
+}
@@ -0,0 +1,159 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+
+use crate::access::{require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::idempotency::run_once;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{AdjustmentReason, InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// Units of a sale brought back by the customer and refunded
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct SaleReturn {
+    pub id: u64,
+    pub sale_id: u64,
+    pub item_id: u32,
+    pub quantity: u32,     // Units of the sale returned, in grams or millilitres for weighed goods
+    pub refund: f64,       // Share of the sale's total paid back, including tax
+    pub net_refund: f64,   // The refund less its tax, which revenue and royalty figures deduct
+    pub restocked: bool,   // Whether the units went back into stock
+    pub recorded_by: Principal,
+    pub timestamp: u64,    // Time of the return in nanoseconds since the Unix epoch
+}
+
+/// Every return, oldest first
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ReturnsLedger {
+    pub entries: Vec<SaleReturn>, // Entry `n` has ID `n`
+}
+
+impl ReturnsLedger {
+    /// Units of a sale already returned
+    fn returned(&self, sale_id: u64) -> u32 {
+        self.entries.iter().filter(|entry| entry.sale_id == sale_id).map(|entry| entry.quantity).sum()
+    }
+}
+
+impl SupermarketManager {
+    /// Refunds units of a sale, putting them back into stock when `restock` is set
+    pub fn record_return(&mut self, sale_id: u64, quantity: u32, restock: bool, recorded_by: Principal, now: u64) -> Result<SaleReturn, InventoryError> {
+        let sale = self.sales.get(sale_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Sale {} not found", sale_id),
+        })?;
+        let returnable = sale.quantity.saturating_sub(self.returns.returned(sale_id));
+        Validator::new()
+            .check(quantity > 0, "quantity", "must be positive")
+            .check(quantity <= returnable, "quantity", format!("must be at most the {} units not yet returned", returnable))
+            .check(
+                !restock || !self.bundles.definitions.contains_key(&sale.stock_item_id),
+                "restock",
+                "is not possible for a bundle; put its components back by hand",
+            )
+            .finish()?;
+        let share = quantity as f64 / sale.quantity as f64;
+        let entry = SaleReturn {
+            id: self.returns.entries.len() as u64,
+            sale_id,
+            item_id: sale.item_id,
+            quantity,
+            refund: self.config.round_amount(sale.total * share),
+            net_refund: self.config.round_amount(sale.net_total() * share),
+            restocked: restock,
+            recorded_by,
+            timestamp: now,
+        };
+        self.returns.entries.push(entry.clone());
+        self.refund_from_shift(recorded_by, &entry);
+        let log = format!(
+            "Sale {} had {} units returned for {} at {}",
+            sale_id,
+            quantity,
+            self.config.format_amount(entry.refund),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        if restock {
+            if let Some(stock) = self.items.get(&sale.stock_item_id) {
+                let units = (sale.stock_units as f64 * share).round() as u32; // Packs return their base item's units
+                self.adjust_item_quantity(stock.id, stock.quantity.saturating_add(units), AdjustmentReason::Returned);
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Returns recorded in `from..to`
+    pub fn returns_between(&self, from: u64, to: u64) -> impl Iterator<Item = &SaleReturn> {
+        self.returns.entries.iter().filter(move |entry| (from..to).contains(&entry.timestamp))
+    }
+}
+
+// Refunds units of a sale, e.g. a faulty product brought back to the till. `restock` puts the
+// units back into stock; leave it unset for goods that cannot be sold again.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn record_return(sale_id: u64, quantity: u32, restock: bool, idempotency_key: Option<String>) -> Result<SaleReturn, InventoryError> {
+    require_permission("record_return", Permission::RecordSales)?;
+    run_once("record_return", idempotency_key, || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().record_return(sale_id, quantity, restock, ic_cdk::caller(), ic_cdk::api::time())
+        })
+    })
+}
+
+// Retrieves the returns recorded from `from` up to `to`, in nanoseconds since the Unix epoch.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_returns(from: u64, to: u64) -> Result<Vec<SaleReturn>, InventoryError> {
+    require_reader("get_returns", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().returns_between(from, to).cloned().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::SalesChannel;
+    use crate::sales::Sale;
+
+    fn returned(id: u64, sale_id: u64, quantity: u32) -> SaleReturn {
+        SaleReturn {
+            id,
+            sale_id,
+            item_id: 1,
+            quantity,
+            refund: 0.0,
+            net_refund: 0.0,
+            restocked: false,
+            recorded_by: Principal::anonymous(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn returns_are_limited_to_the_units_not_yet_returned() {
+        let mut manager = SupermarketManager::new();
+        manager.sales.extend(vec![Sale {
+            id: 0,
+            item_id: 1,
+            quantity: 2,
+            unit_price: 1.0,
+            total: 2.0,
+            tax: 0.0,
+            channel: SalesChannel::default(),
+            stock_item_id: 1,
+            stock_units: 2,
+            timestamp: 0,
+            test: false,
+        }]);
+        let err = manager.record_return(0, 3, false, Principal::anonymous(), 0).unwrap_err();
+        assert!(matches!(err, InventoryError::Validation { .. }));
+
+        // More units returned than sold, as left behind by a ledger replaced under its returns
+        manager.returns.entries = vec![returned(0, 0, 2), returned(1, 0, 1)];
+        let err = manager.record_return(0, 1, false, Principal::anonymous(), 0).unwrap_err();
+        assert!(matches!(err, InventoryError::Validation { .. }));
+        assert_eq!(manager.returns.entries.len(), 2);
+    }
+}
@@ -0,0 +1,337 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use time::{Date, Month};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::chain::{require_head_office, StoreReply};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_TIERS: usize = 10;                // Bands one royalty formula can have
+const UNIX_EPOCH_JULIAN_DAY: i32 = 2440588; // Julian day number of 1 January 1970
+const STATEMENT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// A band of the royalty base charged at its own rate
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RoyaltyTier {
+    pub above: f64,    // The part of the base above this amount, up to the next tier, is charged at `rate_pct`
+    pub rate_pct: f64, // Percentage of that part owed to the franchisor
+}
+
+/// How the franchisor's royalty is worked out from a month's royalty base
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RoyaltyFormula {
+    pub tiers: Vec<RoyaltyTier>, // In ascending order of `above`, the first usually above 0
+    pub fixed_fee: f64,          // Owed every month on top of the tiered royalty
+    pub minimum_fee: f64,        // The least owed for a month, whatever the base
+}
+
+impl RoyaltyFormula {
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        let mut validator = Validator::new();
+        validator
+            .check(self.tiers.len() <= MAX_TIERS, "tiers", format!("must have at most {} entries", MAX_TIERS))
+            .check(self.fixed_fee.is_finite() && self.fixed_fee >= 0.0, "fixed_fee", "must not be negative")
+            .check(self.minimum_fee.is_finite() && self.minimum_fee >= 0.0, "minimum_fee", "must not be negative");
+        for (i, tier) in self.tiers.iter().enumerate() {
+            validator
+                .check(tier.above.is_finite() && tier.above >= 0.0, &format!("tiers[{}].above", i), "must not be negative")
+                .check(
+                    i == 0 || tier.above > self.tiers[i - 1].above,
+                    &format!("tiers[{}].above", i),
+                    "must be above the previous tier",
+                )
+                .check(
+                    tier.rate_pct.is_finite() && (0.0..=100.0).contains(&tier.rate_pct),
+                    &format!("tiers[{}].rate_pct", i),
+                    "must be between 0 and 100",
+                );
+        }
+        validator.finish()
+    }
+
+    /// Royalty owed on a month's royalty base, before rounding
+    pub fn royalty(&self, base: f64) -> f64 {
+        let base = base.max(0.0);
+        let tiered: f64 = self
+            .tiers
+            .iter()
+            .enumerate()
+            .map(|(i, tier)| {
+                let ceiling = self.tiers.get(i + 1).map_or(f64::INFINITY, |next| next.above);
+                (base.min(ceiling) - tier.above).max(0.0) * tier.rate_pct / 100.0
+            })
+            .sum();
+        (tiered + self.fixed_fee).max(self.minimum_fee)
+    }
+}
+
+/// A month's royalty, fixed once generated
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RoyaltyStatement {
+    pub year: i32,
+    pub month: u8,               // 1 for January
+    pub from: u64,               // Local midnight starting the month, in nanoseconds since the Unix epoch
+    pub to: u64,                 // Local midnight starting the next month
+    pub net_sales: f64,          // Takings after tax, test sales left out
+    pub returns: f64,            // Refunds after tax of returns recorded in the month
+    pub royalty_base: f64,       // Net sales less returns
+    pub royalty_due: f64,        // Owed to the franchisor under `formula`
+    pub formula: RoyaltyFormula, // The formula in force when the statement was generated
+    pub sales_count: u64,
+    pub returns_count: u64,
+    pub currency_code: String,
+    pub generated_at: u64,
+}
+
+/// One store's statement for a month, as collected by head office
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreRoyaltyStatement {
+    pub store: Principal,
+    pub name: String,
+    pub statement: Option<RoyaltyStatement>, // None when the store has no statement for the month or could not be asked
+    pub error: Option<String>,               // Why the statement could not be collected
+}
+
+/// The franchise's royalty formula and the monthly statements generated under it
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Royalties {
+    pub formula: Option<RoyaltyFormula>,                   // None until the franchisor or owner sets one
+    pub statements: BTreeMap<(i32, u8), RoyaltyStatement>, // Statements by (year, month); never changed once generated
+}
+
+/// The month before the given one
+fn previous_month(year: i32, month: u8) -> (i32, u8) {
+    if month == 1 { (year - 1, 12) } else { (year, month - 1) }
+}
+
+impl SupermarketManager {
+    /// Time local midnight starts the first day of a month, in nanoseconds since the Unix epoch
+    fn month_start(&self, year: i32, month: u8) -> Result<u64, InventoryError> {
+        let first = Month::try_from(month)
+            .and_then(|month| Date::from_calendar_date(year, month, 1))
+            .map_err(|_| InventoryError::InvalidInput { msg: format!("{}-{:02} is not a valid month", year, month) })?;
+        let day = (first.to_julian_day() - UNIX_EPOCH_JULIAN_DAY).max(0) as u64;
+        Ok(self.config.local_day_start(day))
+    }
+
+    /// The (year, month) a time falls in, in the store's timezone
+    fn local_month(&self, timestamp: u64) -> (i32, u8) {
+        let day = self.config.local_day(timestamp) as i32 + UNIX_EPOCH_JULIAN_DAY;
+        let date = Date::from_julian_day(day).expect("local days are within the supported range");
+        (date.year(), date.month() as u8)
+    }
+
+    /// Sets the formula royalties are worked out with
+    /// - `by`: The principal the change is logged as coming from
+    pub fn set_royalty_formula(&mut self, formula: RoyaltyFormula, by: Principal) {
+        self.royalties.formula = Some(formula);
+        let log = format!("Royalty formula set by {} at {}", by, SupermarketManager::get_current_time());
+        self.logs.push(log);
+    }
+
+    /// Works out and locks the royalty statement for a month that has ended
+    pub fn generate_royalty_statement(&mut self, year: i32, month: u8, now: u64) -> Result<RoyaltyStatement, InventoryError> {
+        let formula = self.royalties.formula.clone().ok_or_else(|| InventoryError::InvalidInput {
+            msg: "No royalty formula has been set".to_string(),
+        })?;
+        let from = self.month_start(year, month)?;
+        let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+        let to = self.month_start(next_year, next_month)?;
+        if to > now {
+            return Err(InventoryError::InvalidInput { msg: format!("{}-{:02} has not ended yet", year, month) });
+        }
+        if self.royalties.statements.contains_key(&(year, month)) {
+            return Err(InventoryError::Conflict { msg: format!("The statement for {}-{:02} is already locked", year, month) });
+        }
+
+        let (mut net_sales, mut sales_count) = (0.0, 0);
+        for sale in self.sales.iter_from(self.sales.first_since(from)).take_while(|sale| sale.timestamp < to) {
+            if !sale.test {
+                net_sales += sale.net_total();
+                sales_count += 1;
+            }
+        }
+        let (mut returns, mut returns_count) = (0.0, 0);
+        for entry in self.returns_between(from, to) {
+            if !self.sales.get(entry.sale_id).is_some_and(|sale| sale.test) {
+                returns += entry.net_refund;
+                returns_count += 1;
+            }
+        }
+        let net_sales = self.config.round_amount(net_sales);
+        let returns = self.config.round_amount(returns);
+        let royalty_base = self.config.round_amount(net_sales - returns);
+        let statement = RoyaltyStatement {
+            year,
+            month,
+            from,
+            to,
+            net_sales,
+            returns,
+            royalty_base,
+            royalty_due: self.config.round_amount(formula.royalty(royalty_base)),
+            formula,
+            sales_count,
+            returns_count,
+            currency_code: self.config.currency_code.clone(),
+            generated_at: now,
+        };
+        self.royalties.statements.insert((year, month), statement.clone());
+        let log = format!(
+            "Royalty statement for {}-{:02} locked with {} due at {}",
+            year,
+            month,
+            self.config.format_amount(statement.royalty_due),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(statement)
+    }
+
+    /// Generates the statement for the month just ended, if a formula is set and it has not been
+    /// generated yet
+    pub fn generate_due_royalty_statement(&mut self, now: u64) {
+        if self.royalties.formula.is_none() {
+            return;
+        }
+        let (year, month) = self.local_month(now);
+        let (year, month) = previous_month(year, month);
+        if !self.royalties.statements.contains_key(&(year, month)) {
+            if let Err(error) = self.generate_royalty_statement(year, month, now) {
+                let log = format!("Royalty statement for {}-{:02} failed: {:?} at {}", year, month, error, SupermarketManager::get_current_time());
+                self.logs.push(log);
+            }
+        }
+    }
+}
+
+/// Locks each month's royalty statement once the month has ended
+pub fn start_royalty_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(STATEMENT_INTERVAL_SECS), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().generate_due_royalty_statement(ic_cdk::api::time());
+        });
+    });
+}
+
+/// Checks the caller is the franchisor, i.e. this store's head office, or a store role
+fn require_franchise_party(endpoint: &str, role: Role) -> Result<(), InventoryError> {
+    if require_head_office().is_err() {
+        require_reader(endpoint, role)?;
+    }
+    Ok(())
+}
+
+// Sets the formula royalties are worked out with. Only the store's owner or its head office
+// may change it; statements already generated keep the formula they were generated under. Once a
+// governance canister is set, this takes an executed `SetRoyaltyFormula` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_royalty_formula(formula: RoyaltyFormula) -> Result<(), InventoryError> {
+    if require_head_office().is_err() {
+        require_caller("set_royalty_formula", Role::Owner)?;
+    }
+    formula.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_royalty_formula(formula, ic_cdk::caller());
+        Ok(())
+    })
+}
+
+// Retrieves the formula royalties are worked out with, if one has been set.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_royalty_formula() -> Result<Option<RoyaltyFormula>, InventoryError> {
+    require_franchise_party("get_royalty_formula", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().royalties.formula.clone()))
+}
+
+// Generates and locks the royalty statement for a month that has ended, ahead of the daily
+// timer. A month's statement can only be generated once.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn generate_royalty_statement(year: i32, month: u8) -> Result<RoyaltyStatement, InventoryError> {
+    if require_head_office().is_err() {
+        require_caller("generate_royalty_statement", Role::Manager)?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().generate_royalty_statement(year, month, ic_cdk::api::time())
+    })
+}
+
+// Retrieves the locked royalty statement for a month. Both the franchisee's managers and the
+// franchisor's head office canister may read it.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_royalty_statement(year: i32, month: u8) -> Result<RoyaltyStatement, InventoryError> {
+    require_franchise_party("get_royalty_statement", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().royalties.statements.get(&(year, month)).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("No royalty statement for {}-{:02}", year, month),
+        })
+    })
+}
+
+// Retrieves every locked royalty statement, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_royalty_statements() -> Result<Vec<RoyaltyStatement>, InventoryError> {
+    require_franchise_party("list_royalty_statements", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().royalties.statements.values().cloned().collect()))
+}
+
+// Collects every registered store's royalty statement for a month. Stores that have no
+// statement for it, or cannot be reached, are reported with an error rather than failing the call.
+// This function is marked as `#[update]` because it calls other canisters.
+#[update(guard = "rate_limit")]
+async fn collect_royalty_statements(year: i32, month: u8) -> Result<Vec<StoreRoyaltyStatement>, InventoryError> {
+    require_caller("collect_royalty_statements", Role::Manager)?;
+    let stores: Vec<(Principal, String)> = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().chain.stores.iter().map(|(store, name)| (*store, name.clone())).collect()
+    });
+    if stores.is_empty() {
+        return Err(InventoryError::InvalidInput { msg: "No stores are registered with this head office".to_string() });
+    }
+    let mut collected = Vec::with_capacity(stores.len());
+    for (store, name) in stores {
+        let result: StoreReply<RoyaltyStatement> = ic_cdk::call(store, "get_royalty_statement", (year, month)).await;
+        let (statement, error) = match result {
+            Ok((Ok(statement),)) => (Some(statement), None),
+            Ok((Err(error),)) => (None, Some(format!("Store refused: {:?}", error))),
+            Err((code, msg)) => (None, Some(format!("Store call failed: {:?} {}", code, msg))),
+        };
+        collected.push(StoreRoyaltyStatement { store, name, statement, error });
+    }
+    Ok(collected)
+}
+
+// Sends a royalty formula to a registered store, which takes it as coming from its head office.
+// This function is marked as `#[update]` because it calls other canisters.
+#[update(guard = "rate_limit")]
+async fn send_royalty_formula(store: Principal, formula: RoyaltyFormula) -> Result<(), InventoryError> {
+    require_caller("send_royalty_formula", Role::Owner)?;
+    formula.validate()?;
+    let registered = INVENTORY_MANAGER.with(|inventory| inventory.borrow().chain.stores.contains_key(&store));
+    if !registered {
+        return Err(InventoryError::NotFound { msg: format!("Store {} is not registered with this head office", store) });
+    }
+    let result: StoreReply<()> = ic_cdk::call(store, "set_royalty_formula", (formula,)).await;
+    match result {
+        Ok((Ok(()),)) => {}
+        Ok((Err(error),)) => return Err(InventoryError::CallFailed { msg: format!("Store refused: {:?}", error) }),
+        Err((code, msg)) => return Err(InventoryError::CallFailed { msg: format!("Store call failed: {:?} {}", code, msg) }),
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let log = format!("Royalty formula sent to store {} at {}", store, SupermarketManager::get_current_time());
+        inventory.borrow_mut().logs.push(log);
+    });
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use ic_stable_structures::StableBTreeMap;
+use std::collections::HashMap;
+
+use crate::access::{require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::events::InventoryEventPayload;
+use crate::history::ItemHistoryEvent;
+use crate::idempotency::run_once;
+use crate::journal::JournalEvent;
+use crate::load::{load_degraded, DEGRADED_HISTORY_LIMIT};
+use crate::ratelimit::rate_limit;
+use crate::shifts::Tender;
+use crate::storage::{self, Memory};
+use crate::validation::Validator;
+use crate::usage::metered;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_PAGE_SALES: u32 = 1000; // Most sales `get_sales` returns per call
+
+/// A single sale of one item, recorded when stock leaves the shelf through the till
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Sale {
+    pub id: u64,               // Sequential ID of the sale
+    pub item_id: u32,          // ID of the item sold
+    pub quantity: u32,         // Number of units sold, in grams or millilitres for weighed goods
+    pub unit_price: f64,       // Channel price per unit (per kilogram or litre for weighed goods) at the time of sale
+    pub total: f64,            // Amount charged for the whole line, including tax
+    pub tax: f64,              // Tax contained in the total
+    pub channel: SalesChannel, // Where the sale was made
+    pub stock_item_id: u32,    // Item the stock was taken from; the base item when a pack is sold, the bundle itself for a bundle
+    pub stock_units: u32,      // Units taken from the stock item; for a bundle, the bundles sold
+    pub timestamp: u64,        // Time of the sale in nanoseconds since the Unix epoch
+    pub test: bool,            // Recorded by an integration test; left out of reports by default
+}
+
+impl Sale {
+    /// Takings from the line after tax, which revenue reports count
+    pub fn net_total(&self) -> f64 {
+        self.total - self.tax
+    }
+}
+
+/// A page of the sales ledger
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct SalesPage {
+    pub entries: Vec<Sale>, // Sales from the requested ID, oldest first
+    pub next_offset: u64,   // ID to read the next page from; test sales left out still move it on
+    pub next_id: u64,       // ID the next sale will get
+}
+
+/// Append-only ledger of sales
+///
+/// Sales live in stable memory keyed by ID, which is also their position in the ledger, so
+/// years of trading do not have to fit on the heap.
+pub struct SalesLedger {
+    entries: StableBTreeMap<u64, Sale, Memory>, // Every sale in the order it was recorded
+}
+
+impl SalesLedger {
+    /// Opens the ledger in its stable memory region, keeping any sales already there
+    pub fn init() -> Self {
+        SalesLedger { entries: StableBTreeMap::init(storage::memory(storage::SALES)) }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Sale> {
+        self.entries.get(&id)
+    }
+
+    /// Every sale from ID `from` on, in the order they were recorded
+    pub fn iter_from(&self, from: u64) -> impl DoubleEndedIterator<Item = Sale> + '_ {
+        self.entries.values_range(from..)
+    }
+
+    /// ID of the first sale made at or after `timestamp`; the ledger is in time order
+    pub fn first_since(&self, timestamp: u64) -> u64 {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.get(mid) {
+                Some(sale) if sale.timestamp < timestamp => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+        low
+    }
+
+    fn push(&mut self, sale: Sale) {
+        debug_assert_eq!(sale.id, self.len());
+        self.entries.insert(sale.id, sale);
+    }
+
+    /// Drops every sale from ID `len` on, e.g. to apply a differential snapshot
+    pub fn truncate(&mut self, len: u64) {
+        while self.entries.last_key_value().is_some_and(|(id, _)| id >= len) {
+            self.entries.pop_last();
+        }
+    }
+
+    /// Appends sales recorded elsewhere, e.g. by the store a snapshot was taken from
+    pub fn extend(&mut self, sales: Vec<Sale>) {
+        for sale in sales {
+            self.entries.insert(sale.id, sale);
+        }
+    }
+
+    /// Moves every sale of an item, or of its stock, onto another item, returning how many sales changed
+    pub fn repoint_item(&mut self, from: u32, to: u32) -> u64 {
+        let affected: Vec<Sale> = self.entries.values().filter(|sale| sale.item_id == from || sale.stock_item_id == from).collect();
+        for mut sale in affected.iter().cloned() {
+            if sale.item_id == from {
+                sale.item_id = to;
+            }
+            if sale.stock_item_id == from {
+                sale.stock_item_id = to;
+            }
+            self.entries.insert(sale.id, sale);
+        }
+        affected.len() as u64
+    }
+
+    /// Replaces every sale, e.g. when a snapshot is restored
+    pub fn replace(&mut self, sales: Vec<Sale>) {
+        self.entries.clear_new();
+        self.extend(sales);
+    }
+}
+
+impl SupermarketManager {
+    /// Sells units of an item, decrementing its stock, recording the sale in the ledger and
+    /// issuing its receipt; the sale counts as paid in cash
+    /// - `item_id`: The ID of the item being sold
+    /// - `quantity`: The number of units sold
+    /// - `test`: Whether the sale is a test transaction; stock still moves, but reports leave it out
+    /// - `channel`: Where the sale was made, which sets its price and tax
+    /// - `customer_id`: Loyalty customer buying, who earns points and gets their redeemed discount; ignored for test sales
+    /// - `now`: The time of the sale in nanoseconds since the Unix epoch
+    pub fn record_sale(
+        &mut self,
+        item_id: u32,
+        quantity: u32,
+        test: bool,
+        channel: SalesChannel,
+        customer_id: Option<u64>,
+        now: u64,
+    ) -> Result<Sale, InventoryError> {
+        self.record_basket(&[(item_id, quantity)], test, channel, Tender::Cash, customer_id, now).map(|mut sales| sales.remove(0))
+    }
+
+    /// Sells units of an item without issuing a receipt, for callers that issue one per basket
+    /// - `discount`: Amount taken off the line's total, with its tax reduced in proportion
+    fn sell(&mut self, item_id: u32, quantity: u32, test: bool, channel: SalesChannel, discount: f64, now: u64) -> Result<Sale, InventoryError> {
+        let demand = self.stock_demand(item_id, quantity)?;
+        self.check_stock(&[(item_id, quantity)], channel)?; // Refuse to sell stock we do not have
+        let (unit_price, mut total, mut tax) = self.price_line(item_id, quantity, channel);
+        if discount > 0.0 && total > 0.0 {
+            tax = self.config.round_amount(tax * (total - discount) / total);
+            total = self.config.round_amount(total - discount);
+        }
+        let (stock_item_id, stock_units) = if self.bundles.definitions.contains_key(&item_id) {
+            (item_id, quantity) // The components are in the bundle definition
+        } else {
+            demand[0]
+        };
+        let sale_id = self.sales.len();
+        let mut cost = Some(0.0);
+        let mut changes = Vec::new();
+        for &(id, units) in &demand {
+            let stock = self.items.get(&id).expect("stock_demand only returns existing items");
+            let old_quantity = stock.quantity;
+            let units_per_cost_unit = stock.unit.stock_units_per_price_unit();
+            self.journal_event(JournalEvent::StockSold { item_id: id, sale_id, units });
+            cost = cost.zip(self.costing.consume(id, units, units_per_cost_unit)).map(|(a, b)| a + b);
+            if !test {
+                self.velocity.record(id, channel, units, now);
+            }
+            changes.push((id, old_quantity, old_quantity - units));
+        }
+
+        let sale = Sale {
+            id: sale_id,
+            item_id,
+            quantity,
+            unit_price,
+            total,
+            tax,
+            channel,
+            stock_item_id,
+            stock_units,
+            timestamp: now,
+            test,
+        };
+        self.sales.push(sale.clone());
+        if let Some(cost) = cost {
+            self.costing.sale_costs.insert(sale.id, cost);
+        }
+        let log = format!(
+            "Item {} sold {} units{} at {}",
+            item_id,
+            quantity,
+            if test { " (test)" } else { "" },
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log); // Log the sale with the current timestamp
+        self.record_history(item_id, ItemHistoryEvent::Sold { sale_id: sale.id, quantity, test });
+        for &(id, units) in demand.iter().filter(|&&(id, _)| id != item_id) {
+            self.record_history(id, ItemHistoryEvent::StockTaken { sale_id: sale.id, sold_item_id: item_id, units });
+        }
+        for (id, old_quantity, new_quantity) in changes {
+            self.check_stock_events(id, old_quantity, new_quantity, false);
+            self.publish_event(InventoryEventPayload::StockChanged { item_id: id, old_quantity, new_quantity }, now);
+        }
+        if !test { // Subscribers build revenue figures from these
+            self.publish_event(
+                InventoryEventPayload::SaleRecorded { sale_id: sale.id, item_id, quantity, total, channel },
+                now,
+            );
+        }
+        Ok(sale)
+    }
+
+    /// Sells several lines as one transaction on a single receipt; either every line is recorded or none is
+    /// - `lines`: Pairs of (item ID, quantity) being sold
+    /// - `channel`: Where the sale was made
+    /// - `tender`: How the basket was paid, which decides whether it counts towards the cashier's drawer
+    /// - `customer_id`: Loyalty customer buying, who earns points and gets their redeemed discount
+    /// - `now`: The time of the sale in nanoseconds since the Unix epoch
+    pub fn record_sales(
+        &mut self,
+        lines: &[(u32, u32)],
+        channel: SalesChannel,
+        tender: Tender,
+        customer_id: Option<u64>,
+        now: u64,
+    ) -> Result<Vec<Sale>, InventoryError> {
+        self.record_basket(lines, false, channel, tender, customer_id, now)
+    }
+
+    /// Sells a basket on one receipt, spreading the customer's redeemed discount over its lines
+    fn record_basket(
+        &mut self,
+        lines: &[(u32, u32)],
+        test: bool,
+        channel: SalesChannel,
+        tender: Tender,
+        customer_id: Option<u64>,
+        now: u64,
+    ) -> Result<Vec<Sale>, InventoryError> {
+        self.enforce_sale_rules(lines, now)?;
+        self.check_stock(lines, channel)?;
+        let customer_id = customer_id.filter(|_| !test);
+        let line_totals: Vec<f64> = lines.iter().map(|&(item_id, quantity)| self.price_line(item_id, quantity, channel).1).collect();
+        let discounts = self.loyalty_discounts(customer_id, &line_totals)?;
+        let sales = lines
+            .iter()
+            .zip(&discounts)
+            .map(|(&(item_id, quantity), &discount)| self.sell(item_id, quantity, test, channel, discount, now))
+            .collect::<Result<Vec<Sale>, InventoryError>>()?;
+        let cashier = ic_cdk::caller();
+        let receipt_number = self.issue_receipt(&sales, cashier, now);
+        self.attribute_to_shift(cashier, &sales, tender);
+        if let Some(customer_id) = customer_id {
+            self.settle_loyalty(customer_id, &sales, receipt_number, discounts.iter().sum());
+        }
+        Ok(sales)
+    }
+
+    /// Checks that every line of a basket exists and is in stock, leaving other channels' buffers alone
+    /// - `lines`: Pairs of (item ID, quantity) being sold
+    /// - `channel`: Channel the basket is sold on
+    pub fn check_stock(&self, lines: &[(u32, u32)], channel: SalesChannel) -> Result<(), InventoryError> {
+        let mut requested: HashMap<u32, u32> = HashMap::new();
+        for &(item_id, quantity) in lines { // Sum lines by stock item so packs, bundles and repeats are checked against total demand
+            for (stock_item_id, stock_units) in self.stock_demand(item_id, quantity)? {
+                let total = requested.entry(stock_item_id).or_insert(0);
+                *total = total.saturating_add(stock_units);
+            }
+        }
+        for (&item_id, &quantity) in &requested {
+            let available = self.available_to_promise(item_id, channel);
+            if available < quantity {
+                return Err(InventoryError::InsufficientStock { item_id, available, requested: quantity });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sales reports should count: every sale, or only real ones unless `include_test` is set
+    pub fn reportable_sales(&self, include_test: bool) -> impl DoubleEndedIterator<Item = Sale> + '_ {
+        self.sales.iter_from(0).filter(move |sale| include_test || !sale.test)
+    }
+
+    /// Up to `limit` sales starting at ID `offset`, leaving out test sales unless `include_test` is set
+    pub fn sales_page(&self, offset: u64, limit: u32, include_test: bool) -> SalesPage {
+        let next_id = self.sales.len();
+        SalesPage {
+            entries: self.sales.iter_from(offset).take(limit as usize).filter(|sale| include_test || !sale.test).collect(),
+            next_offset: offset.saturating_add(limit as u64).min(next_id).max(offset),
+            next_id,
+        }
+    }
+}
+
+// Records a sale of an item on a channel, in-store by default, and decrements its stock. Only
+// principals designated with `set_test_principal` may flag a sale as `test`. A `customer_id`
+// credits the sale to a loyalty account and applies the discount it has redeemed.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn record_sale(
+    item_id: u32,
+    quantity: u32,
+    idempotency_key: Option<String>,
+    test: Option<bool>,
+    channel: Option<SalesChannel>,
+    customer_id: Option<u64>,
+) -> Result<Sale, InventoryError> {
+    require_permission("record_sale", Permission::RecordSales)?;
+    metered("record_sale", || {
+        run_once("record_sale", idempotency_key, || {
+            Validator::new().check(quantity > 0, "quantity", "must be positive").finish()?;
+            let test = test.unwrap_or(false);
+            INVENTORY_MANAGER.with(|inventory| {
+                let mut inventory = inventory.borrow_mut();
+                if test && !inventory.access.test_principals.contains(&ic_cdk::caller()) {
+                    return Err(InventoryError::Unauthorized { msg: "Only test principals may record test sales".to_string() });
+                }
+                inventory.record_sale(item_id, quantity, test, channel.unwrap_or_default(), customer_id, ic_cdk::api::time())
+            })
+        })
+    })
+}
+
+// Retrieves up to `limit` sales starting at sale ID `offset`. Pass the previous page's
+// `next_offset` to read on; pages are smaller while the canister is shedding load.
+// Test sales are left out unless `include_test` is set.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_sales(offset: u64, limit: u32, include_test: Option<bool>) -> Result<SalesPage, InventoryError> {
+    require_reader("get_sales", Role::Manager)?;
+    metered("get_sales", || {
+        let mut limit = limit.min(MAX_PAGE_SALES);
+        if load_degraded() {
+            limit = limit.min(DEGRADED_HISTORY_LIMIT as u32);
+        }
+        INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().sales_page(offset, limit, include_test.unwrap_or(false))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sale(id: u64, test: bool) -> Sale {
+        Sale {
+            id,
+            item_id: 1,
+            quantity: 1,
+            unit_price: 1.0,
+            total: 1.0,
+            tax: 0.0,
+            channel: SalesChannel::default(),
+            stock_item_id: 1,
+            stock_units: 1,
+            timestamp: 0,
+            test,
+        }
+    }
+
+    #[test]
+    fn pages_move_on_past_left_out_test_sales() {
+        let mut manager = SupermarketManager::new();
+        manager.sales.extend(vec![sale(0, false), sale(1, true), sale(2, true), sale(3, false), sale(4, false)]);
+        let ids = |page: &SalesPage| page.entries.iter().map(|sale| sale.id).collect::<Vec<_>>();
+
+        let first = manager.sales_page(0, 3, false);
+        assert_eq!((ids(&first), first.next_offset, first.next_id), (vec![0], 3, 5));
+        let last = manager.sales_page(first.next_offset, 3, false);
+        assert_eq!((ids(&last), last.next_offset), (vec![3, 4], 5));
+        assert_eq!(ids(&manager.sales_page(1, 2, true)), vec![1, 2]);
+        assert!(manager.sales_page(9, 3, false).entries.is_empty());
+    }
+}
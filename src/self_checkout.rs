@@ -0,0 +1,234 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::channels::SalesChannel;
+use crate::idempotency::run_once;
+use crate::loyalty::CustomerKey;
+use crate::ratelimit::rate_limit;
+use crate::sales::Sale;
+use crate::shifts::Tender;
+use crate::validation::{validate_lines, Validator};
+use crate::usage::metered;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_TRUST: u32 = 100;
+const INITIAL_TRUST: u32 = 50; // Score given to a customer the first time they use self-checkout
+
+/// Tunable settings for self-checkout audits
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct SelfCheckoutConfig {
+    pub audit_rate: f64,   // Probability (0.0 - 1.0) that a customer with the initial trust score is audited
+    pub pass_reward: u32,  // Trust points gained when an audit finds nothing wrong
+    pub fail_penalty: u32, // Trust points lost when an audit finds a discrepancy
+}
+
+impl Default for SelfCheckoutConfig {
+    fn default() -> Self {
+        SelfCheckoutConfig {
+            audit_rate: 0.05,
+            pass_reward: 5,
+            fail_penalty: 25,
+        }
+    }
+}
+
+impl SelfCheckoutConfig {
+    /// Checks that the settings are usable
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        if !(0.0..=1.0).contains(&self.audit_rate) {
+            return Err(InventoryError::InvalidInput { msg: "audit_rate must be between 0.0 and 1.0".to_string() });
+        }
+        Ok(())
+    }
+}
+
+/// Result of a staff audit of a self-checkout transaction
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum AuditOutcome {
+    Passed, // The basket matched what was scanned
+    Failed, // Unscanned or mis-scanned items were found
+}
+
+/// A basket scanned at a self-checkout terminal
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct SelfCheckoutTransaction {
+    pub id: u64,                              // Unique ID of the transaction
+    pub customer: String,                     // Loyalty card or handle identifying the customer
+    pub sales: Vec<Sale>,                     // Sales recorded for the scanned lines
+    pub audit_required: bool,                 // Whether staff must check the basket before the customer leaves
+    pub audit_outcome: Option<AuditOutcome>,  // Outcome once an audit has been recorded
+    pub timestamp: u64,                       // Time of the transaction in nanoseconds since the Unix epoch
+}
+
+/// Audit settings, customer trust scores and self-checkout transaction history
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct SelfCheckout {
+    pub config: SelfCheckoutConfig,                            // Audit settings
+    pub trust_scores: HashMap<String, u32>,                    // Trust score (0 - 100) keyed by customer
+    pub transactions: BTreeMap<u64, SelfCheckoutTransaction>,  // Transactions keyed by ID
+    pub rng_state: u64,                                        // State of the generator used to pick audits
+}
+
+impl SelfCheckout {
+    /// Current trust score of a customer, or the initial score if they have never been seen
+    pub fn trust_score(&self, customer: &str) -> u32 {
+        self.trust_scores.get(customer).copied().unwrap_or(INITIAL_TRUST)
+    }
+
+    /// Audit probability for a customer, scaled by how far their trust is from the maximum
+    ///
+    /// The initial score audits at the configured rate, a fully trusted customer at a tenth
+    /// of it (so every customer is still checked occasionally) and a score of zero at twice the rate.
+    pub fn audit_probability(&self, customer: &str) -> f64 {
+        let distrust = (MAX_TRUST - self.trust_score(customer)) as f64 / (MAX_TRUST - INITIAL_TRUST) as f64;
+        let rate = self.config.audit_rate * distrust;
+        rate.max(self.config.audit_rate / 10.0).min(1.0)
+    }
+
+    /// Draws a uniform number in [0, 1) using splitmix64, mixing in the call time
+    fn next_random(&mut self, now: u64) -> f64 {
+        self.rng_state = self.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15 ^ now);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl SupermarketManager {
+    /// Records a self-checkout basket and decides whether it needs a staff audit
+    /// - `customer`: Loyalty card or handle identifying the customer; a registered card earns points
+    /// - `lines`: Pairs of (item ID, quantity) scanned by the customer
+    /// - `now`: The time of the transaction in nanoseconds since the Unix epoch
+    pub fn self_checkout_sale(&mut self, customer: String, lines: &[(u32, u32)], now: u64) -> Result<SelfCheckoutTransaction, InventoryError> {
+        let card_holder = self.loyalty.by_key.get(&CustomerKey::Card(customer.clone())).copied();
+        let sales = self.record_sales(lines, SalesChannel::InStore, Tender::Card, card_holder, now)?;
+        let probability = self.self_checkout.audit_probability(&customer);
+        let audit_required = self.self_checkout.next_random(now) < probability;
+
+        let id = self.self_checkout.transactions.len() as u64;
+        let transaction = SelfCheckoutTransaction {
+            id,
+            customer,
+            sales,
+            audit_required,
+            audit_outcome: None,
+            timestamp: now,
+        };
+        self.self_checkout.transactions.insert(id, transaction.clone());
+        Ok(transaction)
+    }
+
+    /// Records the result of a staff audit and adjusts the customer's trust score
+    /// - `transaction_id`: The self-checkout transaction that was audited
+    /// - `outcome`: What the audit found
+    ///
+    /// Returns the customer's new trust score.
+    pub fn record_audit_outcome(&mut self, transaction_id: u64, outcome: AuditOutcome) -> Result<u32, InventoryError> {
+        let checkout = &mut self.self_checkout;
+        let transaction = checkout.transactions.get_mut(&transaction_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Self-checkout transaction {} not found", transaction_id),
+        })?;
+        transaction.audit_outcome = Some(outcome);
+        let customer = transaction.customer.clone();
+
+        let current = checkout.trust_score(&customer);
+        let updated = match outcome {
+            AuditOutcome::Passed => (current + checkout.config.pass_reward).min(MAX_TRUST),
+            AuditOutcome::Failed => current.saturating_sub(checkout.config.fail_penalty),
+        };
+        checkout.trust_scores.insert(customer.clone(), updated);
+
+        let log = format!(
+            "Self-checkout transaction {} audit {:?} for customer {} at {}",
+            transaction_id,
+            outcome,
+            customer,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log); // Log the audit with the current timestamp
+        Ok(updated)
+    }
+}
+
+// Records a self-checkout basket and reports whether staff must audit it. Kiosks need the
+// `RecordSales` permission.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn self_checkout_sale(customer: String, lines: Vec<(u32, u32)>, idempotency_key: Option<String>) -> Result<SelfCheckoutTransaction, InventoryError> {
+    require_permission("self_checkout_sale", Permission::RecordSales)?;
+    metered("self_checkout_sale", || {
+        run_once("self_checkout_sale", idempotency_key, || {
+            Validator::new().name("customer", &customer).finish()?;
+            validate_lines(&lines)?;
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().self_checkout_sale(customer, &lines, ic_cdk::api::time())
+            })
+        })
+    })
+}
+
+// Records the outcome of a staff audit, feeding it back into the customer's trust score.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn record_audit_outcome(transaction_id: u64, outcome: AuditOutcome) -> Result<u32, InventoryError> {
+    require_caller("record_audit_outcome", Role::Clerk)?;
+    metered("record_audit_outcome", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().record_audit_outcome(transaction_id, outcome)
+        })
+    })
+}
+
+// Retrieves the self-checkout transactions still waiting for a staff audit.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_pending_audits() -> Result<Vec<SelfCheckoutTransaction>, InventoryError> {
+    require_reader("get_pending_audits", Role::Clerk)?;
+    metered("get_pending_audits", || {
+        INVENTORY_MANAGER.with(|inventory| {
+            Ok(inventory.borrow().self_checkout.transactions.values()
+                .filter(|t| t.audit_required && t.audit_outcome.is_none())
+                .cloned()
+                .collect())
+        })
+    })
+}
+
+// Retrieves a customer's self-checkout trust score.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_trust_score(customer: String) -> Result<u32, InventoryError> {
+    require_reader("get_trust_score", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().self_checkout.trust_score(&customer))
+    })
+}
+
+// Replaces the self-checkout audit settings.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_self_checkout_config(config: SelfCheckoutConfig) -> Result<(), InventoryError> {
+    require_caller("set_self_checkout_config", Role::Manager)?;
+    config.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.self_checkout.config = config;
+        Ok(())
+    })
+}
+
+// Retrieves the self-checkout audit settings.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_self_checkout_config() -> SelfCheckoutConfig {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().self_checkout.config.clone()
+    })
+}
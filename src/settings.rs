@@ -0,0 +1,172 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+
+use crate::access::{require_caller, Role};
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_MINUTE: i64 = 60 * 1_000_000_000;
+const NANOS_PER_DAY: i64 = 24 * 60 * NANOS_PER_MINUTE;
+const MAX_DECIMAL_PLACES: u8 = 4;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+
+/// Name, currency and locale settings of the store
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreConfig {
+    pub store_name: String,                       // Name shown on receipts and reported by `get_store_info`
+    pub currency_code: String,                    // ISO 4217 code prices are in, e.g. "EUR"
+    pub decimal_places: u8,                       // Minor-unit digits amounts are rounded to, e.g. 2 for cents
+    pub utc_offset_minutes: i32,                  // Store timezone; reports bucket days at local midnight
+    pub default_low_stock_threshold: Option<u32>, // Low-stock level for items without a reorder rule; None to report only ruled items
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            store_name: "Supermarket".to_string(),
+            currency_code: "USD".to_string(),
+            decimal_places: 2,
+            utc_offset_minutes: 0,
+            default_low_stock_threshold: None,
+        }
+    }
+}
+
+impl StoreConfig {
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        Validator::new()
+            .name("store_name", &self.store_name)
+            .check(
+                self.currency_code.len() == 3 && self.currency_code.chars().all(|c| c.is_ascii_uppercase()),
+                "currency_code",
+                "must be a three-letter ISO 4217 code",
+            )
+            .check(self.decimal_places <= MAX_DECIMAL_PLACES, "decimal_places", format!("must be at most {}", MAX_DECIMAL_PLACES))
+            .check(
+                self.utc_offset_minutes.abs() <= MAX_UTC_OFFSET_MINUTES,
+                "utc_offset_minutes",
+                format!("must be within ±{}", MAX_UTC_OFFSET_MINUTES),
+            )
+            .finish()
+    }
+
+    /// Rounds an amount to the currency's minor unit
+    pub fn round_amount(&self, amount: f64) -> f64 {
+        let scale = 10f64.powi(self.decimal_places as i32);
+        (amount * scale).round() / scale
+    }
+
+    /// Renders an amount with the currency's decimal places and code, e.g. "12.50 EUR"
+    pub fn format_amount(&self, amount: f64) -> String {
+        format!("{:.*} {}", self.decimal_places as usize, amount, self.currency_code)
+    }
+
+    /// Day number of a time in the store's timezone, counted from the Unix epoch
+    /// - `timestamp`: Time in nanoseconds since the Unix epoch
+    pub fn local_day(&self, timestamp: u64) -> u64 {
+        let local = timestamp as i64 + self.utc_offset_minutes as i64 * NANOS_PER_MINUTE;
+        local.max(0) as u64 / NANOS_PER_DAY as u64
+    }
+
+    /// Time local midnight starts a day counted by `local_day`, in nanoseconds since the Unix epoch
+    pub fn local_day_start(&self, day: u64) -> u64 {
+        (day as i64 * NANOS_PER_DAY - self.utc_offset_minutes as i64 * NANOS_PER_MINUTE).max(0) as u64
+    }
+}
+
+/// Installation arguments that set a store up when its canister is deployed
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InitArgs {
+    pub store_name: String,
+    pub owner: Option<Principal>,      // Store owner; the installing principal if None
+    pub currency_code: Option<String>, // ISO 4217 code prices are in; USD if None
+    pub managers: Vec<Principal>,      // Principals given the Manager role from the start
+}
+
+/// Public description of the store
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreInfo {
+    pub store_name: String,
+    pub owner: Option<Principal>, // None only if the canister was installed without an owner
+    pub currency_code: String,
+    pub decimal_places: u8,
+    pub utc_offset_minutes: i32,
+}
+
+impl SupermarketManager {
+    /// Sets up a freshly installed store from its installation arguments
+    /// - `installer`: The principal that installed the canister
+    pub fn apply_init_args(&mut self, args: InitArgs, installer: Principal) -> Result<(), InventoryError> {
+        let owner = args.owner.unwrap_or(installer);
+        let config = StoreConfig {
+            store_name: args.store_name.trim().to_string(),
+            currency_code: args.currency_code.unwrap_or_else(|| StoreConfig::default().currency_code),
+            ..StoreConfig::default()
+        };
+        config.validate()?;
+        let mut validator = Validator::new();
+        validator.check(owner != Principal::anonymous(), "owner", "must not be anonymous");
+        for manager in &args.managers {
+            validator
+                .check(*manager != Principal::anonymous(), "managers", "must not contain the anonymous principal")
+                .check(*manager != owner, "managers", "must not contain the owner");
+        }
+        validator.finish()?;
+        for manager in args.managers {
+            self.access.roles.insert(manager, Role::Manager);
+        }
+        self.access.roles.insert(owner, Role::Owner);
+        let log = format!(
+            "Store {} installed with owner {} at {}",
+            config.store_name,
+            owner,
+            SupermarketManager::get_current_time()
+        );
+        self.config = config;
+        self.logs.push(log);
+        Ok(())
+    }
+
+    pub fn store_info(&self) -> StoreInfo {
+        StoreInfo {
+            store_name: self.config.store_name.clone(),
+            owner: self.access.roles.iter().find(|(_, role)| **role == Role::Owner).map(|(principal, _)| *principal),
+            currency_code: self.config.currency_code.clone(),
+            decimal_places: self.config.decimal_places,
+            utc_offset_minutes: self.config.utc_offset_minutes,
+        }
+    }
+}
+
+// Replaces the store name, currency and locale settings.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_config(config: StoreConfig) -> Result<(), InventoryError> {
+    require_caller("set_config", Role::Manager)?;
+    config.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.config = config;
+        inventory.rebuild_inventory_summary(ic_cdk::api::time()); // The low-stock default may have changed
+        let log = format!("Store settings changed at {}", SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves the store name, currency and locale settings, e.g. so clients can format prices.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_config() -> StoreConfig {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().config.clone())
+}
+
+// Retrieves the store's name, owner and currency, e.g. to check which store a canister serves.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_store_info() -> StoreInfo {
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().store_info())
+}
@@ -0,0 +1,357 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::returns::SaleReturn;
+use crate::sales::Sale;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_SHIFTS: usize = 10_000; // Closed shifts kept; the oldest is dropped first
+
+/// How a basket was paid for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tender {
+    Cash,  // Into the drawer of the till the sale was recorded on
+    Card,  // On a card terminal, e.g. at self-checkout
+    Token, // By a ledger transfer to the store
+}
+
+/// A cashier's time on a till, from counting the float in to counting the drawer out
+///
+/// Sales the cashier records while the shift is open are attributed to it; only those paid in
+/// cash go into the drawer. Refunds they pay out for returns come out of it.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Shift {
+    pub id: u64,
+    pub cashier: Principal,
+    pub opened_by: Principal,      // The cashier, or a manager opening the till for them
+    pub opened_at: u64,
+    pub opening_float: f64,        // Cash in the drawer when the shift opened
+    pub sale_ids: Vec<u64>,        // Sales attributed to the shift, in the order recorded
+    pub return_ids: Vec<u64>,      // Returns refunded during the shift
+    pub sales_total: f64,          // Takings of the attributed sales, including tax
+    pub cash_total: f64,           // The part of the takings paid in cash
+    pub non_cash_total: f64,       // The part of the takings paid by card or token
+    pub tax_total: f64,
+    pub refunds_total: f64,        // Refunds paid out of the drawer
+    pub closed_at: Option<u64>,    // None while the shift is open
+    pub counted_cash: Option<f64>, // Cash counted in the drawer at close
+}
+
+impl Shift {
+    /// Cash the drawer should hold: the float plus cash takings less refunds
+    pub fn expected_cash(&self) -> f64 {
+        self.opening_float + self.cash_total - self.refunds_total
+    }
+}
+
+/// A shift's expected and counted cash, for end-of-day reconciliation
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ShiftReport {
+    pub shift: Shift,
+    pub sales_count: u64,
+    pub returns_count: u64,
+    pub expected_cash: f64,
+    pub counted_cash: Option<f64>,
+    pub variance: Option<f64>, // Counted less expected; negative when the drawer is short
+    pub currency_code: String,
+}
+
+/// Cashier shifts, open and closed
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Shifts {
+    pub shifts: BTreeMap<u64, Shift>,  // Shifts keyed by ID, oldest first
+    pub open: HashMap<Principal, u64>, // Open shift per cashier
+    pub next_id: u64,
+}
+
+impl SupermarketManager {
+    /// Opens a shift for a cashier with the float counted into their drawer
+    pub fn open_shift(&mut self, cashier: Principal, opening_float: f64, opened_by: Principal, now: u64) -> Result<Shift, InventoryError> {
+        if self.access.role_of(&cashier).is_none() && !self.access.is_granted(&cashier, Permission::RecordSales) {
+            return Err(InventoryError::InvalidInput { msg: format!("{} is not staff allowed to record sales", cashier) });
+        }
+        if let Some(id) = self.shifts.open.get(&cashier) {
+            return Err(InventoryError::Conflict { msg: format!("{} already has shift {} open", cashier, id) });
+        }
+        let id = self.shifts.next_id;
+        self.shifts.next_id += 1;
+        let shift = Shift {
+            id,
+            cashier,
+            opened_by,
+            opened_at: now,
+            opening_float: self.config.round_amount(opening_float),
+            sale_ids: Vec::new(),
+            return_ids: Vec::new(),
+            sales_total: 0.0,
+            cash_total: 0.0,
+            non_cash_total: 0.0,
+            tax_total: 0.0,
+            refunds_total: 0.0,
+            closed_at: None,
+            counted_cash: None,
+        };
+        self.shifts.shifts.insert(id, shift.clone());
+        self.shifts.open.insert(cashier, id);
+        let log = format!(
+            "Shift {} opened for {} with a float of {} at {}",
+            id,
+            cashier,
+            self.config.format_amount(shift.opening_float),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(shift)
+    }
+
+    /// Closes a cashier's open shift with the cash counted in the drawer
+    pub fn close_shift(&mut self, cashier: Principal, counted_cash: f64, now: u64) -> Result<ShiftReport, InventoryError> {
+        let report = self.settle_shift(cashier, counted_cash, now)?;
+        let log = format!(
+            "Shift {} closed with {} counted against {} expected at {}",
+            report.shift.id,
+            self.config.format_amount(report.counted_cash.unwrap_or_default()),
+            self.config.format_amount(report.expected_cash),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(report)
+    }
+
+    /// Marks a cashier's open shift closed with the counted cash, reports its variance and drops
+    /// the oldest closed shifts beyond `MAX_SHIFTS`, never the one just closed
+    fn settle_shift(&mut self, cashier: Principal, counted_cash: f64, now: u64) -> Result<ShiftReport, InventoryError> {
+        let id = self.shifts.open.remove(&cashier).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("{} has no open shift", cashier),
+        })?;
+        let counted_cash = self.config.round_amount(counted_cash);
+        let shift = self.shifts.shifts.get_mut(&id).expect("open shifts are kept");
+        shift.closed_at = Some(now);
+        shift.counted_cash = Some(counted_cash);
+        let report = self.shift_report(id)?;
+        let closed: Vec<u64> = self.shifts.shifts
+            .values()
+            .filter(|shift| shift.closed_at.is_some() && shift.id != id)
+            .map(|shift| shift.id)
+            .collect();
+        for old in closed.iter().take((closed.len() + 1).saturating_sub(MAX_SHIFTS)) {
+            self.shifts.shifts.remove(old);
+        }
+        Ok(report)
+    }
+
+    /// Attributes sales a cashier just recorded to their open shift, if they have one
+    /// - `tender`: How the basket was paid; only cash counts towards the drawer
+    pub fn attribute_to_shift(&mut self, cashier: Principal, sales: &[Sale], tender: Tender) {
+        let Some(shift) = self.shifts.open.get(&cashier).and_then(|id| self.shifts.shifts.get_mut(id)) else {
+            return;
+        };
+        for sale in sales.iter().filter(|sale| !sale.test) {
+            shift.sale_ids.push(sale.id);
+            shift.sales_total += sale.total;
+            match tender {
+                Tender::Cash => shift.cash_total += sale.total,
+                Tender::Card | Tender::Token => shift.non_cash_total += sale.total,
+            }
+            shift.tax_total += sale.tax;
+        }
+    }
+
+    /// Charges a refund to the open shift of whoever paid it out, if they have one
+    pub fn refund_from_shift(&mut self, cashier: Principal, refund: &SaleReturn) {
+        if let Some(shift) = self.shifts.open.get(&cashier).and_then(|id| self.shifts.shifts.get_mut(id)) {
+            shift.return_ids.push(refund.id);
+            shift.refunds_total += refund.refund;
+        }
+    }
+
+    /// A shift's takings, refunds and cash variance
+    pub fn shift_report(&self, id: u64) -> Result<ShiftReport, InventoryError> {
+        let shift = self.shifts.shifts.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Shift {} not found", id),
+        })?;
+        let mut shift = shift.clone();
+        shift.sales_total = self.config.round_amount(shift.sales_total);
+        shift.cash_total = self.config.round_amount(shift.cash_total);
+        shift.non_cash_total = self.config.round_amount(shift.non_cash_total);
+        shift.tax_total = self.config.round_amount(shift.tax_total);
+        shift.refunds_total = self.config.round_amount(shift.refunds_total);
+        let expected_cash = self.config.round_amount(shift.expected_cash());
+        Ok(ShiftReport {
+            sales_count: shift.sale_ids.len() as u64,
+            returns_count: shift.return_ids.len() as u64,
+            expected_cash,
+            counted_cash: shift.counted_cash,
+            variance: shift.counted_cash.map(|counted| self.config.round_amount(counted - expected_cash)),
+            currency_code: self.config.currency_code.clone(),
+            shift,
+        })
+    }
+}
+
+// Opens a shift for `cashier` with the float counted into the drawer. Cashiers open their own
+// shifts; opening one for someone else takes a manager.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn open_shift(cashier: Principal, opening_float: f64) -> Result<Shift, InventoryError> {
+    if cashier == ic_cdk::caller() {
+        require_permission("open_shift", Permission::RecordSales)?;
+    } else {
+        require_caller("open_shift", Role::Manager)?;
+    }
+    Validator::new().price("opening_float", opening_float).finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().open_shift(cashier, opening_float, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Closes the caller's open shift with the cash counted in the drawer, returning the expected
+// cash and the variance.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn close_shift(counted_cash: f64) -> Result<ShiftReport, InventoryError> {
+    require_permission("close_shift", Permission::RecordSales)?;
+    Validator::new().price("counted_cash", counted_cash).finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().close_shift(ic_cdk::caller(), counted_cash, ic_cdk::api::time())
+    })
+}
+
+// Retrieves a shift's takings, refunds, expected and counted cash for end-of-day reconciliation.
+// Cashiers can read their own shifts; other shifts take a manager.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_shift_report(shift_id: u64) -> Result<ShiftReport, InventoryError> {
+    let own = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().shifts.shifts.get(&shift_id).is_some_and(|shift| shift.cashier == ic_cdk::caller())
+    });
+    if !own {
+        require_reader("get_shift_report", Role::Manager)?;
+    }
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().shift_report(shift_id))
+}
+
+// Retrieves the shifts opened from `from` to `to` (nanoseconds since the Unix epoch), oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_shifts(from: u64, to: u64) -> Result<Vec<Shift>, InventoryError> {
+    require_reader("list_shifts", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().shifts.shifts
+            .values()
+            .filter(|shift| shift.opened_at >= from && shift.opened_at < to)
+            .cloned()
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::SalesChannel;
+
+    fn sale(id: u64, total: f64, tax: f64, test: bool) -> Sale {
+        Sale {
+            id,
+            item_id: 1,
+            quantity: 1,
+            unit_price: total,
+            total,
+            tax,
+            channel: SalesChannel::default(),
+            stock_item_id: 1,
+            stock_units: 1,
+            timestamp: 0,
+            test,
+        }
+    }
+
+    fn open_shift(manager: &mut SupermarketManager, cashier: Principal, opening_float: f64) -> u64 {
+        let id = manager.shifts.next_id;
+        manager.shifts.next_id += 1;
+        manager.shifts.shifts.insert(id, Shift {
+            id,
+            cashier,
+            opened_by: cashier,
+            opened_at: 0,
+            opening_float,
+            sale_ids: Vec::new(),
+            return_ids: Vec::new(),
+            sales_total: 0.0,
+            cash_total: 0.0,
+            non_cash_total: 0.0,
+            tax_total: 0.0,
+            refunds_total: 0.0,
+            closed_at: None,
+            counted_cash: None,
+        });
+        manager.shifts.open.insert(cashier, id);
+        id
+    }
+
+    #[test]
+    fn variance_is_counted_less_expected() {
+        let cashier = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        let id = open_shift(&mut manager, cashier, 100.0);
+        manager.attribute_to_shift(cashier, &[sale(0, 12.5, 2.0, false), sale(1, 7.5, 1.2, false), sale(2, 50.0, 8.0, true)], Tender::Cash);
+        manager.attribute_to_shift(cashier, &[sale(3, 30.0, 5.0, false)], Tender::Card);
+        let refund = SaleReturn {
+            id: 0,
+            sale_id: 1,
+            item_id: 1,
+            quantity: 1,
+            refund: 7.5,
+            net_refund: 6.3,
+            restocked: true,
+            recorded_by: cashier,
+            timestamp: 0,
+        };
+        manager.refund_from_shift(cashier, &refund);
+
+        let open = manager.shift_report(id).unwrap();
+        assert_eq!((open.sales_count, open.returns_count), (3, 1)); // Test sales are not takings
+        assert_eq!((open.shift.sales_total, open.shift.cash_total, open.shift.non_cash_total), (50.0, 20.0, 30.0));
+        assert_eq!(open.expected_cash, 112.5); // The card payment never reached the drawer
+        assert_eq!(open.variance, None);
+
+        let closed = manager.settle_shift(cashier, 110.0, 5).unwrap();
+        assert_eq!((closed.shift.closed_at, closed.counted_cash), (Some(5), Some(110.0)));
+        assert_eq!(closed.variance, Some(-2.5)); // The drawer is short
+        assert!(manager.shifts.open.is_empty());
+        assert!(manager.settle_shift(cashier, 110.0, 6).is_err()); // Already closed
+    }
+
+    #[test]
+    fn closing_drops_the_oldest_closed_shifts_but_not_its_own() {
+        let (cashier, other) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut manager = SupermarketManager::new();
+        let long_running = open_shift(&mut manager, cashier, 0.0);
+        for _ in 0..MAX_SHIFTS {
+            open_shift(&mut manager, other, 0.0);
+            manager.settle_shift(other, 0.0, 1).unwrap();
+        }
+        assert_eq!(manager.shifts.shifts.len(), MAX_SHIFTS + 1); // Open shifts do not count
+
+        let report = manager.settle_shift(cashier, 0.0, 2).unwrap();
+        assert_eq!(report.shift.id, long_running);
+        assert_eq!(manager.shifts.shifts.len(), MAX_SHIFTS);
+        assert!(manager.shifts.shifts.contains_key(&long_running));
+        assert!(!manager.shifts.shifts.contains_key(&(long_running + 1))); // The oldest other one went instead
+    }
+
+    #[test]
+    fn other_cashiers_sales_are_not_attributed() {
+        let (cashier, other) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut manager = SupermarketManager::new();
+        let id = open_shift(&mut manager, cashier, 0.0);
+        manager.attribute_to_shift(other, &[sale(0, 10.0, 0.0, false)], Tender::Cash);
+        assert!(manager.shift_report(id).unwrap().shift.sale_ids.is_empty());
+    }
+}
@@ -0,0 +1,635 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use ic_stable_structures::Memory as _;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::cost::{measured, HeavyOperation};
+use crate::journal::JournalEvent;
+use crate::logs::LogEntry;
+use crate::payments::Payment;
+use crate::ratelimit::rate_limit;
+use crate::reorder::{ReorderRule, ReorderSuggestion};
+use crate::returns::SaleReturn;
+use crate::sales::Sale;
+use crate::shifts::Shift;
+use crate::storage;
+use crate::{InventoryError, InventoryItem, SupermarketManager, INVENTORY_MANAGER};
+
+pub type SnapshotId = u64;
+
+const SNAPSHOT_FORMAT_VERSION: u32 = 6; // 2 added sequence numbers and timestamps to log entries, 3 the source canister, 4 test sales, 5 sales channels and tax, 6 returns, sale costs and shifts
+const OLDEST_RESTORABLE_VERSION: u32 = 6;  // Earlier snapshots hold sales without the returns, costs and shifts recorded against them
+const CHUNK_SIZE: u64 = 1024 * 1024;       // Bytes per download chunk, well under the response size limit
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// The store's business data as written to a snapshot
+///
+/// Keys, governance settings and caches are left out: a restored store keeps its own.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreSnapshot {
+    pub format_version: u32,                     // Layout of this record, for future migrations
+    pub created_at: u64,                         // Time of the snapshot in nanoseconds since the Unix epoch
+    pub items: Vec<InventoryItem>,               // Every item, including archived ones
+    pub logs: Vec<LogEntry>,                     // Log entries kept under the retention policy
+    pub sales: Vec<Sale>,                        // The full sales ledger
+    pub sale_costs: Vec<(u64, f64)>,             // Cost of goods keyed by sale ID
+    pub returns: Vec<SaleReturn>,                // Every return, oldest first
+    pub shifts: Vec<Shift>,                      // Cashier shifts, open and kept closed ones
+    pub reorder_rules: Vec<(u32, ReorderRule)>,  // Reorder rules keyed by item ID
+    pub reorder_suggestions: Vec<ReorderSuggestion>, // Open reorder suggestions
+    pub esl_bindings: Vec<(String, u32)>,        // Item ID keyed by shelf label ID
+    pub roles: Vec<(Principal, Role)>,           // Staff roles
+    pub payments: Vec<Payment>,                  // Token payment records
+    pub source_canister: Option<Principal>,      // Canister the snapshot was taken from; None before version 3
+}
+
+/// Changes since a full snapshot, restorable on top of it
+///
+/// Differentials are cumulative: each one holds every change since its base, so only the
+/// base and the latest differential are needed to restore.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StoreDelta {
+    pub format_version: u32,                         // Layout of this record, for future migrations
+    pub created_at: u64,                             // Time of the differential in nanoseconds since the Unix epoch
+    pub source_canister: Principal,                  // Canister the differential was taken from
+    pub base_sha256: Vec<u8>,                        // Digest of the encoded full snapshot this applies to
+    pub upserted_items: Vec<InventoryItem>,          // Items added or changed since the base
+    pub removed_items: Vec<u32>,                     // Items deleted since the base
+    pub logs_from: u64,                              // Sequence number the base's log ended before
+    pub logs: Vec<LogEntry>,                         // Log entries written since the base
+    pub sales_from: u64,                             // Sale ID the base's ledger ended before
+    pub sales: Vec<Sale>,                            // Sales recorded since the base
+    pub sale_costs: Vec<(u64, f64)>,                 // Cost of goods of the sales recorded since the base
+    pub returns_from: u64,                           // Return ID the base's returns ended before
+    pub returns: Vec<SaleReturn>,                    // Returns recorded since the base
+    pub upserted_shifts: Vec<Shift>,                 // Shifts opened or changed since the base
+    pub removed_shifts: Vec<u64>,                    // Closed shifts dropped since the base
+    pub upserted_payments: Vec<Payment>,             // Payments started or settled since the base
+    pub reorder_rules: Vec<(u32, ReorderRule)>,      // Every reorder rule; small enough to send whole
+    pub reorder_suggestions: Vec<ReorderSuggestion>, // Every open reorder suggestion
+    pub esl_bindings: Vec<(String, u32)>,            // Every shelf label binding
+    pub roles: Vec<(Principal, Role)>,               // Every staff role
+}
+
+/// A principal reference to rewrite while restoring, e.g. the production owner to the staging owner
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PrincipalMapping {
+    pub from: Principal, // Principal as it appears in the backup
+    pub to: Principal,   // Principal to store in its place
+}
+
+/// Principal rewrites for one restore
+///
+/// The canister a backup was taken from maps to the restoring canister unless the mapping says
+/// otherwise, so references to the store itself keep pointing at the store.
+struct PrincipalMap(HashMap<Principal, Principal>);
+
+impl PrincipalMap {
+    fn new(mapping: Vec<PrincipalMapping>, source_canister: Option<Principal>) -> Result<Self, InventoryError> {
+        let mut map = HashMap::new();
+        for PrincipalMapping { from, to } in mapping {
+            if map.insert(from, to).is_some_and(|earlier| earlier != to) {
+                return Err(InventoryError::InvalidInput { msg: format!("{} is mapped more than once", from) });
+            }
+        }
+        if let Some(source) = source_canister {
+            map.entry(source).or_insert_with(ic_cdk::id);
+        }
+        Ok(PrincipalMap(map))
+    }
+
+    fn get(&self, principal: Principal) -> Principal {
+        self.0.get(&principal).copied().unwrap_or(principal)
+    }
+
+    /// Remaps staff roles; when two principals map to the same one, it keeps the higher role
+    fn roles(&self, roles: Vec<(Principal, Role)>) -> HashMap<Principal, Role> {
+        let mut mapped = HashMap::new();
+        for (principal, role) in roles {
+            let entry = mapped.entry(self.get(principal)).or_insert(role);
+            *entry = (*entry).max(role);
+        }
+        mapped
+    }
+
+    fn payments(&self, payments: &mut [Payment]) {
+        for payment in payments {
+            payment.payer.owner = self.get(payment.payer.owner);
+            payment.ledger_canister_id = self.get(payment.ledger_canister_id);
+        }
+    }
+
+    fn returns(&self, returns: &mut [SaleReturn]) {
+        for entry in returns {
+            entry.recorded_by = self.get(entry.recorded_by);
+        }
+    }
+
+    fn shifts(&self, shifts: &mut [Shift]) {
+        for shift in shifts {
+            shift.cashier = self.get(shift.cashier);
+            shift.opened_by = self.get(shift.opened_by);
+        }
+    }
+}
+
+/// Whether two records encode identically; used to find what changed since a base snapshot
+fn same<T: CandidType>(a: &T, b: &T) -> bool {
+    matches!((candid::encode_one(a), candid::encode_one(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Where a snapshot lives in stable memory
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub id: SnapshotId,
+    pub created_at: u64,          // Time of the snapshot in nanoseconds since the Unix epoch
+    pub size: u64,                // Length of the encoded snapshot in bytes
+    pub chunk_count: u64,         // Number of chunks `download_snapshot` serves it in
+    pub sha256: Vec<u8>,          // Digest of the encoded snapshot, to check a download is complete
+    pub offset: u64,              // Start of the snapshot in the snapshot region of stable memory
+    pub base: Option<SnapshotId>, // Full snapshot a differential applies to; None for a full snapshot
+}
+
+/// Index of snapshots written to stable memory
+///
+/// Snapshots are appended to their own region of stable memory. Space is reclaimed when the
+/// newest snapshot is deleted, and all of it once no snapshot is left.
+#[derive(Default)]
+pub struct SnapshotStore {
+    pub snapshots: BTreeMap<SnapshotId, SnapshotInfo>, // Snapshots keyed by ID
+    pub next_id: SnapshotId,                           // ID handed to the next snapshot
+    pub end: u64,                                      // First free byte of the snapshot region
+    pub staged_chunks: HashMap<Principal, Vec<u8>>,    // Restore data uploaded ahead of restore_snapshot, per caller
+    pub restored_base: Option<Vec<u8>>,                // Digest of the full snapshot just restored, awaiting a differential
+}
+
+impl SupermarketManager {
+    /// Copies the business data into a snapshot record
+    pub fn snapshot(&self, now: u64) -> StoreSnapshot {
+        StoreSnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            created_at: now,
+            items: self.items.values().collect(), // In ID order
+            logs: self.logs.entries().collect(),
+            sales: self.sales.iter_from(0).collect(),
+            sale_costs: self.costing.sale_costs.iter().map(|(id, cost)| (*id, *cost)).collect(),
+            returns: self.returns.entries.clone(),
+            shifts: self.shifts.shifts.values().cloned().collect(),
+            reorder_rules: self.reorder.rules.iter().map(|(id, rule)| (*id, rule.clone())).collect(),
+            reorder_suggestions: self.reorder.suggestions.values().cloned().collect(),
+            esl_bindings: self.esl.bindings.iter().map(|(label, id)| (label.clone(), *id)).collect(),
+            roles: self.access.roles.iter().map(|(p, r)| (*p, *r)).collect(),
+            payments: self.payments.records.clone(),
+            source_canister: Some(ic_cdk::id()),
+        }
+    }
+
+    /// Records what changed since a full snapshot
+    /// - `base`: The decoded full snapshot
+    /// - `base_sha256`: Digest of its encoding
+    pub fn delta_since(&self, base: &StoreSnapshot, base_sha256: Vec<u8>, now: u64) -> StoreDelta {
+        let current = self.snapshot(now);
+        let base_items: HashMap<u32, &InventoryItem> = base.items.iter().map(|item| (item.id, item)).collect();
+        let base_payments: HashMap<u64, &Payment> = base.payments.iter().map(|payment| (payment.id, payment)).collect();
+        let logs_from = base.logs.last().map_or(0, |entry| entry.seq + 1);
+        let sales_from = base.sales.len() as u64;
+        let returns_from = base.returns.len() as u64;
+        let base_shifts: HashMap<u64, &Shift> = base.shifts.iter().map(|shift| (shift.id, shift)).collect();
+        StoreDelta {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            created_at: now,
+            source_canister: ic_cdk::id(),
+            base_sha256,
+            removed_items: base.items.iter().map(|item| item.id).filter(|id| !self.items.contains_key(id)).collect(),
+            upserted_items: current.items
+                .into_iter()
+                .filter(|item| base_items.get(&item.id).is_none_or(|old| !same(*old, item)))
+                .collect(),
+            logs_from,
+            logs: current.logs.into_iter().filter(|entry| entry.seq >= logs_from).collect(),
+            sales_from,
+            sales: current.sales.into_iter().filter(|sale| sale.id >= sales_from).collect(),
+            sale_costs: current.sale_costs.into_iter().filter(|(id, _)| *id >= sales_from).collect(),
+            returns_from,
+            returns: current.returns.into_iter().filter(|entry| entry.id >= returns_from).collect(),
+            removed_shifts: base.shifts.iter().map(|shift| shift.id).filter(|id| !self.shifts.shifts.contains_key(id)).collect(),
+            upserted_shifts: current.shifts
+                .into_iter()
+                .filter(|shift| base_shifts.get(&shift.id).is_none_or(|old| !same(*old, shift)))
+                .collect(),
+            upserted_payments: current.payments
+                .into_iter()
+                .filter(|payment| base_payments.get(&payment.id).is_none_or(|old| !same(*old, payment)))
+                .collect(),
+            reorder_rules: current.reorder_rules,
+            reorder_suggestions: current.reorder_suggestions,
+            esl_bindings: current.esl_bindings,
+            roles: current.roles,
+        }
+    }
+
+    /// Replaces the business data with a snapshot's contents
+    /// - `snapshot`: The decoded snapshot
+    /// - `restored_by`: The owner performing the restore, who stays owner afterwards
+    /// - `mapping`: Principals to rewrite, e.g. when cloning production into staging
+    pub fn restore(&mut self, mut snapshot: StoreSnapshot, restored_by: Principal, mapping: Vec<PrincipalMapping>) -> Result<(), InventoryError> {
+        if !(OLDEST_RESTORABLE_VERSION..=SNAPSHOT_FORMAT_VERSION).contains(&snapshot.format_version) {
+            return Err(InventoryError::InvalidInput {
+                msg: format!("Unsupported snapshot format version {}", snapshot.format_version),
+            });
+        }
+        let map = PrincipalMap::new(mapping, snapshot.source_canister)?;
+        map.payments(&mut snapshot.payments);
+        map.returns(&mut snapshot.returns);
+        map.shifts(&mut snapshot.shifts);
+        self.journal_event(JournalEvent::CatalogRestored { items: snapshot.items });
+        self.logs.replace(snapshot.logs);
+        self.sales.replace(snapshot.sales);
+        self.receipts.unlink_sales(); // The restored sales were printed on the source store's receipts, if any
+        self.replace_sale_records(snapshot.sale_costs, snapshot.returns, snapshot.shifts);
+        self.reorder.rules = snapshot.reorder_rules.into_iter().collect();
+        self.reorder.suggestions = snapshot.reorder_suggestions.into_iter().map(|s| (s.id, s)).collect();
+        self.reorder.next_suggestion_id = self.reorder.suggestions.keys().next_back().map_or(0, |id| id + 1);
+        self.esl.bindings = snapshot.esl_bindings.into_iter().collect();
+        let item_ids: Vec<u32> = self.items.keys().collect();
+        for item_id in item_ids {
+            self.esl.mark_changed(item_id); // Labels must be resent whatever they showed before
+        }
+        self.access.roles = map.roles(snapshot.roles);
+        self.access.transfer_owner(restored_by); // Cloning a store must not lock out whoever restored it
+        self.payments.records = snapshot.payments;
+        self.rebuild_velocity(ic_cdk::api::time());
+        self.rebuild_item_history();
+        let log = format!(
+            "Restored snapshot taken at {} by {} at {}",
+            snapshot.created_at,
+            restored_by,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Applies a differential on top of the full snapshot it was taken against
+    ///
+    /// The base must have been restored immediately before, so the store still holds exactly
+    /// the base's data.
+    pub fn apply_delta(&mut self, mut delta: StoreDelta, restored_by: Principal, mapping: Vec<PrincipalMapping>) -> Result<(), InventoryError> {
+        if !(OLDEST_RESTORABLE_VERSION..=SNAPSHOT_FORMAT_VERSION).contains(&delta.format_version) {
+            return Err(InventoryError::InvalidInput {
+                msg: format!("Unsupported snapshot format version {}", delta.format_version),
+            });
+        }
+        if self.snapshots.restored_base.as_ref() != Some(&delta.base_sha256) {
+            return Err(InventoryError::Conflict {
+                msg: "Restore the differential's base snapshot first".to_string(),
+            });
+        }
+        let map = PrincipalMap::new(mapping, Some(delta.source_canister))?;
+        map.payments(&mut delta.upserted_payments);
+        map.returns(&mut delta.returns);
+        map.shifts(&mut delta.upserted_shifts);
+        for id in &delta.removed_items {
+            self.journal_event(JournalEvent::ItemRemoved { item_id: *id });
+            self.esl.mark_changed(*id);
+        }
+        for item in delta.upserted_items {
+            self.esl.mark_changed(item.id);
+            self.journal_event(JournalEvent::ItemPut { item });
+        }
+        let mut logs: Vec<LogEntry> = self.logs.entries().filter(|entry| entry.seq < delta.logs_from).collect();
+        logs.extend(delta.logs); // Replaces the restore's own log line, whose sequence number the source reused
+        self.logs.replace(logs);
+        self.sales.truncate(delta.sales_from);
+        self.sales.extend(delta.sales);
+        self.receipts.unlink_sales();
+        self.extend_sale_records(delta.sales_from, delta.sale_costs, delta.returns_from, delta.returns, delta.upserted_shifts, delta.removed_shifts);
+        for payment in delta.upserted_payments {
+            match self.payments.records.iter_mut().find(|p| p.id == payment.id) {
+                Some(existing) => *existing = payment,
+                None => self.payments.records.push(payment),
+            }
+        }
+        self.reorder.rules = delta.reorder_rules.into_iter().collect();
+        self.reorder.suggestions = delta.reorder_suggestions.into_iter().map(|s| (s.id, s)).collect();
+        self.reorder.next_suggestion_id = self.reorder.suggestions.keys().next_back().map_or(0, |id| id + 1);
+        self.esl.bindings = delta.esl_bindings.into_iter().collect();
+        self.access.roles = map.roles(delta.roles);
+        self.access.transfer_owner(restored_by);
+        self.snapshots.restored_base = None; // A later differential needs the base restored again
+        self.rebuild_velocity(ic_cdk::api::time());
+        self.rebuild_item_history();
+        let log = format!(
+            "Applied differential snapshot taken at {} by {} at {}",
+            delta.created_at,
+            restored_by,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Replaces the costs, returns and shifts recorded against sales with a snapshot's, which
+    /// refer to the sale IDs of the ledger it came with
+    fn replace_sale_records(&mut self, sale_costs: Vec<(u64, f64)>, returns: Vec<SaleReturn>, shifts: Vec<Shift>) {
+        self.costing.sale_costs = sale_costs.into_iter().collect();
+        self.returns.entries = returns;
+        self.shifts.shifts = shifts.into_iter().map(|shift| (shift.id, shift)).collect();
+        self.reindex_shifts();
+    }
+
+    /// Brings the costs, returns and shifts recorded against sales up to a differential's
+    ///
+    /// Costs of sales from `sales_from` and returns from `returns_from` are replaced, so none are
+    /// left pointing at sales the differential's ledger no longer holds.
+    fn extend_sale_records(
+        &mut self,
+        sales_from: u64,
+        sale_costs: Vec<(u64, f64)>,
+        returns_from: u64,
+        returns: Vec<SaleReturn>,
+        upserted_shifts: Vec<Shift>,
+        removed_shifts: Vec<u64>,
+    ) {
+        self.costing.sale_costs.retain(|id, _| *id < sales_from);
+        self.costing.sale_costs.extend(sale_costs);
+        self.returns.entries.truncate(returns_from as usize);
+        self.returns.entries.extend(returns);
+        for id in &removed_shifts {
+            self.shifts.shifts.remove(id);
+        }
+        self.shifts.shifts.extend(upserted_shifts.into_iter().map(|shift| (shift.id, shift)));
+        self.reindex_shifts();
+    }
+
+    /// Rebuilds the open shift per cashier and the next shift ID from the shifts kept
+    fn reindex_shifts(&mut self) {
+        let open = self.shifts.shifts.values().filter(|shift| shift.closed_at.is_none());
+        self.shifts.open = open.map(|shift| (shift.cashier, shift.id)).collect();
+        self.shifts.next_id = self.shifts.shifts.keys().next_back().map_or(0, |id| id + 1);
+    }
+}
+
+/// Writes bytes at the end of the snapshot region, growing it as needed
+fn append_to_stable(store: &mut SnapshotStore, bytes: &[u8]) -> Result<u64, InventoryError> {
+    let memory = storage::memory(storage::SNAPSHOTS);
+    let offset = store.end;
+    let needed = offset + bytes.len() as u64;
+    let capacity = memory.size() * WASM_PAGE_SIZE;
+    if needed > capacity && memory.grow((needed - capacity).div_ceil(WASM_PAGE_SIZE)) < 0 {
+        return Err(InventoryError::CallFailed { msg: "Could not grow stable memory".to_string() });
+    }
+    memory.write(offset, bytes);
+    store.end = needed;
+    Ok(offset)
+}
+
+fn require_snapshot(store: &SnapshotStore, id: SnapshotId) -> Result<&SnapshotInfo, InventoryError> {
+    store.snapshots.get(&id).ok_or_else(|| InventoryError::NotFound { msg: format!("Snapshot {} not found", id) })
+}
+
+/// Writes an encoded snapshot or differential to stable memory and indexes it
+fn store_snapshot(inventory: &mut SupermarketManager, bytes: &[u8], base: Option<SnapshotId>, now: u64) -> Result<SnapshotId, InventoryError> {
+    let offset = append_to_stable(&mut inventory.snapshots, bytes)?;
+    let id = inventory.snapshots.next_id;
+    inventory.snapshots.next_id += 1;
+    inventory.snapshots.snapshots.insert(id, SnapshotInfo {
+        id,
+        created_at: now,
+        size: bytes.len() as u64,
+        chunk_count: (bytes.len() as u64).div_ceil(CHUNK_SIZE),
+        sha256: Sha256::digest(bytes).to_vec(),
+        offset,
+        base,
+    });
+    let log = match base {
+        Some(base) => format!("Differential snapshot {} of snapshot {} created at {}", id, base, SupermarketManager::get_current_time()),
+        None => format!("Snapshot {} created at {}", id, SupermarketManager::get_current_time()),
+    };
+    inventory.logs.push(log);
+    Ok(id)
+}
+
+/// Takes the staged chunks of the caller followed by `chunks`
+fn take_restore_bytes(store: &mut SnapshotStore, caller: Principal, chunks: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = store.staged_chunks.remove(&caller).unwrap_or_default();
+    bytes.extend(chunks.into_iter().flatten());
+    bytes
+}
+
+// Serializes the store's business data into stable memory and returns the new snapshot's ID.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn create_snapshot() -> Result<SnapshotId, InventoryError> {
+    require_reader("create_snapshot", Role::Manager)?;
+    let now = ic_cdk::api::time();
+    let bytes = measured(HeavyOperation::Snapshot, || {
+        INVENTORY_MANAGER.with(|inventory| candid::encode_one(inventory.borrow().snapshot(now)))
+    })
+    .map_err(|err| InventoryError::InvalidInput { msg: format!("Could not encode snapshot: {}", err) })?;
+    INVENTORY_MANAGER.with(|inventory| store_snapshot(&mut inventory.borrow_mut(), &bytes, None, now))
+}
+
+// Stores the changes since a full snapshot as a differential, much smaller than a new full
+// snapshot. Restore the base, then the latest differential, to recover the current state.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn create_differential_snapshot(base: SnapshotId) -> Result<SnapshotId, InventoryError> {
+    require_reader("create_differential_snapshot", Role::Manager)?;
+    let now = ic_cdk::api::time();
+    let bytes = measured(HeavyOperation::Snapshot, || {
+        INVENTORY_MANAGER.with(|inventory| {
+            let inventory = inventory.borrow();
+            let info = require_snapshot(&inventory.snapshots, base)?;
+            if info.base.is_some() {
+                return Err(InventoryError::InvalidInput { msg: format!("Snapshot {} is itself a differential", base) });
+            }
+            let mut base_bytes = vec![0; info.size as usize];
+            storage::memory(storage::SNAPSHOTS).read(info.offset, &mut base_bytes);
+            let base_snapshot: StoreSnapshot = candid::decode_one(&base_bytes).map_err(|err| InventoryError::InvalidInput {
+                msg: format!("Could not decode snapshot {}: {}", base, err),
+            })?;
+            let delta = inventory.delta_since(&base_snapshot, info.sha256.clone(), now);
+            candid::encode_one(delta).map_err(|err| InventoryError::InvalidInput { msg: format!("Could not encode snapshot: {}", err) })
+        })
+    })?;
+    INVENTORY_MANAGER.with(|inventory| store_snapshot(&mut inventory.borrow_mut(), &bytes, Some(base), now))
+}
+
+// Retrieves one chunk of a snapshot; chunks concatenated in order form the encoded snapshot.
+// This function is marked as `#[update]` so the access audit keeps a record of the call.
+#[update(guard = "rate_limit")]
+fn download_snapshot(id: SnapshotId, chunk: u64) -> Result<Vec<u8>, InventoryError> {
+    require_reader("download_snapshot", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let info = require_snapshot(&inventory.snapshots, id)?;
+        if chunk >= info.chunk_count {
+            return Err(InventoryError::InvalidInput { msg: format!("Snapshot {} has {} chunks", id, info.chunk_count) });
+        }
+        let start = chunk * CHUNK_SIZE;
+        let mut buf = vec![0; CHUNK_SIZE.min(info.size - start) as usize];
+        storage::memory(storage::SNAPSHOTS).read(info.offset + start, &mut buf);
+        Ok(buf)
+    })
+}
+
+// Retrieves every snapshot with its size, chunk count and digest.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_snapshots() -> Result<Vec<SnapshotInfo>, InventoryError> {
+    require_reader("list_snapshots", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().snapshots.snapshots.values().cloned().collect()))
+}
+
+// Deletes a snapshot.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn delete_snapshot(id: SnapshotId) -> Result<(), InventoryError> {
+    require_caller("delete_snapshot", Role::Owner)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let store = &mut inventory.borrow_mut().snapshots;
+        let info = store.snapshots.remove(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Snapshot {} not found", id),
+        })?;
+        store.end = match store.snapshots.values().next_back() {
+            None => 0,
+            Some(_) if info.offset + info.size == store.end => info.offset, // Deleted the newest one
+            Some(_) => store.end,
+        };
+        Ok(())
+    })
+}
+
+// Uploads part of a snapshot ahead of `restore_snapshot`, for snapshots larger than a single message.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn stage_restore_chunk(chunk: Vec<u8>) -> Result<u64, InventoryError> {
+    require_caller("stage_restore_chunk", Role::Owner)?;
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let staged = inventory.snapshots.staged_chunks.entry(caller).or_default();
+        staged.extend_from_slice(&chunk);
+        Ok(staged.len() as u64)
+    })
+}
+
+// Replaces the store's business data with a downloaded snapshot, for disaster recovery or
+// cloning a store into a new canister. The snapshot is any staged chunks followed by `chunks`.
+// Principals in `mapping` are rewritten, and the source canister becomes this canister.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn restore_snapshot(chunks: Vec<Vec<u8>>, mapping: Option<Vec<PrincipalMapping>>) -> Result<(), InventoryError> {
+    require_caller("restore_snapshot", Role::Owner)?;
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        let bytes = take_restore_bytes(&mut inventory.snapshots, caller, chunks);
+        let snapshot: StoreSnapshot = candid::decode_one(&bytes).map_err(|err| InventoryError::InvalidInput {
+            msg: format!("Not a valid snapshot: {}", err),
+        })?;
+        inventory.restore(snapshot, caller, mapping.unwrap_or_default())?;
+        inventory.snapshots.restored_base = Some(Sha256::digest(&bytes).to_vec());
+        Ok(())
+    })
+}
+
+// Applies a downloaded differential snapshot on top of its base, which must have been restored
+// with `restore_snapshot` just before. Chunks and `mapping` work as for `restore_snapshot`.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn restore_differential_snapshot(chunks: Vec<Vec<u8>>, mapping: Option<Vec<PrincipalMapping>>) -> Result<(), InventoryError> {
+    require_caller("restore_differential_snapshot", Role::Owner)?;
+    let caller = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.governance.ensure_direct_change_allowed()?;
+        let bytes = take_restore_bytes(&mut inventory.snapshots, caller, chunks);
+        let delta: StoreDelta = candid::decode_one(&bytes).map_err(|err| InventoryError::InvalidInput {
+            msg: format!("Not a valid differential snapshot: {}", err),
+        })?;
+        inventory.apply_delta(delta, caller, mapping.unwrap_or_default())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn returned(id: u64, sale_id: u64) -> SaleReturn {
+        SaleReturn {
+            id,
+            sale_id,
+            item_id: 1,
+            quantity: 1,
+            refund: 1.0,
+            net_refund: 1.0,
+            restocked: false,
+            recorded_by: Principal::anonymous(),
+            timestamp: 0,
+        }
+    }
+
+    fn shift(id: u64, cashier: Principal, sale_ids: Vec<u64>, closed: bool) -> Shift {
+        Shift {
+            id,
+            cashier,
+            opened_by: cashier,
+            opened_at: 0,
+            opening_float: 0.0,
+            sales_total: sale_ids.len() as f64,
+            cash_total: sale_ids.len() as f64,
+            non_cash_total: 0.0,
+            sale_ids,
+            return_ids: Vec::new(),
+            tax_total: 0.0,
+            refunds_total: 0.0,
+            closed_at: closed.then_some(1),
+            counted_cash: None,
+        }
+    }
+
+    #[test]
+    fn restore_replaces_the_records_kept_against_sales() {
+        let (cashier, other) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut manager = SupermarketManager::new();
+        manager.costing.sale_costs.insert(7, 4.0);
+        manager.returns.entries = vec![returned(0, 7)];
+        manager.shifts.shifts.insert(0, shift(0, cashier, vec![7], false));
+        manager.shifts.open.insert(cashier, 0);
+
+        manager.replace_sale_records(vec![(0, 2.0)], vec![returned(0, 0)], vec![shift(3, cashier, vec![0], true), shift(4, other, Vec::new(), false)]);
+        assert_eq!(manager.costing.sale_costs, HashMap::from([(0, 2.0)]));
+        assert_eq!(manager.returns.entries.iter().map(|entry| entry.sale_id).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(manager.shifts.open, HashMap::from([(other, 4)])); // The local cashier's shift went with its sales
+        assert_eq!(manager.shifts.next_id, 5);
+    }
+
+    #[test]
+    fn differential_replaces_the_records_of_later_sales() {
+        let cashier = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        manager.replace_sale_records(
+            vec![(0, 1.0), (1, 1.5), (2, 9.0)],  // The cost of sale 2 is left from the ledger the base replaced
+            vec![returned(0, 0), returned(1, 2)],
+            vec![shift(0, cashier, vec![0, 1], false)],
+        );
+
+        manager.extend_sale_records(2, vec![(2, 3.0)], 1, vec![returned(1, 2), returned(2, 3)], vec![shift(0, cashier, vec![0, 1, 2, 3], true)], Vec::new());
+        assert_eq!(manager.costing.sale_costs, HashMap::from([(0, 1.0), (1, 1.5), (2, 3.0)]));
+        assert_eq!(manager.returns.entries.iter().map(|entry| (entry.id, entry.sale_id)).collect::<Vec<_>>(), vec![(0, 0), (1, 2), (2, 3)]);
+        assert_eq!(manager.shifts.shifts[&0].sale_ids, vec![0, 1, 2, 3]);
+        assert!(manager.shifts.open.is_empty());
+
+        manager.extend_sale_records(2, Vec::new(), 1, Vec::new(), Vec::new(), vec![0]);
+        assert_eq!(manager.costing.sale_costs.len(), 2);
+        assert_eq!(manager.returns.entries.len(), 1);
+        assert!(manager.shifts.shifts.is_empty());
+    }
+}
@@ -0,0 +1,508 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{AdjustmentReason, InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+/// Where a stocktake is in its workflow
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum StocktakeStatus {
+    Open,      // Counts are being submitted
+    Finalized, // Variances were applied to stock or held for approval
+    Cancelled, // Abandoned without touching stock
+}
+
+/// What happened to a counted item's variance when its stocktake was finalized
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum VarianceStatus {
+    AutoApplied,     // Within tolerance, so stock was corrected straight away
+    PendingApproval, // Beyond tolerance; stock is left alone until a manager decides
+    Approved,        // Beyond tolerance and applied by a manager
+    Rejected,        // Beyond tolerance and discarded by a manager
+}
+
+/// How large a variance may be before a manager has to approve it
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VarianceTolerance {
+    pub max_value_pct: f64, // Allowed variance as a percentage of the expected stock's value, e.g. 1.0 for ±1%
+    pub floor_value: f64,   // Variances worth at most this much are always allowed, so nearly empty shelves do not need approval
+}
+
+impl VarianceTolerance {
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        Validator::new()
+            .check(self.max_value_pct.is_finite() && self.max_value_pct >= 0.0, "tolerance.max_value_pct", "must be a non-negative number")
+            .check(self.floor_value.is_finite() && self.floor_value >= 0.0, "tolerance.floor_value", "must be a non-negative number")
+            .finish()
+    }
+}
+
+/// The configured variance tolerances
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VarianceTolerances {
+    pub categories: Vec<(String, VarianceTolerance)>, // Tolerances per item category
+    pub default: Option<VarianceTolerance>,           // Tolerance for items in no configured category
+}
+
+/// A count of one item submitted during a stocktake
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemCount {
+    pub counted: u32,          // Units found, in grams or millilitres for weighed goods
+    pub counted_by: Principal, // Employee who submitted the count
+    pub counted_at: u64,       // Time of the count in nanoseconds since the Unix epoch
+}
+
+/// Difference between the expected and counted stock of one item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VarianceLine {
+    pub item_id: u32,
+    pub name: String,
+    pub expected: u32,              // Quantity frozen when the stocktake started
+    pub counted: u32,               // Quantity found
+    pub variance: i64,              // Counted minus expected; negative means stock went missing
+    pub variance_value: f64,        // Variance priced at the item's current price
+    pub allowed_value: Option<f64>, // Largest variance value the tolerance allowed; None when no tolerance applies
+    pub status: VarianceStatus,
+    pub new_quantity: Option<u32>,  // Quantity the item was, or in a preview would be, set to by the variance
+}
+
+/// Result of a finalized stocktake
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VarianceReport {
+    pub stocktake_id: u64,
+    pub location: String,
+    pub finalized_at: u64,         // Time of finalizing in nanoseconds since the Unix epoch
+    pub lines: Vec<VarianceLine>,  // Counted items, largest absolute variance value first
+    pub uncounted: Vec<u32>,       // Items in scope that nobody counted; their stock was left alone
+    pub total_variance_value: f64, // Sum of the variance values
+}
+
+/// A stock counting session
+///
+/// Expected quantities are frozen when the session starts. Sales continue while staff count,
+/// so finalizing applies each item's variance to its current stock rather than overwriting it
+/// with the count.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Stocktake {
+    pub id: u64,
+    pub location: String,                 // Area being counted, e.g. "Aisle 4" or "Back store"
+    pub status: StocktakeStatus,
+    pub started_by: Principal,            // Manager who started the session
+    pub started_at: u64,                  // Time the expected quantities were frozen
+    pub expected: BTreeMap<u32, u32>,     // Frozen quantities keyed by item ID; these items are in scope
+    pub counts: BTreeMap<u32, ItemCount>, // Latest count per item
+    pub report: Option<VarianceReport>,   // Set once the session is finalized
+}
+
+/// A variance waiting for, or given, a manager's decision
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VarianceException {
+    pub stocktake_id: u64,
+    pub location: String,
+    pub finalized_at: u64, // Time the stocktake was finalized in nanoseconds since the Unix epoch
+    pub line: VarianceLine,
+}
+
+/// Every stocktake, open or closed, and the variance tolerances applied when finalizing
+///
+/// Without any tolerance configured every variance is applied straight away.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Stocktakes {
+    pub sessions: BTreeMap<u64, Stocktake>,               // Stocktakes keyed by ID
+    pub next_id: u64,                                     // ID handed to the next stocktake
+    pub tolerances: BTreeMap<String, VarianceTolerance>,  // Tolerances keyed by item category
+    pub default_tolerance: Option<VarianceTolerance>,     // Tolerance for items in no configured category
+}
+
+impl Stocktakes {
+    fn open(&self, id: u64) -> Result<&Stocktake, InventoryError> {
+        let stocktake = self.sessions.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Stocktake {} not found", id),
+        })?;
+        if stocktake.status != StocktakeStatus::Open {
+            return Err(InventoryError::Conflict { msg: format!("Stocktake {} is {:?}", id, stocktake.status) });
+        }
+        Ok(stocktake)
+    }
+
+    fn open_mut(&mut self, id: u64) -> Result<&mut Stocktake, InventoryError> {
+        let stocktake = self.sessions.get_mut(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Stocktake {} not found", id),
+        })?;
+        if stocktake.status != StocktakeStatus::Open {
+            return Err(InventoryError::Conflict { msg: format!("Stocktake {} is {:?}", id, stocktake.status) });
+        }
+        Ok(stocktake)
+    }
+
+    fn tolerance_for(&self, category: Option<&str>) -> Option<&VarianceTolerance> {
+        category.and_then(|category| self.tolerances.get(category)).or(self.default_tolerance.as_ref())
+    }
+}
+
+/// Stock after a variance is applied to it
+fn varied_quantity(current: u32, variance: i64) -> u32 {
+    (current as i64 + variance).clamp(0, u32::MAX as i64) as u32
+}
+
+impl SupermarketManager {
+    /// Sets or removes the variance tolerance of a category, or of other items when `category` is None
+    pub fn set_variance_tolerance(&mut self, category: Option<String>, tolerance: Option<VarianceTolerance>) {
+        let stocktakes = &mut self.stocktakes;
+        match (category.clone(), tolerance) {
+            (Some(category), Some(t)) => { stocktakes.tolerances.insert(category, t); }
+            (Some(category), None) => { stocktakes.tolerances.remove(&category); }
+            (None, t) => stocktakes.default_tolerance = t,
+        }
+        let log = format!(
+            "Variance tolerance for {} changed at {}",
+            category.as_deref().unwrap_or("other items"),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+    }
+
+    /// Opens a stocktake, freezing the expected quantity of every item in scope
+    /// - `location`: Area being counted
+    /// - `item_ids`: Items to count, or None for every item that is not archived
+    pub fn start_stocktake(&mut self, location: String, item_ids: Option<Vec<u32>>, started_by: Principal, now: u64) -> Result<u64, InventoryError> {
+        if self.stocktakes.sessions.values().any(|s| s.status == StocktakeStatus::Open && s.location == location) {
+            return Err(InventoryError::Conflict { msg: format!("A stocktake of {} is already open", location) });
+        }
+        let expected: BTreeMap<u32, u32> = match item_ids {
+            Some(ids) => ids
+                .into_iter()
+                .map(|id| self.items.get(&id).map(|item| (id, item.quantity)).ok_or_else(|| InventoryError::NotFound {
+                    msg: format!("Item {} not found", id),
+                }))
+                .collect::<Result<_, _>>()?,
+            None => self.items.values().filter(|item| !item.archived).map(|item| (item.id, item.quantity)).collect(),
+        };
+        let id = self.stocktakes.next_id;
+        self.stocktakes.next_id += 1;
+        let log = format!(
+            "Stocktake {} of {} started with {} items at {}",
+            id,
+            location,
+            expected.len(),
+            SupermarketManager::get_current_time()
+        );
+        self.stocktakes.sessions.insert(id, Stocktake {
+            id,
+            location,
+            status: StocktakeStatus::Open,
+            started_by,
+            started_at: now,
+            expected,
+            counts: BTreeMap::new(),
+            report: None,
+        });
+        self.logs.push(log);
+        Ok(id)
+    }
+
+    /// Records counted quantities; a later count of the same item replaces the earlier one
+    /// - `counts`: Pairs of (item ID, counted quantity)
+    pub fn submit_stocktake_counts(&mut self, id: u64, counts: &[(u32, u32)], counted_by: Principal, now: u64) -> Result<(), InventoryError> {
+        let mut validator = Validator::new();
+        {
+            let stocktake = self.stocktakes.open_mut(id)?;
+            for (i, &(item_id, _)) in counts.iter().enumerate() {
+                validator.check(stocktake.expected.contains_key(&item_id), &format!("counts[{}].item_id", i), "is not part of this stocktake");
+            }
+        }
+        for (i, &(item_id, counted)) in counts.iter().enumerate() {
+            if let Some(item) = self.items.get(&item_id) {
+                validator.quantity(&format!("counts[{}].counted", i), counted, item.unit);
+            }
+        }
+        validator.finish()?;
+        let stocktake = self.stocktakes.open_mut(id)?;
+        for &(item_id, counted) in counts {
+            stocktake.counts.insert(item_id, ItemCount { counted, counted_by, counted_at: now });
+        }
+        Ok(())
+    }
+
+    /// The variance report finalizing an open stocktake would produce, with the stock levels
+    /// variances within tolerance would set, without touching stock
+    pub fn variance_report(&self, id: u64, now: u64) -> Result<VarianceReport, InventoryError> {
+        let stocktake = self.stocktakes.open(id)?;
+        let mut lines = Vec::new();
+        let mut uncounted = Vec::new();
+        for (&item_id, &expected) in &stocktake.expected {
+            let (Some(count), Some(item)) = (stocktake.counts.get(&item_id), self.items.get(&item_id)) else {
+                uncounted.push(item_id); // Not counted, or removed since the stocktake started
+                continue;
+            };
+            let variance = count.counted as i64 - expected as i64;
+            let unit_value = item.price / item.unit.stock_units_per_price_unit() as f64;
+            let variance_value = unit_value * variance as f64;
+            let allowed_value = self.stocktakes
+                .tolerance_for(item.category.as_deref())
+                .map(|t| (unit_value * expected as f64 * t.max_value_pct / 100.0).max(t.floor_value));
+            let within = allowed_value.is_none_or(|allowed| variance_value.abs() <= allowed);
+            lines.push(VarianceLine {
+                item_id,
+                name: item.name.clone(),
+                expected,
+                counted: count.counted,
+                variance,
+                variance_value,
+                allowed_value,
+                status: if within { VarianceStatus::AutoApplied } else { VarianceStatus::PendingApproval },
+                new_quantity: within.then(|| varied_quantity(item.quantity, variance)),
+            });
+        }
+        lines.sort_by(|a, b| b.variance_value.abs().total_cmp(&a.variance_value.abs()));
+        Ok(VarianceReport {
+            stocktake_id: id,
+            location: stocktake.location.clone(),
+            finalized_at: now,
+            total_variance_value: lines.iter().map(|line| line.variance_value).sum(),
+            lines,
+            uncounted,
+        })
+    }
+
+    /// Closes a stocktake, applying every counted item's variance to its stock as a recount
+    ///
+    /// Returns the variance report.
+    pub fn finalize_stocktake(&mut self, id: u64, now: u64) -> Result<VarianceReport, InventoryError> {
+        let mut report = self.variance_report(id, now)?;
+        for line in report.lines.iter_mut().filter(|line| line.status == VarianceStatus::AutoApplied) {
+            line.new_quantity = Some(self.apply_variance(line.item_id, line.variance));
+        }
+        let session = self.stocktakes.sessions.get_mut(&id).expect("variance_report checked the stocktake");
+        session.status = StocktakeStatus::Finalized;
+        session.report = Some(report.clone());
+        let log = format!(
+            "Stocktake {} of {} finalized with a variance of {} at {}",
+            id,
+            report.location,
+            self.config.format_amount(report.total_variance_value),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(report)
+    }
+
+    /// Applies a variance to an item's current stock as a recount and returns the new quantity
+    fn apply_variance(&mut self, item_id: u32, variance: i64) -> u32 {
+        let current = self.items.get(&item_id).map_or(0, |item| item.quantity);
+        let new_quantity = varied_quantity(current, variance);
+        if variance != 0 {
+            self.adjust_item_quantity(item_id, new_quantity, AdjustmentReason::Recount);
+        }
+        new_quantity
+    }
+
+    /// Approves or rejects a variance that was beyond tolerance; approving applies it to the
+    /// item's current stock
+    pub fn decide_variance(&mut self, stocktake_id: u64, item_id: u32, approve: bool) -> Result<VarianceLine, InventoryError> {
+        let line = self.stocktakes.sessions
+            .get(&stocktake_id)
+            .and_then(|s| s.report.as_ref())
+            .and_then(|r| r.lines.iter().find(|line| line.item_id == item_id))
+            .ok_or_else(|| InventoryError::NotFound {
+                msg: format!("Stocktake {} has no variance for item {}", stocktake_id, item_id),
+            })?;
+        if line.status != VarianceStatus::PendingApproval {
+            return Err(InventoryError::Conflict { msg: format!("The variance is {:?}", line.status) });
+        }
+        let variance = line.variance;
+        let new_quantity = if approve && self.items.contains_key(&item_id) { Some(self.apply_variance(item_id, variance)) } else { None };
+        let report = self.stocktakes.sessions
+            .get_mut(&stocktake_id)
+            .and_then(|s| s.report.as_mut())
+            .expect("the report was found above");
+        let line = report.lines.iter_mut().find(|line| line.item_id == item_id).expect("the line was found above");
+        line.status = if approve { VarianceStatus::Approved } else { VarianceStatus::Rejected };
+        line.new_quantity = new_quantity;
+        let line = line.clone();
+        let log = format!(
+            "Stocktake {} variance of {} for item {} {:?} at {}",
+            stocktake_id,
+            variance,
+            item_id,
+            line.status,
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(line)
+    }
+}
+
+// Starts a stocktake of a location, freezing the expected quantities of the given items, or of
+// every item that is not archived when `item_ids` is None. Returns the stocktake ID.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn start_stocktake(location: String, item_ids: Option<Vec<u32>>) -> Result<u64, InventoryError> {
+    require_permission("start_stocktake", Permission::Stocktake)?;
+    Validator::new().name("location", &location).finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().start_stocktake(location, item_ids, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Submits counted quantities as (item ID, counted) pairs for an open stocktake.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn submit_stocktake_counts(id: u64, counts: Vec<(u32, u32)>) -> Result<(), InventoryError> {
+    require_permission("submit_stocktake_counts", Permission::CountStock)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().submit_stocktake_counts(id, &counts, ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Finalizes a stocktake: computes variances, adjusts stock with reason `Recount` and returns
+// the variance report.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn finalize_stocktake(id: u64) -> Result<VarianceReport, InventoryError> {
+    require_permission("finalize_stocktake", Permission::Stocktake)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().finalize_stocktake(id, ic_cdk::api::time())
+    })
+}
+
+// Previews `finalize_stocktake`: returns the variance report it would produce, with the stock
+// levels it would set, leaving the stocktake open and stock untouched.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn simulate_finalize_stocktake(id: u64) -> Result<VarianceReport, InventoryError> {
+    require_reader("simulate_finalize_stocktake", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| inventory.borrow().variance_report(id, ic_cdk::api::time()))
+}
+
+// Cancels an open stocktake without touching stock.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_stocktake(id: u64) -> Result<(), InventoryError> {
+    require_permission("cancel_stocktake", Permission::Stocktake)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.stocktakes.open_mut(id)?.status = StocktakeStatus::Cancelled;
+        let log = format!("Stocktake {} cancelled at {}", id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Approves a variance that was beyond tolerance, applying it to the item's current stock.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn approve_variance(stocktake_id: u64, item_id: u32) -> Result<VarianceLine, InventoryError> {
+    require_permission("approve_variance", Permission::Stocktake)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().decide_variance(stocktake_id, item_id, true)
+    })
+}
+
+// Rejects a variance that was beyond tolerance, leaving stock as it is; recount the item instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn reject_variance(stocktake_id: u64, item_id: u32) -> Result<VarianceLine, InventoryError> {
+    require_permission("reject_variance", Permission::Stocktake)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().decide_variance(stocktake_id, item_id, false)
+    })
+}
+
+// Sets the variance tolerance of an item category, or the default for other items when
+// `category` is None. A `tolerance` of None removes it. Once a governance canister is set, this
+// takes an executed `SetVarianceTolerance` proposal instead.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_variance_tolerance(category: Option<String>, tolerance: Option<VarianceTolerance>) -> Result<(), InventoryError> {
+    require_caller("set_variance_tolerance", Role::Manager)?;
+    if let Some(t) = &tolerance {
+        t.validate()?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.set_variance_tolerance(category, tolerance);
+        Ok(())
+    })
+}
+
+// Retrieves the variance tolerances per category and the default for other items.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_variance_tolerances() -> Result<VarianceTolerances, InventoryError> {
+    require_reader("get_variance_tolerances", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let stocktakes = &inventory.borrow().stocktakes;
+        Ok(VarianceTolerances {
+            categories: stocktakes.tolerances.iter().map(|(c, t)| (c.clone(), t.clone())).collect(),
+            default: stocktakes.default_tolerance.clone(),
+        })
+    })
+}
+
+// Retrieves variances that were beyond tolerance: only those awaiting a decision, or also
+// decided ones when `include_decided` is set. Largest absolute value first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_variance_exceptions(include_decided: Option<bool>) -> Result<Vec<VarianceException>, InventoryError> {
+    require_reader("get_variance_exceptions", Role::Manager)?;
+    let include_decided = include_decided.unwrap_or(false);
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let mut exceptions: Vec<VarianceException> = inventory.stocktakes.sessions
+            .values()
+            .filter_map(|s| s.report.as_ref())
+            .flat_map(|report| {
+                report.lines
+                    .iter()
+                    .filter(|line| match line.status {
+                        VarianceStatus::PendingApproval => true,
+                        VarianceStatus::Approved | VarianceStatus::Rejected => include_decided,
+                        VarianceStatus::AutoApplied => false,
+                    })
+                    .map(|line| VarianceException {
+                        stocktake_id: report.stocktake_id,
+                        location: report.location.clone(),
+                        finalized_at: report.finalized_at,
+                        line: line.clone(),
+                    })
+            })
+            .collect();
+        exceptions.sort_by(|a, b| b.line.variance_value.abs().total_cmp(&a.line.variance_value.abs()));
+        Ok(exceptions)
+    })
+}
+
+// Retrieves a stocktake with its frozen quantities and the counts submitted so far.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_stocktake(id: u64) -> Result<Stocktake, InventoryError> {
+    require_reader("get_stocktake", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().stocktakes.sessions.get(&id).cloned().ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Stocktake {} not found", id),
+        })
+    })
+}
+
+// Retrieves the variance report of a finalized stocktake.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_variance_report(id: u64) -> Result<VarianceReport, InventoryError> {
+    require_reader("get_variance_report", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let stocktake = inventory.stocktakes.sessions.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Stocktake {} not found", id),
+        })?;
+        stocktake.report.clone().ok_or_else(|| InventoryError::Conflict {
+            msg: format!("Stocktake {} is {:?}", id, stocktake.status),
+        })
+    })
+}
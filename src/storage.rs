@@ -0,0 +1,58 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::journal::JournalEntry;
+use crate::logs::{LogEntry, RetentionPolicy};
+use crate::receipts::Receipt;
+use crate::sales::Sale;
+use crate::InventoryItem;
+
+/// A region of stable memory given to one structure
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const ITEMS: MemoryId = MemoryId::new(0);         // The item catalog
+pub const LOGS: MemoryId = MemoryId::new(1);          // Log entries by sequence number
+pub const LOG_FIRST_SEQ: MemoryId = MemoryId::new(2); // Sequence number of the oldest kept log entry
+pub const SALES: MemoryId = MemoryId::new(3);         // The sales ledger
+pub const JOURNAL_INDEX: MemoryId = MemoryId::new(4); // Offsets of the journal's entries
+pub const JOURNAL_DATA: MemoryId = MemoryId::new(5);  // The journal's entries
+pub const SNAPSHOTS: MemoryId = MemoryId::new(6);     // Encoded snapshots, appended one after another
+pub const HEAP_STATE: MemoryId = MemoryId::new(7);    // Heap state saved by `pre_upgrade` for `post_upgrade`
+pub const RECEIPTS: MemoryId = MemoryId::new(8);      // Receipts by number
+pub const LOG_RETENTION: MemoryId = MemoryId::new(9); // The log retention policy
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+/// The stable memory region with the given ID
+///
+/// Each ID must back a single structure; two structures over one region corrupt each other.
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// Stores records in stable memory as their Candid encoding
+macro_rules! candid_storable {
+    ($($record:ty),*) => {
+        $(
+            impl Storable for $record {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(candid::encode_one(self).expect("records always encode"))
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    candid::decode_one(&bytes).expect("stable memory holds records written by `to_bytes`")
+                }
+
+                const BOUND: Bound = Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+candid_storable!(InventoryItem, LogEntry, Sale, JournalEntry, Receipt, RetentionPolicy);
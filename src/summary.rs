@@ -0,0 +1,197 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::btree_map::{BTreeMap, Entry};
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::costing::UNCATEGORIZED;
+use crate::{InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MICROS_PER_UNIT: f64 = 1_000_000.0; // Retail value is summed in millionths so adding and taking away items never drifts
+
+/// Items of one category
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct CategoryCount {
+    pub category: String,
+    pub items: u64,
+}
+
+/// Headline figures for the whole inventory
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InventorySummary {
+    pub total_skus: u64,                // Items that are not archived
+    pub archived_items: u64,
+    pub total_units: u64,               // Stock on hand in stock units: pieces, grams or millilitres
+    pub total_retail_value: f64,        // Stock on hand at current selling prices
+    pub currency_code: String,
+    pub categories: Vec<CategoryCount>, // Ordered by category
+    pub low_stock_items: u64,           // Items at or below their reorder rule's threshold or the store default
+    pub expired_items: u64,             // Items in stock past their expiration date
+    pub as_of: u64,
+}
+
+/// Running totals behind the inventory summary, kept in step with every change to the catalog
+///
+/// Each change takes the item's old figures out and puts its new ones in, so reading the summary
+/// never scans the catalog. Items count as expired once their date passes without any change, so
+/// in-stock items wait in `expiring` by date and are moved to `expired` as time passes them.
+/// Like the sales figures, the totals are rebuilt from the catalog after an upgrade.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct SummaryCounters {
+    skus: u64,
+    archived: u64,
+    units: u64,
+    retail_micros: i128,
+    categories: BTreeMap<String, u64>,
+    low_stock: u64,
+    expiring: BTreeMap<u64, u64>, // Items in stock by expiration date in seconds, still ahead at the last sweep
+    expired: u64,                 // Items in stock whose expiration date had passed at the last sweep
+    swept_until: u64,             // Time of the last sweep in seconds since the Unix epoch
+}
+
+/// What one item adds to the running totals
+struct ItemFigures {
+    category: String,
+    units: u64,
+    retail_micros: i128,
+    low_stock: bool,
+    expires_at: Option<u64>, // Set while the item is in stock
+}
+
+impl SummaryCounters {
+    fn count(&mut self, figures: Option<&ItemFigures>, add: bool) {
+        let Some(figures) = figures else {
+            step(&mut self.archived, add);
+            return;
+        };
+        step(&mut self.skus, add);
+        step_entry(&mut self.categories, figures.category.clone(), add);
+        if add {
+            self.units += figures.units;
+            self.retail_micros += figures.retail_micros;
+        } else {
+            self.units -= figures.units;
+            self.retail_micros -= figures.retail_micros;
+        }
+        if figures.low_stock {
+            step(&mut self.low_stock, add);
+        }
+        match figures.expires_at {
+            Some(expires_at) if expires_at <= self.swept_until => step(&mut self.expired, add),
+            Some(expires_at) => step_entry(&mut self.expiring, expires_at, add),
+            None => {}
+        }
+    }
+
+    /// Moves the items whose expiration date has passed by `now_secs` to the expired count
+    fn sweep(&mut self, now_secs: u64) {
+        while let Some(entry) = self.expiring.first_entry() {
+            if *entry.key() > now_secs {
+                break;
+            }
+            self.expired += entry.remove();
+        }
+        self.swept_until = self.swept_until.max(now_secs);
+    }
+
+    /// Expired items as of `now_secs`, including those passed since the last sweep
+    fn expired_at(&self, now_secs: u64) -> u64 {
+        self.expired + self.expiring.range(..=now_secs).map(|(_, items)| items).sum::<u64>()
+    }
+}
+
+fn step(counter: &mut u64, add: bool) {
+    if add {
+        *counter += 1;
+    } else {
+        *counter -= 1;
+    }
+}
+
+/// Steps the count under `key`, dropping the key once nothing is left under it
+fn step_entry<K: Ord>(counts: &mut BTreeMap<K, u64>, key: K, add: bool) {
+    match counts.entry(key) {
+        Entry::Occupied(mut entry) => {
+            step(entry.get_mut(), add);
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        Entry::Vacant(entry) if add => {
+            entry.insert(1);
+        }
+        Entry::Vacant(_) => {}
+    }
+}
+
+impl SupermarketManager {
+    /// The item's share of the totals, or None for an archived item that only counts as archived
+    fn summary_figures(&self, item: &InventoryItem) -> Option<ItemFigures> {
+        if item.archived {
+            return None;
+        }
+        // A pack's stock is its base item's and a bundle's is its components'
+        let holds_stock = !matches!(item.unit, Unit::Pack { .. }) && !self.bundles.definitions.contains_key(&item.id);
+        let units = if holds_stock { item.quantity as u64 } else { 0 };
+        let retail = item.price / item.unit.stock_units_per_price_unit() as f64 * units as f64;
+        let threshold = self.reorder.rules.get(&item.id).map(|rule| rule.threshold).or(self.config.default_low_stock_threshold);
+        Some(ItemFigures {
+            category: item.category.clone().unwrap_or_else(|| UNCATEGORIZED.to_string()),
+            units,
+            retail_micros: (retail * MICROS_PER_UNIT).round() as i128,
+            low_stock: threshold.is_some_and(|threshold| item.quantity <= threshold),
+            expires_at: (item.quantity > 0).then_some(item.expiration_date),
+        })
+    }
+
+    /// Makes a change that can move an item's figures, taking them out before and putting them back after
+    pub fn recount_item<R>(&mut self, item_id: u32, change: impl FnOnce(&mut Self) -> R) -> R {
+        if let Some(item) = self.items.get(&item_id) {
+            let figures = self.summary_figures(&item);
+            self.summary.count(figures.as_ref(), false);
+        }
+        let result = change(self);
+        if let Some(item) = self.items.get(&item_id) {
+            let figures = self.summary_figures(&item);
+            self.summary.count(figures.as_ref(), true);
+        }
+        self.summary.sweep(ic_cdk::api::time() / NANOS_PER_SEC);
+        result
+    }
+
+    /// Recomputes the totals from the whole catalog, after an upgrade or a change that can touch every item
+    pub fn rebuild_inventory_summary(&mut self, now: u64) {
+        let mut summary = SummaryCounters { swept_until: now / NANOS_PER_SEC, ..Default::default() };
+        for item in self.items.values() {
+            summary.count(self.summary_figures(&item).as_ref(), true);
+        }
+        self.summary = summary;
+    }
+
+    /// The running totals as of `now`
+    pub fn inventory_summary(&self, now: u64) -> InventorySummary {
+        let summary = &self.summary;
+        InventorySummary {
+            total_skus: summary.skus,
+            archived_items: summary.archived,
+            total_units: summary.units,
+            total_retail_value: self.config.round_amount(summary.retail_micros as f64 / MICROS_PER_UNIT),
+            currency_code: self.config.currency_code.clone(),
+            categories: summary.categories.iter().map(|(category, &items)| CategoryCount { category: category.clone(), items }).collect(),
+            low_stock_items: summary.low_stock,
+            expired_items: summary.expired_at(now / NANOS_PER_SEC),
+            as_of: now,
+        }
+    }
+}
+
+// Retrieves item, stock and value totals for the whole inventory. They are kept up to date as
+// items change, so this costs the same however large the catalog is.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_inventory_summary() -> Result<InventorySummary, InventoryError> {
+    require_reader("get_inventory_summary", Role::Clerk)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().inventory_summary(ic_cdk::api::time())))
+}
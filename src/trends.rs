@@ -0,0 +1,225 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::BTreeMap;
+
+use crate::access::Role;
+use crate::breakglass::require_reader;
+use crate::history::ItemHistoryEvent;
+use crate::load::admit_expensive_call;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_TREND_BUCKETS: u64 = 1000; // Most buckets one trend query returns
+const EPOCH_TO_MONDAY: u64 = 3;      // 1 January 1970 was a Thursday; shifting by 3 days starts weeks on a Monday
+const DAYS_PER_WEEK: u64 = 7;
+
+/// A figure tracked for an item over time
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum TrendMetric {
+    StockLevel, // Units on hand, in grams or millilitres for weighed goods
+    Price,      // The item's own price
+    UnitsSold,  // Units sold per day, test sales excluded
+    Revenue,    // Sales total after tax per day, test sales excluded
+}
+
+/// Width of the buckets a trend is downsampled into
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum TrendResolution {
+    Daily,  // Local midnight to midnight
+    Weekly, // Monday to Sunday in the store's timezone
+}
+
+impl TrendResolution {
+    fn bucket_of(self, day: u64) -> u64 {
+        match self {
+            TrendResolution::Daily => day,
+            TrendResolution::Weekly => (day + EPOCH_TO_MONDAY) / DAYS_PER_WEEK,
+        }
+    }
+
+    /// First and last day of a bucket
+    fn days_of(self, bucket: u64) -> (u64, u64) {
+        match self {
+            TrendResolution::Daily => (bucket, bucket),
+            TrendResolution::Weekly => {
+                let monday = bucket * DAYS_PER_WEEK;
+                (monday.saturating_sub(EPOCH_TO_MONDAY), monday + DAYS_PER_WEEK - 1 - EPOCH_TO_MONDAY)
+            }
+        }
+    }
+}
+
+/// One downsampled point of a trend
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TrendBucket {
+    pub start: u64,         // Local midnight the bucket starts at, in nanoseconds since the Unix epoch
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,           // Time-weighted for stock and price; the mean day for sales figures
+    pub total: Option<f64>, // Units or revenue over the whole bucket; None for stock and price
+}
+
+/// Downsamples a step series, where each point holds from its time until the next one
+///
+/// Parts of a span before the first point are unknown and left out; a bucket with nothing known is skipped.
+fn step_bucket(points: &[(u64, f64)], start: u64, from: u64, to: u64) -> Option<TrendBucket> {
+    let first = points.partition_point(|&(time, _)| time <= from);
+    let mut current = first.checked_sub(1).map(|i| (from, points[i].1));
+    let (mut min, mut max, mut weighted, mut covered) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0u64);
+    let mut add = |(since, value): (u64, f64), until: u64| {
+        if until > since {
+            min = min.min(value);
+            max = max.max(value);
+            weighted += value * (until - since) as f64;
+            covered += until - since;
+        }
+    };
+    for &(time, value) in points[first..].iter().take_while(|&&(time, _)| time < to) {
+        if let Some(held) = current {
+            add(held, time);
+        }
+        current = Some((time, value));
+    }
+    if let Some(held) = current {
+        add(held, to);
+    }
+    (covered > 0).then(|| TrendBucket { start, min, max, avg: weighted / covered as f64, total: None })
+}
+
+impl SupermarketManager {
+    /// Stock level after each change the item's timeline records, oldest first
+    ///
+    /// Walks back from the current level, so it stays right when older entries were dropped.
+    fn stock_series(&self, item_id: u32) -> Vec<(u64, f64)> {
+        let Some(item) = self.items.get(&item_id) else { return Vec::new() };
+        let holds_stock = !matches!(item.unit, Unit::Pack { .. }) && !self.bundles.definitions.contains_key(&item_id);
+        let mut after = item.quantity;
+        let mut points = Vec::new();
+        let mut known_before = true;
+        for entry in self.item_history.timelines.get(&item_id).into_iter().flat_map(|t| t.entries.iter().rev()) {
+            let before = match entry.event {
+                ItemHistoryEvent::QuantityChanged { old_quantity, .. } => Some(old_quantity),
+                ItemHistoryEvent::Sold { quantity, .. } if holds_stock => Some(after.saturating_add(quantity)),
+                ItemHistoryEvent::StockTaken { units, .. } => Some(after.saturating_add(units)),
+                ItemHistoryEvent::Created { .. } => None, // Nothing is known of the stock before it
+                _ => continue,
+            };
+            points.push((entry.timestamp, after as f64));
+            match before {
+                Some(before) => after = before,
+                None => {
+                    known_before = false;
+                    break;
+                }
+            }
+        }
+        if known_before {
+            points.push((0, after as f64));
+        }
+        points.reverse();
+        points
+    }
+
+    /// The item's price after each recorded change, oldest first, from when it was added
+    fn price_series(&self, item_id: u32) -> Vec<(u64, f64)> {
+        let Some(item) = self.items.get(&item_id) else { return Vec::new() };
+        let changes: Vec<_> = self.price_history.changes.iter().filter(|change| change.item_id == item_id).collect();
+        let added_at = self.item_history.timelines.get(&item_id).and_then(|timeline| {
+            timeline.entries.iter().find(|entry| matches!(entry.event, ItemHistoryEvent::Created { replaced: false, .. }))
+        });
+        let mut points = vec![(added_at.map_or(0, |entry| entry.timestamp), changes.first().map_or(item.price, |c| c.old_price))];
+        points.extend(changes.iter().map(|change| (change.changed_at, change.new_price)));
+        points
+    }
+
+    /// A metric of one item downsampled into buckets between `from` and `to`
+    /// - `from`, `to`: Range in nanoseconds since the Unix epoch; `to` is capped at `now`
+    pub fn item_trend(
+        &self,
+        item_id: u32,
+        metric: TrendMetric,
+        resolution: TrendResolution,
+        from: u64,
+        to: u64,
+        now: u64,
+    ) -> Result<Vec<TrendBucket>, InventoryError> {
+        if !self.items.contains_key(&item_id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", item_id) });
+        }
+        let to = to.min(now);
+        let (first_day, last_day) = (self.config.local_day(from), self.config.local_day(to.saturating_sub(1)));
+        let buckets = resolution.bucket_of(first_day)..=resolution.bucket_of(last_day);
+        Validator::new()
+            .check(from < to, "from", "must be before to and the current time")
+            .check(
+                from >= to || buckets.end() - buckets.start() < MAX_TREND_BUCKETS,
+                "to",
+                format!("must be at most {} buckets after from", MAX_TREND_BUCKETS),
+            )
+            .finish()?;
+
+        let daily: BTreeMap<u64, f64> = match metric {
+            TrendMetric::StockLevel | TrendMetric::Price => BTreeMap::new(),
+            TrendMetric::UnitsSold | TrendMetric::Revenue => {
+                let mut daily = BTreeMap::new();
+                for sale in self.reportable_sales(false).filter(|s| s.item_id == item_id && (from..to).contains(&s.timestamp)) {
+                    let amount = if metric == TrendMetric::Revenue { sale.net_total() } else { sale.quantity as f64 };
+                    *daily.entry(self.config.local_day(sale.timestamp)).or_insert(0.0) += amount;
+                }
+                daily
+            }
+        };
+        let points = match metric {
+            TrendMetric::StockLevel => self.stock_series(item_id),
+            TrendMetric::Price => self.price_series(item_id),
+            TrendMetric::UnitsSold | TrendMetric::Revenue => Vec::new(),
+        };
+
+        Ok(buckets
+            .filter_map(|bucket| {
+                let (bucket_first, bucket_last) = resolution.days_of(bucket);
+                let start = self.config.local_day_start(bucket_first);
+                let (first, last) = (bucket_first.max(first_day), bucket_last.min(last_day));
+                match metric {
+                    TrendMetric::StockLevel | TrendMetric::Price => {
+                        let span_from = self.config.local_day_start(first).max(from);
+                        let span_to = self.config.local_day_start(last + 1).min(to);
+                        step_bucket(&points, start, span_from, span_to)
+                    }
+                    TrendMetric::UnitsSold | TrendMetric::Revenue => {
+                        let days: Vec<f64> = (first..=last).map(|day| daily.get(&day).copied().unwrap_or(0.0)).collect();
+                        let total: f64 = days.iter().sum();
+                        Some(TrendBucket {
+                            start,
+                            min: days.iter().copied().fold(f64::INFINITY, f64::min),
+                            max: days.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                            avg: total / days.len() as f64,
+                            total: Some(total),
+                        })
+                    }
+                }
+            })
+            .collect())
+    }
+}
+
+// Retrieves a metric of an item between `from` and `to` (default now), in nanoseconds since
+// the Unix epoch, downsampled to daily or weekly buckets with their min, max and average, so
+// charts get exactly the resolution they draw.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_trend(
+    item_id: u32,
+    metric: TrendMetric,
+    resolution: TrendResolution,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Vec<TrendBucket>, InventoryError> {
+    require_reader("get_item_trend", Role::Manager)?;
+    admit_expensive_call()?;
+    let now = ic_cdk::api::time();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().item_trend(item_id, metric, resolution, from, to.unwrap_or(now), now)
+    })
+}
@@ -0,0 +1,278 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::costing::UNCATEGORIZED;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_TRIAL_DAYS: u32 = 365;
+const KEEP_VELOCITY_RATIO: f64 = 0.8;        // Share of the category's sales rate a trial must reach to be kept
+const DISCONTINUE_VELOCITY_RATIO: f64 = 0.4; // Below this share of the category's rate the item is dropped
+const MARGIN_SHORTFALL_PCT: f64 = 5.0;       // Points of margin under the category's that count against a trial
+
+/// What to do with an item once its trial is over
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum TrialVerdict {
+    Keep,        // Sells and earns in line with its category
+    ReorderLess, // Sells, but slower or at a thinner margin than its category
+    Discontinue, // Sells far slower than its category
+}
+
+/// The figures a recommendation was made from
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TrialEvidence {
+    pub category: String,
+    pub days: u32,                         // Days of trading the figures cover
+    pub units_sold: u64,                   // In grams or millilitres for weighed goods
+    pub units_per_day: f64,
+    pub revenue: f64,                      // Takings after tax
+    pub margin_pct: Option<f64>,           // Gross margin of the costed sales; None if none were costed
+    pub benchmark_items: u32,              // Other items of the category compared against
+    pub benchmark_units_per_day: f64,      // Their average sales rate over the same days
+    pub benchmark_margin_pct: Option<f64>, // Their gross margin over the same days
+    pub velocity_ratio: Option<f64>,       // The item's rate as a share of the benchmark's; None when the category sold nothing
+}
+
+/// A keep or drop recommendation with the evidence behind it
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct TrialRecommendation {
+    pub verdict: TrialVerdict,
+    pub reason: String,
+    pub evidence: TrialEvidence,
+    pub final_verdict: bool, // False for a preview while the trial is still running
+    pub evaluated_at: u64,
+}
+
+/// A new product being tried out
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Trial {
+    pub item_id: u32,
+    pub started_at: u64,                             // Start of the trial window in nanoseconds since the Unix epoch
+    pub ends_at: u64,                                // End of the window; the item is evaluated once it has passed
+    pub started_by: Principal,
+    pub recommendation: Option<TrialRecommendation>, // Set when the trial is evaluated
+}
+
+/// Items on trial, and those whose trial has been evaluated
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Trials {
+    pub trials: BTreeMap<u32, Trial>, // Trials keyed by item ID
+}
+
+/// Units, revenue and costed sales of one item over a window
+#[derive(Default)]
+struct SalesTally {
+    units: u64,
+    revenue: f64,
+    costed_revenue: f64,
+    cost: f64,
+}
+
+impl SalesTally {
+    fn margin_pct(&self) -> Option<f64> {
+        (self.costed_revenue > 0.0).then(|| (self.costed_revenue - self.cost) / self.costed_revenue * 100.0)
+    }
+}
+
+impl SupermarketManager {
+    /// Compares a trial item's sales and margin with the rest of its category up to `now` or the
+    /// end of the window, whichever comes first
+    pub fn evaluate_trial(&self, item_id: u32, now: u64) -> Result<TrialRecommendation, InventoryError> {
+        let trial = self.trials.trials.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} is not on trial", item_id),
+        })?;
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        let to = now.min(trial.ends_at);
+        let days = (to.saturating_sub(trial.started_at) / NANOS_PER_DAY).max(1);
+        let category = item.category.clone();
+
+        // The benchmark is every other item of the category that is not itself on trial
+        let benchmark: Vec<u32> = self.items
+            .values()
+            .filter(|other| other.id != item_id && !other.archived && other.category == category)
+            .filter(|other| self.trials.trials.get(&other.id).is_none_or(|t| t.recommendation.is_some()))
+            .map(|other| other.id)
+            .collect();
+        let mut tallies: HashMap<u32, SalesTally> = HashMap::new();
+        for sale in self.sales.iter_from(self.sales.first_since(trial.started_at)).take_while(|sale| sale.timestamp < to) {
+            if sale.test || (sale.item_id != item_id && !benchmark.contains(&sale.item_id)) {
+                continue;
+            }
+            let tally = tallies.entry(sale.item_id).or_default();
+            tally.units += sale.quantity as u64;
+            tally.revenue += sale.net_total();
+            if let Some(&cost) = self.costing.sale_costs.get(&sale.id) {
+                tally.costed_revenue += sale.net_total();
+                tally.cost += cost;
+            }
+        }
+        let own = tallies.remove(&item_id).unwrap_or_default();
+        let mut peers = SalesTally::default();
+        for tally in tallies.values() {
+            peers.units += tally.units;
+            peers.revenue += tally.revenue;
+            peers.costed_revenue += tally.costed_revenue;
+            peers.cost += tally.cost;
+        }
+
+        let units_per_day = own.units as f64 / days as f64;
+        let benchmark_units_per_day = if benchmark.is_empty() { 0.0 } else { peers.units as f64 / benchmark.len() as f64 / days as f64 };
+        let velocity_ratio = (benchmark_units_per_day > 0.0).then(|| units_per_day / benchmark_units_per_day);
+        let margin_pct = own.margin_pct();
+        let benchmark_margin_pct = peers.margin_pct();
+        let thin_margin = matches!((margin_pct, benchmark_margin_pct), (Some(own), Some(peers)) if own < peers - MARGIN_SHORTFALL_PCT);
+
+        let (verdict, reason) = match velocity_ratio {
+            _ if own.units == 0 => (TrialVerdict::Discontinue, "No units sold during the trial".to_string()),
+            None => (TrialVerdict::Keep, "Sold while the rest of its category sold nothing to compare with".to_string()),
+            Some(ratio) if ratio < DISCONTINUE_VELOCITY_RATIO => (
+                TrialVerdict::Discontinue,
+                format!("Sold at {:.0}% of its category's average rate", ratio * 100.0),
+            ),
+            Some(ratio) if ratio < KEEP_VELOCITY_RATIO => (
+                TrialVerdict::ReorderLess,
+                format!("Sold at {:.0}% of its category's average rate", ratio * 100.0),
+            ),
+            Some(_) if thin_margin => (
+                TrialVerdict::ReorderLess,
+                format!("Sold well, but its margin is more than {} points below its category's", MARGIN_SHORTFALL_PCT),
+            ),
+            Some(ratio) => (TrialVerdict::Keep, format!("Sold at {:.0}% of its category's average rate", ratio * 100.0)),
+        };
+        Ok(TrialRecommendation {
+            verdict,
+            reason,
+            evidence: TrialEvidence {
+                category: category.unwrap_or_else(|| UNCATEGORIZED.to_string()),
+                days: days as u32,
+                units_sold: own.units,
+                units_per_day,
+                revenue: self.config.round_amount(own.revenue),
+                margin_pct,
+                benchmark_items: benchmark.len() as u32,
+                benchmark_units_per_day,
+                benchmark_margin_pct,
+                velocity_ratio,
+            },
+            final_verdict: now >= trial.ends_at,
+            evaluated_at: now,
+        })
+    }
+
+    /// Evaluates every trial whose window has passed and records its recommendation
+    pub fn evaluate_due_trials(&mut self, now: u64) {
+        let due: Vec<u32> = self.trials.trials
+            .values()
+            .filter(|trial| trial.recommendation.is_none() && now >= trial.ends_at)
+            .map(|trial| trial.item_id)
+            .collect();
+        for item_id in due {
+            let log = match self.evaluate_trial(item_id, now) {
+                Ok(recommendation) => {
+                    let log = format!(
+                        "Trial of item {} ended: {:?} ({}) at {}",
+                        item_id,
+                        recommendation.verdict,
+                        recommendation.reason,
+                        SupermarketManager::get_current_time()
+                    );
+                    self.trials.trials.get_mut(&item_id).expect("collected from the trials").recommendation = Some(recommendation);
+                    log
+                }
+                Err(error) => {
+                    self.trials.trials.remove(&item_id); // The item is gone, so there is nothing left to recommend
+                    format!("Trial of item {} dropped: {:?} at {}", item_id, error, SupermarketManager::get_current_time())
+                }
+            };
+            self.logs.push(log);
+        }
+    }
+}
+
+/// Evaluates trials daily once their window has passed
+pub fn start_trial_timer() {
+    ic_cdk::timer::set_timer_interval(Duration::from_nanos(NANOS_PER_DAY), || {
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().evaluate_due_trials(ic_cdk::api::time());
+        });
+    });
+}
+
+// Puts an item on trial for `days` days from now, after which it is compared with its category
+// and a keep, reorder-less or discontinue recommendation is recorded.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn start_trial(item_id: u32, days: u32) -> Result<Trial, InventoryError> {
+    require_caller("start_trial", Role::Manager)?;
+    Validator::new()
+        .check(days > 0, "days", "must be positive")
+        .check(days <= MAX_TRIAL_DAYS, "days", format!("must be at most {}", MAX_TRIAL_DAYS))
+        .finish()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.sellable_item(item_id)?;
+        if inventory.trials.trials.get(&item_id).is_some_and(|trial| trial.recommendation.is_none()) {
+            return Err(InventoryError::Conflict { msg: format!("Item {} is already on trial", item_id) });
+        }
+        let now = ic_cdk::api::time();
+        let trial = Trial {
+            item_id,
+            started_at: now,
+            ends_at: now + days as u64 * NANOS_PER_DAY,
+            started_by: ic_cdk::caller(),
+            recommendation: None,
+        };
+        inventory.trials.trials.insert(item_id, trial.clone());
+        let log = format!("Item {} put on a {}-day trial at {}", item_id, days, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(trial)
+    })
+}
+
+// Stops tracking an item's trial, whether or not it has been evaluated.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn cancel_trial(item_id: u32) -> Result<(), InventoryError> {
+    require_caller("cancel_trial", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.trials.trials.remove(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} is not on trial", item_id),
+        })?;
+        let log = format!("Trial of item {} cancelled at {}", item_id, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves every trial, running or evaluated, ordered by item ID.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_trials() -> Result<Vec<Trial>, InventoryError> {
+    require_reader("list_trials", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().trials.trials.values().cloned().collect()))
+}
+
+// Retrieves an item's trial recommendation: the recorded one once the trial has been evaluated,
+// otherwise a preview from the sales so far.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_trial_recommendation(item_id: u32) -> Result<TrialRecommendation, InventoryError> {
+    require_reader("get_trial_recommendation", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        if let Some(recommendation) = inventory.trials.trials.get(&item_id).and_then(|trial| trial.recommendation.clone()) {
+            return Ok(recommendation);
+        }
+        inventory.evaluate_trial(item_id, ic_cdk::api::time())
+    })
+}
@@ -0,0 +1,308 @@
+use ic_stable_structures::Memory as _;
+use serde::Deserialize;
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem::take;
+
+use crate::access::AccessControl;
+use crate::allocation::Fulfillment;
+use crate::assortments::Assortments;
+use crate::audit::AccessAudit;
+use crate::back_in_stock::BackInStock;
+use crate::breakglass::BreakGlass;
+use crate::bundles::Bundles;
+use crate::bus::IntegrationBus;
+use crate::chain::Chain;
+use crate::channels::Channels;
+use crate::cold_chain::ColdChain;
+use crate::confidential::ConfidentialStore;
+use crate::costing::Costing;
+use crate::dao::DaoGovernance;
+use crate::deprecation::Deprecations;
+use crate::duplicates::Duplicates;
+use crate::encryption::ExportEncryption;
+use crate::esl::EslFeed;
+use crate::events::EventBus;
+use crate::exchange::ExchangeRates;
+use crate::governance::Governance;
+use crate::history::ItemHistory;
+use crate::hooks::HookRegistry;
+use crate::loyalty::Loyalty;
+use crate::markdowns::Markdowns;
+use crate::payments::Payments;
+use crate::projections::ProjectionJob;
+use crate::pricing::PriceHistory;
+use crate::promotions::Promotions;
+use crate::ratelimit::RateLimitConfig;
+use crate::reconciliation::Reconciliation;
+use crate::reorder::ReorderPlanner;
+use crate::returns::ReturnsLedger;
+use crate::royalties::Royalties;
+use crate::self_checkout::SelfCheckout;
+use crate::settings::StoreConfig;
+use crate::shifts::Shifts;
+use crate::snapshot::{SnapshotId, SnapshotInfo};
+use crate::stocktake::Stocktakes;
+use crate::storage;
+use crate::summary::SummaryCounters;
+use crate::trials::Trials;
+use crate::usage::UsageAnalytics;
+use crate::valuation::ValuationStore;
+use crate::velocity::SalesVelocity;
+use crate::waste::WasteLedger;
+use crate::watchlists::Watchlists;
+use crate::webhooks::Webhooks;
+use crate::SupermarketManager;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const LENGTH_BYTES: u64 = 8; // The saved state is preceded by its length
+
+/// The part of the manager that lives on the heap and has to outlast an upgrade
+///
+/// Items, logs, sales, receipts and the journal are already in stable memory and are left out,
+/// and so are caches and in-flight work that mean nothing to the new code: load and instruction
+/// counters, certified responses, idempotency results, rate limit buckets, async flows and what
+/// a running projection job has rebuilt. The job itself is kept and marked cancelled. The state
+/// is saved as Candid, so fields added to a saved struct later must be `Option`s for state saved
+/// by the previous version to decode.
+#[derive(CandidType, Deserialize)]
+pub struct HeapState {
+    reorder: ReorderPlanner,
+    self_checkout: SelfCheckout,
+    esl: EslFeed,
+    access: AccessControl,
+    webhooks: Webhooks,
+    export_encryption: ExportEncryption,
+    payments: Payments,
+    confidential: ConfidentialStore,
+    governance: Governance,
+    dao: DaoGovernance,
+    snapshots: Vec<SnapshotInfo>,     // The snapshot index; the snapshots are in their own region already
+    next_snapshot_id: SnapshotId,
+    snapshots_end: u64,
+    audit: AccessAudit,
+    break_glass: BreakGlass,
+    events: EventBus,
+    reconciliation: Reconciliation,
+    bus: IntegrationBus,
+    usage: UsageAnalytics,
+    stocktakes: Stocktakes,
+    deprecations: Deprecations,
+    costing: Costing,
+    velocity: SalesVelocity,          // Saved rather than rebuilt, which would scan the sales ledger
+    price_history: PriceHistory,
+    promotions: Promotions,
+    bundles: Bundles,
+    fulfillment: Fulfillment,
+    item_history: ItemHistory,        // Saved rather than rebuilt, which would scan the sales ledger
+    receipt_index: HashMap<u64, u64>, // Saved rather than rebuilt, which would scan the receipts
+    receipts_linked_from: u64,
+    config: StoreConfig,
+    channels: Channels,
+    back_in_stock: BackInStock,
+    watchlists: Watchlists,
+    rate_limit_config: RateLimitConfig,
+    rate_limit_exempt: BTreeSet<Principal>,
+    rate_limit_rejected: u64,
+    waste: WasteLedger,
+    loyalty: Loyalty,
+    markdowns: Markdowns,
+    hooks: HookRegistry,
+    chain: Chain,
+    exchange: ExchangeRates,
+    returns: ReturnsLedger,
+    royalties: Royalties,
+    duplicates: Duplicates,
+    valuations: ValuationStore,
+    assortments: Assortments,
+    trials: Trials,
+    summary: SummaryCounters,         // Saved rather than rebuilt, which would scan the catalog
+    cold_chain: ColdChain,
+    shifts: Shifts,
+    projection_jobs: BTreeMap<u64, ProjectionJob>,
+    next_projection_job_id: u64,
+}
+
+impl SupermarketManager {
+    /// Moves the heap state out of the manager, leaving defaults behind
+    fn take_heap_state(&mut self) -> HeapState {
+        HeapState {
+            reorder: take(&mut self.reorder),
+            self_checkout: take(&mut self.self_checkout),
+            esl: take(&mut self.esl),
+            access: take(&mut self.access),
+            webhooks: take(&mut self.webhooks),
+            export_encryption: take(&mut self.export_encryption),
+            payments: take(&mut self.payments),
+            confidential: take(&mut self.confidential),
+            governance: take(&mut self.governance),
+            dao: take(&mut self.dao),
+            snapshots: take(&mut self.snapshots.snapshots).into_values().collect(),
+            next_snapshot_id: self.snapshots.next_id,
+            snapshots_end: self.snapshots.end,
+            audit: take(&mut self.audit),
+            break_glass: take(&mut self.break_glass),
+            events: take(&mut self.events),
+            reconciliation: take(&mut self.reconciliation),
+            bus: take(&mut self.bus),
+            usage: take(&mut self.usage),
+            stocktakes: take(&mut self.stocktakes),
+            deprecations: take(&mut self.deprecations),
+            costing: take(&mut self.costing),
+            velocity: take(&mut self.velocity),
+            price_history: take(&mut self.price_history),
+            promotions: take(&mut self.promotions),
+            bundles: take(&mut self.bundles),
+            fulfillment: take(&mut self.fulfillment),
+            item_history: take(&mut self.item_history),
+            receipt_index: take(&mut self.receipts.by_sale),
+            receipts_linked_from: self.receipts.linked_from,
+            config: take(&mut self.config),
+            channels: take(&mut self.channels),
+            back_in_stock: take(&mut self.back_in_stock),
+            watchlists: take(&mut self.watchlists),
+            rate_limit_config: take(&mut self.rate_limits.config),
+            rate_limit_exempt: take(&mut self.rate_limits.exempt),
+            rate_limit_rejected: self.rate_limits.rejected,
+            waste: take(&mut self.waste),
+            loyalty: take(&mut self.loyalty),
+            markdowns: take(&mut self.markdowns),
+            hooks: take(&mut self.hooks),
+            chain: take(&mut self.chain),
+            exchange: take(&mut self.exchange),
+            returns: take(&mut self.returns),
+            royalties: take(&mut self.royalties),
+            duplicates: take(&mut self.duplicates),
+            valuations: take(&mut self.valuations),
+            assortments: take(&mut self.assortments),
+            trials: take(&mut self.trials),
+            summary: take(&mut self.summary),
+            cold_chain: take(&mut self.cold_chain),
+            shifts: take(&mut self.shifts),
+            projection_jobs: take(&mut self.projections.jobs),
+            next_projection_job_id: self.projections.next_job_id,
+        }
+    }
+
+    /// Puts heap state saved before an upgrade back into the manager
+    fn restore_heap_state(&mut self, state: HeapState) {
+        self.reorder = state.reorder;
+        self.self_checkout = state.self_checkout;
+        self.esl = state.esl;
+        self.access = state.access;
+        self.webhooks = state.webhooks;
+        self.export_encryption = state.export_encryption;
+        self.payments = state.payments;
+        self.confidential = state.confidential;
+        self.governance = state.governance;
+        self.dao = state.dao;
+        self.snapshots.snapshots = state.snapshots.into_iter().map(|info| (info.id, info)).collect::<BTreeMap<_, _>>();
+        self.snapshots.next_id = state.next_snapshot_id;
+        self.snapshots.end = state.snapshots_end;
+        self.audit = state.audit;
+        self.break_glass = state.break_glass;
+        self.events = state.events;
+        self.reconciliation = state.reconciliation;
+        self.reconciliation.running = false; // A run in progress did not survive the upgrade
+        self.bus = state.bus;
+        self.usage = state.usage;
+        self.stocktakes = state.stocktakes;
+        self.deprecations = state.deprecations;
+        self.costing = state.costing;
+        self.velocity = state.velocity;
+        self.price_history = state.price_history;
+        self.promotions = state.promotions;
+        self.bundles = state.bundles;
+        self.fulfillment = state.fulfillment;
+        self.item_history = state.item_history;
+        self.receipts.by_sale = state.receipt_index;
+        self.receipts.linked_from = state.receipts_linked_from;
+        self.config = state.config;
+        self.channels = state.channels;
+        self.back_in_stock = state.back_in_stock;
+        self.watchlists = state.watchlists;
+        self.rate_limits.config = state.rate_limit_config;
+        self.rate_limits.exempt = state.rate_limit_exempt;
+        self.rate_limits.rejected = state.rate_limit_rejected;
+        self.waste = state.waste;
+        self.loyalty = state.loyalty;
+        self.markdowns = state.markdowns;
+        self.hooks = state.hooks;
+        self.chain = state.chain;
+        self.exchange = state.exchange;
+        self.returns = state.returns;
+        self.royalties = state.royalties;
+        self.duplicates = state.duplicates;
+        self.valuations = state.valuations;
+        self.assortments = state.assortments;
+        self.trials = state.trials;
+        self.summary = state.summary;
+        self.cold_chain = state.cold_chain;
+        self.shifts = state.shifts;
+        self.projections.jobs = state.projection_jobs;
+        self.projections.next_job_id = state.next_projection_job_id;
+    }
+
+    /// Writes the heap state to its stable memory region, ahead of an upgrade
+    pub fn save_heap_state(&mut self) {
+        let bytes = candid::encode_one(self.take_heap_state()).expect("heap state always encodes");
+        let memory = storage::memory(storage::HEAP_STATE);
+        let needed = LENGTH_BYTES + bytes.len() as u64;
+        let capacity = memory.size() * WASM_PAGE_SIZE;
+        if needed > capacity && memory.grow((needed - capacity).div_ceil(WASM_PAGE_SIZE)) < 0 {
+            ic_cdk::trap("Could not grow stable memory to save the heap state");
+        }
+        memory.write(0, &(bytes.len() as u64).to_le_bytes());
+        memory.write(LENGTH_BYTES, &bytes);
+    }
+
+    /// Reads back the heap state saved before an upgrade
+    ///
+    /// Returns false if there is none, as after upgrading from a version that did not save it.
+    pub fn load_heap_state(&mut self) -> bool {
+        let memory = storage::memory(storage::HEAP_STATE);
+        if memory.size() == 0 {
+            return false;
+        }
+        let mut length = [0; LENGTH_BYTES as usize];
+        memory.read(0, &mut length);
+        let mut bytes = vec![0; u64::from_le_bytes(length) as usize];
+        if bytes.is_empty() {
+            return false;
+        }
+        memory.read(LENGTH_BYTES, &mut bytes);
+        match candid::decode_one::<HeapState>(&bytes) {
+            Ok(state) => {
+                self.restore_heap_state(state);
+                self.cancel_interrupted_projection_jobs(ic_cdk::api::time());
+            }
+            Err(error) => ic_cdk::trap(&format!("Saved heap state does not decode: {}", error)), // Fails the upgrade rather than lose it
+        }
+        memory.write(0, &0u64.to_le_bytes()); // Consumed; a later upgrade saves it afresh
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::Role;
+
+    #[test]
+    fn heap_state_survives_encoding() {
+        let owner = Principal::from_slice(&[1]);
+        let mut manager = SupermarketManager::new();
+        manager.access.roles.insert(owner, Role::Owner);
+        manager.config.currency_code = "EUR".to_string();
+        manager.snapshots.next_id = 3;
+        manager.snapshots.end = 4096;
+
+        let bytes = candid::encode_one(manager.take_heap_state()).unwrap();
+        assert!(manager.access.roles.is_empty());
+        manager.restore_heap_state(candid::decode_one(&bytes).unwrap());
+
+        assert_eq!(manager.access.role_of(&owner), Some(Role::Owner));
+        assert_eq!(manager.config.currency_code, "EUR");
+        assert_eq!((manager.snapshots.next_id, manager.snapshots.end), (3, 4096));
+    }
+}
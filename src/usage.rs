@@ -0,0 +1,224 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const HOURLY_BUCKETS: usize = 7 * 24;       // Hours of history kept per principal
+const MAX_TRACKED_PRINCIPALS: usize = 1000; // The least recently seen principal is dropped beyond this
+
+/// Call counts and sizes over some period
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct UsageCounters {
+    pub calls: u64,            // Calls made
+    pub errors: u64,           // Calls that returned an error
+    pub request_bytes: u64,    // Candid-encoded arguments received
+    pub response_bytes: u64,   // Candid-encoded results returned
+    pub instructions: u64,     // Instructions executed by the calls
+    pub max_instructions: u64, // Most instructions used by a single call
+}
+
+impl UsageCounters {
+    fn merge(&mut self, other: &UsageCounters) {
+        self.calls += other.calls;
+        self.errors += other.errors;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+        self.instructions += other.instructions;
+        self.max_instructions = self.max_instructions.max(other.max_instructions);
+    }
+}
+
+/// Usage in one hour
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct UsageBucket {
+    pub hour_start: u64,         // Start of the hour in nanoseconds since the Unix epoch
+    pub counters: UsageCounters, // Usage in that hour
+}
+
+/// Everything recorded about one caller
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct IntegrationUsage {
+    pub principal: Principal,                         // The caller
+    pub label: Option<String>,                        // Name given to the integration, e.g. "webshop"
+    pub totals: UsageCounters,                        // Usage since the principal was first seen
+    pub by_endpoint: BTreeMap<String, UsageCounters>, // Usage per endpoint
+    pub hourly: VecDeque<UsageBucket>,                // Recent usage per hour, oldest first
+    pub first_seen: u64,                              // Time of the first call in nanoseconds since the Unix epoch
+    pub last_seen: u64,                               // Time of the latest call in nanoseconds since the Unix epoch
+}
+
+/// One row of the usage report
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct UsageSummary {
+    pub principal: Principal,
+    pub label: Option<String>,
+    pub counters: UsageCounters,          // Usage over the report period
+    pub error_rate: f64,                  // Share of calls that returned an error
+    pub avg_instructions: u64,            // Instructions per call
+    pub busiest_endpoint: Option<String>, // Endpoint with the most instructions since the principal was first seen
+}
+
+/// API usage per calling principal
+///
+/// Only replicated executions are recorded: state changes made by non-replicated query calls
+/// are discarded, so queries show up only when called as updates.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct UsageAnalytics {
+    pub principals: HashMap<Principal, IntegrationUsage>, // Usage keyed by caller
+    pub labels: HashMap<Principal, String>,               // Names given to integration principals
+}
+
+impl UsageAnalytics {
+    /// Records one call
+    /// - `call`: Counters of the single call, with `calls` set to 1
+    pub fn record(&mut self, principal: Principal, endpoint: &str, call: &UsageCounters, now: u64) {
+        if !self.principals.contains_key(&principal) && self.principals.len() >= MAX_TRACKED_PRINCIPALS {
+            let stalest = self.principals.values().min_by_key(|usage| usage.last_seen).map(|usage| usage.principal);
+            if let Some(stalest) = stalest {
+                self.principals.remove(&stalest);
+            }
+        }
+        let usage = self.principals.entry(principal).or_insert_with(|| IntegrationUsage {
+            principal,
+            label: None,
+            totals: UsageCounters::default(),
+            by_endpoint: BTreeMap::new(),
+            hourly: VecDeque::new(),
+            first_seen: now,
+            last_seen: now,
+        });
+        usage.last_seen = now;
+        usage.totals.merge(call);
+        usage.by_endpoint.entry(endpoint.to_string()).or_default().merge(call);
+        let hour_start = now - now % NANOS_PER_HOUR;
+        if usage.hourly.back().is_none_or(|bucket| bucket.hour_start != hour_start) {
+            usage.hourly.push_back(UsageBucket { hour_start, counters: UsageCounters::default() });
+            while usage.hourly.len() > HOURLY_BUCKETS {
+                usage.hourly.pop_front();
+            }
+        }
+        let bucket = usage.hourly.back_mut().expect("a bucket was just ensured");
+        bucket.counters.merge(call);
+    }
+
+    /// Usage of every principal since a given time, most instructions first
+    /// - `since`: Start of the period in nanoseconds since the Unix epoch; hourly buckets overlapping it are included
+    pub fn report(&self, since: u64) -> Vec<UsageSummary> {
+        let mut report: Vec<UsageSummary> = self.principals
+            .values()
+            .map(|usage| {
+                let mut counters = UsageCounters::default();
+                for bucket in usage.hourly.iter().filter(|b| b.hour_start + NANOS_PER_HOUR > since) {
+                    counters.merge(&bucket.counters);
+                }
+                UsageSummary {
+                    principal: usage.principal,
+                    label: self.labels.get(&usage.principal).cloned(),
+                    error_rate: if counters.calls == 0 { 0.0 } else { counters.errors as f64 / counters.calls as f64 },
+                    avg_instructions: counters.instructions.checked_div(counters.calls).unwrap_or(0),
+                    busiest_endpoint: usage.by_endpoint.iter().max_by_key(|(_, c)| c.instructions).map(|(e, _)| e.clone()),
+                    counters,
+                }
+            })
+            .filter(|summary| summary.counters.calls > 0)
+            .collect();
+        report.sort_by_key(|summary| std::cmp::Reverse(summary.counters.instructions));
+        report
+    }
+}
+
+/// Whether an endpoint's result counts as an error in the usage analytics
+pub trait UsageOutcome {
+    fn is_error(&self) -> bool {
+        false
+    }
+}
+
+impl<T, E> UsageOutcome for Result<T, E> {
+    fn is_error(&self) -> bool {
+        self.is_err()
+    }
+}
+
+impl UsageOutcome for () {}
+impl<T> UsageOutcome for Option<T> {}
+impl<T> UsageOutcome for Vec<T> {}
+
+/// Runs an endpoint body and records the call against the caller's usage
+///
+/// `f` must not hold a borrow of the inventory when it returns.
+pub fn metered<R: CandidType + UsageOutcome>(endpoint: &str, f: impl FnOnce() -> R) -> R {
+    let request_bytes = ic_cdk::api::call::arg_data_raw_size() as u64;
+    let result = f();
+    let response_bytes = candid::encode_one(&result).map_or(0, |bytes| bytes.len() as u64);
+    let instructions = ic_cdk::api::performance_counter(0); // Counted from the start of the message
+    let call = UsageCounters {
+        calls: 1,
+        errors: result.is_error() as u64,
+        request_bytes,
+        response_bytes,
+        instructions,
+        max_instructions: instructions,
+    };
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().usage.record(ic_cdk::caller(), endpoint, &call, ic_cdk::api::time());
+    });
+    result
+}
+
+impl SupermarketManager {
+    /// Full usage record of one principal, with its label
+    pub fn integration_usage(&self, principal: &Principal) -> Option<IntegrationUsage> {
+        let mut usage = self.usage.principals.get(principal)?.clone();
+        usage.label = self.usage.labels.get(principal).cloned();
+        Some(usage)
+    }
+}
+
+// Retrieves API usage per principal over the last `hours` hours (default 24), most
+// instructions first, to spot integrations that burn cycles or fail a lot.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_usage_report(hours: Option<u32>) -> Result<Vec<UsageSummary>, InventoryError> {
+    require_reader("get_usage_report", Role::Manager)?;
+    let hours = hours.unwrap_or(24).min(HOURLY_BUCKETS as u32) as u64;
+    let since = ic_cdk::api::time().saturating_sub(hours * NANOS_PER_HOUR);
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().usage.report(since))
+    })
+}
+
+// Retrieves the full usage record of one principal, including per-endpoint and hourly counts.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_integration_usage(principal: Principal) -> Result<Option<IntegrationUsage>, InventoryError> {
+    require_reader("get_integration_usage", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().integration_usage(&principal))
+    })
+}
+
+// Names an integration principal in usage reports, or clears its name.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_integration_label(principal: Principal, label: Option<String>) -> Result<(), InventoryError> {
+    require_caller("set_integration_label", Role::Manager)?;
+    if let Some(label) = &label {
+        Validator::new().name("label", label).finish()?;
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        match label {
+            Some(label) => inventory.usage.labels.insert(principal, label),
+            None => inventory.usage.labels.remove(&principal),
+        };
+        Ok(())
+    })
+}
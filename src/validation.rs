@@ -0,0 +1,113 @@
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+
+use crate::{InventoryError, InventoryItem, Unit};
+
+const MAX_NAME_CHARS: usize = 100;                          // Longest item name, so shelf labels and receipts can show it
+const MAX_PRICE: f64 = 1_000_000.0;                         // Highest price per unit, catching misplaced decimal points
+const MAX_QUANTITY: u32 = 1_000_000;                        // Most units of an item, in kilograms or litres for weighed goods
+const MAX_BASKET_LINES: usize = 500;                        // Lines in one checkout
+const MAX_EXPIRY_AHEAD_SECS: u64 = 50 * 365 * 24 * 60 * 60; // Furthest expiration date; later values are usually milliseconds
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A field that failed validation and why
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct ValidationError {
+    pub field: String,   // Argument or field path, e.g. `lines[2].quantity`
+    pub message: String, // What is wrong with it
+}
+
+/// Collects every problem with a request so callers can fix them all at once
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<ValidationError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    /// Records an error for `field` unless `ok` holds
+    pub fn check(&mut self, ok: bool, field: &str, message: impl Into<String>) -> &mut Self {
+        if !ok {
+            self.errors.push(ValidationError { field: field.to_string(), message: message.into() });
+        }
+        self
+    }
+
+    /// Checks a name or identifier is present, short enough and free of control characters
+    pub fn name(&mut self, field: &str, name: &str) -> &mut Self {
+        let length = name.trim().chars().count();
+        self.check(length > 0, field, "must not be empty")
+            .check(length <= MAX_NAME_CHARS, field, format!("must be at most {} characters", MAX_NAME_CHARS))
+            .check(!name.chars().any(char::is_control), field, "must not contain control characters")
+    }
+
+    /// Checks a price is a finite, non-negative number below the cap
+    pub fn price(&mut self, field: &str, price: f64) -> &mut Self {
+        self.check(price.is_finite(), field, "must be a finite number")
+            .check(!price.is_sign_negative(), field, "must not be negative")
+            .check(!price.is_finite() || price <= MAX_PRICE, field, format!("must be at most {}", MAX_PRICE))
+    }
+
+    /// Checks a stock level or sale quantity is within the cap for the item's unit
+    pub fn quantity(&mut self, field: &str, quantity: u32, unit: Unit) -> &mut Self {
+        let cap = MAX_QUANTITY.saturating_mul(unit.stock_units_per_price_unit());
+        self.check(quantity <= cap, field, format!("must be at most {}", cap))
+    }
+
+    /// Checks an expiration date in seconds is neither in the past nor implausibly far ahead
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn expiration_date(&mut self, field: &str, expiration_date: u64, now: u64) -> &mut Self {
+        let now_secs = now / NANOS_PER_SEC;
+        self.check(expiration_date >= now_secs, field, "must not be in the past")
+            .check(
+                expiration_date <= now_secs.saturating_add(MAX_EXPIRY_AHEAD_SECS),
+                field,
+                "is too far in the future; expected seconds since the Unix epoch",
+            )
+    }
+
+    /// Returns every recorded error, or Ok if there were none
+    pub fn finish(&mut self) -> Result<(), InventoryError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(InventoryError::Validation { errors: std::mem::take(&mut self.errors) })
+        }
+    }
+}
+
+/// Checks every field of an item about to be added
+/// - `now`: The current time in nanoseconds since the Unix epoch
+pub fn validate_item(item: &InventoryItem, now: u64) -> Result<(), InventoryError> {
+    let mut validator = Validator::new();
+    validator
+        .name("name", &item.name)
+        .price("price", item.price)
+        .quantity("quantity", item.quantity, item.unit)
+        .expiration_date("expiration_date", item.expiration_date, now);
+    if let Some(category) = &item.category {
+        validator.name("category", category);
+    }
+    validator.finish()
+}
+
+/// Checks a new stock level for an item
+pub fn validate_stock_level(quantity: u32, unit: Unit) -> Result<(), InventoryError> {
+    Validator::new().quantity("quantity", quantity, unit).finish()
+}
+
+/// Checks the shape of a basket: not empty, not too long and no zero quantities
+/// - `lines`: Pairs of (item ID, quantity) being sold
+pub fn validate_lines(lines: &[(u32, u32)]) -> Result<(), InventoryError> {
+    let mut validator = Validator::new();
+    validator
+        .check(!lines.is_empty(), "lines", "must contain at least one line")
+        .check(lines.len() <= MAX_BASKET_LINES, "lines", format!("must have at most {} lines", MAX_BASKET_LINES));
+    for (i, &(_, quantity)) in lines.iter().enumerate() {
+        validator.check(quantity > 0, &format!("lines[{}].quantity", i), "must be positive");
+    }
+    validator.finish()
+}
@@ -0,0 +1,176 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::bundles::Bundles;
+use crate::certification::{certified_root, labeled_tree, HashTree};
+use crate::costing::UNCATEGORIZED;
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, InventoryItem, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+const MAX_VALUATIONS: usize = 1000; // Snapshots kept; the oldest is dropped first
+
+/// Stock held in one category, valued at retail and at cost
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct CategoryValuation {
+    pub category: String,
+    pub items: u32,          // Items with stock on hand
+    pub retail_value: f64,   // Stock at current selling prices
+    pub cost_value: f64,     // Stock at cost, for the items whose cost is known
+    pub uncosted_items: u32, // Items left out of `cost_value` because some of their stock has no cost
+}
+
+/// Inventory holdings at a point in time, as evidence for insurers and auditors
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ValuationSnapshot {
+    pub id: u64,
+    pub taken_at: u64,                      // Time of the valuation in nanoseconds since the Unix epoch
+    pub taken_by: Principal,
+    pub currency_code: String,
+    pub categories: Vec<CategoryValuation>, // Ordered by category
+    pub total_retail_value: f64,
+    pub total_cost_value: f64,
+    pub uncosted_items: u32,
+    pub journal_seq: u64,                   // Journal entries applied to the catalog when it was valued
+    pub state_hash: Vec<u8>,                // SHA-256 over the candid encoding of every item, in ID order
+}
+
+/// A snapshot with the certificate proving the canister holds it
+///
+/// The certified tree has the leaf `valuations/<id as 8 big-endian bytes>`, which is the SHA-256
+/// of the candid encoding of `snapshot`.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct CertifiedValuation {
+    pub snapshot: ValuationSnapshot,
+    pub certificate: Vec<u8>, // The subnet's certificate over the canister's certified data
+    pub witness: Vec<u8>,     // CBOR hash tree leading from the certified data to the snapshot's leaf
+}
+
+/// Valuation snapshots taken so far and the hashes certified for them
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct ValuationStore {
+    pub snapshots: BTreeMap<u64, (ValuationSnapshot, [u8; 32])>, // Snapshots by ID with the hash of their encoding
+    pub next_id: u64,
+}
+
+impl ValuationStore {
+    /// The valuations half of the certified tree, pruned to the snapshot `keep` when given
+    pub fn tree(&self, keep: Option<u64>) -> HashTree {
+        let labels: Vec<[u8; 8]> = self.snapshots.keys().map(|id| id.to_be_bytes()).collect();
+        let entries: Vec<(&[u8], &[u8])> = labels
+            .iter()
+            .zip(self.snapshots.values())
+            .map(|(label, (_, hash))| (label.as_slice(), hash.as_slice()))
+            .collect();
+        let keep = keep.map(u64::to_be_bytes);
+        HashTree::Labeled(b"valuations".to_vec(), Box::new(labeled_tree(&entries, keep.as_ref().map(|label| label.as_slice()))))
+    }
+}
+
+/// Whether an item holds stock of its own; packs draw on their base item and bundles on their components
+fn holds_stock(item: &InventoryItem, bundles: &Bundles) -> bool {
+    !matches!(item.unit, Unit::Pack { .. }) && !bundles.definitions.contains_key(&item.id)
+}
+
+impl SupermarketManager {
+    /// Values every item with stock on hand, per category, at retail and at cost
+    pub fn take_valuation(&mut self, taken_by: Principal, now: u64) -> Result<ValuationSnapshot, InventoryError> {
+        let mut categories: BTreeMap<String, CategoryValuation> = BTreeMap::new();
+        let mut state = Sha256::new();
+        for item in self.items.values() {
+            let encoded = candid::encode_one(&item).map_err(|err| InventoryError::InvalidInput { msg: format!("Could not encode item {}: {}", item.id, err) })?;
+            state.update(encoded);
+            if item.quantity == 0 || !holds_stock(&item, &self.bundles) {
+                continue;
+            }
+            let category = item.category.clone().unwrap_or_else(|| UNCATEGORIZED.to_string());
+            let line = categories.entry(category.clone()).or_insert_with(|| CategoryValuation { category, ..Default::default() });
+            line.items += 1;
+            line.retail_value += item.price / item.unit.stock_units_per_price_unit() as f64 * item.quantity as f64;
+            match self.item_cost(item.id)?.stock_value {
+                Some(value) => line.cost_value += value,
+                None => line.uncosted_items += 1,
+            }
+        }
+        let mut categories: Vec<CategoryValuation> = categories.into_values().collect();
+        for line in &mut categories {
+            line.retail_value = self.config.round_amount(line.retail_value);
+            line.cost_value = self.config.round_amount(line.cost_value);
+        }
+
+        let id = self.valuations.next_id;
+        self.valuations.next_id += 1;
+        let snapshot = ValuationSnapshot {
+            id,
+            taken_at: now,
+            taken_by,
+            currency_code: self.config.currency_code.clone(),
+            total_retail_value: self.config.round_amount(categories.iter().map(|line| line.retail_value).sum()),
+            total_cost_value: self.config.round_amount(categories.iter().map(|line| line.cost_value).sum()),
+            uncosted_items: categories.iter().map(|line| line.uncosted_items).sum(),
+            categories,
+            journal_seq: self.journal.len(),
+            state_hash: state.finalize().to_vec(),
+        };
+        let encoded = candid::encode_one(&snapshot).map_err(|err| InventoryError::InvalidInput { msg: format!("Could not encode valuation: {}", err) })?;
+        self.valuations.snapshots.insert(id, (snapshot.clone(), Sha256::digest(encoded).into()));
+        while self.valuations.snapshots.len() > MAX_VALUATIONS {
+            self.valuations.snapshots.pop_first();
+        }
+        self.certify_state();
+        let log = format!(
+            "Valuation snapshot {} taken at {} retail, {} cost at {}",
+            id,
+            self.config.format_amount(snapshot.total_retail_value),
+            self.config.format_amount(snapshot.total_cost_value),
+            SupermarketManager::get_current_time()
+        );
+        self.logs.push(log);
+        Ok(snapshot)
+    }
+}
+
+// Values the stock on hand per category at retail and at cost and certifies the result, so it
+// can be handed to insurers or auditors as evidence of holdings at that time.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn take_valuation_snapshot() -> Result<ValuationSnapshot, InventoryError> {
+    require_caller("take_valuation_snapshot", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().take_valuation(ic_cdk::caller(), ic_cdk::api::time())
+    })
+}
+
+// Retrieves a valuation snapshot with the certificate and witness proving it, which anyone can
+// check against the IC root key without trusting the canister.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_valuation_snapshot(id: u64) -> Result<CertifiedValuation, InventoryError> {
+    require_reader("get_valuation_snapshot", Role::Manager)?;
+    admit_expensive_call()?;
+    let certificate = ic_cdk::api::data_certificate().ok_or_else(|| InventoryError::InvalidInput {
+        msg: "Certificates are only available in query calls".to_string(),
+    })?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let (snapshot, _) = inventory.valuations.snapshots.get(&id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Valuation snapshot {} not found", id),
+        })?;
+        let http_assets = HashTree::Pruned(inventory.http.tree(None).digest());
+        let witness = certified_root(http_assets, inventory.valuations.tree(Some(id))).to_cbor();
+        Ok(CertifiedValuation { snapshot: snapshot.clone(), certificate, witness })
+    })
+}
+
+// Retrieves every valuation snapshot kept, oldest first, without certificates.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_valuation_snapshots() -> Result<Vec<ValuationSnapshot>, InventoryError> {
+    require_reader("list_valuation_snapshots", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().valuations.snapshots.values().map(|(snapshot, _)| snapshot.clone()).collect()))
+}
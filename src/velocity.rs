@@ -0,0 +1,150 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+use std::collections::{HashMap, VecDeque};
+
+use crate::channels::SalesChannel;
+use crate::sales::Sale;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const SHORT_WINDOW_DAYS: u64 = 7;
+pub const LONG_WINDOW_DAYS: u64 = 30; // Also the number of daily totals kept per item
+
+/// Recent sales rate of an item
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ItemVelocity {
+    pub item_id: u32,
+    pub units_last_7_days: u64,  // Stock units sold in the last 7 days, including units sold in packs
+    pub units_last_30_days: u64, // Stock units sold in the last 30 days
+    pub per_day_7: f64,          // 7-day average units per day
+    pub per_day_30: f64,         // 30-day average units per day
+}
+
+/// When an item is expected to run out at its recent sales rate
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct StockoutForecast {
+    pub item_id: u32,
+    pub quantity: u32,              // Stock on hand
+    pub units_per_day: f64,         // Rate used: the 7-day average, or the 30-day one if nothing sold this week
+    pub days_of_cover: Option<f64>, // Days until stock runs out; None if the item is not selling
+    pub stockout_at: Option<u64>,   // Expected stockout time in nanoseconds since the Unix epoch
+}
+
+/// Daily sales totals per stock item and channel over the last 30 days
+///
+/// Updated on every sale so velocity queries never scan the sales ledger.
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct SalesVelocity {
+    pub daily: HashMap<(u32, SalesChannel), VecDeque<(u64, u64)>>, // (day number, units sold) per item ID and channel, oldest day first
+}
+
+impl SalesVelocity {
+    /// Adds a sale to the item's total for the day on its channel
+    pub fn record(&mut self, item_id: u32, channel: SalesChannel, units: u32, now: u64) {
+        let day = now / NANOS_PER_DAY;
+        let days = self.daily.entry((item_id, channel)).or_default();
+        match days.back_mut() {
+            Some((last, total)) if *last == day => *total += units as u64,
+            Some((last, _)) if *last > day => {} // Sales are recorded in time order; ignore anything older
+            _ => days.push_back((day, units as u64)),
+        }
+        while days.front().is_some_and(|&(first, _)| first + LONG_WINDOW_DAYS <= day) {
+            days.pop_front();
+        }
+    }
+
+    /// Units of an item sold in the last `window` days, counting today, on one channel or all of them
+    fn units_in(&self, item_id: u32, channel: Option<SalesChannel>, window: u64, now: u64) -> u64 {
+        let today = now / NANOS_PER_DAY;
+        SalesChannel::ALL
+            .into_iter()
+            .filter(|&c| channel.is_none_or(|channel| c == channel))
+            .filter_map(|c| self.daily.get(&(item_id, c)))
+            .flat_map(|days| days.iter())
+            .filter(|&&(day, _)| day + window > today)
+            .map(|&(_, units)| units)
+            .sum()
+    }
+
+    /// Sales rate of an item over every channel
+    pub fn velocity(&self, item_id: u32, now: u64) -> ItemVelocity {
+        self.channel_velocity(item_id, None, now)
+    }
+
+    /// Sales rate of an item on one channel, or over every channel when `channel` is None
+    pub fn channel_velocity(&self, item_id: u32, channel: Option<SalesChannel>, now: u64) -> ItemVelocity {
+        let units_last_7_days = self.units_in(item_id, channel, SHORT_WINDOW_DAYS, now);
+        let units_last_30_days = self.units_in(item_id, channel, LONG_WINDOW_DAYS, now);
+        ItemVelocity {
+            item_id,
+            units_last_7_days,
+            units_last_30_days,
+            per_day_7: units_last_7_days as f64 / SHORT_WINDOW_DAYS as f64,
+            per_day_30: units_last_30_days as f64 / LONG_WINDOW_DAYS as f64,
+        }
+    }
+}
+
+impl SupermarketManager {
+    /// Recomputes the daily totals from the sales ledger, e.g. after a snapshot is restored
+    pub fn rebuild_velocity(&mut self, now: u64) {
+        let mut velocity = SalesVelocity::default();
+        let since = now.saturating_sub(LONG_WINDOW_DAYS * NANOS_PER_DAY);
+        for sale in self.sales.iter_from(self.sales.first_since(since)).filter(|sale| !sale.test) {
+            self.record_velocity(&mut velocity, &sale);
+        }
+        self.velocity = velocity;
+    }
+
+    /// Adds the stock a sale took to a set of daily totals, counting a bundle's components
+    pub fn record_velocity(&self, velocity: &mut SalesVelocity, sale: &Sale) {
+        let demand = match self.stock_demand(sale.item_id, sale.quantity) {
+            Ok(demand) if self.bundles.definitions.contains_key(&sale.item_id) => demand,
+            _ => vec![(sale.stock_item_id, sale.stock_units)],
+        };
+        for (item_id, units) in demand {
+            velocity.record(item_id, sale.channel, units, sale.timestamp);
+        }
+    }
+
+    /// Forecasts when an item runs out; packs are forecast from their base item's stock
+    /// - `channel`: Channel whose sales rate is used, as if only it drew on the stock; None for all of them
+    pub fn forecast_stockout(&self, item_id: u32, channel: Option<SalesChannel>, now: u64) -> Result<StockoutForecast, InventoryError> {
+        let (stock_item_id, per_pack) = self.stock_units(item_id, 1)?;
+        let stock = self.items.get(&stock_item_id).expect("stock_units only returns existing items");
+        let velocity = self.velocity.channel_velocity(stock_item_id, channel, now);
+        let units_per_day = if velocity.per_day_7 > 0.0 { velocity.per_day_7 } else { velocity.per_day_30 };
+        let days_of_cover = (units_per_day > 0.0).then(|| stock.quantity as f64 / units_per_day);
+        Ok(StockoutForecast {
+            item_id,
+            quantity: stock.quantity / per_pack.max(1),
+            units_per_day: units_per_day / per_pack.max(1) as f64,
+            days_of_cover,
+            stockout_at: days_of_cover.map(|days| now.saturating_add((days * NANOS_PER_DAY as f64) as u64)),
+        })
+    }
+}
+
+// Retrieves an item's 7- and 30-day sales averages in stock units per day, on one channel or
+// over all of them.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_velocity(id: u32, channel: Option<SalesChannel>) -> Result<ItemVelocity, InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        if !inventory.items.contains_key(&id) {
+            return Err(InventoryError::NotFound { msg: format!("Item {} not found", id) });
+        }
+        Ok(inventory.velocity.channel_velocity(id, channel, ic_cdk::api::time()))
+    })
+}
+
+// Forecasts when an item will sell out at its recent sales rate, on one channel or over all of them.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn forecast_stockout_date(id: u32, channel: Option<SalesChannel>) -> Result<StockoutForecast, InventoryError> {
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().forecast_stockout(id, channel, ic_cdk::api::time())
+    })
+}
@@ -0,0 +1,89 @@
+use ic_cdk_macros::query;
+use serde::{Serialize, Deserialize};
+use candid::CandidType;
+
+use crate::location::ShelfLocation;
+use crate::usage::metered;
+use crate::{InventoryItem, Unit, INVENTORY_MANAGER};
+
+pub const API_VERSION: u32 = 2; // Version of the newest endpoints
+const SUPPORTED_VERSIONS: [u32; 2] = [1, 2];
+
+/// Which versions of the interface the canister serves
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ApiVersion {
+    pub version: u32,        // Newest version; its endpoints carry a `_v2` style suffix
+    pub supported: Vec<u32>, // Every version still served, oldest first
+}
+
+/// An item as the v1 endpoints return it
+///
+/// The shape is frozen so frontends built against v1 keep decoding it; fields added to
+/// `InventoryItem` since, such as the storage class, are only returned by the v2 endpoints.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct InventoryItemV1 {
+    pub id: u32,
+    pub name: String,
+    pub quantity: u32,
+    pub price: f64,
+    pub expiration_date: u64,
+    pub archived: bool,
+    pub unit: Unit,
+    pub version: u64,
+    pub location: Option<ShelfLocation>,
+    pub barcode: Option<String>,
+    pub category: Option<String>,
+}
+
+impl From<InventoryItem> for InventoryItemV1 {
+    fn from(item: InventoryItem) -> Self {
+        InventoryItemV1 {
+            id: item.id,
+            name: item.name,
+            quantity: item.quantity,
+            price: item.price,
+            expiration_date: item.expiration_date,
+            archived: item.archived,
+            unit: item.unit,
+            version: item.version,
+            location: item.location,
+            barcode: item.barcode,
+            category: item.category,
+        }
+    }
+}
+
+// Retrieves the interface versions the canister serves, so a frontend can pick the endpoints
+// whose records it understands.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_api_version() -> ApiVersion {
+    ApiVersion { version: API_VERSION, supported: SUPPORTED_VERSIONS.to_vec() }
+}
+
+// Retrieves an item by ID with every field, including those added after v1.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_inventory_item_v2(id: u32) -> Option<InventoryItem> {
+    metered("get_inventory_item_v2", || {
+        INVENTORY_MANAGER.with(|inventory| inventory.borrow().get_item(id))
+    })
+}
+
+// Retrieves every item that is not archived, with every field.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_inventory_items_v2() -> Vec<InventoryItem> {
+    metered("list_inventory_items_v2", || {
+        INVENTORY_MANAGER.with(|inventory| inventory.borrow().list_items())
+    })
+}
+
+// Retrieves every archived item, with every field.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn list_archived_items_v2() -> Vec<InventoryItem> {
+    metered("list_archived_items_v2", || {
+        INVENTORY_MANAGER.with(|inventory| inventory.borrow().list_archived_items())
+    })
+}
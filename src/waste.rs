@@ -0,0 +1,187 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_permission, Permission, Role};
+use crate::breakglass::require_reader;
+use crate::costing::UNCATEGORIZED;
+use crate::idempotency::run_once;
+use crate::ratelimit::rate_limit;
+use crate::validation::Validator;
+use crate::{AdjustmentReason, InventoryError, SupermarketManager, Unit, INVENTORY_MANAGER};
+
+/// Why stock was written off
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WasteCause {
+    Expired,  // Past its expiration date
+    Damaged,  // Broken, crushed or otherwise unsellable
+    Theft,    // Known or suspected stolen
+    Spillage, // Leaked or spilled, typically liquids and loose goods
+}
+
+/// Stock written off in one go
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct WasteEntry {
+    pub id: u64,
+    pub item_id: u32,
+    pub quantity: u32,     // Units written off, in grams or millilitres for weighed goods
+    pub cause: WasteCause,
+    pub cost: Option<f64>, // Cost of the units, taken from the oldest batches; None if it is unknown
+    pub retail_value: f64, // What the units would have sold for at the item's price
+    pub recorded_by: Principal,
+    pub timestamp: u64,    // Time of the write-off in nanoseconds since the Unix epoch
+}
+
+/// Waste over a set of write-offs
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct WasteLine {
+    pub entries: u64,
+    pub quantity: u64,         // Units written off
+    pub cost: f64,             // Cost of the write-offs whose cost is known
+    pub uncosted_entries: u64, // Write-offs left out of `cost` because their cost is unknown
+    pub retail_value: f64,     // Takings lost at the items' prices
+}
+
+impl WasteLine {
+    fn add(&mut self, entry: &WasteEntry) {
+        self.entries += 1;
+        self.quantity += entry.quantity as u64;
+        match entry.cost {
+            Some(cost) => self.cost += cost,
+            None => self.uncosted_entries += 1,
+        }
+        self.retail_value += entry.retail_value;
+    }
+
+    fn convert(&mut self, convert: &impl Fn(f64) -> f64) {
+        self.cost = convert(self.cost);
+        self.retail_value = convert(self.retail_value);
+    }
+}
+
+/// Waste per cause and per category over a period
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct WasteReport {
+    pub from: u64,                               // Start of the period in nanoseconds since the Unix epoch
+    pub to: u64,                                 // End of the period, exclusive
+    pub causes: BTreeMap<WasteCause, WasteLine>, // Totals per cause
+    pub categories: BTreeMap<String, WasteLine>, // Totals per category
+    pub total: WasteLine,                        // Totals over every write-off in the period
+    pub currency: String,                        // Currency of every amount in the report
+}
+
+impl WasteReport {
+    /// Restates every amount in another currency
+    pub fn convert(&mut self, currency: String, convert: impl Fn(f64) -> f64) {
+        self.causes.values_mut().for_each(|line| line.convert(&convert));
+        self.categories.values_mut().for_each(|line| line.convert(&convert));
+        self.total.convert(&convert);
+        self.currency = currency;
+    }
+}
+
+/// Every write-off, oldest first
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct WasteLedger {
+    pub entries: Vec<WasteEntry>, // Entry `n` has ID `n`
+}
+
+impl SupermarketManager {
+    /// Takes units out of stock as waste and records them in the ledger
+    pub fn record_waste(&mut self, item_id: u32, quantity: u32, cause: WasteCause, recorded_by: Principal, now: u64) -> Result<WasteEntry, InventoryError> {
+        let item = self.items.get(&item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        Validator::new()
+            .check(!matches!(item.unit, Unit::Pack { .. }), "item_id", "is a pack; write off its base item")
+            .check(!self.bundles.definitions.contains_key(&item_id), "item_id", "is a bundle; write off its components")
+            .finish()?;
+        if item.quantity < quantity {
+            return Err(InventoryError::InsufficientStock { item_id, available: item.quantity, requested: quantity });
+        }
+        let new_quantity = item.quantity - quantity;
+        let retail_value = self.config.round_amount(item.line_total(quantity));
+        let units_per_cost_unit = item.unit.stock_units_per_price_unit();
+        let cost = self.costing.consume(item_id, quantity, units_per_cost_unit).map(|cost| self.config.round_amount(cost));
+        let entry = WasteEntry {
+            id: self.waste.entries.len() as u64,
+            item_id,
+            quantity,
+            cause,
+            cost,
+            retail_value,
+            recorded_by,
+            timestamp: now,
+        };
+        self.waste.entries.push(entry.clone());
+        let log = format!("Item {} had {} units written off as {:?} at {}", item_id, quantity, cause, SupermarketManager::get_current_time());
+        self.logs.push(log);
+        self.adjust_item_quantity(item_id, new_quantity, AdjustmentReason::Waste);
+        Ok(entry)
+    }
+
+    /// Waste quantity and value per cause and category of the write-offs in `from..to`
+    pub fn waste_report(&self, from: u64, to: u64) -> WasteReport {
+        let mut report = WasteReport {
+            from,
+            to,
+            causes: BTreeMap::new(),
+            categories: BTreeMap::new(),
+            total: WasteLine::default(),
+            currency: self.config.currency_code.clone(),
+        };
+        for entry in self.waste.entries.iter().filter(|entry| (from..to).contains(&entry.timestamp)) {
+            let category = self.items
+                .get(&entry.item_id)
+                .and_then(|item| item.category.clone())
+                .unwrap_or_else(|| UNCATEGORIZED.to_string());
+            report.total.add(entry);
+            report.causes.entry(entry.cause).or_default().add(entry);
+            report.categories.entry(category).or_default().add(entry);
+        }
+        report
+    }
+}
+
+// Writes stock off as waste, e.g. expired or damaged goods, and takes it out of stock.
+// This function is marked as `#[update]` because it modifies state.
+// A repeated `idempotency_key` from the same caller is ignored rather than applied twice.
+#[update(guard = "rate_limit")]
+fn record_waste(item_id: u32, qty: u32, cause: WasteCause, idempotency_key: Option<String>) -> Result<WasteEntry, InventoryError> {
+    require_permission("record_waste", Permission::RecordWaste)?;
+    run_once("record_waste", idempotency_key, || {
+        Validator::new().check(qty > 0, "qty", "must be positive").finish()?;
+        INVENTORY_MANAGER.with(|inventory| {
+            inventory.borrow_mut().record_waste(item_id, qty, cause, ic_cdk::caller(), ic_cdk::api::time())
+        })
+    })
+}
+
+// Retrieves waste quantity, cost and retail value per cause and per category from `from` up to
+// `to`, in nanoseconds since the Unix epoch. Amounts are in the store's currency, or in
+// `currency` at its latest cached exchange rate.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_waste_report(from: u64, to: u64, currency: Option<String>) -> Result<WasteReport, InventoryError> {
+    require_reader("get_waste_report", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let inventory = inventory.borrow();
+        let mut report = inventory.waste_report(from, to);
+        if let Some(currency) = currency {
+            let rate = inventory.exchange_rate(&currency, ic_cdk::api::time())?;
+            report.convert(currency, |amount| inventory.convert_amount(amount, &rate));
+        }
+        Ok(report)
+    })
+}
+
+// Retrieves the write-offs of one item, oldest first.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_item_waste(item_id: u32) -> Result<Vec<WasteEntry>, InventoryError> {
+    require_reader("get_item_waste", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().waste.entries.iter().filter(|entry| entry.item_id == item_id).cloned().collect())
+    })
+}
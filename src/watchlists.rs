@@ -0,0 +1,167 @@
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Principal};
+use std::collections::BTreeMap;
+
+use crate::access::{require_caller, Role};
+use crate::breakglass::require_reader;
+use crate::promotions::PromotionStatus;
+use crate::ratelimit::rate_limit;
+use crate::webhooks::WebhookEvent;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const MAX_WATCHES_PER_CUSTOMER: usize = 200; // Anyone may watch items, so each watchlist is capped
+
+/// An item on a customer's watchlist
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Watch {
+    pub item_id: u32,
+    pub customer: Principal,
+    pub watched_at: u64,           // Time the item was added in nanoseconds since the Unix epoch
+    pub watched_price: f64,        // Effective price when the item was added
+    pub last_alerted: Option<f64>, // Effective price of the latest drop the customer was alerted to
+}
+
+/// Customers' watchlists, keyed by (item ID, customer) so an item's watchers are read as a range
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Watchlists {
+    pub watches: BTreeMap<(u32, Principal), Watch>,
+}
+
+impl SupermarketManager {
+    /// Lowest price an item currently sells for in store: its own price less the best
+    /// discount of an approved bundle promotion it is the discounted item of
+    pub fn effective_price(&self, item_id: u32) -> Option<f64> {
+        let item = self.items.get(&item_id)?;
+        let discount_pct = self.promotions.promotions
+            .values()
+            .filter(|p| p.status == PromotionStatus::Approved && p.discounted_item_id == item_id)
+            .map(|p| p.discount_pct)
+            .max()
+            .unwrap_or(0);
+        Some(self.config.round_amount(item.price * (100 - discount_pct.min(100)) as f64 / 100.0))
+    }
+
+    /// Alerts an item's watchers if its effective price fell below `old_price`
+    /// - `old_price`: Effective price before the change; None for an item that did not exist
+    pub fn alert_price_drop(&mut self, item_id: u32, old_price: Option<f64>) {
+        let (Some(old_price), Some(new_price)) = (old_price, self.effective_price(item_id)) else {
+            return;
+        };
+        if new_price >= old_price {
+            return;
+        }
+        let watchers: Vec<Principal> = self.watchlists.watches
+            .range((item_id, Principal::management_canister())..) // The empty principal sorts first
+            .take_while(|((id, _), _)| *id == item_id)
+            .map(|(&(_, customer), _)| customer)
+            .collect();
+        for customer in watchers {
+            if let Some(watch) = self.watchlists.watches.get_mut(&(item_id, customer)) {
+                watch.last_alerted = Some(new_price);
+            }
+            self.notify_webhooks(WebhookEvent::PriceDrop { item_id, old_price, new_price, customer });
+        }
+    }
+
+    /// Number of customers watching each watched item, most watched first
+    pub fn watcher_counts(&self) -> Vec<(u32, u64)> {
+        let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+        for &(item_id, _) in self.watchlists.watches.keys() {
+            *counts.entry(item_id).or_default() += 1;
+        }
+        let mut counts: Vec<(u32, u64)> = counts.into_iter().collect();
+        counts.sort_by_key(|&(item_id, count)| (std::cmp::Reverse(count), item_id));
+        counts
+    }
+}
+
+// Adds an item to the caller's watchlist so they are alerted when its price drops.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn watch_item(item_id: u32) -> Result<Watch, InventoryError> {
+    let customer = ic_cdk::caller();
+    if customer == Principal::anonymous() {
+        return Err(InventoryError::Unauthorized { msg: "Sign in to keep a watchlist".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let watched_price = inventory.effective_price(item_id).ok_or_else(|| InventoryError::NotFound {
+            msg: format!("Item {} not found", item_id),
+        })?;
+        if let Some(watch) = inventory.watchlists.watches.get(&(item_id, customer)) {
+            return Ok(watch.clone());
+        }
+        let watching = inventory.watchlists.watches.keys().filter(|(_, principal)| *principal == customer).count();
+        if watching >= MAX_WATCHES_PER_CUSTOMER {
+            return Err(InventoryError::Conflict {
+                msg: format!("A watchlist holds at most {} items", MAX_WATCHES_PER_CUSTOMER),
+            });
+        }
+        let watch = Watch { item_id, customer, watched_at: ic_cdk::api::time(), watched_price, last_alerted: None };
+        inventory.watchlists.watches.insert((item_id, customer), watch.clone());
+        Ok(watch)
+    })
+}
+
+// Removes an item from the caller's watchlist. Needs no role, as it only touches the caller's own.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn unwatch_item(item_id: u32) -> Result<(), InventoryError> {
+    let customer = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        match inventory.borrow_mut().watchlists.watches.remove(&(item_id, customer)) {
+            Some(_) => Ok(()),
+            None => Err(InventoryError::NotFound { msg: format!("Item {} is not on your watchlist", item_id) }),
+        }
+    })
+}
+
+// Retrieves the caller's watchlist.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_my_watchlist() -> Vec<Watch> {
+    let customer = ic_cdk::caller();
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow().watchlists.watches.values().filter(|watch| watch.customer == customer).cloned().collect()
+    })
+}
+
+// Retrieves every watch, optionally only those on one item or of one customer.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_watches(item_id: Option<u32>, customer: Option<Principal>) -> Result<Vec<Watch>, InventoryError> {
+    require_reader("get_watches", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().watchlists.watches
+            .values()
+            .filter(|watch| item_id.is_none_or(|id| watch.item_id == id))
+            .filter(|watch| customer.is_none_or(|customer| watch.customer == customer))
+            .cloned()
+            .collect())
+    })
+}
+
+// Removes an item from a customer's watchlist, e.g. on the customer's request.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_watch(item_id: u32, customer: Principal) -> Result<(), InventoryError> {
+    require_caller("remove_watch", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        if inventory.watchlists.watches.remove(&(item_id, customer)).is_none() {
+            return Err(InventoryError::NotFound { msg: format!("{} is not watching item {}", customer, item_id) });
+        }
+        let log = format!("Watch of item {} by {} removed at {}", item_id, customer, SupermarketManager::get_current_time());
+        inventory.logs.push(log);
+        Ok(())
+    })
+}
+
+// Retrieves how many customers watch each item, most watched first, for merchandising.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_watcher_counts() -> Result<Vec<(u32, u64)>, InventoryError> {
+    require_reader("get_watcher_counts", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| Ok(inventory.borrow().watcher_counts()))
+}
@@ -0,0 +1,363 @@
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext,
+};
+use ic_cdk_macros::{update, query};
+use serde::{Serialize, Deserialize};
+use candid::{CandidType, Nat, Principal};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use crate::access::{require_caller, Role};
+use crate::back_in_stock::Subscriber;
+use crate::breakglass::require_reader;
+use crate::bus::BusPayload;
+use crate::cost::{measured, HeavyOperation};
+use crate::load::admit_expensive_call;
+use crate::ratelimit::rate_limit;
+use crate::{InventoryError, SupermarketManager, INVENTORY_MANAGER};
+
+const DELIVERY_INTERVAL_SECS: u64 = 30;     // How often the delivery queue is processed
+const BASE_BACKOFF_NANOS: u64 = 30_000_000_000; // Delay before the first retry, doubled on each later one
+const MAX_RESPONSE_BYTES: u64 = 2048;       // Only the status code matters, so keep responses small
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Kinds of event a webhook can subscribe to
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WebhookEventKind {
+    LowStock,
+    ExpiredItem,
+    LargeAdjustment,
+    AccessAnomaly,
+    BreakGlassRequested,
+    PaymentDiscrepancy,
+    BackInStock,
+    PriceDrop,
+    TemperatureBreach,
+}
+
+/// A critical event reported to webhooks
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum WebhookEvent {
+    LowStock { item_id: u32, quantity: u32, threshold: u32 },          // Stock fell to or below its reorder threshold, or the store's default one
+    ExpiredItem { item_id: u32, expiration_date: u64 },                // An item in stock is past its expiration date
+    LargeAdjustment { item_id: u32, old_quantity: u32, new_quantity: u32 }, // A manual quantity change exceeded the configured size
+    AccessAnomaly { principal: Principal, endpoint: String, reason: String }, // A principal's calls were flagged as unusual
+    BreakGlassRequested { recovery_principal: Principal, activates_at: u64 }, // Emergency read access was requested
+    PaymentDiscrepancy { payment_id: Option<u64>, block_index: u64, issue: String }, // A payment does not match the ledger
+    BackInStock { item_id: u32, quantity: u32, subscriber: Subscriber }, // A delivery brought back an item a customer is waiting for
+    PriceDrop { item_id: u32, old_price: f64, new_price: f64, customer: Principal }, // The effective price of an item a customer watches fell
+    TemperatureBreach { location: String, celsius: f64, min_celsius: f64, max_celsius: f64 }, // A monitored location's reading left its range
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookEvent::LowStock { .. } => WebhookEventKind::LowStock,
+            WebhookEvent::ExpiredItem { .. } => WebhookEventKind::ExpiredItem,
+            WebhookEvent::LargeAdjustment { .. } => WebhookEventKind::LargeAdjustment,
+            WebhookEvent::AccessAnomaly { .. } => WebhookEventKind::AccessAnomaly,
+            WebhookEvent::BreakGlassRequested { .. } => WebhookEventKind::BreakGlassRequested,
+            WebhookEvent::PaymentDiscrepancy { .. } => WebhookEventKind::PaymentDiscrepancy,
+            WebhookEvent::BackInStock { .. } => WebhookEventKind::BackInStock,
+            WebhookEvent::PriceDrop { .. } => WebhookEventKind::PriceDrop,
+            WebhookEvent::TemperatureBreach { .. } => WebhookEventKind::TemperatureBreach,
+        }
+    }
+}
+
+/// A URL registered to receive event notifications
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Webhook {
+    pub id: u64,                          // Unique ID of the webhook
+    pub url: String,                      // HTTPS URL the notifications are POSTed to
+    pub events: Vec<WebhookEventKind>,    // Events the webhook is notified about
+    pub registered_by: Principal,         // Manager who registered the webhook
+}
+
+/// State of a single notification delivery
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,   // Waiting for its first attempt or a retry
+    Delivered, // The endpoint answered with a 2xx status
+    Failed,    // Every attempt failed
+}
+
+/// One notification to one webhook, with its delivery attempts
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct WebhookDelivery {
+    pub id: u64,                    // Unique ID of the delivery
+    pub webhook_id: u64,            // Webhook the notification is for
+    pub event: WebhookEvent,        // Event being reported
+    pub payload: String,            // JSON body that is POSTed
+    pub status: DeliveryStatus,     // Current delivery state
+    pub attempts: u32,              // Number of attempts made so far
+    pub next_attempt_at: u64,       // Earliest time of the next attempt in nanoseconds since the Unix epoch
+    pub last_error: Option<String>, // Reason the most recent attempt failed
+}
+
+/// Settings for webhook events and delivery
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct WebhookConfig {
+    pub large_adjustment_threshold: u32, // Quantity change at or above which a LargeAdjustment is reported
+    pub max_attempts: u32,               // Attempts made before a delivery is marked as failed
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            large_adjustment_threshold: 100,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// Checks that the settings are usable
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        if self.max_attempts == 0 {
+            return Err(InventoryError::InvalidInput { msg: "max_attempts must be at least 1".to_string() });
+        }
+        Ok(())
+    }
+}
+
+/// Registered webhooks and the queue and log of their deliveries
+#[derive(Serialize, Deserialize, CandidType, Default)]
+pub struct Webhooks {
+    pub config: WebhookConfig,                      // Event and delivery settings
+    pub hooks: BTreeMap<u64, Webhook>,              // Registered webhooks keyed by ID
+    pub deliveries: BTreeMap<u64, WebhookDelivery>, // Every delivery keyed by ID
+    pub expired_reported: HashSet<u32>,             // Items already reported as expired
+    pub next_webhook_id: u64,                       // ID handed to the next registered webhook
+    pub last_expired_scan: Option<u64>,             // Time the expired-item scan last ran
+}
+
+impl SupermarketManager {
+    /// Queues a notification for every webhook subscribed to the event
+    /// - `event`: The event to report
+    pub fn notify_webhooks(&mut self, event: WebhookEvent) {
+        self.publish_to_bus(BusPayload::Alert(event.clone()), ic_cdk::api::time());
+        let body = serde_json::json!({
+            "event": &event,
+            "timestamp": SupermarketManager::get_current_time(),
+        })
+        .to_string();
+        let hooks = &self.webhooks.hooks;
+        let deliveries = &mut self.webhooks.deliveries;
+        for hook in hooks.values().filter(|h| h.events.contains(&event.kind())) {
+            let id = deliveries.len() as u64;
+            deliveries.insert(id, WebhookDelivery {
+                id,
+                webhook_id: hook.id,
+                event: event.clone(),
+                payload: body.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                next_attempt_at: 0, // Due straight away
+                last_error: None,
+            });
+        }
+    }
+
+    /// Reports low stock and large adjustments after an item's quantity changed
+    /// - `item_id`: The item whose quantity changed
+    /// - `old_quantity`: The quantity before the change
+    /// - `new_quantity`: The quantity after the change
+    /// - `manual`: Whether the change was a manual adjustment rather than a sale
+    pub fn check_stock_events(&mut self, item_id: u32, old_quantity: u32, new_quantity: u32, manual: bool) {
+        if manual && old_quantity.abs_diff(new_quantity) >= self.webhooks.config.large_adjustment_threshold {
+            self.notify_webhooks(WebhookEvent::LargeAdjustment { item_id, old_quantity, new_quantity });
+        }
+        let threshold = self.reorder.rules.get(&item_id).map(|rule| rule.threshold).or(self.config.default_low_stock_threshold);
+        if let Some(threshold) = threshold {
+            if old_quantity > threshold && new_quantity <= threshold { // Only report when crossing the threshold
+                self.notify_webhooks(WebhookEvent::LowStock { item_id, quantity: new_quantity, threshold });
+            }
+        }
+    }
+
+    /// Reports every item in stock that has passed its expiration date, once per item
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    pub fn check_expired_items(&mut self, now: u64) {
+        let now_secs = now / NANOS_PER_SEC;
+        let expired: Vec<(u32, u64)> = self.items
+            .values()
+            .filter(|item| !item.archived && item.quantity > 0 && item.expiration_date <= now_secs)
+            .filter(|item| !self.webhooks.expired_reported.contains(&item.id))
+            .map(|item| (item.id, item.expiration_date))
+            .collect();
+        for (item_id, expiration_date) in expired {
+            self.webhooks.expired_reported.insert(item_id);
+            self.notify_webhooks(WebhookEvent::ExpiredItem { item_id, expiration_date });
+        }
+        self.webhooks.last_expired_scan = Some(now);
+    }
+
+    /// Claims every delivery that is due, scheduling its retry before the attempt is made
+    /// - `now`: The current time in nanoseconds since the Unix epoch
+    ///
+    /// Returns (delivery ID, URL, payload) for each attempt to make.
+    fn take_due_deliveries(&mut self, now: u64) -> Vec<(u64, String, String)> {
+        let hooks = &self.webhooks.hooks;
+        let mut due = Vec::new();
+        for delivery in self.webhooks.deliveries.values_mut() {
+            if delivery.status != DeliveryStatus::Pending || delivery.next_attempt_at > now {
+                continue;
+            }
+            let Some(hook) = hooks.get(&delivery.webhook_id) else {
+                delivery.status = DeliveryStatus::Failed; // The webhook was removed while the delivery was queued
+                delivery.last_error = Some("Webhook removed".to_string());
+                continue;
+            };
+            let backoff = BASE_BACKOFF_NANOS.saturating_mul(1u64 << delivery.attempts.min(16));
+            delivery.attempts += 1;
+            delivery.next_attempt_at = now.saturating_add(backoff);
+            due.push((delivery.id, hook.url.clone(), delivery.payload.clone()));
+        }
+        due
+    }
+
+    /// Records the result of a delivery attempt
+    /// - `delivery_id`: The delivery that was attempted
+    /// - `result`: Ok on a 2xx response, or the reason the attempt failed
+    fn finish_delivery(&mut self, delivery_id: u64, result: Result<(), String>) {
+        let max_attempts = self.webhooks.config.max_attempts;
+        if let Some(delivery) = self.webhooks.deliveries.get_mut(&delivery_id) {
+            match result {
+                Ok(()) => {
+                    delivery.status = DeliveryStatus::Delivered;
+                    delivery.last_error = None;
+                }
+                Err(error) => {
+                    if delivery.attempts >= max_attempts {
+                        delivery.status = DeliveryStatus::Failed;
+                    }
+                    delivery.last_error = Some(error);
+                }
+            }
+        }
+    }
+}
+
+/// POSTs a JSON payload to an HTTPS URL, succeeding on a 2xx response
+pub(crate) async fn post_notification(url: String, payload: String) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() }],
+        body: Some(payload.into_bytes()),
+        transform: Some(TransformContext::new(webhook_transform, vec![])),
+    };
+    let success = Nat::from(200u32)..Nat::from(300u32);
+    match http_request(request).await {
+        Ok((response,)) if success.contains(&response.status) => Ok(()),
+        Ok((response,)) => Err(format!("Endpoint answered with status {}", response.status)),
+        Err((code, msg)) => Err(format!("HTTPS outcall failed: {:?} {}", code, msg)),
+    }
+}
+
+/// Sends every due delivery and records the outcomes
+fn process_deliveries() {
+    let due = INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().take_due_deliveries(ic_cdk::api::time())
+    });
+    for (delivery_id, url, payload) in due {
+        ic_cdk::spawn(async move {
+            let result = post_notification(url, payload).await;
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().finish_delivery(delivery_id, result);
+            });
+        });
+    }
+}
+
+/// Registers the timers that scan for expired items and deliver queued notifications
+pub fn start_webhook_timers() {
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
+        measured(HeavyOperation::ExpiredScan, || {
+            INVENTORY_MANAGER.with(|inventory| {
+                inventory.borrow_mut().check_expired_items(ic_cdk::api::time());
+            });
+        });
+    });
+    ic_cdk::timer::set_timer_interval(Duration::from_secs(DELIVERY_INTERVAL_SECS), process_deliveries);
+}
+
+// Strips everything but the status from webhook responses so replicas agree on the result.
+// This function is marked as `#[query]` because it is called by the management canister.
+#[query]
+fn webhook_transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Registers a URL to be notified about the given events.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn register_webhook(url: String, events: Vec<WebhookEventKind>) -> Result<u64, InventoryError> {
+    require_caller("register_webhook", Role::Manager)?;
+    if !url.starts_with("https://") {
+        return Err(InventoryError::InvalidInput { msg: "Webhook URLs must use https://".to_string() });
+    }
+    if events.is_empty() {
+        return Err(InventoryError::InvalidInput { msg: "A webhook must subscribe to at least one event".to_string() });
+    }
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        let id = inventory.webhooks.next_webhook_id;
+        inventory.webhooks.next_webhook_id += 1;
+        inventory.webhooks.hooks.insert(id, Webhook { id, url, events, registered_by: ic_cdk::caller() });
+        Ok(id)
+    })
+}
+
+// Removes a registered webhook.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn remove_webhook(id: u64) -> Result<(), InventoryError> {
+    require_caller("remove_webhook", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        inventory.borrow_mut().webhooks.hooks.remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| InventoryError::NotFound { msg: format!("Webhook {} not found", id) })
+    })
+}
+
+// Retrieves the registered webhooks.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_webhooks() -> Result<Vec<Webhook>, InventoryError> {
+    require_reader("get_webhooks", Role::Manager)?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().webhooks.hooks.values().cloned().collect())
+    })
+}
+
+// Replaces the webhook event and delivery settings.
+// This function is marked as `#[update]` because it modifies state.
+#[update(guard = "rate_limit")]
+fn set_webhook_config(config: WebhookConfig) -> Result<(), InventoryError> {
+    require_caller("set_webhook_config", Role::Manager)?;
+    config.validate()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        let mut inventory = inventory.borrow_mut();
+        inventory.dao.ensure_direct_change_allowed()?;
+        inventory.webhooks.config = config;
+        Ok(())
+    })
+}
+
+// Retrieves every webhook delivery with its status and attempts.
+// This function is marked as `#[query]` because it only reads state.
+#[query]
+fn get_webhook_delivery_log() -> Result<Vec<WebhookDelivery>, InventoryError> {
+    require_reader("get_webhook_delivery_log", Role::Manager)?;
+    admit_expensive_call()?;
+    INVENTORY_MANAGER.with(|inventory| {
+        Ok(inventory.borrow().webhooks.deliveries.values().cloned().collect())
+    })
+}